@@ -18,6 +18,9 @@ pub const BLOCK_SPCOM_RANGE: Range<usize> = 8..16;
 
 pub const NON_MP_OCTETS: u8 = 138;
 
+// blocks from the server carry the generation of their msg_id between the header and the payload, big endian, in the clear
+pub const BLOCK_GENERATION_OCTETS: usize = 4;
+
 #[derive(Clone,Debug)]
 pub struct Block {
     pub addr: String,
@@ -32,6 +35,15 @@ impl Block {
         }
     }
 
+    // Position of the block within its message. The mp_first block uses MPIDX for the block count, so every other block
+    // carries its position - 1 there
+    pub fn position(&self) -> u8 {
+        if self.data.get(BLOCK_ISMLP_RANGE).unwrap().load::<u8>() == 0 || self.data.get(BLOCK_MPNO0_RANGE).unwrap().load::<u8>() == 1 {
+            return 0;
+        }
+        self.data.get(BLOCK_MPIDX_RANGE).unwrap().load::<u8>().wrapping_add(1)
+    }
+
     pub fn block_size_validation(&self) -> bool {
 
         if self.data.len() < 16 {
@@ -0,0 +1,311 @@
+/*
+    Command line interface for the server binary
+    Every option can be given as a flag or in the --config file (see config.rs), flags win. Whatever is left over gets the defaults below
+*/
+
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::config::{self, ConfigError, ConfigFile};
+use crate::credential_manager;
+use crate::media;
+use crate::retry;
+use crate::smpp;
+use crate::user;
+
+const DEFAULT_CREDFILE: &str = "credfile.cfg";
+const DEFAULT_HOMESERVER_CREDFILE: &str = "homeserver_creds.cfg";
+const DEFAULT_MODEM_BAUD: u32 = 115200;
+const SOCK_IN_NAME: &str = "boost_sin.sock";
+const SOCK_OUT_NAME: &str = "boost_sout.sock";
+pub const SESSION_DB_NAME: &str = "sessions.db";
+pub const MATRIX_STORE_NAME: &str = "matrix_store";
+
+#[derive(Parser, Debug)]
+#[command(name = "boost", version, about = "Matrix <-> SMS bridge server")]
+pub struct Cli {
+    /// TOML file with defaults for any of the options below
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Bridge bot credential file
+    #[arg(long, global = true)]
+    pub credfile: Option<PathBuf>,
+
+    /// Homeserver credential file
+    #[arg(long, global = true)]
+    pub homeserver_creds: Option<PathBuf>,
+
+    /// Directory for the session store, matrix encryption keys and transport sockets
+    #[arg(long, global = true)]
+    pub data_dir: Option<PathBuf>,
+
+    /// Log filter, as for RUST_LOG (eg. "debug" or "boost=trace") [default: $RUST_LOG, or info]
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+
+    #[command(flatten)]
+    pub transport: TransportArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Run the bridge (the default)
+    Run,
+    /// Load and validate the configuration without connecting to anything
+    CheckConfig,
+    /// Print the bcrypt hash of a password, for the password field of the credential file
+    HashPassword {
+        /// Prompted for if not given (or read from stdin when it is not a terminal)
+        password: Option<String>,
+    },
+    /// Manage the bridge bots, in the config file's [bots] if it has one or the credential file otherwise
+    #[command(subcommand, alias = "credentials")]
+    Creds(CredsCommand),
+    /// Print a config file with the legacy credential files folded into its [homeserver] and [bots]
+    ConvertConfig {
+        /// Write to this file instead of stdout (it must not exist yet)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum CredsCommand {
+    /// List the bridge bots in the credential file
+    List,
+    /// Check the credential file the same way the server does on startup
+    Validate,
+    /// Add a bridge bot, prompting for its password
+    Add(AddCredential),
+    /// Remove a bridge bot by bot address or block name
+    Remove {
+        bot_address: String,
+    },
+}
+
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+pub struct AddCredential {
+    /// Puppeting bot on the homeserver, eg. discordbot@matrix.example.com
+    #[arg(long)]
+    pub bot_address: String,
+    /// External platform the bot bridges to
+    #[arg(long)]
+    pub service_name: String,
+    /// Username the phone authenticates to this bot with
+    #[arg(long)]
+    pub username: String,
+    #[arg(long)]
+    pub dm_space_id: String,
+    #[arg(long)]
+    pub admin_room_id: String,
+    /// Homeserver section the bot lives on, needed when there is more than one
+    #[arg(long)]
+    pub homeserver: Option<String>,
+    /// Block name in the file [default: <service-name>_<username>]
+    #[arg(long)]
+    pub name: Option<String>,
+    /// Use an existing bcrypt hash instead of prompting for the password
+    #[arg(long)]
+    pub password_hash: Option<String>,
+}
+
+#[derive(ValueEnum, Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    Socket,
+    Modem,
+    Smpp,
+}
+
+#[derive(clap::Args, Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TransportArgs {
+    /// SMS transport to use [default: socket]
+    #[arg(long = "transport", global = true, value_enum)]
+    #[serde(rename = "kind")]
+    pub kind: Option<TransportKind>,
+
+    /// Socket transport: datagram socket we receive on [default: <data-dir>/boost_sin.sock]
+    #[arg(long, global = true)]
+    pub sock_in: Option<PathBuf>,
+    /// Socket transport: datagram socket we send to [default: <data-dir>/boost_sout.sock]
+    #[arg(long, global = true)]
+    pub sock_out: Option<PathBuf>,
+
+    /// Modem transport: serial device of the modem
+    #[arg(long = "modem-tty", global = true)]
+    #[serde(rename = "tty")]
+    pub modem_tty: Option<String>,
+    /// Modem transport: baud rate [default: 115200]
+    #[arg(long = "modem-baud", global = true)]
+    #[serde(rename = "baud")]
+    pub modem_baud: Option<u32>,
+
+    /// SMPP transport: host:port of the SMSC
+    #[arg(long = "smpp-address", global = true)]
+    #[serde(rename = "address")]
+    pub smpp_address: Option<String>,
+    /// SMPP transport: system_id to bind with
+    #[arg(long = "smpp-system-id", global = true)]
+    #[serde(rename = "system_id")]
+    pub smpp_system_id: Option<String>,
+    /// SMPP transport: password to bind with
+    #[arg(long = "smpp-password", global = true)]
+    #[serde(rename = "password")]
+    pub smpp_password: Option<String>,
+    /// SMPP transport: our number, as the phone sees it
+    #[arg(long = "smpp-source-addr", global = true)]
+    #[serde(rename = "source_addr")]
+    pub smpp_source_addr: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum TransportSettings {
+    Socket { sock_in: PathBuf, sock_out: PathBuf },
+    Modem { tty: String, baud: u32 },
+    Smpp(smpp::SmppConfig),
+}
+
+// Everything the server needs to start, with the config file and defaults applied
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub config: Option<PathBuf>,
+    pub credfile: PathBuf,
+    pub homeserver_creds: PathBuf,
+    pub data_dir: PathBuf,
+    pub log_level: Option<String>,  // None leaves it to RUST_LOG
+    pub transport: TransportSettings,
+    pub bots: Option<BTreeMap<String, config::BotConfig>>,  // [bots] of the config file, used instead of credfile
+    pub homeservers: Option<BTreeMap<String, config::HomeserverConfig>>,  // [homeservers] of the config file, used instead of homeserver_creds
+    pub retry: config::RetryConfig,
+    pub queue: config::QueueConfig,
+    pub media: config::MediaConfig,
+}
+
+fn load_config_file(cli: &Cli) -> Result<ConfigFile, ConfigError> {
+    match &cli.config {
+        Some(path) => ConfigFile::load(path),
+        None => Ok(ConfigFile::default()),
+    }
+}
+
+// Just where the bots live, for subcommands that do not care about the rest of the settings
+pub fn resolve_bot_source(cli: &Cli) -> Result<config::BotSource, ConfigError> {
+    if let Some(credfile) = &cli.credfile {
+        return Ok(config::BotSource::Credfile(credfile.clone()));
+    }
+    let file = load_config_file(cli)?;
+    match (&cli.config, file.bots) {
+        (Some(config_path), Some(_)) => Ok(config::BotSource::Config(config_path.clone())),
+        _ => Ok(config::BotSource::Credfile(file.credfile.unwrap_or_else(|| PathBuf::from(DEFAULT_CREDFILE)))),
+    }
+}
+
+// The --config file (if any) with the legacy credential files it or the flags point at folded in, as TOML
+pub fn convert_config(cli: &Cli) -> Result<String, ConfigError> {
+    let file = load_config_file(cli)?;
+    let credfile = cli.credfile.clone().or(file.credfile.clone()).unwrap_or_else(|| PathBuf::from(DEFAULT_CREDFILE));
+    let homeserver_creds = cli.homeserver_creds.clone().or(file.homeserver_creds.clone()).unwrap_or_else(|| PathBuf::from(DEFAULT_HOMESERVER_CREDFILE));
+    config::to_toml(&config::convert_legacy(file, &credfile, &homeserver_creds)?)
+}
+
+fn required<T>(value: Option<T>, flag: &str) -> anyhow::Result<T> {
+    value.ok_or_else(|| anyhow::Error::msg(format!("--{} is required for this transport", flag)))
+}
+
+impl Settings {
+    pub fn resolve(cli: &Cli) -> anyhow::Result<Settings> {
+        let file = load_config_file(cli)?;
+
+        let data_dir = cli.data_dir.clone().or(file.data_dir).unwrap_or_else(|| PathBuf::from("."));
+        let (args, file_args) = (&cli.transport, file.transport);
+        let transport = match args.kind.or(file_args.kind).unwrap_or(TransportKind::Socket) {
+            TransportKind::Socket => TransportSettings::Socket {
+                sock_in: args.sock_in.clone().or(file_args.sock_in).unwrap_or_else(|| data_dir.join(SOCK_IN_NAME)),
+                sock_out: args.sock_out.clone().or(file_args.sock_out).unwrap_or_else(|| data_dir.join(SOCK_OUT_NAME)),
+            },
+            TransportKind::Modem => TransportSettings::Modem {
+                tty: required(args.modem_tty.clone().or(file_args.modem_tty), "modem-tty")?,
+                baud: args.modem_baud.or(file_args.modem_baud).unwrap_or(DEFAULT_MODEM_BAUD),
+            },
+            TransportKind::Smpp => TransportSettings::Smpp(smpp::SmppConfig::new(
+                &required(args.smpp_address.clone().or(file_args.smpp_address), "smpp-address")?,
+                &required(args.smpp_system_id.clone().or(file_args.smpp_system_id), "smpp-system-id")?,
+                &required(args.smpp_password.clone().or(file_args.smpp_password), "smpp-password")?,
+                &required(args.smpp_source_addr.clone().or(file_args.smpp_source_addr), "smpp-source-addr")?,
+            )),
+        };
+
+        Ok(Settings {
+            config: cli.config.clone(),
+            credfile: cli.credfile.clone().or(file.credfile).unwrap_or_else(|| PathBuf::from(DEFAULT_CREDFILE)),
+            homeserver_creds: cli.homeserver_creds.clone().or(file.homeserver_creds).unwrap_or_else(|| PathBuf::from(DEFAULT_HOMESERVER_CREDFILE)),
+            data_dir,
+            log_level: cli.log_level.clone().or(file.log_level),
+            transport,
+            // a credential file given on the command line wins over the config file's own bots/homeserver
+            bots: if cli.credfile.is_some() { None } else { file.bots },
+            homeservers: if cli.homeserver_creds.is_some() { None } else { file.homeservers },
+            retry: file.retry,
+            queue: file.queue,
+            media: file.media,
+        })
+    }
+
+    pub fn bot_credentials(&self) -> Result<Vec::<credential_manager::BridgeBotCredentials>, ConfigError> {
+        match &self.bots {
+            Some(bots) => Ok(bots.values().map(config::BotConfig::credentials).collect()),
+            None => credential_manager::load_credential_file(&self.credfile),
+        }
+    }
+
+    // Where bot_credentials() came from, for writing back a rotated password
+    pub fn bot_source(&self) -> config::BotSource {
+        match (&self.bots, &self.config) {
+            (Some(_), Some(config_path)) => config::BotSource::Config(config_path.clone()),
+            _ => config::BotSource::Credfile(self.credfile.clone()),
+        }
+    }
+
+    pub fn homeservers(&self) -> Result<credential_manager::NamedHomeservers, ConfigError> {
+        match &self.homeservers {
+            Some(homeservers) => Ok(homeservers.iter().map(|(name, homeserver)| (name.clone(), homeserver.credentials())).collect()),
+            None => credential_manager::load_homeservers(&self.homeserver_creds),
+        }
+    }
+
+    // Bots and the homeservers they live on, checked against each other
+    pub fn credentials(&self) -> Result<(Vec::<credential_manager::BridgeBotCredentials>, credential_manager::NamedHomeservers), ConfigError> {
+        let bot_credentials = self.bot_credentials()?;
+        let homeservers = self.homeservers()?;
+        let homeserver_names: Vec<&str> = homeservers.iter().map(|(name, _)| name.as_str()).collect();
+        config::check_bot_homeservers(&bot_credentials, &homeserver_names)?;
+        Ok((bot_credentials, homeservers))
+    }
+
+    pub fn retry_policy(&self) -> retry::RetryPolicy {
+        self.retry.apply(retry::RetryPolicy::default())
+    }
+
+    pub fn user_policies(&self) -> user::UserPolicies {
+        user::UserPolicies {
+            queue: self.queue.apply(user::QueuePolicy::default()),
+            media: self.media.apply(media::MediaPolicy::default()),
+        }
+    }
+
+    pub fn session_db_path(&self) -> PathBuf {
+        self.data_dir.join(SESSION_DB_NAME)
+    }
+
+    // matrix-sdk state and crypto stores, one directory per homeserver
+    pub fn matrix_store_dir(&self) -> PathBuf {
+        self.data_dir.join(MATRIX_STORE_NAME)
+    }
+}
@@ -17,7 +17,7 @@ pub enum CommandValue {
     RequestKnownUsers = 7, // Request last N users on a given domain
     SignOut = 14, // Deauthenticate the sender from a given bot (send bot_idx)
     SignOutSuccess = 17,
-    RevokeAllClients = 13, // Revoke _all_ boost clients authentication with a given bot (requires authentication with the bot obv) (send bot address)
+    RevokeAllClients = 13, // Revoke _all_ boost clients authentication with a given bot (requires the bot's username and password again) (send domain_idx, username, password, optionally a new password)
     RequestDomains = 15,
     DomainUpdate = 18,
     ChannelUpdate = 16, // response to RequestKnownUsers
@@ -28,11 +28,17 @@ pub enum CommandValue {
     TargetUserNotFound = 6,
     FindUser = 19, // Find a user by their remote id, add to known_users
     UserFound = 20, // CMD_19 successful! Client should send RequestKnownUsers
+    FetchMedia = 24, // [domain_idx][budget u32][mxc uri] - download a media item and stream it to the client
+    MediaStart = 25, // response to FetchMedia, announces the transfer id and size
+    MediaChunk = 26, // [transfer_id u16][offset u32][bytes] - one part of a media transfer, the next is sent once this is acked
+    CancelMedia = 27, // [transfer_id u16]
     
     // general
     Error = 8,
     InvalidCommand = 9,
     BlockAck = 11,
+    MissingBlocks = 22, // [msg_id][block positions...] the receiver is still waiting on, sender retransmits only those
+    FlushQueue = 23, // Client is back online - resend anything unacked and anything held while it was unreachable
     
     // internal only
     Data = 255,
@@ -51,7 +57,6 @@ impl std::convert::TryFrom<u8> for CommandValue {
         else if command_value == CommandValue::SignOutSuccess as CommandInt {  Ok(CommandValue::SignOutSuccess) }
         else if command_value == CommandValue::RevokeAllClients as CommandInt {  Ok(CommandValue::RevokeAllClients) } // for some reason this is flagged as unreachable
         else if command_value == CommandValue::RequestDomains as CommandInt {  Ok(CommandValue::RequestDomains) }
-        else if command_value == CommandValue::RequestDomains as CommandInt {  Ok(CommandValue::RequestDomains) }
         else if command_value == CommandValue::DomainUpdate as CommandInt {  Ok(CommandValue::DomainUpdate) }
         else if command_value == CommandValue::ChannelUpdate as CommandInt {  Ok(CommandValue::ChannelUpdate) }
 
@@ -60,11 +65,17 @@ impl std::convert::TryFrom<u8> for CommandValue {
         else if command_value == CommandValue::TargetUserNotFound as CommandInt {  Ok(CommandValue::TargetUserNotFound) }
         else if command_value == CommandValue::FindUser as CommandInt {  Ok(CommandValue::FindUser) }
         else if command_value == CommandValue::UserFound as CommandInt {  Ok(CommandValue::UserFound) }
+        else if command_value == CommandValue::FetchMedia as CommandInt {  Ok(CommandValue::FetchMedia) }
+        else if command_value == CommandValue::MediaStart as CommandInt {  Ok(CommandValue::MediaStart) }
+        else if command_value == CommandValue::MediaChunk as CommandInt {  Ok(CommandValue::MediaChunk) }
+        else if command_value == CommandValue::CancelMedia as CommandInt {  Ok(CommandValue::CancelMedia) }
 
         
         else if command_value == CommandValue::Error as CommandInt {  Ok(CommandValue::Error) }
         else if command_value == CommandValue::InvalidCommand as CommandInt {  Ok(CommandValue::InvalidCommand) }
         else if command_value == CommandValue::BlockAck as CommandInt { Ok(CommandValue::BlockAck) }
+        else if command_value == CommandValue::MissingBlocks as CommandInt { Ok(CommandValue::MissingBlocks) }
+        else if command_value == CommandValue::FlushQueue as CommandInt { Ok(CommandValue::FlushQueue) }
         
         else if command_value == CommandValue::Data as CommandInt { Ok(CommandValue::Data) }
         else { Err("Unknown command") }
@@ -0,0 +1,588 @@
+/*
+    Server configuration file (TOML) - homeserver, bridge bots, transport and retry tuning in one place
+    The legacy credfile.cfg / homeserver_creds.cfg INI files are still accepted, they are read into the same types
+    (see parse_legacy_credfile / parse_legacy_homeservers) and can be turned into a config file with convert_legacy
+*/
+
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use crate::cli;
+use crate::credential_manager;
+use crate::media;
+use crate::retry;
+use crate::user;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigErrorKind {
+    Io,  // file could not be read or written
+    Parse,  // not valid TOML / key=value, or does not fit the config layout
+    Invalid,  // well formed, but not usable (duplicates, missing keys, conflicting settings)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub kind: ConfigErrorKind,
+    pub path: Option<PathBuf>,
+    pub line: Option<usize>,  // 1-based
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(kind: ConfigErrorKind, line: Option<usize>, message: impl Into<String>) -> ConfigError {
+        ConfigError { kind, path: None, line, column: None, message: message.into() }
+    }
+
+    fn at(kind: ConfigErrorKind, contents: &str, offset: usize, message: impl Into<String>) -> ConfigError {
+        let (line, column) = line_col(contents, offset);
+        ConfigError { kind, path: None, line: Some(line), column: Some(column), message: message.into() }
+    }
+
+    fn from_toml(contents: &str, why: toml::de::Error) -> ConfigError {
+        match why.span() {
+            Some(span) => ConfigError::at(ConfigErrorKind::Parse, contents, span.start, why.message()),
+            None => ConfigError::new(ConfigErrorKind::Parse, None, why.message()),
+        }
+    }
+
+    // attach the file the error came from, if nothing closer has been attached already
+    pub fn in_file(mut self, path: &Path) -> ConfigError {
+        self.path.get_or_insert_with(|| path.to_path_buf());
+        self
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.path, self.line, self.column) {
+            (Some(path), Some(line), Some(column)) => write!(f, "{}:{}:{}: {}", path.display(), line, column, self.message),
+            (Some(path), Some(line), None) => write!(f, "{}:{}: {}", path.display(), line, self.message),
+            (Some(path), None, _) => write!(f, "{}: {}", path.display(), self.message),
+            (None, Some(line), _) => write!(f, "line {}: {}", line, self.message),
+            (None, None, _) => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn line_col(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset.min(contents.len())];
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+pub fn read_file(path: &Path) -> Result<String, ConfigError> {
+    std::fs::read_to_string(path)
+        .map_err(|why| ConfigError::new(ConfigErrorKind::Io, None, format!("Unable to open file: {}", why)).in_file(path))
+}
+
+fn non_empty<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    if value.trim().is_empty() {
+        return Err(serde::de::Error::custom("value cannot be empty"));
+    }
+    Ok(value)
+}
+
+fn some_non_empty<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Ok(Some(non_empty(deserializer)?))
+}
+
+fn supported_platform<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let value = non_empty(deserializer)?;
+    credential_manager::check_platform(&value).map_err(serde::de::Error::custom)?;
+    Ok(value)
+}
+
+fn non_negative<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    let value = f64::deserialize(deserializer)?;
+    if !value.is_finite() || value < 0.0 {
+        return Err(serde::de::Error::custom("must be a non-negative number"));
+    }
+    Ok(Some(value))
+}
+
+// a year - anything longer is a typo, and a retry delay still has to fit a Duration once added to an Instant
+const MAX_DELAY_SECS: f64 = 365.0 * 24.0 * 60.0 * 60.0;
+
+fn delay_secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    let value = f64::deserialize(deserializer)?;
+    if !(0.0..=MAX_DELAY_SECS).contains(&value) {
+        return Err(serde::de::Error::custom(format!("must be a number of seconds from 0 to {}", MAX_DELAY_SECS)));
+    }
+    Ok(Some(value))
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HomeserverConfig {
+    #[serde(deserialize_with = "non_empty")]
+    pub url: String,
+    #[serde(deserialize_with = "non_empty")]
+    pub username: String,
+    #[serde(default, deserialize_with = "some_non_empty", skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(default, deserialize_with = "some_non_empty", skip_serializing_if = "Option::is_none")]
+    pub access_token: Option<String>,
+    #[serde(default, deserialize_with = "some_non_empty", skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+}
+
+impl HomeserverConfig {
+    pub fn credentials(&self) -> credential_manager::HomeserverCredentials {
+        credential_manager::HomeserverCredentials {
+            address: self.url.clone(),
+            username: self.username.clone(),
+            password: self.password.clone().unwrap_or_default(),
+            access_token: self.access_token.clone(),
+            device_id: self.device_id.clone(),
+        }
+    }
+
+    // A password, or an access token along with the device it was issued for
+    fn check_login(&self, name: &str) -> Result<(), String> {
+        match (&self.password, &self.access_token, &self.device_id) {
+            (_, Some(_), None) => Err(format!("Homeserver {} has an access_token but no device_id", name)),
+            (None, None, _) => Err(format!("Homeserver {} needs a password or an access_token", name)),
+            _ => Ok(()),
+        }
+    }
+}
+
+// The name doubles as the homeserver's directory under the store, which is wiped on a fresh login - it has to stay inside
+fn check_homeserver_name(name: &str) -> Result<(), String> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(component)), None) if component == name => Ok(()),
+        _ => Err(format!("Homeserver name \"{}\" is not a plain directory name", name)),
+    }
+}
+
+// One [bots.<name>] table, same keys as a credfile.cfg block
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BotConfig {
+    #[serde(deserialize_with = "non_empty")]
+    pub bot_address: String,
+    #[serde(deserialize_with = "supported_platform")]
+    pub service_name: String,
+    #[serde(deserialize_with = "non_empty")]
+    pub username: String,
+    #[serde(deserialize_with = "non_empty")]
+    pub password: String,  // bcrypt hash, see credential_manager::hash_password
+    #[serde(deserialize_with = "non_empty")]
+    pub dm_space_id: String,
+    #[serde(deserialize_with = "non_empty")]
+    pub admin_room_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub homeserver: Option<String>,  // [homeservers.<name>] the bot lives on, can be left out if there is only one
+}
+
+impl BotConfig {
+    pub fn credentials(&self) -> credential_manager::BridgeBotCredentials {
+        credential_manager::BridgeBotCredentials::new(
+            self.bot_address.to_lowercase(),
+            self.service_name.clone(),
+            self.username.to_lowercase(),
+            self.password.clone(),
+            self.dm_space_id.clone(),
+            self.admin_room_id.clone(),
+        ).with_homeserver(self.homeserver.clone())
+    }
+}
+
+// Overrides for the transport's retry::RetryPolicy, anything unset keeps the default
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    #[serde(default, deserialize_with = "delay_secs")]
+    pub initial_delay_secs: Option<f64>,
+    #[serde(default, deserialize_with = "non_negative")]
+    pub multiplier: Option<f64>,
+    #[serde(default, deserialize_with = "delay_secs")]
+    pub max_delay_secs: Option<f64>,
+    #[serde(default, deserialize_with = "non_negative")]
+    pub jitter: Option<f64>,
+    pub max_attempts: Option<u32>,
+    pub max_cost: Option<u32>,
+}
+
+impl RetryConfig {
+    pub fn apply(&self, mut policy: retry::RetryPolicy) -> retry::RetryPolicy {
+        if let Some(v) = self.initial_delay_secs { policy.initial_delay = Duration::from_secs_f64(v); }
+        if let Some(v) = self.multiplier { policy.multiplier = v; }
+        if let Some(v) = self.max_delay_secs { policy.max_delay = Duration::from_secs_f64(v); }
+        if let Some(v) = self.jitter { policy.jitter = v; }
+        if let Some(v) = self.max_attempts { policy.max_attempts = v; }
+        if let Some(v) = self.max_cost { policy.max_cost = Some(v); }
+        policy
+    }
+}
+
+// Overrides for the user::QueuePolicy held messages are kept under
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct QueueConfig {
+    pub retention_secs: Option<u64>,
+}
+
+impl QueueConfig {
+    pub fn apply(&self, mut policy: user::QueuePolicy) -> user::QueuePolicy {
+        if let Some(v) = self.retention_secs { policy.retention = Duration::from_secs(v); }
+        policy
+    }
+}
+
+// Overrides for the media::MediaPolicy fetch_media works to
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MediaConfig {
+    pub budget_bytes: Option<u32>,
+}
+
+impl MediaConfig {
+    pub fn apply(&self, mut policy: media::MediaPolicy) -> media::MediaPolicy {
+        if let Some(v) = self.budget_bytes { policy.budget = v; }
+        policy
+    }
+}
+
+// Layout of the --config file
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub credfile: Option<PathBuf>,
+    pub homeserver_creds: Option<PathBuf>,
+    pub data_dir: Option<PathBuf>,
+    pub log_level: Option<String>,
+    pub homeservers: Option<BTreeMap<String, HomeserverConfig>>,  // instead of homeserver_creds
+    pub bots: Option<BTreeMap<String, BotConfig>>,  // instead of credfile
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub transport: cli::TransportArgs,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub retry: RetryConfig,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub queue: QueueConfig,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub media: MediaConfig,
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<ConfigFile, ConfigError> {
+        let contents = read_file(path)?;
+        ConfigFile::parse(&contents).map_err(|why| why.in_file(path))
+    }
+
+    pub fn parse(contents: &str) -> Result<ConfigFile, ConfigError> {
+        let config: ConfigFile = toml::from_str(contents).map_err(|why| ConfigError::from_toml(contents, why))?;
+        if config.credfile.is_some() && config.bots.is_some() {
+            return Err(ConfigError::new(ConfigErrorKind::Invalid, None, "Both credfile and [bots] are set, use one or the other"));
+        }
+        if config.homeserver_creds.is_some() && config.homeservers.is_some() {
+            return Err(ConfigError::new(ConfigErrorKind::Invalid, None, "Both homeserver_creds and [homeservers] are set, use one or the other"));
+        }
+        for (name, homeserver) in config.homeservers.iter().flatten() {
+            check_homeserver_name(name).map_err(|why| ConfigError::new(ConfigErrorKind::Invalid, None, why))?;
+            homeserver.check_login(name).map_err(|why| ConfigError::new(ConfigErrorKind::Invalid, None, why))?;
+        }
+        check_duplicate_bots(contents)?;
+        Ok(config)
+    }
+}
+
+// second pass over the file for the spans of each bot_address, serde only sees one bot at a time
+fn check_duplicate_bots(contents: &str) -> Result<(), ConfigError> {
+    #[derive(Deserialize)]
+    struct BotAddresses {
+        #[serde(default)]
+        bots: BTreeMap<String, BotAddress>,
+    }
+    #[derive(Deserialize)]
+    struct BotAddress {
+        bot_address: toml::Spanned<String>,
+    }
+
+    let parsed: BotAddresses = toml::from_str(contents).map_err(|why| ConfigError::from_toml(contents, why))?;
+    let mut addresses: Vec<toml::Spanned<String>> = parsed.bots.into_values().map(|bot| bot.bot_address).collect();
+    addresses.sort_by_key(|address| address.span().start);
+    for (i, address) in addresses.iter().enumerate() {
+        let lowered = address.get_ref().to_lowercase();
+        if addresses[..i].iter().any(|earlier| earlier.get_ref().to_lowercase() == lowered) {
+            return Err(ConfigError::at(ConfigErrorKind::Invalid, contents, address.span().start, format!("Duplicated bot address \"{}\"", lowered)));
+        }
+    }
+    Ok(())
+}
+
+// Where the bridge bots are kept, for the creds subcommands
+#[derive(Debug, Clone, PartialEq)]
+pub enum BotSource {
+    Credfile(PathBuf),  // legacy credfile.cfg
+    Config(PathBuf),  // [bots] of a config file
+}
+
+impl BotSource {
+    // Store a new bcrypt hash for a bot, eg. after revoke_all_clients rotates it
+    pub fn set_password(&self, bot_address: &str, password_hash: &str) -> Result<(), ConfigError> {
+        match self {
+            BotSource::Credfile(credfile) => credential_manager::set_credential_password(credfile, bot_address, password_hash),
+            BotSource::Config(config_path) => set_bot_password(config_path, bot_address, password_hash),
+        }
+    }
+}
+
+// Add a [bots.<name>] table to a config file, leaving the rest of the file (comments included) as it was
+pub fn add_bot(config_path: &Path, name: &str, bot: &BotConfig) -> Result<(), ConfigError> {
+    let contents = read_file(config_path)?;
+    let mut document: toml_edit::DocumentMut = contents.parse()
+        .map_err(|why: toml_edit::TomlError| ConfigError::new(ConfigErrorKind::Parse, None, why.message()).in_file(config_path))?;
+
+    let bots = document.entry("bots").or_insert_with(|| {
+        let mut bots = toml_edit::Table::new();
+        bots.set_implicit(true);
+        toml_edit::Item::Table(bots)
+    });
+    let bots = match bots.as_table_mut() {
+        Some(v) => v,
+        None => return Err(ConfigError::new(ConfigErrorKind::Invalid, None, "bots is not a table").in_file(config_path)),
+    };
+    if bots.contains_key(name) {
+        return Err(ConfigError::new(ConfigErrorKind::Invalid, None, format!("Duplicated block name \"{}\"", name)).in_file(config_path));
+    }
+    let mut table = toml_edit::Table::new();
+    for (key, value) in [
+        ("bot_address", &bot.bot_address),
+        ("service_name", &bot.service_name),
+        ("username", &bot.username),
+        ("password", &bot.password),
+        ("dm_space_id", &bot.dm_space_id),
+        ("admin_room_id", &bot.admin_room_id),
+    ] {
+        table.insert(key, toml_edit::value(value.as_str()));
+    }
+    if let Some(homeserver) = &bot.homeserver {
+        table.insert("homeserver", toml_edit::value(homeserver.as_str()));
+    }
+    bots.insert(name, toml_edit::Item::Table(table));
+
+    let new_contents = document.to_string();
+    ConfigFile::parse(&new_contents).map_err(|why| why.in_file(config_path))?;
+    write_file(config_path, &new_contents)
+}
+
+// Remove the [bots.<name>] table for a bot address or name, returns the removed name
+pub fn remove_bot(config_path: &Path, bot_address_or_name: &str) -> Result<String, ConfigError> {
+    let contents = read_file(config_path)?;
+    let config = ConfigFile::parse(&contents).map_err(|why| why.in_file(config_path))?;
+    let bot_address = bot_address_or_name.to_lowercase();
+    let name = config.bots.unwrap_or_default().into_iter()
+        .find(|(name, bot)| name == bot_address_or_name || bot.bot_address.to_lowercase() == bot_address)
+        .map(|(name, _)| name)
+        .ok_or_else(|| ConfigError::new(ConfigErrorKind::Invalid, None, format!("No bot for \"{}\"", bot_address_or_name)).in_file(config_path))?;
+
+    let mut document: toml_edit::DocumentMut = contents.parse()
+        .map_err(|why: toml_edit::TomlError| ConfigError::new(ConfigErrorKind::Parse, None, why.message()).in_file(config_path))?;
+    if let Some(bots) = document.get_mut("bots").and_then(|bots| bots.as_table_like_mut()) {
+        bots.remove(&name);
+    }
+    write_file(config_path, &document.to_string())?;
+    Ok(name)
+}
+
+pub fn set_bot_password(config_path: &Path, bot_address: &str, password_hash: &str) -> Result<(), ConfigError> {
+    let contents = read_file(config_path)?;
+    let config = ConfigFile::parse(&contents).map_err(|why| why.in_file(config_path))?;
+    let bot_address = bot_address.to_lowercase();
+    let name = config.bots.unwrap_or_default().into_iter()
+        .find(|(_, bot)| bot.bot_address.to_lowercase() == bot_address)
+        .map(|(name, _)| name)
+        .ok_or_else(|| ConfigError::new(ConfigErrorKind::Invalid, None, format!("No bot for \"{}\"", bot_address)).in_file(config_path))?;
+
+    let mut document: toml_edit::DocumentMut = contents.parse()
+        .map_err(|why: toml_edit::TomlError| ConfigError::new(ConfigErrorKind::Parse, None, why.message()).in_file(config_path))?;
+    if let Some(bot) = document.get_mut("bots").and_then(|bots| bots.get_mut(&name)).and_then(|bot| bot.as_table_like_mut()) {
+        bot.insert("password", toml_edit::value(password_hash));
+    }
+    write_file(config_path, &document.to_string())
+}
+
+pub fn write_file(path: &Path, contents: &str) -> Result<(), ConfigError> {
+    std::fs::write(path, contents)
+        .map_err(|why| ConfigError::new(ConfigErrorKind::Io, None, format!("Unable to write file: {}", why)).in_file(path))
+}
+
+/*
+    Legacy INI format - [name] headers followed by key=value lines
+    Only the first '=' splits, so values (bcrypt hashes, passwords) may contain their own
+*/
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LegacyEntry {
+    pub line: usize,
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LegacySection {
+    pub name: String,
+    pub line: usize,
+    pub span: Range<usize>,  // header up to the next header, in bytes
+    pub entries: Vec<LegacyEntry>,
+}
+
+pub fn read_legacy_sections(contents: &str) -> Result<Vec<LegacySection>, ConfigError> {
+    let mut sections: Vec<LegacySection> = vec![];
+    let mut offset = 0;
+    for (i, raw_line) in contents.split_inclusive('\n').enumerate() {
+        let (line_no, line_start) = (i + 1, offset);
+        offset += raw_line.len();
+        let line = raw_line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            let name = line[1..line.len() - 1].trim();
+            if name.is_empty() {
+                return Err(ConfigError::new(ConfigErrorKind::Parse, Some(line_no), "Empty section name"));
+            }
+            if let Some(previous) = sections.last_mut() {
+                previous.span.end = line_start;
+            }
+            sections.push(LegacySection { name: name.to_string(), line: line_no, span: line_start..contents.len(), entries: vec![] });
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => (key.trim(), value.trim()),
+            _ => return Err(ConfigError::new(ConfigErrorKind::Parse, Some(line_no), format!("Invalid line \"{}\", expected key=value", line))),
+        };
+        let section = match sections.last_mut() {
+            Some(v) => v,
+            None => return Err(ConfigError::new(ConfigErrorKind::Parse, Some(line_no), format!("Key \"{}\" outside of a [section]", key))),
+        };
+        if value.is_empty() {
+            return Err(ConfigError::new(ConfigErrorKind::Invalid, Some(line_no), format!("No value set for key \"{}\"", key)));
+        }
+        if section.entries.iter().any(|entry| entry.key == key) {
+            return Err(ConfigError::new(ConfigErrorKind::Invalid, Some(line_no), format!("Attempt to double-set property \"{}\"", key)));
+        }
+        section.entries.push(LegacyEntry { line: line_no, key: key.to_string(), value: value.to_string() });
+    }
+    Ok(sections)
+}
+
+// Pick the known keys out of a section, erroring on anything unknown or any required key missing
+fn legacy_values<'a>(section: &'a LegacySection, keys: &[&'a str], optional_keys: &[&'a str]) -> Result<HashMap<&'a str, &'a LegacyEntry>, ConfigError> {
+    let mut values = HashMap::new();
+    for entry in &section.entries {
+        match keys.iter().chain(optional_keys).find(|key| **key == entry.key) {
+            Some(key) => { values.insert(*key, entry); }
+            None => return Err(ConfigError::new(ConfigErrorKind::Parse, Some(entry.line), format!("Unknown key \"{}\" in [{}]", entry.key, section.name))),
+        }
+    }
+    if let Some(missing) = keys.iter().find(|key| !values.contains_key(*key)) {
+        return Err(ConfigError::new(ConfigErrorKind::Invalid, Some(section.line), format!("Missing key \"{}\" in [{}]", missing, section.name)));
+    }
+    Ok(values)
+}
+
+// credfile.cfg, in file order
+pub fn parse_legacy_credfile(contents: &str) -> Result<Vec<(String, BotConfig)>, ConfigError> {
+    let mut bots: Vec<(String, BotConfig)> = vec![];
+    for section in read_legacy_sections(contents)? {
+        let values = legacy_values(&section, &["bot_address", "service_name", "username", "password", "dm_space_id", "admin_room_id"], &["homeserver"])?;
+        if bots.iter().any(|(name, _)| *name == section.name) {
+            return Err(ConfigError::new(ConfigErrorKind::Invalid, Some(section.line), format!("Duplicated block name \"{}\"", section.name)));
+        }
+        if let Err(why) = credential_manager::check_platform(&values["service_name"].value) {
+            return Err(ConfigError::new(ConfigErrorKind::Invalid, Some(values["service_name"].line), why));
+        }
+        let bot_address = values["bot_address"].value.to_lowercase();
+        if bots.iter().any(|(_, bot)| bot.bot_address.to_lowercase() == bot_address) {
+            return Err(ConfigError::new(ConfigErrorKind::Invalid, Some(values["bot_address"].line), format!("Duplicated bot address \"{}\"", bot_address)));
+        }
+
+        bots.push((section.name.clone(), BotConfig {
+            bot_address: values["bot_address"].value.clone(),
+            service_name: values["service_name"].value.clone(),
+            username: values["username"].value.clone(),
+            password: values["password"].value.clone(),
+            dm_space_id: values["dm_space_id"].value.clone(),
+            admin_room_id: values["admin_room_id"].value.clone(),
+            homeserver: values.get("homeserver").map(|entry| entry.value.clone()),
+        }));
+    }
+    Ok(bots)
+}
+
+// homeserver_creds.cfg, one section per homeserver, in file order
+pub fn parse_legacy_homeservers(contents: &str) -> Result<Vec<(String, HomeserverConfig)>, ConfigError> {
+    let mut homeservers: Vec<(String, HomeserverConfig)> = vec![];
+    for section in read_legacy_sections(contents)? {
+        check_homeserver_name(&section.name).map_err(|why| ConfigError::new(ConfigErrorKind::Invalid, Some(section.line), why))?;
+        let values = legacy_values(&section, &["url", "username"], &["password", "access_token", "device_id"])?;
+        if homeservers.iter().any(|(name, _)| *name == section.name) {
+            return Err(ConfigError::new(ConfigErrorKind::Invalid, Some(section.line), format!("Duplicated homeserver name \"{}\"", section.name)));
+        }
+        let homeserver = HomeserverConfig {
+            url: values["url"].value.clone(),
+            username: values["username"].value.clone(),
+            password: values.get("password").map(|entry| entry.value.clone()),
+            access_token: values.get("access_token").map(|entry| entry.value.clone()),
+            device_id: values.get("device_id").map(|entry| entry.value.clone()),
+        };
+        homeserver.check_login(&section.name).map_err(|why| ConfigError::new(ConfigErrorKind::Invalid, Some(section.line), why))?;
+        homeservers.push((section.name.clone(), homeserver));
+    }
+    if homeservers.is_empty() {
+        return Err(ConfigError::new(ConfigErrorKind::Invalid, None, "No homeserver section"));
+    }
+    Ok(homeservers)
+}
+
+// Every bot has to end up on exactly one of the homeservers, wherever the two were loaded from
+pub fn check_bot_homeservers(bot_credentials: &[credential_manager::BridgeBotCredentials], homeserver_names: &[&str]) -> Result<(), ConfigError> {
+    for botcred in bot_credentials {
+        match &botcred.homeserver {
+            Some(name) if !homeserver_names.contains(&name.as_str()) => {
+                return Err(ConfigError::new(ConfigErrorKind::Invalid, None, format!("Bot {} is on unknown homeserver \"{}\"", botcred.bot_address, name)));
+            }
+            None if homeserver_names.len() > 1 => {
+                return Err(ConfigError::new(ConfigErrorKind::Invalid, None, format!("Bot {} needs a homeserver, there is more than one", botcred.bot_address)));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Fill in whichever of [homeservers] and [bots] a config is missing from the legacy files
+pub fn convert_legacy(mut config: ConfigFile, credfile: &Path, homeserver_creds: &Path) -> Result<ConfigFile, ConfigError> {
+    if config.bots.is_none() {
+        let bots = parse_legacy_credfile(&read_file(credfile)?).map_err(|why| why.in_file(credfile))?;
+        config.bots = Some(bots.into_iter().collect());
+    }
+    if config.homeservers.is_none() {
+        let homeservers = parse_legacy_homeservers(&read_file(homeserver_creds)?).map_err(|why| why.in_file(homeserver_creds))?;
+        config.homeservers = Some(homeservers.into_iter().collect());
+    }
+    config.credfile = None;
+    config.homeserver_creds = None;
+    Ok(config)
+}
+
+pub fn to_toml(config: &ConfigFile) -> Result<String, ConfigError> {
+    toml::to_string(config).map_err(|why| ConfigError::new(ConfigErrorKind::Invalid, None, format!("Unable to write config: {}", why)))
+}
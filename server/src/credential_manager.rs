@@ -4,66 +4,56 @@
 
 // #![allow(unused)] 
 
-use std::fs;
-use regex::Regex;
+use std::path::Path;
 use bcrypt;
 use std::hash::Hash;
 
+use crate::config::{self, ConfigError, ConfigErrorKind};
+
 const SUPPORTED_PLATFORMS: &[&str] = &["discord", "instagram", "fb_messenger", "test_platform"];
 
-#[derive(PartialEq, Eq, Hash)]
+pub fn check_platform(service_name: &str) -> Result<(), String> {
+    if SUPPORTED_PLATFORMS.contains(&service_name) {
+        Ok(())
+    } else {
+        Err(format!("Unsupported platform: {}", service_name))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub struct HomeserverCredentials {
     pub address: String,
     pub username: String,
-    pub password: String,
+    pub password: String,  // empty if logging in with an access token
+    pub access_token: Option<String>,  // pre-issued token for device_id, used instead of the password
+    pub device_id: Option<String>,  // with a password, the device to log in as rather than a new one
 }
 
-pub fn load_homeserver_creds(credfile_path: &'static str) -> Result<HomeserverCredentials, String> {
-    let contents = match fs::read_to_string(credfile_path) {
-        Ok(contents) => contents,
-        Err(_) => return Err("Unable to open credential file".to_string())
-    };
-
-    let credline_seperate_re = Regex::new("[\r\n]+").unwrap();
-    let header_line_re = Regex::new("\\[.+\\]").unwrap();
-    let kv_split_re = Regex::new("=").unwrap();
-
-    let mut url = "";
-    let mut username = "";
-    let mut password = "";
-
-    let lines = credline_seperate_re.split(&contents);
-    for line in lines {
-        if line.trim().is_empty() || header_line_re.is_match(line) {
-            continue;
-        }
-
-        let credpair_kv: Vec::<_> = kv_split_re.split(line.trim()).collect();
-        if credpair_kv.len() != 2 {
-            return Err(format!("Invalid line in credential file: \"{}\"", line));
-        }
-
-        let cred_key = credpair_kv[0];
-        let cred_value = credpair_kv[1];
-
-        match cred_key {
-            "url" => url = cred_value,
-            "username" => username = cred_value,
-            "password" => password = cred_value,
-            &_ => panic!("Unknown line in credfile: {}", cred_key)
-        };
+// Legacy homeserver_creds.cfg with a single homeserver, see config::parse_legacy_homeservers
+pub fn load_homeserver_creds(credfile_path: impl AsRef<Path>) -> Result<HomeserverCredentials, ConfigError> {
+    let credfile_path = credfile_path.as_ref();
+    let mut homeservers = load_homeservers(credfile_path)?;
+    if homeservers.len() > 1 {
+        return Err(invalid(credfile_path, format!("Expected one homeserver, found {}", homeservers.len())));
     }
+    Ok(homeservers.remove(0).1)
+}
 
-    Ok(HomeserverCredentials {
-        address: url.to_string(),
-        username: username.to_string(),
-        password: password.to_string()
-    })
+// (section name, credentials) for each homeserver
+pub type NamedHomeservers = Vec::<(String, HomeserverCredentials)>;
 
+// Every [name] section of a legacy homeserver_creds.cfg, in file order
+pub fn load_homeservers(credfile_path: impl AsRef<Path>) -> Result<NamedHomeservers, ConfigError> {
+    let credfile_path = credfile_path.as_ref();
+    let contents = config::read_file(credfile_path)?;
+    match config::parse_legacy_homeservers(&contents) {
+        Ok(homeservers) => Ok(homeservers.into_iter().map(|(name, homeserver)| (name, homeserver.credentials())).collect()),
+        Err(why) => Err(why.in_file(credfile_path)),
+    }
 }
 
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct BridgeBotCredentials {
     pub bot_address: String, // address of the puppeting bot on our homeserver
     pub service_name: String, // name of the external service, used to handle username conflicts between platforms
@@ -72,6 +62,8 @@ pub struct BridgeBotCredentials {
 
     pub dm_room_id: String,
     pub admin_room_id: String,
+
+    pub homeserver: Option<String>,  // name of the homeserver section the bot lives on, None if there is only one
 }
 
 impl BridgeBotCredentials {
@@ -85,10 +77,16 @@ impl BridgeBotCredentials {
             password,
             dm_room_id,
             admin_room_id,
+            homeserver: None,
         }
 
     }
 
+    pub fn with_homeserver(mut self, homeserver: Option<String>) -> BridgeBotCredentials {
+        self.homeserver = homeserver;
+        self
+    }
+
     pub fn validate_credentials(&self, username: &str, password: &[u8]) -> Result<bool, bcrypt::BcryptError> {
         // technically double-checking as username is used to find the correct BridgeBotCredentials, but stil worth doing
         if username != self.username {
@@ -100,99 +98,135 @@ impl BridgeBotCredentials {
             Err(why) => return Err(why), 
         };
     }
-}
 
-fn set_credential(store: &mut String, key: &str, val: String) -> Result<(), String> {
-    if store == "" {
-        *store = val.to_string();
-        Ok(())
-    } else {
-        Err(format!("Attempt to double-set property \"{}\"", key))
+    pub fn set_password_hash(&mut self, password_hash: String) {
+        self.password = password_hash;
     }
 }
 
-pub fn load_credential_file(credfile_path: &'static str) -> Result<Vec::<BridgeBotCredentials>, String> {
-    let mut current_credentials: Vec::<BridgeBotCredentials> = vec![];
-    let contents = match fs::read_to_string(credfile_path) {
-        Ok(cont) => cont,
-        Err(_) => return Err("Unable to open credential file".to_string()),
-    };
+// Hash for the password field of a credential block - bcrypt, cost 12
+pub fn hash_password(password: &str) -> Result<String, String> {
+    if password.is_empty() {
+        return Err("Password cannot be empty".to_string());
+    }
+    bcrypt::hash(password, bcrypt::DEFAULT_COST).map_err(|why| format!("Failed to hash password: {}", why))
+}
 
-    let allcred_split_re = Regex::new("\\[.+?\\]").unwrap(); // would like to have ^$ but isn't working, might be a CRFL issue
-    let credline_seperate_re = Regex::new("[\r\n]+").unwrap();
-    let kv_split_re = Regex::new("=").unwrap();
-    
-    
-    let all_credentials = allcred_split_re.split(&contents);
+// Legacy credfile.cfg, see config::parse_legacy_credfile
+pub fn load_credential_file(credfile_path: impl AsRef<Path>) -> Result<Vec::<BridgeBotCredentials>, ConfigError> {
+    let credfile_path = credfile_path.as_ref();
+    let contents = config::read_file(credfile_path)?;
+    parse_credentials(&contents).map_err(|why| why.in_file(credfile_path))
+}
 
+pub fn parse_credentials(contents: &str) -> Result<Vec::<BridgeBotCredentials>, ConfigError> {
+    Ok(config::parse_legacy_credfile(contents)?.iter().map(|(_, bot)| bot.credentials()).collect())
+}
 
-    for botcred_details in all_credentials {
+// Every block in the file along with its section name
+pub fn list_credentials(credfile_path: impl AsRef<Path>) -> Result<Vec::<(String, BridgeBotCredentials)>, ConfigError> {
+    let credfile_path = credfile_path.as_ref();
+    let contents = config::read_file(credfile_path)?;
+    match config::parse_legacy_credfile(&contents) {
+        Ok(bots) => Ok(bots.into_iter().map(|(name, bot)| (name, bot.credentials())).collect()),
+        Err(why) => Err(why.in_file(credfile_path)),
+    }
+}
 
-        if botcred_details.trim().is_empty() {
-            // artifact of using regex - just empty, skip it
-            continue;
-        }
+fn invalid(credfile_path: &Path, message: String) -> ConfigError {
+    ConfigError::new(ConfigErrorKind::Invalid, None, message).in_file(credfile_path)
+}
 
-        let credential_pairs = credline_seperate_re.split(&botcred_details);
+// Append a block to the file (creating it if needed). The file is only written if the result passes load_credential_file
+pub fn add_credential(credfile_path: impl AsRef<Path>, name: &str, botcred: &BridgeBotCredentials) -> Result<(), ConfigError> {
+    let credfile_path = credfile_path.as_ref();
+    let contents = if credfile_path.exists() {
+        config::read_file(credfile_path)?
+    } else {
+        String::new()
+    };
 
-        let mut ccred_bot_address: String = "".to_string();
-        let mut ccred_service_name: String = "".to_string();
-        let mut ccred_username: String = "".to_string();
-        let mut ccred_password: String = "".to_string();
-        let mut ccred_dm_space_id: String = "".to_string();
-        let mut ccred_admin_room_id: String = "".to_string();
+    if name.trim().is_empty() || name.contains(['[', ']', '\r', '\n']) {
+        return Err(invalid(credfile_path, format!("Invalid block name \"{}\"", name)));
+    }
 
+    // keep whatever line endings the file already uses
+    let newline = if contents.contains("\r\n") { "\r\n" } else { "\n" };
+    let mut new_contents = contents.clone();
+    if !new_contents.is_empty() && !new_contents.ends_with('\n') {
+        new_contents.push_str(newline);
+    }
+    if !new_contents.is_empty() {
+        new_contents.push_str(newline);
+    }
+    new_contents.push_str(&format!("[{}]{}", name, newline));
+    for (key, value) in [
+        ("bot_address", &botcred.bot_address),
+        ("service_name", &botcred.service_name),
+        ("username", &botcred.username),
+        ("password", &botcred.password),
+        ("dm_space_id", &botcred.dm_room_id),
+        ("admin_room_id", &botcred.admin_room_id),
+    ] {
+        new_contents.push_str(&format!("{}={}{}", key, value, newline));
+    }
+    if let Some(homeserver) = &botcred.homeserver {
+        new_contents.push_str(&format!("homeserver={}{}", homeserver, newline));
+    }
 
-        for credpair in credential_pairs {
+    parse_credentials(&new_contents).map_err(|why| why.in_file(credfile_path))?;
+    config::write_file(credfile_path, &new_contents)
+}
 
-            if credpair.trim().is_empty() {
-                // yet another regex artifact :(
-                continue;
-            }
+// Replace the password of a block in place, everything else in the file is left alone
+pub fn set_credential_password(credfile_path: impl AsRef<Path>, bot_address: &str, password_hash: &str) -> Result<(), ConfigError> {
+    let credfile_path = credfile_path.as_ref();
+    let contents = config::read_file(credfile_path)?;
+    let sections = config::read_legacy_sections(&contents).map_err(|why| why.in_file(credfile_path))?;
 
-            let credpair_kv: Vec::<_> = kv_split_re.split(credpair.trim()).collect();
-            if credpair_kv.len() != 2 {
-                return Err(format!("Invalid line in credential file: \"{}\"", credpair));
-            }
+    let bot_address = bot_address.to_lowercase();
+    let section = match sections.into_iter().find(|section| section.entries.iter().any(|entry| entry.key == "bot_address" && entry.value.to_lowercase() == bot_address)) {
+        Some(v) => v,
+        None => return Err(invalid(credfile_path, format!("No credential block for \"{}\"", bot_address))),
+    };
 
-            let cred_key = credpair_kv[0];
-            let cred_value = credpair_kv[1];
-            if cred_value.trim().is_empty() {
-                return Err(format!("No value set for key \"{}\"", cred_key));
+    let mut new_contents = String::new();
+    let mut offset = 0;
+    for raw_line in contents.split_inclusive('\n') {
+        let in_section = section.span.contains(&offset);
+        offset += raw_line.len();
+        match raw_line.split_once('=') {
+            Some((key, _)) if in_section && key.trim() == "password" => {
+                let line_ending = &raw_line[raw_line.trim_end_matches(['\r', '\n']).len()..];
+                new_contents.push_str(&format!("{}={}{}", key, password_hash, line_ending));
             }
-
-            match cred_key {
-                "bot_address" => set_credential(&mut ccred_bot_address, cred_key, cred_value.to_lowercase())?,
-                "service_name" => {
-                    if !SUPPORTED_PLATFORMS.contains(&cred_value) {
-                        return Err(format!("Unsupported platform: {}", cred_value));
-                    }
-                    set_credential(&mut ccred_service_name, cred_key, cred_value.to_string())?;
-                }
-                "username" => set_credential(&mut ccred_username, cred_key, cred_value.to_lowercase())?,
-                "password" => set_credential(&mut ccred_password, cred_key, cred_value.to_string())?,
-                "dm_space_id" => set_credential(&mut ccred_dm_space_id, cred_key, cred_value.to_string())?,
-                "admin_room_id" => set_credential(&mut ccred_admin_room_id, cred_key, cred_value.to_string())?,
-
-                _ => return Err(format!("Unknown key \"{}\" in credential file", cred_key)),
-            };
-
+            _ => new_contents.push_str(raw_line),
         }
+    }
 
-        // Check for a duplicated bot address
-        for existing_botcred in &current_credentials {
-            if existing_botcred.bot_address == ccred_bot_address {
-                return Err(format!("Duplicated bot address \"{}\"", ccred_bot_address));
-            }
-        } 
-
-        if ccred_bot_address != "" && ccred_service_name != "" && ccred_username != "" && ccred_password != "" && ccred_dm_space_id != "" && ccred_admin_room_id != "" {
-            current_credentials.push(BridgeBotCredentials::new(ccred_bot_address, ccred_service_name, ccred_username, ccred_password, ccred_dm_space_id, ccred_admin_room_id));
-        } else {
-            return Err("Missing values for a bot's credentials".to_string());
-        }
+    parse_credentials(&new_contents).map_err(|why| why.in_file(credfile_path))?;
+    config::write_file(credfile_path, &new_contents)
+}
 
-    }
+// Remove the block for a bot address (or with the given block name), returns the removed block's name
+pub fn remove_credential(credfile_path: impl AsRef<Path>, bot_address_or_name: &str) -> Result<String, ConfigError> {
+    let credfile_path = credfile_path.as_ref();
+    let contents = config::read_file(credfile_path)?;
+    let sections = config::read_legacy_sections(&contents).map_err(|why| why.in_file(credfile_path))?;
+
+    let bot_address = bot_address_or_name.to_lowercase();
+    let removed = sections.into_iter().find(|section| {
+        section.name == bot_address_or_name
+            || section.entries.iter().any(|entry| entry.key == "bot_address" && entry.value.to_lowercase() == bot_address)
+    });
+    let section = match removed {
+        Some(v) => v,
+        None => return Err(invalid(credfile_path, format!("No credential block for \"{}\"", bot_address_or_name))),
+    };
 
-    Ok(current_credentials)
+    let mut new_contents = contents.clone();
+    new_contents.replace_range(section.span, "");
+    parse_credentials(&new_contents).map_err(|why| why.in_file(credfile_path))?;
+    config::write_file(credfile_path, &new_contents)?;
+    Ok(section.name)
 }
@@ -0,0 +1,200 @@
+/*
+    Logged in matrix clients, one per configured homeserver, and logging them in
+    Each bridge bot names the homeserver it lives on (BridgeBotCredentials::homeserver), or leaves it out when there is only one
+    Every client keeps its state and end-to-end encryption keys in its own sqlite store under the data directory, so encrypted
+    rooms are decrypted (and sent to encrypted) by matrix-sdk itself and the bots only ever see plaintext
+*/
+
+use matrix_sdk::{Client, SessionMeta};
+use matrix_sdk::matrix_auth::{MatrixSession, MatrixSessionTokens};
+use matrix_sdk::ruma::UserId;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::api::client::uiaa;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use log::{error, info, warn};
+
+use crate::credential_manager;
+use crate::session_store::{self, SessionStore};
+
+#[derive(Clone, Default)]
+pub struct HomeserverClients {
+    clients: BTreeMap<String, Arc<Client>>,
+}
+
+impl HomeserverClients {
+    pub fn new() -> HomeserverClients {
+        HomeserverClients::default()
+    }
+
+    pub fn single(name: &str, client: Arc<Client>) -> HomeserverClients {
+        let mut clients = HomeserverClients::new();
+        clients.insert(name, client);
+        clients
+    }
+
+    pub fn insert(&mut self, name: &str, client: Arc<Client>) {
+        self.clients.insert(name.to_string(), client);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Client>> {
+        self.clients.get(name).cloned()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.clients.keys()
+    }
+
+    // The client a bot should use - the one it names, or the only one there is if it names none
+    pub fn for_bot(&self, botcred: &credential_manager::BridgeBotCredentials) -> Option<Arc<Client>> {
+        match &botcred.homeserver {
+            Some(name) => self.get(name),
+            None if self.clients.len() == 1 => self.clients.values().next().cloned(),
+            None => None,
+        }
+    }
+}
+
+// Log in, do the initial sync and leave a task syncing in the background
+pub async fn connect<S: SessionStore>(name: &str, homeserver_creds: &credential_manager::HomeserverCredentials, store_dir: &Path, session_store: &S) -> anyhow::Result<Arc<Client>> {
+    let client = Arc::new(login(name, homeserver_creds, store_dir, session_store).await?);
+    client.sync_once(matrix_sdk::config::SyncSettings::default()).await?;
+    info!("Initial client sync performed for {}", name);
+
+    // not fatal, messages still get through encrypted - other users' clients just warn that our device is unverified
+    if let Err(why) = verify_device(name, &client, homeserver_creds).await {
+        warn!("Failed to cross-sign our device on homeserver {}: {}", name, why);
+    }
+
+    let syncing_client = client.clone();
+    let name = name.to_string();
+    tokio::spawn(async move {
+        if let Err(why) = syncing_client.sync(matrix_sdk::config::SyncSettings::default()).await {
+            error!("Sync with homeserver {} stopped: {}", name, why);
+        }
+    });
+    Ok(client)
+}
+
+// Where a homeserver's client keeps its sqlite state and crypto store
+pub fn store_path(store_dir: &Path, name: &str) -> PathBuf {
+    store_dir.join(name)
+}
+
+async fn build_client(name: &str, homeserver_creds: &credential_manager::HomeserverCredentials, store_dir: &Path) -> anyhow::Result<Client> {
+    Ok(Client::builder()
+        .homeserver_url(&homeserver_creds.address)
+        .sqlite_store(store_path(store_dir, name), None)
+        .build().await?)
+}
+
+// The crypto store belongs to a single device, so a login that makes a new one has to start from an empty store
+fn clear_store(name: &str, store_dir: &Path) -> anyhow::Result<()> {
+    let path = store_path(store_dir, name);
+    if path.exists() {
+        warn!("Clearing the store of homeserver {} for a new device, keys for the old one are lost", name);
+        std::fs::remove_dir_all(&path)?;
+    }
+    Ok(())
+}
+
+// Cross-sign our own device so other users' clients trust what it sends. The first device of an account creates the
+// cross-signing keys, which takes the password. Later ones sign themselves if the store already holds the
+// self-signing key, otherwise someone has to verify the device from another session
+pub async fn verify_device(name: &str, client: &Client, homeserver_creds: &credential_manager::HomeserverCredentials) -> anyhow::Result<()> {
+    let encryption = client.encryption();
+    encryption.wait_for_e2ee_initialization_tasks().await;
+
+    if let Err(why) = encryption.bootstrap_cross_signing_if_needed(None).await {
+        let Some(response) = why.as_uiaa_response() else { return Err(why.into()) };
+        if homeserver_creds.password.is_empty() {
+            return Err(anyhow::Error::msg("creating the cross-signing keys needs the account password"));
+        }
+        let mut password = uiaa::Password::new(uiaa::UserIdentifier::UserIdOrLocalpart(homeserver_creds.username.clone()), homeserver_creds.password.clone());
+        password.session = response.session.clone();
+        encryption.bootstrap_cross_signing(Some(uiaa::AuthData::Password(password))).await?;
+        info!("Created cross-signing keys for homeserver {}", name);
+    }
+
+    let device = encryption.get_own_device().await?.ok_or_else(|| anyhow::Error::msg("our own device is missing from the crypto store"))?;
+    if device.is_cross_signed_by_owner() {
+        return Ok(());
+    }
+    match encryption.cross_signing_status().await {
+        Some(status) if status.has_self_signing => {
+            device.verify().await?;
+            info!("Cross-signed device {} on homeserver {}", device.device_id(), name);
+        }
+        _ => warn!("Device {} on homeserver {} is not cross-signed, verify it from another session of {}", device.device_id(), name, homeserver_creds.username),
+    }
+    Ok(())
+}
+
+fn matrix_session(user_id: &str, device_id: &str, access_token: &str, refresh_token: Option<String>) -> anyhow::Result<MatrixSession> {
+    Ok(MatrixSession {
+        meta: SessionMeta { user_id: UserId::parse(user_id)?, device_id: device_id.into() },
+        tokens: MatrixSessionTokens { access_token: access_token.to_string(), refresh_token },
+    })
+}
+
+// Reuse a login wherever possible, every password login adds another device to the account. In order: the configured
+// access token, the session saved by the last run, and only then the password
+pub async fn login<S: SessionStore>(name: &str, homeserver_creds: &credential_manager::HomeserverCredentials, store_dir: &Path, session_store: &S) -> anyhow::Result<Client> {
+    UserId::parse(&homeserver_creds.username)
+        .map_err(|why| anyhow::Error::msg(format!("Bad username \"{}\" for homeserver {}: {}", homeserver_creds.username, name, why)))?;
+
+    if let Some(access_token) = &homeserver_creds.access_token {
+        let device_id = homeserver_creds.device_id.as_deref()
+            .ok_or_else(|| anyhow::Error::msg(format!("Homeserver {} has an access token but no device id", name)))?;
+        let client = build_client(name, homeserver_creds, store_dir).await?;
+        client.matrix_auth().restore_session(matrix_session(&homeserver_creds.username, device_id, access_token, None)?).await?;
+        info!("Using the configured access token for homeserver {} (device {})", name, device_id);
+        return Ok(client);
+    }
+
+    match session_store.load_homeserver_session(name) {
+        Ok(Some(stored)) if stored.homeserver_url == homeserver_creds.address && stored.user_id == homeserver_creds.username => {
+            let client = build_client(name, homeserver_creds, store_dir).await?;
+            client.matrix_auth().restore_session(matrix_session(&stored.user_id, &stored.device_id, &stored.access_token, stored.refresh_token.clone())?).await?;
+            match client.whoami().await {
+                Ok(_) => {
+                    info!("Restored the previous session with homeserver {} (device {})", name, stored.device_id);
+                    return Ok(client);
+                }
+                // logged out or the device was deleted, anything else (eg. the homeserver is down) is not worth a new device
+                Err(why) if matches!(why.client_api_error_kind(), Some(ErrorKind::UnknownToken { .. })) => {
+                    warn!("Saved session with homeserver {} is no longer valid, logging in again", name);
+                }
+                Err(why) => return Err(why.into()),
+            }
+        }
+        Ok(_) => {}  // nothing saved, or it was for another account
+        Err(why) => warn!("Failed to load the saved session for homeserver {}: {}", name, why),
+    }
+
+    // a fixed device id keeps its keys, anything else is a fresh device
+    if homeserver_creds.device_id.is_none() {
+        clear_store(name, store_dir)?;
+    }
+    let client = build_client(name, homeserver_creds, store_dir).await?;
+    let mut login = client.matrix_auth().login_username(&homeserver_creds.username, &homeserver_creds.password).initial_device_display_name("boost");
+    if let Some(device_id) = &homeserver_creds.device_id {
+        login = login.device_id(device_id);
+    }
+    let response = login.send().await?;
+    info!("Logged in to homeserver {} as device {}", name, response.device_id);
+
+    let session = session_store::HomeserverSession {
+        name: name.to_string(),
+        homeserver_url: homeserver_creds.address.clone(),
+        user_id: response.user_id.to_string(),
+        device_id: response.device_id.to_string(),
+        access_token: response.access_token.clone(),
+        refresh_token: response.refresh_token.clone(),
+    };
+    if let Err(why) = session_store.save_homeserver_session(&session) {
+        error!("Failed to save the session for homeserver {}: {}", name, why);
+    }
+    Ok(client)
+}
@@ -1,198 +1,580 @@
 pub mod block;
 pub mod user;
 mod message;
-mod command;
+pub mod command;
 mod outgoing_message;
-mod matrix_bot;
-mod matrix_message;
+pub mod matrix_bot;
+pub mod matrix_message;
 pub mod sms;
+pub mod pdu;
+pub mod modem;
+pub mod smpp;
+pub mod netsim;
+pub mod retry;
 pub mod credential_manager;
+pub mod session_store;
+pub mod cli;
+pub mod config;
+pub mod homeserver;
+pub mod render;
+pub mod media;
 
 use std::env;
 use log::{error, info, warn};
-use std::sync::Arc;
 use std::collections::HashMap;
 use crate::sms::HandleSMS;
+use crate::session_store::SessionStore;
+use clap::Parser;
 
 use matrix_sdk;
 use bitvec::prelude::*;
 
-const SOCK_IN_PATH: &str = "/home/emlyn/pets/boost/boost_sin.sock";
-const SOCK_OUT_PATH: &str = "/home/emlyn/pets/boost/boost_sout.sock";
-const CREDFILE_PATH: &str = "credfile.cfg";
-const HOMESERVER_CREDFILE_PATH: &str = "homeserver_creds.cfg";
-
-pub async fn init() -> anyhow::Result<(Arc<matrix_sdk::Client>, Vec::<credential_manager::BridgeBotCredentials>, sms::SocketSMSHandler)> {
- 	env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-    env::set_var("RUST_BACKTRACE", "1"); // set backtrace for debugging
-
-    let socket = sms::SocketSMSHandler::new(std::path::Path::new(SOCK_IN_PATH), std::path::Path::new(SOCK_OUT_PATH))?;
-    
-    // Load our bot credentials from our credential file
-    let bot_credentials = match credential_manager::load_credential_file(CREDFILE_PATH) {
+pub async fn init(settings: &cli::Settings) -> anyhow::Result<(homeserver::HomeserverClients, Vec::<credential_manager::BridgeBotCredentials>, session_store::SqliteSessionStore)> {
+    // Load our bot credentials and the homeservers they live on
+    let (bot_credentials, homeserver_creds) = match settings.credentials() {
         Ok(creds) => creds,
-        Err(why) => return Err(anyhow::Error::msg(format!("Error loading credential file: {}", why))),
+        Err(why) => return Err(anyhow::Error::msg(format!("Error loading credentials: {}", why))),
     };    
-    info!("Loaded credential file");
+    info!("Loaded {} bot(s) on {} homeserver(s)", bot_credentials.len(), homeserver_creds.len());
 
-
-    // authenticate to matrix homeserver
-    let homeserver_creds = match credential_manager::load_homeserver_creds(HOMESERVER_CREDFILE_PATH) {
-        Ok(creds) => creds,
-        Err(why) => return Err(anyhow::Error::msg(format!("Error loading homeserver creds: {}", why))),
+    let session_store = match session_store::SqliteSessionStore::open(settings.session_db_path()) {
+        Ok(store) => store,
+        Err(why) => return Err(anyhow::Error::msg(format!("Error opening session store: {}", why))),
     };
-    info!("Loaded homeserver credential file");
-    let _user_id = matrix_sdk::ruma::UserId::parse(&homeserver_creds.username).expect("Failed to create user id from credfile username");
-    let client = Arc::new(
-        matrix_sdk::Client::builder()
-            .homeserver_url(&homeserver_creds.address)
-            .build()
-            .await?
-    );
-    client.matrix_auth().login_username(&homeserver_creds.username, &homeserver_creds.password).send().await?;
-    info!("Logged in to homeserver");
-    client.sync_once(matrix_sdk::config::SyncSettings::default()).await?;
-    info!("Initial client sync performed");
-    
-    // initialize sync thread
-    let syncing_client = client.clone();
-    tokio::spawn( async move {
-        let _ = syncing_client.sync(matrix_sdk::config::SyncSettings::default()).await;
-    });
+    info!("Opened session store");
+
+
+    // authenticate to every homeserver, each gets its own sync task and encryption store
+    let mut homeservers = homeserver::HomeserverClients::new();
+    for (name, creds) in &homeserver_creds {
+        homeservers.insert(name, homeserver::connect(name, creds, &settings.matrix_store_dir(), &session_store).await?);
+    }
 
    	info!("Initialization  complete");
-    Ok(( client, bot_credentials, socket ))
+    Ok(( homeservers, bot_credentials, session_store ))
+}
+
+fn init_logging(log_level: Option<&str>) {
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if let Some(log_level) = log_level {
+        builder.parse_filters(log_level);
+    }
+    builder.init();
 }
 
 #[tokio::main]
 pub async fn run() -> anyhow::Result<()> {
-	let (client, bot_credentials, sms_agent) = init().await?;
-
-    let mut users: HashMap<String, user::User<sms::SocketSMSHandler>> = HashMap::new(); // (Phone no., User struct)
+    let cli = cli::Cli::parse();
 
-    let mut pending_msgs: Vec::<(String, usize, matrix_message::MatrixMessage)> = vec![]; // (ph number, domain idx, message)
-    let mut pending_control_msgs: Vec::<(String, matrix_message::MatrixBotControlMessage)> = vec![]; // (ph number, ctrl message)
+    match cli.command.clone().unwrap_or(cli::Command::Run) {
+        cli::Command::Run => {
+            let settings = cli::Settings::resolve(&cli)?;
+            init_logging(settings.log_level.as_deref());
+            serve(&settings).await
+        }
+        cli::Command::CheckConfig => {
+            let settings = cli::Settings::resolve(&cli)?;
+            init_logging(settings.log_level.as_deref());
+            check_config(&settings)
+        }
+        cli::Command::HashPassword { password } => {
+            let password = match password {
+                Some(v) => v,
+                None => read_password()?,
+            };
+            println!("{}", credential_manager::hash_password(&password).map_err(anyhow::Error::msg)?);
+            Ok(())
+        }
+        cli::Command::Creds(creds_command) => manage_credentials(&cli::resolve_bot_source(&cli)?, creds_command),
+        cli::Command::ConvertConfig { output } => {
+            let converted = cli::convert_config(&cli)?;
+            match output {
+                Some(path) => {
+                    if path.exists() {
+                        return Err(anyhow::Error::msg(format!("{} already exists, not overwriting it", path.display())));
+                    }
+                    config::write_file(&path, &converted)?;
+                    println!("Wrote {}", path.display());
+                }
+                None => print!("{}", converted),
+            }
+            Ok(())
+        }
+    }
+}
 
-    loop {
-    
-        // loop over all users, and within that all matrix channels to see if we have messages we need to send
-        for (addr, user) in &mut users {
+async fn serve(settings: &cli::Settings) -> anyhow::Result<()> {
+    env::set_var("RUST_BACKTRACE", "1"); // set backtrace for debugging
+	let (homeservers, bot_credentials, session_store) = init(settings).await?;
+    match &settings.transport {
+        cli::TransportSettings::Socket { sock_in, sock_out } => {
+            let sms_agent = sms::SocketSMSHandler::new(sock_in, sock_out)?.with_retry_policy(settings.retry_policy());
+            main_loop(homeservers, &bot_credentials, Some(&settings.bot_source()), &settings.user_policies(), &sms_agent, &session_store).await
+        }
+        cli::TransportSettings::Modem { tty, baud } => {
+            let sms_agent = modem::ModemSMSHandler::new(tty, *baud)?.with_retry_policy(settings.retry_policy());
+            main_loop(homeservers, &bot_credentials, Some(&settings.bot_source()), &settings.user_policies(), &sms_agent, &session_store).await
+        }
+        cli::TransportSettings::Smpp(smpp_config) => {
+            let sms_agent = smpp::SmppSMSHandler::new(smpp_config.clone())?.with_retry_policy(settings.retry_policy());
+            main_loop(homeservers, &bot_credentials, Some(&settings.bot_source()), &settings.user_policies(), &sms_agent, &session_store).await
+        }
+    }
+}
 
+// Everything init() would load, without logging in or opening the transport
+pub fn check_config(settings: &cli::Settings) -> anyhow::Result<()> {
+    let (bot_credentials, homeservers) = settings.credentials()?;
+    match (&settings.bots, &settings.config) {
+        (Some(_), Some(config_path)) => println!("{}: {} bridge bot(s)", config_path.display(), bot_credentials.len()),
+        _ => println!("{}: {} bridge bot(s)", settings.credfile.display(), bot_credentials.len()),
+    }
 
-            // refresh users outgoing messages
-            user.refresh_outgoing();
-        
-            for i in 0..user.matrix_bot_channels.len() {
-                let channel = &user.matrix_bot_channels[i];
+    for (name, homeserver_creds) in &homeservers {
+        matrix_sdk::ruma::UserId::parse(&homeserver_creds.username)
+            .map_err(|why| anyhow::Error::msg(format!("Bad username \"{}\" for homeserver {}: {}", homeserver_creds.username, name, why)))?;
+        let n_bots = bot_credentials.iter().filter(|botcred| botcred.homeserver.as_deref().map_or(homeservers.len() == 1, |bot_homeserver| bot_homeserver == name)).count();
+        println!("homeserver {}: {} on {}, {} bridge bot(s)", name, homeserver_creds.username, homeserver_creds.address, n_bots);
+    }
 
-                let outgoing_msg = channel.1.try_recv();
-                if outgoing_msg.is_ok() {
-                    let outgoing_msg = outgoing_msg.expect("Failed to unwrap OK value (incoming matrix msg in main loop)");
+    if !settings.data_dir.is_dir() {
+        return Err(anyhow::Error::msg(format!("Data directory {} does not exist", settings.data_dir.display())));
+    }
+    match &settings.transport {
+        cli::TransportSettings::Socket { sock_in, sock_out } => println!("transport: socket, in {} out {}", sock_in.display(), sock_out.display()),
+        cli::TransportSettings::Modem { tty, baud } => println!("transport: modem on {} at {} baud", tty, baud),
+        cli::TransportSettings::Smpp(smpp_config) => println!("transport: smpp to {} as {}", smpp_config.address, smpp_config.system_id),
+    }
 
-                    pending_msgs.push((addr.clone(), i, outgoing_msg));
-                }
+    println!("Configuration OK");
+    Ok(())
+}
 
+// Prompt twice without echo on a terminal, otherwise take the first line of stdin so it can be piped in
+fn read_password() -> anyhow::Result<String> {
+    use std::io::IsTerminal;
+    if !std::io::stdin().is_terminal() {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        return Ok(line.trim_end_matches(['\r', '\n']).to_string());
+    }
 
-                let control_msg = channel.3.try_recv();
-                if control_msg.is_ok() {
-                    let control_msg = control_msg.expect("Failed to unwrap OK value (control msg in main loop)");
-                    pending_control_msgs.push((addr.clone(), control_msg));
+    let password = rpassword::prompt_password("Password: ")?;
+    if rpassword::prompt_password("Confirm password: ")? != password {
+        return Err(anyhow::Error::msg("Passwords do not match"));
+    }
+    Ok(password)
+}
 
-                    
-                }
+fn manage_credentials(bot_source: &config::BotSource, creds_command: cli::CredsCommand) -> anyhow::Result<()> {
+    let source_name = match bot_source {
+        config::BotSource::Credfile(path) => path.display().to_string(),
+        config::BotSource::Config(path) => format!("{} [bots]", path.display()),
+    };
+    match creds_command {
+        cli::CredsCommand::List => {
+            for (name, botcred) in list_bots(bot_source)? {
+                println!("[{}] {} ({}) user {}", name, botcred.bot_address, botcred.service_name, botcred.username);
+            }
+        }
+        cli::CredsCommand::Validate => {
+            println!("{}: {} bridge bot(s) OK", source_name, list_bots(bot_source)?.len());
+        }
+        cli::CredsCommand::Add(new_cred) => {
+            let password_hash = match new_cred.password_hash {
+                Some(v) => v,
+                None => credential_manager::hash_password(&read_password()?).map_err(anyhow::Error::msg)?,
+            };
+            let name = new_cred.name.unwrap_or_else(|| format!("{}_{}", new_cred.service_name, new_cred.username));
+            let bot = config::BotConfig {
+                bot_address: new_cred.bot_address,
+                service_name: new_cred.service_name,
+                username: new_cred.username,
+                password: password_hash,
+                dm_space_id: new_cred.dm_space_id,
+                admin_room_id: new_cred.admin_room_id,
+                homeserver: new_cred.homeserver,
+            };
+            match bot_source {
+                config::BotSource::Credfile(path) => credential_manager::add_credential(path, &name, &bot.credentials())?,
+                config::BotSource::Config(path) => config::add_bot(path, &name, &bot)?,
             }
+            println!("Added [{}] to {}", name, source_name);
+        }
+        cli::CredsCommand::Remove { bot_address } => {
+            let name = match bot_source {
+                config::BotSource::Credfile(path) => credential_manager::remove_credential(path, &bot_address)?,
+                config::BotSource::Config(path) => config::remove_bot(path, &bot_address)?,
+            };
+            println!("Removed [{}] from {}", name, source_name);
         }
-        for pending in pending_msgs.drain(..) {
-            info!("received msg on addr {}@{} - sending!", &pending.1, &pending.2.content);
+    }
+    Ok(())
+}
 
-            let user = users.get_mut(&pending.0).expect("Failed to get user by pending message addr");
-            let mut true_content_vec: Vec::<u8> = pending.2.content.as_bytes().to_vec();
-            true_content_vec.insert(0, pending.1.try_into().expect("Failed conversion usize -> u8")); // push platform idx
-            true_content_vec.insert(0, pending.2.room_idx.try_into().expect("Failed conversion usize -> u8"));  // push room idx
+fn list_bots(bot_source: &config::BotSource) -> anyhow::Result<Vec::<(String, credential_manager::BridgeBotCredentials)>> {
+    match bot_source {
+        config::BotSource::Credfile(path) => Ok(credential_manager::list_credentials(path)?),
+        config::BotSource::Config(path) => {
+            let bots = config::ConfigFile::load(path)?.bots.unwrap_or_default();
+            Ok(bots.into_iter().map(|(name, bot)| (name, bot.credentials())).collect())
+        }
+    }
+}
 
-            user.send_message(BitVec::<u8,Lsb0>::from_vec(true_content_vec), false, true);
+// Anything the matrix bot threads can hand to the main loop
+enum MatrixBotEvent {
+    Message(matrix_message::MatrixMessage),
+    Control(matrix_message::MatrixBotControlMessage),
+}
+
+// Wait on every bot channel of every user at once, returns (ph number, domain idx, event)
+async fn recv_matrix_bot_event<T: HandleSMS>(users: &mut HashMap<String, user::User<'_, T>>) -> (String, usize, MatrixBotEvent) {
+    let mut pending_recvs = vec![];
+    for (addr, user) in users.iter_mut() {
+        for (i, channel) in user.matrix_bot_channels.iter_mut().enumerate() {
+            pending_recvs.push(Box::pin(async move {
+                tokio::select! {
+                    Some(msg) = channel.1.recv() => (addr.clone(), i, MatrixBotEvent::Message(msg)),
+                    Some(ctrl) = channel.3.recv() => (addr.clone(), i, MatrixBotEvent::Control(ctrl)),
+                    else => std::future::pending().await,  // bot has gone away, revoke_bot will drop the channel
+                }
+            }));
         }
+    }
 
-        // check for control messages from mbot threads
-        for pending_ctrl in pending_control_msgs.drain(..) {
+    if pending_recvs.is_empty() {
+        return std::future::pending().await;
+    }
+    futures::future::select_all(pending_recvs).await.0
+}
 
-            match pending_ctrl.1 {
-                matrix_message::MatrixBotControlMessage::UpdateChannels{ domain_idx, channels } => {
+// Failing to persist is not fatal, the session is still live - it will just need redoing after a restart
+fn save_session<T: HandleSMS, S: SessionStore>(session_store: &S, user: &user::User<T>) {
+    if let Err(why) = session_store.save(&user.session()) {
+        error!("Failed to save session for {}: {}", &user.address, why);
+    }
+}
 
-                    let mut requesting_user = match users.get_mut(&pending_ctrl.0) {
-                        Some(x) => x,
-                        None => { error!("Failed to get user by pending msg addr");  continue; }
-                    };
+// bot_source is where a password rotated by revoke_all_clients gets written, None keeps the change in memory. Every user,
+// restored or new, is held to policies
+pub async fn main_loop<T: HandleSMS, S: SessionStore>(homeservers: homeserver::HomeserverClients, bot_credentials: &Vec::<credential_manager::BridgeBotCredentials>, bot_source: Option<&config::BotSource>, policies: &user::UserPolicies, sms_agent: &T, session_store: &S) -> anyhow::Result<()> {
+    let mut bot_credentials = bot_credentials.clone();
+
+    let mut users: HashMap<String, user::User<T>> = HashMap::new(); // (Phone no., User struct)
+    for session in session_store.load_all()? {
+        info!("Restoring session for {}", &session.address);
+        let mut restored_user = user::User::restore(homeservers.clone(), session, sms_agent, &bot_credentials).await;
+        restored_user.policies = policies.clone();
+        users.insert(restored_user.address.clone(), restored_user);
+    }
 
-                    let updated_channel_data = &mut BitVec::<u8,Lsb0>::new();
-                    updated_channel_data.append(&mut BitVec::<u8,Lsb0>::from_element(domain_idx));
+    loop {
+        // sleep until the earliest retransmit or missing block request of any user, or forever if nothing is waiting
+        let next_refresh = users.values().flat_map(|user| [user.next_refresh_instant(), user.next_nack_instant()]).flatten().min();
+        let refresh_timer = async {
+            match next_refresh {
+                Some(instant) => tokio::time::sleep_until(instant).await,
+                None => std::future::pending().await,
+            }
+        };
 
-                    let n_channels: usize = channels.len();
-                    for j in 0..n_channels {
-                        let channel = channels.get(j).expect("OOB access on channel list provided by MBCtrl::UpdateChannels");
-                        let mut latest_ch_name_vec = BitVec::<u8,Lsb0>::from_vec(channel.display_name.as_bytes().to_vec());
-                        for channel_name_bit in latest_ch_name_vec.drain(0..latest_ch_name_vec.len()) {
-                            updated_channel_data.push(channel_name_bit);
-                        }
-                        if j < (n_channels - 1) {
-                            for _ in 0..8 {
-                                updated_channel_data.push(false);
-                            }
+        tokio::select! {
+            // check for recv block
+            new_block = sms_agent.recv_block() => {
+                match new_block {
+                    Some(new_block) => {
+                        let sender_addr = new_block.addr.clone();
+                        let revoked = handle_block(&mut users, &homeservers, sms_agent, &mut bot_credentials, bot_source, policies, new_block).await;
+                        for addr in std::iter::once(&sender_addr).chain(revoked.iter()) {
+                            if let Some(user) = users.get(addr) { save_session(session_store, user); }
                         }
-                        
                     }
-                    info!("tx channel_update");
-                    send_command(&mut requesting_user, command::CommandValue::ChannelUpdate as command::CommandInt, updated_channel_data, true); 
-                    requesting_user.client_has_latest_channel_list[domain_idx as usize] = false;
-                },
+                    None => return Err(anyhow::Error::msg("SMS transport closed")),
+                }
+            }
 
-                _ => { error!("rx unsupported mbot_ctrl from bot"); }
+            (addr, domain_idx, event) = recv_matrix_bot_event(&mut users) => {
+                match event {
+                    MatrixBotEvent::Message(msg) => handle_matrix_message(&mut users, addr.clone(), domain_idx, msg),
+                    MatrixBotEvent::Control(ctrl) => handle_matrix_control(&mut users, addr.clone(), domain_idx, ctrl),
+                }
+                if let Some(user) = users.get(&addr) { save_session(session_store, user); }
+            }
+
+            _ = refresh_timer => {
+                // refresh users outgoing messages, chase stalled incoming ones
+                for user in users.values_mut() {
+                    user.refresh_outgoing();
+                    user.request_missing_blocks();
+                    save_session(session_store, user);
+                }
             }
         }
+    }
+}
 
-        // check for recv block
-        let new_block = match sms_agent.recv_block() {
-            None => { continue; },
-            Some(v) => { v }
-        };
+fn handle_matrix_message<T: HandleSMS>(users: &mut HashMap<String, user::User<T>>, addr: String, domain_idx: usize, msg: matrix_message::MatrixMessage) {
+    info!("received msg on addr {}@{} - sending!", &domain_idx, &msg.content);
 
-        let sender_addr = new_block.addr.clone();
+    let user = match users.get_mut(&addr) {
+        Some(x) => x,
+        None => { error!("Failed to get user by pending message addr"); return; }
+    };
+    user.forward_matrix_message(msg.room_idx, domain_idx, msg.content.as_bytes().to_vec());
+}
+
+// control messages from mbot threads
+fn handle_matrix_control<T: HandleSMS>(users: &mut HashMap<String, user::User<T>>, addr: String, domain_idx: usize, ctrl: matrix_message::MatrixBotControlMessage) {
+    match ctrl {
+        // the phone asked for it, or a room joined or left the dm space - either way it gets the whole list again
+        matrix_message::MatrixBotControlMessage::UpdateChannels{ channels } => {
+
+            let requesting_user = match users.get_mut(&addr) {
+                Some(x) => x,
+                None => { error!("Failed to get user by pending msg addr");  return; }
+            };
+
+            let updated_channel_data = &mut BitVec::<u8,Lsb0>::new();
+            updated_channel_data.append(&mut BitVec::<u8,Lsb0>::from_element(domain_idx as u8));
+
+            let n_channels: usize = channels.len();
+            for j in 0..n_channels {
+                let channel = channels.get(j).expect("OOB access on channel list provided by MBCtrl::UpdateChannels");
+                let mut latest_ch_name_vec = BitVec::<u8,Lsb0>::from_vec(channel.display_name.as_bytes().to_vec());
+                for channel_name_bit in latest_ch_name_vec.drain(0..latest_ch_name_vec.len()) {
+                    updated_channel_data.push(channel_name_bit);
+                }
+                if j < (n_channels - 1) {
+                    for _ in 0..8 {
+                        updated_channel_data.push(false);
+                    }
+                }
+                
+            }
+            info!("tx channel_update");
+            send_command(requesting_user, command::CommandValue::ChannelUpdate as command::CommandInt, updated_channel_data, true); 
+            requesting_user.client_has_latest_channel_list[domain_idx] = false;
+            if let Some(bot_info) = requesting_user.matrix_bots.get_mut(domain_idx) {
+                bot_info.num_channels = channels.len();
+                bot_info.channel_infos = channels;
+            }
+        },
+
+        matrix_message::MatrixBotControlMessage::MessageSuccess{ msg_id } => {
+            let requesting_user = match users.get_mut(&addr) {
+                Some(x) => x,
+                None => { error!("Failed to get user by pending msg addr");  return; }
+            };
+            info!("tx delivery_success for msg {}", &msg_id);
+            let mut payload: BitVec::<u8,Lsb0> = bitvec![u8, Lsb0; 0; 8];
+            payload[0..8].store::<u8>(msg_id);
+            send_command(requesting_user, command::CommandValue::DeliverySuccess as command::CommandInt, &mut payload, false);
+        },
+
+        matrix_message::MatrixBotControlMessage::SendFailed{ msg_id, reason } | matrix_message::MatrixBotControlMessage::MediaFailed{ msg_id, reason } => {
+            let requesting_user = match users.get_mut(&addr) {
+                Some(x) => x,
+                None => { error!("Failed to get user by pending msg addr");  return; }
+            };
+            info!("tx error for msg {}: {}", &msg_id, &reason);
+            let mut payload: BitVec::<u8,Lsb0> = bitvec![u8, Lsb0; 0; 8];
+            payload[0..8].store::<u8>(msg_id);
+            payload.append(&mut BitVec::<u8,Lsb0>::from_vec(reason.as_bytes().to_vec()));
+            send_command(requesting_user, command::CommandValue::Error as command::CommandInt, &mut payload, false);
+        },
+
+        matrix_message::MatrixBotControlMessage::UserFound{ msg_id, room_idx } => {
+            let requesting_user = match users.get_mut(&addr) {
+                Some(x) => x,
+                None => { error!("Failed to get user by pending msg addr");  return; }
+            };
+            info!("tx user_found");
+            let mut payload: BitVec::<u8,Lsb0> = bitvec![u8, Lsb0; 0; 24];
+            payload[0..8].store::<u8>(msg_id);
+            payload[8..16].store::<u8>(domain_idx as u8);
+            payload[16..24].store::<u8>(room_idx as u8);
+            send_command(requesting_user, command::CommandValue::UserFound as command::CommandInt, &mut payload, true);
+        },
+
+        matrix_message::MatrixBotControlMessage::UserNotFound{ msg_id, reason } => {
+            let requesting_user = match users.get_mut(&addr) {
+                Some(x) => x,
+                None => { error!("Failed to get user by pending msg addr");  return; }
+            };
+            let mut payload: BitVec::<u8,Lsb0> = bitvec![u8, Lsb0; 0; 8];
+            payload[0..8].store::<u8>(msg_id);
+            payload.append(&mut BitVec::<u8,Lsb0>::from_vec(reason.as_bytes().to_vec()));
+            send_command(requesting_user, command::CommandValue::TargetUserNotFound as command::CommandInt, &mut payload, false);
+        },
+
+        matrix_message::MatrixBotControlMessage::MediaReady{ msg_id, data, mimetype } => {
+            let requesting_user = match users.get_mut(&addr) {
+                Some(x) => x,
+                None => { error!("Failed to get user by pending msg addr");  return; }
+            };
+            info!("tx media_start for msg {}: {} bytes of {}", &msg_id, data.len(), &mimetype);
+            requesting_user.start_media_transfer(msg_id, data, &mimetype);
+        },
+
+        _ => { error!("rx unsupported mbot_ctrl from bot"); }
+    }
+}
+
+// returns the other users a revoke_all_clients signed out, their sessions need saving too
+pub async fn handle_block<'a, T: HandleSMS>(users: &mut HashMap<String, user::User<'a, T>>, homeservers: &homeserver::HomeserverClients, sms_agent: &'a T, bot_credentials: &mut Vec::<credential_manager::BridgeBotCredentials>, bot_source: Option<&config::BotSource>, policies: &user::UserPolicies, new_block: block::Block) -> Vec<String> {
+    let sender_addr = new_block.addr.clone();
 
-        if !users.contains_key(&sender_addr) {
-            users.insert(sender_addr.clone(), user::User::new(client.clone(), sender_addr.clone(), false, &sms_agent));
+    if !users.contains_key(&sender_addr) {
+        let mut new_user = user::User::new(homeservers.clone(), sender_addr.clone(), false, sms_agent);
+        new_user.policies = policies.clone();
+        users.insert(sender_addr.clone(), new_user);
+    }
+
+    let sender = users.get_mut(&sender_addr).unwrap(); // sender is a &mut
+
+    if !new_block.block_size_validation() {
+        send_command(sender, command::CommandValue::Error as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("Message missing header".as_bytes().to_vec()), false);
+        return vec![];
+    }
+
+    let new_block_msgid = new_block.data.get(block::BLOCK_MSGID_RANGE).unwrap().load::<u8>();
+    let new_msg_blockid = new_block.position();  // nonces are derived from the position, not the raw MPIDX field
+    let mut new_block_dec = sender.decrypt_block(new_block_msgid, new_msg_blockid, &new_block);
+
+    let (action, action_data) = sender.receive_block(&mut new_block_dec);
+    let complete = match action {
+        block::BlockReceivedAction::SendBlockAck => { send_block_ack(sender, action_data, new_block_msgid); false },
+        block::BlockReceivedAction::BlockInvalid => {
+            send_command(sender, command::CommandValue::Error as command::CommandInt, &mut BitVec::<u8,Lsb0>::new(), false);
+            false
+        },
+        block::BlockReceivedAction::ProcessMessage => { 
+            send_block_ack(sender, action_data, new_block_msgid);
+            true
+        },
+        block::BlockReceivedAction::ProcessNoAck => true,
+    };
+
+    let mut revoked = vec![];
+    if complete {
+        // the one command that reaches past the sender, everything else only needs the sender
+        let is_revoke_all_clients = sender.messages.get(&new_block_msgid)
+            .is_some_and(|msg| msg.is_command && matches!(command::Command::get_matching_command(&msg.payload), Ok(command::CommandValue::RevokeAllClients)));
+        if is_revoke_all_clients && sender.is_encrypted {
+            revoked = revoke_all_clients(users, &sender_addr, new_block_msgid, bot_credentials, bot_source);
+        } else {
+            process_message(sender, new_block_msgid, bot_credentials).await;
         }
+    }
 
-        let sender = users.get_mut(&sender_addr).unwrap(); // sender is a &mut
+    // any contact means the phone is reachable again
+    let sender = users.get_mut(&sender_addr).unwrap();
+    if sender.is_encrypted && !sender.dead_letters.is_empty() {
+        sender.redeliver_dead_letters();
+    }
+    revoked
+}
+
+// Sign every phone out of a bot, eg. from a spare phone after losing one. The sender has to be signed in to the bot and send its
+// username and password again, and can send a new password along to rotate the credential block so the lost phone cannot sign back in
+fn revoke_all_clients<T: HandleSMS>(users: &mut HashMap<String, user::User<T>>, sender_addr: &String, msg_id: u8, bot_credentials: &mut [credential_manager::BridgeBotCredentials], bot_source: Option<&config::BotSource>) -> Vec<String> {
+    let sender = users.get_mut(sender_addr).unwrap();
+    info!("rx revokeallclients on {}", sender.address);
 
-        if !new_block.block_size_validation() {
-            send_command(sender, command::CommandValue::Error as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("Message missing header".as_bytes().to_vec()), false);
-            continue;
+    // [domain_idx][username]0x00[password]0x00[new password (optional)]
+    let payload_bytes = match sender.messages.get(&msg_id) {
+        Some(msg) => msg.payload.clone().split_off(8).into_vec(),
+        None => { warn!("Failed to get message while processing"); return vec![]; }
+    };
+    let bot_address = match payload_bytes.first().and_then(|domain_idx| sender.matrix_bots.get(*domain_idx as usize)) {
+        Some(bot_info) => bot_info.bot_address.clone(),
+        None => {
+            send_command(sender, command::CommandValue::UnknownDomain as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("No such domain".as_bytes().to_vec()), false);
+            return vec![];
         }
+    };
 
-        let new_block_msgid = new_block.data.get(block::BLOCK_MSGID_RANGE).unwrap().load::<u8>();
-        let new_msg_blockid = if new_block.data.get(block::BLOCK_ISMLP_RANGE).unwrap().load::<u8>() == 1 { new_block.data.get(block::BLOCK_MPIDX_RANGE).unwrap().load::<u8>() } else { 0 };
-        let mut new_block_dec = sender.decrypt_block(new_block_msgid, new_msg_blockid, &new_block);
-
-        let (action, action_data) = sender.receive_block(&mut new_block_dec);
-        match action {
-            block::BlockReceivedAction::SendBlockAck => { send_block_ack(sender, action_data, new_block_msgid); },
-            block::BlockReceivedAction::BlockInvalid => {
-                send_command(sender, command::CommandValue::Error as command::CommandInt, &mut BitVec::<u8,Lsb0>::new(), false);
-            },
-            block::BlockReceivedAction::ProcessMessage => { 
-                send_block_ack(sender, action_data, new_block_msgid);
-                process_message(sender, new_block_msgid, &bot_credentials);
-            },
-            block::BlockReceivedAction::ProcessNoAck => {
-                process_message(sender, new_block_msgid, &bot_credentials);
-            }
-            
+    let mut fields = payload_bytes[1..].splitn(3, |b| *b == 0);
+    let (username, password, new_password) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(username), Some(password), new_password) => (username, password, new_password.unwrap_or_default()),
+        _ => {
+            send_command(sender, command::CommandValue::InvalidCommand as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("Insufficient data in request".as_bytes().to_vec()), false);
+            return vec![];
+        }
+    };
+    let username = match std::str::from_utf8(username) {
+        Ok(v) => v.to_lowercase(),
+        Err(_) => {
+            send_command(sender, command::CommandValue::InvalidCommand as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("Username is not valid UTF-8".as_bytes().to_vec()), false);
+            return vec![];
+        }
+    };
+
+    // being signed in is not enough, whoever holds the lost phone is too
+    let authentication_result = match bot_credentials.iter().find(|botcred| botcred.bot_address == bot_address && botcred.username == username) {
+        Some(botcred) => botcred.validate_credentials(&username, password),
+        None => Ok(false),
+    };
+    match authentication_result {
+        Ok(true) => {},
+        Ok(false) => {
+            send_command(sender, command::CommandValue::Error as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("Incorrect username or password".as_bytes().to_vec()), false);
+            return vec![];
+        },
+        Err(why) => {
+            send_command(sender, command::CommandValue::Error as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec(format!("Password verif failed: {}", why).as_bytes().to_vec()), false);
+            return vec![];
+        },
+    }
+
+    let new_password = match std::str::from_utf8(new_password) {
+        Ok("") => None,
+        Ok(v) => Some(v.to_string()),
+        Err(_) => {
+            send_command(sender, command::CommandValue::InvalidCommand as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("New password is not valid UTF-8".as_bytes().to_vec()), false);
+            return vec![];
         }
+    };
 
+    // a failed rotation is reported, but everyone is still signed out
+    if let Some(new_password) = new_password {
+        if let Err(why) = rotate_password(bot_credentials, bot_source, &bot_address, &new_password) {
+            error!("Failed to rotate the password of {}: {}", &bot_address, why);
+            send_command(sender, command::CommandValue::Error as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec(format!("Password not changed: {}", why).as_bytes().to_vec()), false);
+        }
     }
+
+    let mut revoked = vec![];
+    for user in users.values_mut() {
+        let bot_index = match user.matrix_bots.iter().position(|bot_info| bot_info.bot_address == bot_address) {
+            Some(v) => v,
+            None => continue,
+        };
+        if user.revoke_bot(bot_index).is_ok() {
+            let mut payload: BitVec::<u8,Lsb0> = bitvec![u8, Lsb0; 0; 8];
+            payload[0..8].store::<u8>(bot_index as u8);
+            send_command(user, command::CommandValue::SignOutSuccess as command::CommandInt, &mut payload, true);
+            revoked.push(user.address.clone());
+        }
+    }
+    info!("revoked {} client(s) from {}", revoked.len(), &bot_address);
+    revoked
 }
 
-fn send_block_ack(sender: &mut user::User<sms::SocketSMSHandler>, block_idx: u8, new_block_msgid: u8) {
+// Written back to the bot source first, so the running server never accepts a password the files do not have
+fn rotate_password(bot_credentials: &mut [credential_manager::BridgeBotCredentials], bot_source: Option<&config::BotSource>, bot_address: &str, new_password: &str) -> Result<(), String> {
+    let password_hash = credential_manager::hash_password(new_password)?;
+    if let Some(bot_source) = bot_source {
+        bot_source.set_password(bot_address, &password_hash).map_err(|why| why.to_string())?;
+    }
+    for botcred in bot_credentials.iter_mut().filter(|botcred| botcred.bot_address == bot_address) {
+        botcred.set_password_hash(password_hash.clone());
+    }
+    Ok(())
+}
+
+fn send_block_ack<T: HandleSMS>(sender: &mut user::User<T>, block_idx: u8, new_block_msgid: u8) {
     let mut block_ack_payload = bitvec![u8, Lsb0; 0; command::COMMAND_BITLENGTH + 16]; // +8 for msgId, +8 for blockIdx
     block_ack_payload[0..command::COMMAND_BITLENGTH].store::<command::CommandInt>(command::CommandValue::BlockAck as command::CommandInt);
     block_ack_payload[command::COMMAND_BITLENGTH..command::COMMAND_BITLENGTH + 8].store::<u8>(new_block_msgid); 
@@ -201,14 +583,14 @@ fn send_block_ack(sender: &mut user::User<sms::SocketSMSHandler>, block_idx: u8,
 }
 
 // Wrapper function to User.send_message for commands
-fn send_command(sender: &mut user::User<sms::SocketSMSHandler>, command_type: command::CommandInt, payload: &mut BitVec::<u8,Lsb0>, needs_ack: bool) {
+fn send_command<T: HandleSMS>(sender: &mut user::User<T>, command_type: command::CommandInt, payload: &mut BitVec::<u8,Lsb0>, needs_ack: bool) {
     let mut new_payload = bitvec![u8, Lsb0; 0; command::COMMAND_BITLENGTH];
     new_payload[0..command::COMMAND_BITLENGTH].store::<command::CommandInt>(command_type);
     new_payload.append(payload);
     sender.send_message(new_payload, true, needs_ack);
 }
 
-fn process_message(sender: &mut user::User<sms::SocketSMSHandler>, msg_id: u8, bot_credentials: &Vec::<credential_manager::BridgeBotCredentials>) {
+async fn process_message<T: HandleSMS>(sender: &mut user::User<'_, T>, msg_id: u8, bot_credentials: &Vec::<credential_manager::BridgeBotCredentials>) {
 
     let msg = match sender.messages.get(&msg_id) {
         Some(msg) => msg,
@@ -242,6 +624,8 @@ fn process_message(sender: &mut user::User<sms::SocketSMSHandler>, msg_id: u8, b
                             sender.unused_ids.push(i as u8);
                         }
                         sender.unused_ids.push(0);
+                        sender.id_generations = [0; 32];
+                        sender.media_transfers = vec![];  // their chunks went under the old key
                         send_command(sender, command::CommandValue::DhkeInit as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec(val.to_vec()), false);
                     }
                     Err(e) => send_command(sender, command::CommandValue::Error as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec(e.as_bytes().to_vec()), false),
@@ -308,13 +692,12 @@ fn process_message(sender: &mut user::User<sms::SocketSMSHandler>, msg_id: u8, b
                         let authentication_result = botcred.validate_credentials(&username, password);
                         match authentication_result {
                             Ok(v) => {
-                                if v == true {
-                                    let domain_idx = match sender.authenticate(botcred) {
+                                if v {
+                                    let domain_idx = match sender.authenticate(botcred).await {
                                         Ok(v) => v,
                                         Err(e) => {
-                                            if e == 0 {
-                                                send_command(sender, command::CommandValue::Error as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("Bot limit of 256 reached".as_bytes().to_vec()), false);
-                                            }
+                                            let why = if e == 0 { "Bot limit of 256 reached" } else { "Failed to start bot" };
+                                            send_command(sender, command::CommandValue::Error as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec(why.as_bytes().to_vec()), false);
                                             return;
                                         }
                                     };
@@ -360,7 +743,7 @@ fn process_message(sender: &mut user::User<sms::SocketSMSHandler>, msg_id: u8, b
                     }
                 };
                 sender.client_has_latest_channel_list[domain_idx as usize] = false;
-                let _ = mbot_channel_ref.2.send(matrix_message::MatrixBotControlMessage::RequestChannels { domain_idx: domain_idx.try_into().expect("usize->u8 failed when sending reqch to mbot") });
+                let _ = mbot_channel_ref.2.send(matrix_message::MatrixBotControlMessage::RequestChannels);
             }
 
             command::CommandValue::RequestDomains => { 
@@ -403,7 +786,21 @@ fn process_message(sender: &mut user::User<sms::SocketSMSHandler>, msg_id: u8, b
                     }
                 }
             }
+
+            command::CommandValue::MissingBlocks => {
+                info!("Received missing_blocks on address {}", sender.address);
+
+                if let Err(1) = sender.retransmit_blocks(&actual_payload) {
+                    send_command(sender, command::CommandValue::InvalidCommand as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("Missing block list".as_bytes().to_vec()), false);
+                }
+            }
             
+            command::CommandValue::FlushQueue => {
+                info!("rx flushqueue on {}", sender.address);
+                sender.resend_outgoing();
+                sender.redeliver_dead_letters();
+            }
+
             command::CommandValue::SignOut => {
             	info!("rx signout on {}", sender.address);
 
@@ -420,16 +817,62 @@ fn process_message(sender: &mut user::User<sms::SocketSMSHandler>, msg_id: u8, b
                 };
             }
 
-            command::CommandValue::RevokeAllClients => {
-                info!("rx revokeallclients on {}", sender.address);
-                // todo: implement
-                send_command(sender, command::CommandValue::Error as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("Unimplemented".as_bytes().to_vec()), false);
-            }
-            
             command::CommandValue::FindUser => {
                 info!("rx finduser on {}", sender.address);
-                // todo: implement
-                send_command(sender, command::CommandValue::Error as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("Unimplemented".as_bytes().to_vec()), false);
+
+                // [domain_idx][remote identifier], answered with user_found once the bridge has opened the room
+                let payload_bytes = actual_payload.into_vec();
+                let identifier = match payload_bytes.get(1..).map(std::str::from_utf8) {
+                    Some(Ok(v)) if !v.trim().is_empty() => v.trim().to_string(),
+                    _ => {
+                        send_command(sender, command::CommandValue::InvalidCommand as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("Missing or invalid user identifier".as_bytes().to_vec()), false);
+                        return;
+                    }
+                };
+                let domain_idx = payload_bytes[0] as usize;
+                match sender.matrix_bot_channels.get(domain_idx) {
+                    Some(mbot_channel_ref) => { let _ = mbot_channel_ref.2.send(matrix_message::MatrixBotControlMessage::FindUser { msg_id, identifier }); },
+                    None => send_command(sender, command::CommandValue::UnknownDomain as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("No such domain".as_bytes().to_vec()), false),
+                }
+            }
+
+            command::CommandValue::FetchMedia => {
+                info!("rx fetchmedia on {}", sender.address);
+
+                // [domain_idx][budget u32][mxc uri], a budget of 0 (or over ours) means use ours
+                let payload_bytes = actual_payload.into_vec();
+                let uri = match payload_bytes.get(5..).map(std::str::from_utf8) {
+                    Some(Ok(v)) if !v.trim().is_empty() => v.trim().to_string(),
+                    _ => {
+                        send_command(sender, command::CommandValue::InvalidCommand as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("Missing or invalid media uri".as_bytes().to_vec()), false);
+                        return;
+                    }
+                };
+                let domain_idx = payload_bytes[0] as usize;
+                let requested = u32::from_be_bytes([payload_bytes[1], payload_bytes[2], payload_bytes[3], payload_bytes[4]]);
+                let budget = match requested {
+                    0 => sender.policies.media.budget,
+                    _ => requested.min(sender.policies.media.budget),
+                } as usize;
+                match sender.matrix_bot_channels.get(domain_idx) {
+                    Some(mbot_channel_ref) => { let _ = mbot_channel_ref.2.send(matrix_message::MatrixBotControlMessage::FetchMedia { msg_id, uri, budget }); },
+                    None => send_command(sender, command::CommandValue::UnknownDomain as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("No such domain".as_bytes().to_vec()), false),
+                }
+            }
+
+            command::CommandValue::CancelMedia => {
+                info!("rx cancelmedia on {}", sender.address);
+
+                // [transfer_id u16]
+                let payload_bytes = actual_payload.into_vec();
+                if payload_bytes.len() < 2 {
+                    send_command(sender, command::CommandValue::InvalidCommand as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("Missing transfer id".as_bytes().to_vec()), false);
+                    return;
+                }
+                let transfer_id = u16::from_be_bytes([payload_bytes[0], payload_bytes[1]]);
+                if !sender.cancel_media_transfer(transfer_id) {
+                    send_command(sender, command::CommandValue::InvalidCommand as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("No such media transfer".as_bytes().to_vec()), false);
+                }
             }
 
             _ => { send_command(sender, command::CommandValue::InvalidCommand as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("Unknown Command".as_bytes().to_vec()), false); }
@@ -464,9 +907,11 @@ fn process_message(sender: &mut user::User<sms::SocketSMSHandler>, msg_id: u8, b
         }
         if !(sender.client_has_latest_domain_info) {
             send_command(sender, command::CommandValue::InvalidCommand as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("Domain info out of date - Refusing to send".as_bytes().to_vec()), false);
+            return;
         }
         if !(sender.client_has_latest_channel_list[platform_idx]) {
             send_command(sender, command::CommandValue::InvalidCommand as command::CommandInt, &mut BitVec::<u8,Lsb0>::from_vec("Channel info on domain out of date - Refusing to send".as_bytes().to_vec()), false);
+            return;  // user_idx may be from before a room joined or left
         }
 
         let msg_content_bytes = actual_payload.drain(2..).collect();
@@ -483,6 +928,7 @@ fn process_message(sender: &mut user::User<sms::SocketSMSHandler>, msg_id: u8, b
             room_idx: user_idx,
             display_name: String::new(),
             content: msg_content_str,
+            msg_id: Some(msg_id),
         }) {
             Ok(()) => {},
             Err(_e) => {
@@ -1,21 +1,136 @@
 use matrix_sdk::{
     Client,
-    ruma, ruma::{ events::room::message::SyncRoomMessageEvent }
+    event_handler::EventHandlerHandle,
+    ruma, ruma::{ events::room::message::SyncRoomMessageEvent, events::room::encrypted::OriginalSyncRoomEncryptedEvent },
+    ruma::{ events::SyncStateEvent, events::space::child::SpaceChildEventContent, serde::Raw },
+    ruma::{ api::client::error::{ ErrorKind, RetryAfter }, OwnedTransactionId, TransactionId },
+    ruma::events::{ poll::start::OriginalSyncPollStartEvent, poll::unstable_start::OriginalSyncUnstablePollStartEvent, sticker::OriginalSyncStickerEvent },
+    ruma::{ events::room::MediaSource, OwnedMxcUri },
+    config::RequestConfig,
+    RoomMemberships,
+    media::{ MediaFormat, MediaRequestParameters },
 };
 
 
-use std::sync::mpsc::{Sender, Receiver};
+use tokio::sync::mpsc::{UnboundedSender, UnboundedReceiver, unbounded_channel};
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use log::{info, warn};
 
 use crate::matrix_message::MatrixMessage;
 use crate::matrix_message::MatrixBotControlMessage;
+use crate::render;
+use crate::media;
 
 pub struct MatrixBotChannels(
-    pub Sender::<MatrixMessage>, pub Receiver::<MatrixMessage>,  // TX/RX for actual messages
-    pub Sender::<MatrixBotControlMessage>, pub Receiver::<MatrixBotControlMessage> // TX/RX for control messages
+    pub UnboundedSender::<MatrixMessage>, pub UnboundedReceiver::<MatrixMessage>,  // TX/RX for actual messages
+    pub UnboundedSender::<MatrixBotControlMessage>, pub UnboundedReceiver::<MatrixBotControlMessage> // TX/RX for control messages
 ); // these do NOT form a typical channel pair, eg 0 does not send to 1
 
+// from the room event handlers back to main_loop
+enum RoomEvent {
+    Message { room_id: String, sender: String, content: String, media: Option<MediaSource> },
+    SpaceChild { room_id: String, present: bool },
+    AdminReply { content: String },
+    Echo { event_id: String },
+}
+
+// how long to wait for the echo of a sent message before forgetting about it
+const ECHO_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(600);
+
+// media seen in the rooms, so fetch_media can decrypt what it downloads
+const KNOWN_MEDIA: usize = 256;
+
+const FIND_USER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
+// give up on a rate limited message after this many tries
+const MAX_SEND_ATTEMPTS: u32 = 5;
+
+// backoff step when the homeserver rate limits without saying for how long
+const RATE_LIMIT_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
+
+struct PendingSend {
+    room_id: String,
+    message: MatrixMessage,
+    txn_id: OwnedTransactionId,  // reused on retry, so a send that did land is not duplicated
+    attempts: u32,
+    retry_at: tokio::time::Instant,
+}
+
+struct PendingFind {
+    msg_id: u8,  // of the find_user, echoed back to the phone
+    identifier: String,
+    room_id: Option<String>,  // linked in the bridge's reply, before the room turned up in the space
+    deadline: tokio::time::Instant,
+}
+
+// Management room command that makes the bridge open a dm with someone - assumes a mautrix bridge, they all call it pm
+pub fn start_dm_command(identifier: &str) -> String {
+    format!("pm {}", identifier)
+}
+
+// Whether a room is the dm find_user asked for, going by the room's name and its members' names and localparts.
+// The identifier has to appear as a whole word, a leading @ and a discord style #1234 are ignored
+pub fn room_matches_identifier(identifier: &str, names: &[String]) -> bool {
+    let wanted = identifier.trim().trim_start_matches('@').split('#').next().unwrap_or("").to_lowercase();
+    if wanted.is_empty() {
+        return false;
+    }
+    names.iter().map(|name| name.to_lowercase()).any(|name| {
+        name.match_indices(&wanted).any(|(start, _)| {
+            let before = name[..start].chars().next_back();
+            let after = name[start + wanted.len()..].chars().next();
+            !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+        })
+    })
+}
+
+// Room ids a bridge reply links to, eg. https://matrix.to/#/!abc:example.com or its percent-encoded form
+pub fn mentioned_room_ids(text: &str) -> Vec<String> {
+    let decoded = text.replace("%21", "!").replace("%3A", ":").replace("%3a", ":");
+    decoded.match_indices('!')
+        .map(|(start, _)| decoded[start..].split(|c: char| c.is_whitespace() || "?/)>\"'<,".contains(c)).next().unwrap_or(""))
+        .map(|candidate| candidate.trim_end_matches('.'))
+        .filter(|candidate| candidate.contains(':') && ruma::RoomId::parse(*candidate).is_ok())  // ruma alone lets a bare ! through
+        .map(str::to_string)
+        .collect()
+}
+
+// how long M_LIMIT_EXCEEDED asks us to wait before retrying (retry_after_ms, or the Retry-After header)
+pub fn rate_limit_delay(retry_after: Option<RetryAfter>, attempts: u32) -> std::time::Duration {
+    match retry_after {
+        Some(RetryAfter::Delay(delay)) => delay,
+        Some(RetryAfter::DateTime(at)) => at.duration_since(std::time::SystemTime::now()).unwrap_or_default(),
+        None => RATE_LIMIT_DELAY * (attempts + 1),
+    }
+}
+
+fn send_error_reason(why: &matrix_sdk::Error, room_name: &str) -> String {
+    match why.client_api_error_kind() {
+        Some(ErrorKind::Forbidden { .. }) => format!("Not allowed to send to {}", room_name),
+        Some(ErrorKind::LimitExceeded { .. }) => format!("Rate limited sending to {}", room_name),
+        _ => format!("Failed to send to {}: {}", room_name, why),
+    }
+}
+
+// stickers and polls from anyone but us, already rendered for the phone
+fn forward_rendered(room_event_tx: &UnboundedSender::<RoomEvent>, self_addr: &str, room: &matrix_sdk::room::Room, sender: &ruma::UserId, content: Option<String>, media: Option<MediaSource>) {
+    let content = match content {
+        Some(content) if sender.as_str() != self_addr => content,
+        _ => return,
+    };
+    if let Err(e) = room_event_tx.send(RoomEvent::Message { room_id: room.room_id().to_string(), sender: sender.to_string(), content, media }) {
+        warn!("mbot failed to send msg on room_event_tx - {}", e);
+    }
+}
+
+// (room id, still in the space) for an m.space.child event - removing a child leaves an event with no via behind
+fn space_child_change(ev: &Raw<SyncStateEvent<SpaceChildEventContent>>) -> Option<(String, bool)> {
+    let room_id = ev.get_field::<String>("state_key").ok()??;
+    let present = matches!(ev.deserialize(), Ok(SyncStateEvent::Original(child)) if !child.content.via.is_empty());
+    Some((room_id, present))
+}
+
 
 pub struct MatrixBotInfo {
     pub bot_address: String,
@@ -25,6 +140,7 @@ pub struct MatrixBotInfo {
     pub channel_infos: Vec::<MatrixChannelInfo>,
 }
 
+#[allow(dead_code)]
 pub struct MatrixBot {
     pub client: Arc<Client>,
     pub self_addr: String,
@@ -32,15 +148,22 @@ pub struct MatrixBot {
     pub platform: String, // used for determining how to format the message (appservice name)
     dm_space: matrix_sdk::room::Room,
     admin_room_id: String,
-    pub channels: Vec::<MatrixChannel>,
+    pub channels: Vec::<Option<MatrixChannel>>,  // by the phone's user_id, None where a room has left the space
     pub internal_channels: MatrixBotChannels,
+    room_events: (UnboundedSender::<RoomEvent>, UnboundedReceiver::<RoomEvent>),
+    handlers: Vec::<EventHandlerHandle>,  // on the dm space and admin room
+    pending_finds: Vec::<PendingFind>,  // oldest first
+    pending_echoes: HashMap<String, (u8, tokio::time::Instant)>,  // event id -> (phone msg_id, sent at)
+    pending_sends: Vec::<PendingSend>,  // rate limited, in the order they were sent
+    known_media: VecDeque<(String, MediaSource)>,  // (mxc uri, source) oldest first
 }
 
 impl MatrixBot {
-    pub fn new(client: Arc<Client>, bot_address: String, platform: String, dm_space_name: String, admin_room_id: String, channels: MatrixBotChannels) -> MatrixBot {
-        let self_addr = client.user_id().expect("client without userid!").to_owned().to_string();
-        let dm_space_id = ruma::RoomId::parse(&dm_space_name.as_str()).expect(&format!("Failed to create room ID {}", dm_space_name).as_str());
-        let dm_space = (&*client).get_room(&dm_space_id).expect(format!("Failed to get dm room (ID: {})", dm_space_id).as_str());
+    // Fails if the client is not logged in or the dm space is not one of its rooms, eg. a typo in the credentials
+    pub fn new(client: Arc<Client>, bot_address: String, platform: String, dm_space_name: String, admin_room_id: String, channels: MatrixBotChannels) -> Result<MatrixBot, String> {
+        let self_addr = client.user_id().ok_or("client without userid!")?.to_owned().to_string();
+        let dm_space_id = ruma::RoomId::parse(dm_space_name.as_str()).map_err(|_| format!("Failed to create room ID {}", dm_space_name))?;
+        let dm_space = client.get_room(&dm_space_id).ok_or_else(|| format!("Failed to get dm room (ID: {})", dm_space_id))?;
 
         let mbot = MatrixBot {
             client,
@@ -51,130 +174,473 @@ impl MatrixBot {
             admin_room_id,
             channels: vec![],
             internal_channels: channels,
+            room_events: unbounded_channel(),
+            handlers: vec![],
+            pending_finds: vec![],
+            pending_echoes: HashMap::new(),
+            pending_sends: vec![],
+            known_media: VecDeque::new(),
         };
 
 
-        return mbot;
+        return Ok(mbot);
     }
 
-    pub async fn initialize_channels(&mut self) {
-        let room_child_events = self.dm_space.get_state_events(ruma::events::StateEventType::from("m.space.child")).await.expect("Failed to get child events");
+    pub async fn initialize_channels(&mut self) -> Result<(), String> {
+        let room_child_events = self.dm_space.get_state_events_static::<SpaceChildEventContent>().await.map_err(|why| format!("Failed to get child events: {}", why))?;
             
         for event_enum in room_child_events {
-            let event = match event_enum {
-                matrix_sdk::deserialized_responses::RawAnySyncOrStrippedState::Sync(raw_ev) => { 
-                    raw_ev.deserialize().expect("Failed to deserialize json when getting m.space.child events")
-                },
+            let raw_ev = match event_enum {
+                matrix_sdk::deserialized_responses::RawSyncOrStrippedState::Sync(raw_ev) => raw_ev,
                 _ => { continue; } // used for rooms without an accepted ivnite, never triggers as these do not cause an m.space.child event (i think)
                                          // hmm interestingly Sync seems to include my invited but not accepted ig messages????
             };
-            // take state_key field from event, gives us room id
-            let room_id = event.state_key();
-
-            let convo_id = ruma::RoomId::parse(&room_id).expect(&format!("Failed to get room from m.space.child event (Address {})", room_id).as_str());
-            let latest_convo_room = match self.client.get_room(&convo_id) {
-                Some(room) => room,
-                None => { warn!("failed to join room with id {}", room_id); continue; }  // typically outdated/expired/left rooms ig
-            };
+            match space_child_change(&raw_ev) {
+                Some((room_id, true)) => {
+                    if let Some(channel) = self.open_channel(&room_id) { self.channels.push(Some(channel)); }
+                }
+                _ => { continue; }  // removed from the space, the event is left behind with empty content
+            }
+        }
+        Ok(())
+    }
 
-            let convo_display_name = match latest_convo_room.name() {
-                Some(name) => name,
-                None => { match latest_convo_room.cached_display_name() {  // fixme: .display_name() used to be .cached_display_name()
-                    Some(name) => match name {
-                        matrix_sdk::RoomDisplayName::Named(name) => name,
-                        matrix_sdk::RoomDisplayName::Aliased(name) => name,
-                        matrix_sdk::RoomDisplayName::Calculated(name) => name,
-                        matrix_sdk::RoomDisplayName::EmptyWas(_former_name) => continue, // Abort - Left room, no use
-                        matrix_sdk::RoomDisplayName::Empty => "[Unnamed room]".to_string()
-                    },
-                    None => "[Unnamed room]".to_string()
-                }}
-            };
+    // take state_key field from event, gives us room id
+    fn open_channel(&self, room_id: &str) -> Option<MatrixChannel> {
+        let convo_id = match ruma::RoomId::parse(room_id) {
+            Ok(convo_id) => convo_id,
+            Err(_) => { warn!("Bad room id in m.space.child event (Address {})", room_id); return None; }
+        };
+        let latest_convo_room = match self.client.get_room(&convo_id) {
+            Some(room) => room,
+            None => { warn!("failed to join room with id {}", room_id); return None; }  // typically outdated/expired/left rooms ig
+        };
 
-            // add room 
-            self.channels.push(MatrixChannel {
-                display_name: convo_display_name.to_string(),
-                room: latest_convo_room,
-                room_id: convo_id.to_string(),
-            });
+        let convo_display_name = match latest_convo_room.name() {
+            Some(name) => name,
+            None => { match latest_convo_room.cached_display_name() {  // fixme: .display_name() used to be .cached_display_name()
+                Some(name) => match name {
+                    matrix_sdk::RoomDisplayName::Named(name) => name,
+                    matrix_sdk::RoomDisplayName::Aliased(name) => name,
+                    matrix_sdk::RoomDisplayName::Calculated(name) => name,
+                    matrix_sdk::RoomDisplayName::EmptyWas(_former_name) => return None, // Abort - Left room, no use
+                    matrix_sdk::RoomDisplayName::Empty => "[Unnamed room]".to_string()
+                },
+                None => "[Unnamed room]".to_string()
+            }}
+        };
 
-        }
+        // add room 
+        Some(MatrixChannel {
+            display_name: convo_display_name.to_string(),
+            room: latest_convo_room,
+            room_id: convo_id.to_string(),
+            handlers: vec![],
+        })
     }
 
     pub async fn init(&mut self) {
         // listeners
         // create event handlers
         for i in 0..self.channels.len() {
-            let room_tx_channel = self.internal_channels.0.clone();
-            let ctrl_tx_channel = self.internal_channels.2.clone();
-    
-            let room_idx = i.clone();
-            let self_addr = self.self_addr.clone();
-    
-            (self.channels[i].room).add_event_handler(move |ev: SyncRoomMessageEvent| async move {
-                let sender = ev.sender().as_str().to_owned();
-                let content = match ev {
-                    SyncRoomMessageEvent::Original(msg) => msg.content.body().to_string(),
-                    SyncRoomMessageEvent::Redacted(_msg) => { info!("redacted event - skipping"); return }
+            self.watch_channel(i);
+        }
+
+        // rooms the bridge adds to or removes from the dm space later on
+        let room_event_tx = self.room_events.0.clone();
+        self.handlers.push(self.dm_space.add_event_handler(move |ev: Raw<SyncStateEvent<SpaceChildEventContent>>| async move {
+            if let Some((room_id, present)) = space_child_change(&ev) {
+                let _ = room_event_tx.send(RoomEvent::SpaceChild { room_id, present });
+            }
+        }));
+
+        // replies from the bridge to the commands find_user sends it
+        let admin_room = ruma::RoomId::parse(&self.admin_room_id).ok().and_then(|room_id| self.client.get_room(&room_id));
+        match admin_room {
+            Some(admin_room) => {
+                let room_event_tx = self.room_events.0.clone();
+                let self_addr = self.self_addr.clone();
+                self.handlers.push(admin_room.add_event_handler(move |ev: SyncRoomMessageEvent| async move {
+                    if let SyncRoomMessageEvent::Original(msg) = ev {
+                        if msg.sender.as_str() != self_addr {
+                            let _ = room_event_tx.send(RoomEvent::AdminReply { content: msg.content.body().to_string() });
+                        }
+                    }
+                }));
+            }
+            None => warn!("admin room {} not found - find_user will not work on {}", &self.admin_room_id, &self.platform),
+        }
+    }
+
+    fn watch_channel(&mut self, i: usize) {
+        let (room, room_name) = match &self.channels[i] {
+            Some(channel) => (channel.room.clone(), channel.display_name.clone()),
+            None => return,
+        };
+        let room_event_tx = self.room_events.0.clone();
+        let self_addr = self.self_addr.clone();
+
+        let message_handler = room.add_event_handler(move |ev: SyncRoomMessageEvent, room: matrix_sdk::room::Room| async move {
+            let sender = ev.sender().as_str().to_owned();
+            let event_id = ev.event_id().to_string();
+            let (content, media) = match ev {
+                SyncRoomMessageEvent::Original(msg) => (render::message(&msg.content.msgtype), render::media_source(&msg.content.msgtype)),
+                SyncRoomMessageEvent::Redacted(_msg) => { info!("redacted event - skipping"); return }
+            };
+
+            if sender == self_addr {
+                // message from self - we know it was delivered, main_loop matches it to what we sent
+                info!("received self msg - confirmed delivery");
+                match room_event_tx.send(RoomEvent::Echo { event_id }) {
+                    Ok(_) => {},
+                    Err(_) => warn!("failed to send delivery receipt on room_event_tx")
+                }
+            } else {
+                // main_loop looks up the room's index
+                match room_event_tx.send(RoomEvent::Message { room_id: room.room_id().to_string(), sender, content, media }) {
+                    Ok(_) => {},
+                    Err(e) => warn!("mbot failed to send msg on room_event_tx - {}", e)
                 };
+            }
+        });
+
+        let (tx, addr) = (self.room_events.0.clone(), self.self_addr.clone());
+        let sticker_handler = room.add_event_handler(move |ev: OriginalSyncStickerEvent, room: matrix_sdk::room::Room| async move {
+            forward_rendered(&tx, &addr, &room, &ev.sender, Some(render::sticker(&ev.content)), Some(MediaSource::from(ev.content.source.clone())));
+        });
+        let (tx, addr) = (self.room_events.0.clone(), self.self_addr.clone());
+        let poll_handler = room.add_event_handler(move |ev: OriginalSyncPollStartEvent, room: matrix_sdk::room::Room| async move {
+            forward_rendered(&tx, &addr, &room, &ev.sender, Some(render::poll_start(&ev.content)), None);
+        });
+        let (tx, addr) = (self.room_events.0.clone(), self.self_addr.clone());
+        let unstable_poll_handler = room.add_event_handler(move |ev: OriginalSyncUnstablePollStartEvent, room: matrix_sdk::room::Room| async move {
+            forward_rendered(&tx, &addr, &room, &ev.sender, render::unstable_poll_start(&ev.content), None);
+        });
+
+        // encrypted events only get here if the sdk could not decrypt them, the keys may still turn up via key sharing
+        let encrypted_handler = room.add_event_handler(move |ev: OriginalSyncRoomEncryptedEvent| async move {
+            warn!("unable to decrypt event {} in {} - no room key yet", ev.event_id, room_name);
+        });
+
+        if let Some(channel) = self.channels[i].as_mut() {
+            channel.handlers = vec![message_handler, sticker_handler, poll_handler, unstable_poll_handler, encrypted_handler];
+        }
+    }
+
+    // ask the bridge to open a dm, the room it creates turns up in the dm space (or it points us at an existing one)
+    async fn find_user(&mut self, msg_id: u8, identifier: String) {
+        let admin_room = ruma::RoomId::parse(&self.admin_room_id).ok().and_then(|room_id| self.client.get_room(&room_id));
+        let admin_room = match admin_room {
+            Some(admin_room) => admin_room,
+            None => {
+                let _ = self.internal_channels.2.send(MatrixBotControlMessage::UserNotFound { msg_id, reason: "No admin room for this bridge".to_string() });
+                return;
+            }
+        };
+
+        let command = start_dm_command(&identifier);
+        info!("finding {} on {} - sending \"{}\" to the admin room", &identifier, &self.platform, &command);
+        if let Err(why) = admin_room.send(ruma::events::room::message::RoomMessageEventContent::text_plain(&command)).await {
+            let _ = self.internal_channels.2.send(MatrixBotControlMessage::UserNotFound { msg_id, reason: format!("Failed to reach the bridge: {}", why) });
+            return;
+        }
+        self.pending_finds.push(PendingFind { msg_id, identifier, room_id: None, deadline: tokio::time::Instant::now() + FIND_USER_TIMEOUT });
+    }
+
+    fn resolve_find(&mut self, find_idx: usize, room_idx: usize) {
+        let find = self.pending_finds.remove(find_idx);
+        info!("found {} on {} at room {}", &find.identifier, &self.platform, room_idx);
+        let _ = self.internal_channels.2.send(MatrixBotControlMessage::UserFound { msg_id: find.msg_id, room_idx });
+    }
+
+    // the bridge answers commands in order, so a reply linking a room is about the oldest find it has not linked one for yet
+    fn admin_reply(&mut self, content: String) {
+        let find_idx = match self.pending_finds.iter().position(|find| find.room_id.is_none()) {
+            Some(find_idx) => find_idx,
+            None => return,
+        };
+        let room_id = match mentioned_room_ids(&content).into_iter().next() {
+            Some(room_id) => room_id,
+            None => { info!("bridge replied on {}: {}", &self.platform, &content); return; }
+        };
+        match self.room_idx(&room_id) {
+            // a dm that already exists does not change the space, the bridge just links it
+            Some(room_idx) => self.resolve_find(find_idx, room_idx),
+            None => self.pending_finds[find_idx].room_id = Some(room_id),
+        }
+    }
+
+    // a room new to the space only answers the find_user it is for - anyone can start a dm with us in the meantime
+    async fn resolve_new_room(&mut self, room_idx: usize) {
+        let (room, room_id, mut names) = match &self.channels[room_idx] {
+            Some(channel) => (channel.room.clone(), channel.room_id.clone(), vec![channel.display_name.clone()]),
+            None => return,
+        };
+        if let Ok(members) = room.members_no_sync(RoomMemberships::ACTIVE).await {
+            for member in members.iter().filter(|member| member.user_id().as_str() != self.self_addr) {
+                names.push(member.user_id().localpart().to_string());
+                names.extend(member.display_name().map(str::to_string));
+            }
+        }
+
+        let find_idx = self.pending_finds.iter().position(|find| find.room_id.as_deref() == Some(room_id.as_str()))
+            .or_else(|| self.pending_finds.iter().position(|find| find.room_id.is_none() && room_matches_identifier(&find.identifier, &names)));
+        if let Some(find_idx) = find_idx {
+            self.resolve_find(find_idx, room_idx);
+        }
+    }
+
+    fn expire_finds(&mut self) {
+        let now = tokio::time::Instant::now();
+        let (expired, waiting): (Vec<PendingFind>, Vec<PendingFind>) = self.pending_finds.drain(..).partition(|find| find.deadline <= now);
+        self.pending_finds = waiting;
+        for find in expired {
+            warn!("gave up finding {} on {}", &find.identifier, &self.platform);
+            let _ = self.internal_channels.2.send(MatrixBotControlMessage::UserNotFound { msg_id: find.msg_id, reason: format!("No room from the bridge for {}", find.identifier) });
+        }
+    }
+
+    fn send_failed(&self, msg_id: Option<u8>, reason: String) {
+        warn!("{} on platform {}", &reason, &self.platform);
+        if let Some(msg_id) = msg_id {
+            let _ = self.internal_channels.2.send(MatrixBotControlMessage::SendFailed { msg_id, reason });
+        }
+    }
+
+    async fn send_message(&mut self, room_id: String, message: MatrixMessage, txn_id: OwnedTransactionId, attempts: u32) {
+        // nothing overtakes a message that is waiting out a rate limit in the same room
+        if let Some(retry_at) = self.pending_sends.iter().filter(|pending| pending.room_id == room_id).map(|pending| pending.retry_at).max() {
+            self.pending_sends.push(PendingSend { room_id, message, txn_id, attempts, retry_at });
+            return;
+        }
 
-                if sender == self_addr {
-                    // message from self - we know it was delivered
-                    info!("received self msg - confirmed delivery");
-                    match ctrl_tx_channel.send(MatrixBotControlMessage::MessageSuccess) {
-                        Ok(_) => {},
-                        Err(_) => warn!("failed to send delivery receipt on mbot ctrl channel")
+        let (room, display_name) = match self.channels.iter().flatten().find(|channel| channel.room_id == room_id) {
+            Some(channel) => (channel.room.clone(), channel.display_name.clone()),
+            None => { self.send_failed(message.msg_id, format!("Room {} has left the dm space", &room_id)); return; }
+        };
+        let outgoing_payload = ruma::events::room::message::RoomMessageEventContent::text_plain(&message.content);
+        info!("sending message {} to {} on platform {}", &message.content, &display_name, &self.platform);
+
+        // the sdk would sit out rate limits itself for up to 15 minutes, holding up every other room
+        let result = room.send(outgoing_payload)
+            .with_transaction_id(txn_id.clone())
+            .with_request_config(RequestConfig::new().disable_retry())
+            .await;
+        match result {
+            Ok(response) => {
+                // any echo is still queued in room_events behind us, so it cannot be missed
+                if let Some(msg_id) = message.msg_id {
+                    let now = tokio::time::Instant::now();
+                    self.pending_echoes.retain(|_, (_, sent_at)| now.duration_since(*sent_at) < ECHO_TIMEOUT);
+                    self.pending_echoes.insert(response.event_id.to_string(), (msg_id, now));
+                }
+            }
+            Err(why) => {
+                let retry_after = match why.client_api_error_kind() {
+                    Some(ErrorKind::LimitExceeded { retry_after }) if attempts + 1 < MAX_SEND_ATTEMPTS => Some(*retry_after),
+                    _ => None,
+                };
+                match retry_after {
+                    Some(retry_after) => {
+                        let delay = rate_limit_delay(retry_after, attempts);
+                        info!("rate limited sending to {} on platform {} - retrying in {:?}", &display_name, &self.platform, delay);
+                        self.pending_sends.push(PendingSend { room_id, message, txn_id, attempts: attempts + 1, retry_at: tokio::time::Instant::now() + delay });
                     }
-                } else {
-                    match room_tx_channel.send(MatrixMessage {
-                        room_idx: room_idx,
-                        display_name: sender,
-                        content: content
-                    }) {
-                        Ok(_) => {},
-                        Err(e) => warn!("mbot failed to send msg on room_tx_channel - {}", e)
-                    };
+                    None => self.send_failed(message.msg_id, send_error_reason(&why, &display_name)),
+                }
+            }
+        }
+    }
+
+    async fn retry_sends(&mut self) {
+        let now = tokio::time::Instant::now();
+        let (due, waiting): (Vec<PendingSend>, Vec<PendingSend>) = self.pending_sends.drain(..).partition(|pending| pending.retry_at <= now);
+        self.pending_sends = waiting;
+        for pending in due {
+            self.send_message(pending.room_id, pending.message, pending.txn_id, pending.attempts).await;
+        }
+    }
+
+    fn remember_media(&mut self, source: MediaSource) {
+        if self.known_media.len() >= KNOWN_MEDIA {
+            self.known_media.pop_front();
+        }
+        self.known_media.push_back((render::media_uri(&source), source));
+    }
+
+    // download in the background, the result comes back to the phone's side as MediaReady / MediaFailed
+    fn fetch_media(&self, msg_id: u8, uri: String, budget: usize) {
+        let source = match self.known_media.iter().rev().find(|(known_uri, _)| *known_uri == uri) {
+            Some((_, source)) => source.clone(),
+            None => {
+                // not one we have seen, it can only be fetched if it is unencrypted
+                let mxc = OwnedMxcUri::from(uri.as_str());
+                if !mxc.is_valid() {
+                    let _ = self.internal_channels.2.send(MatrixBotControlMessage::MediaFailed { msg_id, reason: format!("{} is not an mxc:// uri", &uri) });
+                    return;
                 }
+                MediaSource::Plain(mxc)
+            }
+        };
+
+        info!("fetching {} on platform {} for a {} byte budget", &uri, &self.platform, budget);
+        let (client, control_tx) = (self.client.clone(), self.internal_channels.2.clone());
+        tokio::spawn(async move {
+            let request = MediaRequestParameters { source, format: MediaFormat::File };
+            let result = match client.media().get_media_content(&request, true).await {
+                Ok(data) => tokio::task::spawn_blocking(move || media::shrink(data, budget)).await.unwrap_or_else(|why| Err(why.to_string())),
+                Err(why) => Err(format!("Failed to download {}: {}", &uri, why)),
+            };
+            let _ = control_tx.send(match result {
+                Ok((data, mimetype)) => MatrixBotControlMessage::MediaReady { msg_id, data, mimetype },
+                Err(reason) => MatrixBotControlMessage::MediaFailed { msg_id, reason },
             });
+        });
+    }
+
+    // rooms that have left the space are listed without a name or room id, so the others keep their index
+    fn channel_infos(&self) -> Vec::<MatrixChannelInfo> {
+        self.channels.iter().map(|channel| match channel {
+            Some(channel) => channel.convert_to_info(),
+            None => MatrixChannelInfo { room_id: String::new(), display_name: String::new() },
+        }).collect()
+    }
+
+    fn room_idx(&self, room_id: &str) -> Option<usize> {
+        self.channels.iter().position(|channel| channel.as_ref().is_some_and(|channel| channel.room_id == room_id))
+    }
+
+    // keep the channel list in step with the dm space, and tell the phone whenever it changes
+    // the phone addresses rooms by index, so new rooms go on the end and a room that leaves leaves a gap behind
+    async fn update_space_child(&mut self, room_id: String, present: bool) {
+        let existing = self.room_idx(&room_id);
+        match (existing, present) {
+            (None, true) => {
+                let channel = match self.open_channel(&room_id) {
+                    Some(channel) => channel,
+                    None => return,
+                };
+                info!("new room {} ({}) on {}", &channel.display_name, &room_id, &self.platform);
+                self.channels.push(Some(channel));
+                self.watch_channel(self.channels.len() - 1);
+
+                // the phone needs the new list before it can use the index
+                let _ = self.internal_channels.2.send(MatrixBotControlMessage::UpdateChannels { channels: self.channel_infos() });
+                self.resolve_new_room(self.channels.len() - 1).await;
+                return;
+            }
+            (Some(i), false) => {
+                let channel = match self.channels[i].take() {
+                    Some(channel) => channel,
+                    None => return,
+                };
+                info!("room {} ({}) left the space on {}", &channel.display_name, &room_id, &self.platform);
+                for handler in channel.handlers {
+                    self.client.remove_event_handler(handler);
+                }
+            }
+            _ => return,  // nothing changed, eg. the bridge updated the via list
         }
 
+        let _ = self.internal_channels.2.send(MatrixBotControlMessage::UpdateChannels { channels: self.channel_infos() });
     }
-    
+
     pub async fn main_loop(&mut self) {
         loop {
-            // poll for command channel messages
-            let latest_control_msg = self.internal_channels.3.try_recv();
-            // we have a control message to deal with
-            if latest_control_msg.is_ok() {
-                let latest_control_msg = latest_control_msg.expect("Failed to unwrap an OK value (control_msg)");
-                match latest_control_msg {
-                    MatrixBotControlMessage::RequestChannels { domain_idx } => {
-                        info!("rx reqchannels");
-                        let mut channel_infos: Vec::<MatrixChannelInfo> = vec![];
-                        for channel in self.channels.iter() {
-                            channel_infos.push(channel.convert_to_info());
+            let next_find_deadline = self.pending_finds.iter().map(|find| find.deadline).min();
+            let find_timer = async {
+                match next_find_deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let next_retry = self.pending_sends.iter().map(|pending| pending.retry_at).min();
+            let send_timer = async {
+                match next_retry {
+                    Some(retry_at) => tokio::time::sleep_until(retry_at).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                // we have a control message to deal with
+                latest_control_msg = self.internal_channels.3.recv() => {
+                    match latest_control_msg {
+                        Some(MatrixBotControlMessage::RequestChannels) => {
+                            info!("rx reqchannels");
+                            let _ = self.internal_channels.2.send(
+                                MatrixBotControlMessage::UpdateChannels{ channels: self.channel_infos() }
+                            );
                         }
 
-                        let _ = self.internal_channels.2.send(
-                            MatrixBotControlMessage::UpdateChannels{ domain_idx: domain_idx, channels: channel_infos }
-                        );
+                        Some(MatrixBotControlMessage::DeliveryFailed { room_idx, notice }) => {
+                            let notice_payload = ruma::events::room::message::RoomMessageEventContent::notice_plain(&notice);
+                            match self.channels.get(room_idx).and_then(Option::as_ref) {
+                                Some(target_channel) => { let _ = target_channel.room.send(notice_payload).await; },
+                                None => {
+                                    let admin_room = ruma::RoomId::parse(&self.admin_room_id).ok().and_then(|room_id| self.client.get_room(&room_id));
+                                    match admin_room {
+                                        Some(admin_room) => { let _ = admin_room.send(notice_payload).await; },
+                                        None => warn!("no room to report failed delivery in: {}", &notice),
+                                    }
+                                }
+                            }
+                        }
+
+                        Some(MatrixBotControlMessage::FindUser { msg_id, identifier }) => {
+                            self.find_user(msg_id, identifier).await;
+                        }
+
+                        Some(MatrixBotControlMessage::FetchMedia { msg_id, uri, budget }) => self.fetch_media(msg_id, uri, budget),
+
+                        Some(MatrixBotControlMessage::TerminateBot) | None => {
+                            // the client outlives the bot, its handlers would keep firing into closed channels
+                            for handler in self.channels.iter_mut().flatten().flat_map(|channel| channel.handlers.drain(..)).chain(self.handlers.drain(..)) {
+                                self.client.remove_event_handler(handler);
+                            }
+                            return;
+                        }
+                        _ => { warn!("rx unimplemented control msg"); }  // unimplemented
                     }
+                }
 
-                    MatrixBotControlMessage::TerminateBot => {
-                        return;
+                Some(room_event) = self.room_events.1.recv() => {
+                    match room_event {
+                        RoomEvent::Message { room_id, sender, content, media } => {
+                            if let Some(source) = media {
+                                self.remember_media(source);
+                            }
+                            let room_idx = match self.room_idx(&room_id) {
+                                Some(room_idx) => room_idx,
+                                None => { warn!("msg from room {} no longer in the space - dropping", &room_id); continue; }
+                            };
+                            match self.internal_channels.0.send(MatrixMessage { room_idx, display_name: sender, content, msg_id: None }) {
+                                Ok(_) => {},
+                                Err(e) => warn!("mbot failed to send msg on room_tx_channel - {}", e)
+                            };
+                        }
+                        RoomEvent::SpaceChild { room_id, present } => self.update_space_child(room_id, present).await,
+                        RoomEvent::AdminReply { content } => self.admin_reply(content),
+                        RoomEvent::Echo { event_id } => {
+                            if let Some((msg_id, _)) = self.pending_echoes.remove(&event_id) {
+                                let _ = self.internal_channels.2.send(MatrixBotControlMessage::MessageSuccess { msg_id });
+                            }
+                        }
                     }
-                    _ => { warn!("rx unimplemented control msg"); }  // unimplemented
                 }
-            }
-            
 
-            let latest_msg = self.internal_channels.1.try_recv();
-            if latest_msg.is_ok() {
-                let latest_msg = latest_msg.expect("Failed to unwrap an OK value (matrix_msg)");
-                let target_channel = &self.channels[latest_msg.room_idx];
-                let outgoing_payload = ruma::events::room::message::RoomMessageEventContent::text_plain(&latest_msg.content);
-                let _ = target_channel.room.send(outgoing_payload).await;
-                info!("sending message {} to {} on platform {}", &latest_msg.content, &target_channel.display_name, &self.platform);
+                _ = find_timer => self.expire_finds(),
+
+                _ = send_timer => self.retry_sends().await,
+
+                Some(latest_msg) = self.internal_channels.1.recv() => {
+                    let room_id = match self.channels.get(latest_msg.room_idx) {
+                        Some(Some(target_channel)) => target_channel.room_id.clone(),
+                        Some(None) => { self.send_failed(latest_msg.msg_id, format!("Room {} has left the dm space", latest_msg.room_idx)); continue; }
+                        None => { self.send_failed(latest_msg.msg_id, format!("No room {} on this bridge", latest_msg.room_idx)); continue; }
+                    };
+                    self.send_message(room_id, latest_msg, TransactionId::new(), 0).await;
+                }
             }
         }
     }
@@ -187,9 +653,10 @@ pub struct MatrixChannel {
     pub display_name: String,
     room: matrix_sdk::room::Room,
     room_id: String,
+    handlers: Vec::<EventHandlerHandle>,  // removed again if the room leaves the space
 }
 pub struct MatrixChannelInfo {
-    room_id: String,
+    pub room_id: String,
     pub display_name: String,
 }
 
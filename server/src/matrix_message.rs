@@ -4,12 +4,21 @@ pub struct MatrixMessage {
     pub room_idx: usize,
     pub display_name: String,
     pub content: String,
+    pub msg_id: Option<u8>,  // of the phone's msg:data, so its echo can be reported back as delivery_success
 }
 
 
 pub enum MatrixBotControlMessage {
-    RequestChannels { domain_idx: u8 },
-    UpdateChannels { domain_idx: u8, channels: Vec::<matrix_bot::MatrixChannelInfo> },
-    MessageSuccess,
+    RequestChannels,
+    UpdateChannels { channels: Vec::<matrix_bot::MatrixChannelInfo> },  // on request, and whenever a room joins or leaves the dm space
+    MessageSuccess { msg_id: u8 },  // the homeserver echoed the event sent for this msg_id
+    SendFailed { msg_id: u8, reason: String },  // the homeserver would not take the message, relayed to the phone as an error
+    DeliveryFailed { room_idx: usize, notice: String }, // posted as a notice into the room, or the admin room if it is gone
+    FindUser { msg_id: u8, identifier: String },  // msg_id of the phone's find_user, for the reply
+    UserFound { msg_id: u8, room_idx: usize },
+    UserNotFound { msg_id: u8, reason: String },
+    FetchMedia { msg_id: u8, uri: String, budget: usize },  // msg_id of the phone's fetch_media, budget in bytes
+    MediaReady { msg_id: u8, data: Vec<u8>, mimetype: String },  // downloaded and shrunk to the budget
+    MediaFailed { msg_id: u8, reason: String },
     TerminateBot,
 }
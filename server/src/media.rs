@@ -0,0 +1,88 @@
+/*
+    Media fetched for the phone with fetch_media - shrunk to the transport's budget, then sent as a run of media_chunk messages
+    A transfer is not limited to one message: each chunk is a message of its own, and the next one only goes out once
+    the last is acked, which hands the last one's msg_id back to the pool. A transfer holds at most two msg_ids, for media_start
+    and the first chunk, however many chunks it runs to
+    Only images are shrunk, audio and video are not transcoded and have to fit the budget as they are
+*/
+
+use image::codecs::jpeg::JpegEncoder;
+
+use crate::block;
+use crate::render;
+
+// one chunk is this many blocks, leaving room for the command and the chunk header
+pub const MEDIA_CHUNK_BLOCKS: usize = 64;
+pub const MEDIA_CHUNK_HEADER: usize = 2 + 4;  // transfer_id u16, offset u32
+pub const MEDIA_CHUNK_BYTES: usize = MEDIA_CHUNK_BLOCKS * (138 - block::BLOCK_GENERATION_OCTETS) - 1 - MEDIA_CHUNK_HEADER;
+
+// The [media] section of the config file
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediaPolicy {
+    pub budget: u32,  // most bytes fetch_media will send for one item, images are shrunk to fit
+}
+
+impl Default for MediaPolicy {
+    fn default() -> Self {
+        MediaPolicy { budget: 32*1024 }
+    }
+}
+
+// images are scaled down from this until they fit, and given up on below the smallest
+const MAX_IMAGE_SIDE: u32 = 1280;
+const MIN_IMAGE_SIDE: u32 = 48;
+const JPEG_QUALITIES: [u8; 3] = [75, 50, 30];
+
+// (bytes to send, mimetype) - anything but an image has to fit as it is
+pub fn shrink(data: Vec<u8>, budget: usize) -> Result<(Vec<u8>, String), String> {
+    let format = image::guess_format(&data).ok();
+    let mimetype = format.map(|format| format.to_mime_type()).unwrap_or("application/octet-stream").to_string();
+    if data.len() <= budget {
+        return Ok((data, mimetype));
+    }
+    if format.is_none() {
+        return Err(format!("{} is over the {} budget, only images can be shrunk", render::format_size(data.len() as u64), render::format_size(budget as u64)));
+    }
+
+    let original = image::load_from_memory(&data).map_err(|why| format!("Could not read image: {}", why))?;
+    let mut side = original.width().max(original.height()).min(MAX_IMAGE_SIDE);
+    while side >= MIN_IMAGE_SIDE {
+        let scaled = original.thumbnail(side, side).to_rgb8();  // jpeg has no alpha
+        for quality in JPEG_QUALITIES {
+            let mut encoded = vec![];
+            scaled.write_with_encoder(JpegEncoder::new_with_quality(&mut encoded, quality)).map_err(|why| format!("Could not encode image: {}", why))?;
+            if encoded.len() <= budget {
+                return Ok((encoded, "image/jpeg".to_string()));
+            }
+        }
+        side = side * 3 / 4;
+    }
+    Err(format!("Could not shrink image to the {} budget", render::format_size(budget as u64)))
+}
+
+pub struct MediaTransfer {
+    pub transfer_id: u16,
+    pub fetch_msg_id: u8,  // of the phone's fetch_media
+    pub data: Vec<u8>,
+    pub offset: usize,  // start of the next chunk
+    pub in_flight: Option<u8>,  // msg_id of the chunk waiting on its acks
+}
+
+impl MediaTransfer {
+    pub fn new(transfer_id: u16, fetch_msg_id: u8, data: Vec<u8>) -> MediaTransfer {
+        MediaTransfer { transfer_id, fetch_msg_id, data, offset: 0, in_flight: None }
+    }
+
+    // media_chunk payload: [transfer_id u16][offset u32][bytes], None once everything has been sent
+    pub fn next_chunk(&mut self) -> Option<Vec<u8>> {
+        if self.offset >= self.data.len() {
+            return None;
+        }
+        let end = (self.offset + MEDIA_CHUNK_BYTES).min(self.data.len());
+        let mut chunk = self.transfer_id.to_be_bytes().to_vec();
+        chunk.extend_from_slice(&(self.offset as u32).to_be_bytes());
+        chunk.extend_from_slice(&self.data[self.offset..end]);
+        self.offset = end;
+        Some(chunk)
+    }
+}
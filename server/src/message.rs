@@ -1,72 +1,98 @@
 use crate::block;
 
 use bitvec::prelude::*;
-use std::collections::BTreeSet;
+use std::collections::BTreeMap;
+use log::warn;
 
+pub const NACK_DELAY_MS: u64 = 2*1000;  // quiet time on a partial message before asking for the missing blocks, shorter than the sender's whole message refresh
+pub const MAX_NACKS: u32 = 3;  // per stall - reset whenever a new block arrives
 
 pub struct Message {
     pub msg_id: u8, // actually only 5 bits
     pub is_command: bool,
     pub is_multipart: bool,
-    pub num_blocks: u8,
-    pub stored_blocks: BTreeSet<u8>,
+    pub num_blocks: u8, // 0 until the mp_first block has arrived
+    pub stored_blocks: BTreeMap<u8, BitVec<u8, Lsb0>>, // block position -> block payload
 
-    pub payload: BitVec<u8, Lsb0>,
+    pub payload: BitVec<u8, Lsb0>, // only filled in once the message is complete
 
     pub is_complete: bool,
 
     pub received_at: std::time::Instant,
+    pub last_block_instant: tokio::time::Instant,
+    pub nacks_sent: u32,
 }
 
 impl Message {
-    pub fn new(first_block: &mut block::Block) -> Message {
-
-        let num_blocks = if first_block.data.get(block::BLOCK_MPNO0_RANGE).unwrap().load::<u8>() == 1 { first_block.data.get(block::BLOCK_MPIDX_RANGE).unwrap().load::<u8>() + 1 } else { 0 };
+    pub fn new(first_block: &block::Block) -> Message {
+        // first_block is the first block we received, which is not necessarily the mp_first block
         let is_multipart = first_block.data.get(block::BLOCK_ISMLP_RANGE).unwrap().load::<u8>() == 1;
 
         let mut new_msg = Message {
             msg_id: first_block.data.get(block::BLOCK_MSGID_RANGE).unwrap().load::<u8>(),
             is_command: first_block.data.get(block::BLOCK_ISCOM_RANGE).unwrap().load::<u8>() == 1,
             is_multipart,
-            num_blocks,
-            stored_blocks: BTreeSet::new(),
+            num_blocks: if is_multipart { 0 } else { 1 },
+            stored_blocks: BTreeMap::new(),
             payload: bitvec![u8, Lsb0;],
-            is_complete: !is_multipart,
+            is_complete: false,
             received_at: std::time::Instant::now(),
+            last_block_instant: tokio::time::Instant::now(),
+            nacks_sent: 0,
         };
 
-        new_msg.stored_blocks.insert(new_msg.msg_id);
-        let payload_range_start = if is_multipart { block::BLOCK_MPPAY_RANGE.start } else { block::BLOCK_PAYLD_RANGE.start };
-        
-        for i in payload_range_start..first_block.data.len() {
-            new_msg.payload.insert(i-payload_range_start, first_block.data.get(i..=i).unwrap().load::<u8>() == 1);
-        }
-
+        new_msg.add_block(first_block);
         new_msg
+    }
 
+    pub fn has_block(&self, position: u8) -> bool {
+        self.stored_blocks.contains_key(&position)
     }
 
-    pub fn add_block(&mut self, new_block: &mut block::Block) {
-        // update existing message to account for the new block
+    // Positions we know we are missing. Until the mp_first block arrives we do not know the block count, so only gaps
+    // below the highest stored position (plus position 0 itself) can be reported
+    pub fn missing_positions(&self) -> Vec<u8> {
+        if self.is_complete { return vec![]; }
+        let known_blocks = if self.num_blocks != 0 {
+            self.num_blocks as usize
+        } else {
+            self.stored_blocks.keys().next_back().map(|highest| *highest as usize + 1).unwrap_or(0)
+        };
+        (0..known_blocks).map(|position| position as u8).filter(|position| !self.stored_blocks.contains_key(position)).collect()
+    }
 
-        if self.num_blocks == 0 {
-            if new_block.data.get(block::BLOCK_MPNO0_RANGE).unwrap().load::<u8>() == 1 { 
-                self.num_blocks = new_block.data.get(block::BLOCK_MPIDX_RANGE).unwrap().load::<u8>() + 1 // set the number of blocks in the message
-            }
+    pub fn next_nack_instant(&self) -> Option<tokio::time::Instant> {
+        if self.is_complete || !self.is_multipart || self.nacks_sent >= MAX_NACKS { return None; }
+        Some(self.last_block_instant + std::time::Duration::from_millis(NACK_DELAY_MS))
+    }
+
+    pub fn add_block(&mut self, new_block: &block::Block) {
+        if self.is_complete { return; }
+
+        if self.is_multipart && new_block.data.get(block::BLOCK_MPNO0_RANGE).unwrap().load::<u8>() == 1 {
+            self.num_blocks = new_block.data.get(block::BLOCK_MPIDX_RANGE).unwrap().load::<u8>().saturating_add(1);
+            // anything stored past the end cannot belong to this message
+            self.stored_blocks.retain(|position, _| *position < self.num_blocks);
         }
 
-        // since this is only ever triggered on multipart messages, and all blocks except final have the same size, we can compute the position into payload vec as follows
-        //      payload[:mp_msg_size * highest value in stored_blocks <= new_block idx] + block payload + remaining payload values
+        let position = new_block.position();
+        if self.num_blocks != 0 && position >= self.num_blocks {
+            warn!("Block position {} out of range for msg {} of {} blocks - dropping", position, self.msg_id, self.num_blocks);
+            return;
+        }
 
-        let new_block_idx = new_block.data.get(block::BLOCK_MPIDX_RANGE).unwrap().load::<u8>();
-        let mut stb_iter = self.stored_blocks.iter();
-        while *stb_iter.next_back().unwrap() > new_block_idx {} // iterators are mutable so this approach doesnt work
-        let payload_cutoff_point: usize = (*stb_iter.next().unwrap() as usize) * (block::NON_MP_OCTETS as usize);
+        let payload_range_start = if self.is_multipart { block::BLOCK_MPPAY_RANGE.start } else { block::BLOCK_PAYLD_RANGE.start };
+        self.stored_blocks.insert(position, new_block.data[payload_range_start..].to_bitvec());
+        self.last_block_instant = tokio::time::Instant::now();
+        self.nacks_sent = 0;
 
-        let payld_range_start = if self.is_multipart { block::BLOCK_MPPAY_RANGE.start } else { block::BLOCK_PAYLD_RANGE.start };
-        for i in payld_range_start..block::BLOCK_MPPAY_RANGE.end {
-            self.payload.insert(payload_cutoff_point+i-payld_range_start, new_block.data.get(i..=i).unwrap().load::<u8>() == 1);
+        if self.num_blocks != 0 && self.stored_blocks.len() == self.num_blocks as usize {
+            // BTreeMap iterates in position order
+            for block_payload in self.stored_blocks.values() {
+                self.payload.extend_from_bitslice(block_payload);
+            }
+            self.is_complete = true;
         }
     }
 
-}
\ No newline at end of file
+}
@@ -0,0 +1,292 @@
+/*
+    HandleSMS implementation for a USB/serial GSM modem, driven with AT commands in PDU mode
+*/
+
+use bitvec::prelude::*;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use log::{debug, info, warn};
+
+use crate::block;
+use crate::retry;
+use crate::pdu;
+use crate::sms::HandleSMS;
+
+const MODEM_READ_POLL_MS: u64 = 100;
+const MODEM_COMMAND_TIMEOUT_MS: u64 = 5*1000;
+const MODEM_SEND_TIMEOUT_MS: u64 = 60*1000;  // CMGS can take a long time on a weak network
+
+const CTRL_Z: u8 = 0x1a;
+
+pub struct ModemSMSHandler {
+    outbox: Sender<(String, Vec<u8>)>,
+    inbox: tokio::sync::Mutex<UnboundedReceiver<block::Block>>,
+    running: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+    retry_policy: retry::RetryPolicy,
+}
+
+impl Drop for ModemSMSHandler {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(worker) = self.worker.take() { let _ = worker.join(); }
+    }
+}
+
+impl ModemSMSHandler {
+    pub fn new(tty_path: &str, baud_rate: u32) -> anyhow::Result<ModemSMSHandler> {
+        let port = serialport::new(tty_path, baud_rate)
+            .timeout(Duration::from_millis(MODEM_READ_POLL_MS))
+            .open()?;
+        info!("Opened modem on {}", tty_path);
+        ModemSMSHandler::from_port(port)
+    }
+
+    // Takes ownership of an already opened port (eg. one end of a pty in tests) and initializes the modem on it
+    pub fn from_port(mut port: Box<dyn serialport::SerialPort>) -> anyhow::Result<ModemSMSHandler> {
+        port.set_timeout(Duration::from_millis(MODEM_READ_POLL_MS))?;
+
+        let (outbox_tx, outbox_rx) = mpsc::channel();
+        let (inbox_tx, inbox_rx) = unbounded_channel();
+        let running = Arc::new(AtomicBool::new(true));
+
+        let mut worker = ModemWorker {
+            port,
+            rx_buf: vec![],
+            pending_reads: VecDeque::new(),
+            outbox: outbox_rx,
+            inbox: inbox_tx,
+            running: running.clone(),
+        };
+        worker.init()?;
+
+        let worker_handle = std::thread::spawn(move || worker.run());
+
+        Ok(ModemSMSHandler {
+            outbox: outbox_tx,
+            inbox: tokio::sync::Mutex::new(inbox_rx),
+            running,
+            worker: Some(worker_handle),
+            retry_policy: retry::RetryPolicy::default(),
+        })
+    }
+
+    // Policy for users on this transport, defaults to retry::RetryPolicy::default()
+    pub fn with_retry_policy(mut self, retry_policy: retry::RetryPolicy) -> ModemSMSHandler {
+        self.retry_policy = retry_policy;
+        self
+    }
+}
+
+impl HandleSMS for ModemSMSHandler {
+    fn send_block(&self, target: &str, content: &block::Block) {
+        info!("Queueing block for target {}", target);
+        if self.outbox.send((target.to_string(), content.data.as_raw_slice().to_vec())).is_err() {
+            warn!("Modem worker has stopped - dropping block for {}", target);
+        }
+    }
+
+    async fn recv_block(&self) -> Option<block::Block> {
+        self.inbox.lock().await.recv().await
+    }
+
+    fn retry_policy(&self) -> retry::RetryPolicy {
+        self.retry_policy.clone()
+    }
+}
+
+
+enum ModemLine {
+    Line(String),
+    Prompt,
+}
+
+struct ModemWorker {
+    port: Box<dyn serialport::SerialPort>,
+    rx_buf: Vec<u8>,
+    pending_reads: VecDeque<u32>, // storage indices announced by +CMTI that we have not read yet
+    outbox: Receiver<(String, Vec<u8>)>,
+    inbox: UnboundedSender<block::Block>,
+    running: Arc<AtomicBool>,
+}
+
+impl ModemWorker {
+
+    fn init(&mut self) -> anyhow::Result<()> {
+        self.command("AT", MODEM_COMMAND_TIMEOUT_MS)?;
+        self.command("ATE0", MODEM_COMMAND_TIMEOUT_MS)?;  // echo off, otherwise every command comes back as a line
+        self.command("AT+CMGF=0", MODEM_COMMAND_TIMEOUT_MS)?;  // PDU mode, text mode cannot carry binary payloads
+        self.command("AT+CNMI=2,1,0,0,0", MODEM_COMMAND_TIMEOUT_MS)?;  // announce new messages with +CMTI
+        info!("Modem initialized");
+
+        // pick up anything that arrived while we were not listening
+        let listing = self.command("AT+CMGL=4", MODEM_COMMAND_TIMEOUT_MS)?;
+        let mut stored = vec![];
+        let mut lines = listing.iter();
+        while let Some(line) = lines.next() {
+            if let Some(header) = line.strip_prefix("+CMGL:") {
+                let idx = header.split(',').next().and_then(|v| v.trim().parse::<u32>().ok());
+                match (idx, lines.next()) {
+                    (Some(idx), Some(pdu)) => stored.push((idx, pdu.clone())),
+                    _ => warn!("Malformed +CMGL entry: {}", line),
+                }
+            }
+        }
+        for (idx, pdu) in stored {
+            self.deliver_pdu(&pdu);
+            self.delete_stored(idx);
+        }
+        Ok(())
+    }
+
+    fn run(mut self) {
+        while self.running.load(Ordering::SeqCst) {
+            while let Ok((target, payload)) = self.outbox.try_recv() {
+                if let Err(e) = self.send_sms(&target, &payload) {
+                    warn!("Failed to send sms to {}: {}", target, e);
+                }
+            }
+
+            while let Some(idx) = self.pending_reads.pop_front() {
+                self.read_stored(idx);
+            }
+
+            // idle - wait for unsolicited result codes
+            match self.read_line(Instant::now() + Duration::from_millis(MODEM_READ_POLL_MS)) {
+                Ok(Some(ModemLine::Line(line))) => {
+                    if !self.handle_urc(&line) { debug!("Ignoring modem line: {}", line); }
+                }
+                Ok(_) => {},
+                Err(e) => { warn!("Modem read failed: {}", e); std::thread::sleep(Duration::from_millis(MODEM_READ_POLL_MS)); }
+            }
+        }
+        info!("Modem worker stopped");
+    }
+
+    // returns true if the line was an unsolicited result code we know about
+    fn handle_urc(&mut self, line: &str) -> bool {
+        if let Some(cmti) = line.strip_prefix("+CMTI:") {
+            // +CMTI: "SM",3
+            match cmti.rsplit(',').next().and_then(|v| v.trim().parse::<u32>().ok()) {
+                Some(idx) => { debug!("New sms in storage slot {}", idx); self.pending_reads.push_back(idx); },
+                None => warn!("Malformed +CMTI: {}", line),
+            }
+            return true;
+        }
+        false
+    }
+
+    fn read_line(&mut self, deadline: Instant) -> std::io::Result<Option<ModemLine>> {
+        loop {
+            // a line is complete once we see \r\n, the CMGS prompt is "> " with no line ending
+            if let Some(end) = self.rx_buf.windows(2).position(|w| w == b"\r\n") {
+                let line: Vec<u8> = self.rx_buf.drain(..end+2).take(end).collect();
+                let line = String::from_utf8_lossy(&line).trim().to_string();
+                if line.is_empty() { continue; }
+                return Ok(Some(ModemLine::Line(line)));
+            }
+            if self.rx_buf.starts_with(b"> ") {
+                self.rx_buf.drain(..2);
+                return Ok(Some(ModemLine::Prompt));
+            }
+            if Instant::now() >= deadline { return Ok(None); }
+
+            let mut buf = [0u8; 256];
+            match self.port.read(&mut buf) {
+                Ok(n) => self.rx_buf.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Write a command and collect the intermediate result lines up to the final OK
+    fn command(&mut self, command: &str, timeout_ms: u64) -> anyhow::Result<Vec<String>> {
+        debug!("modem tx {}", command);
+        self.port.write_all(format!("{}\r", command).as_bytes())?;
+        self.collect_response(command, timeout_ms)
+    }
+
+    fn collect_response(&mut self, command: &str, timeout_ms: u64) -> anyhow::Result<Vec<String>> {
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+        let mut response = vec![];
+        loop {
+            let line = match self.read_line(deadline)? {
+                Some(ModemLine::Line(line)) => line,
+                Some(ModemLine::Prompt) => continue,
+                None => return Err(anyhow::Error::msg(format!("Timed out waiting for response to {}", command))),
+            };
+
+            if line == "OK" {
+                return Ok(response);
+            } else if line == "ERROR" || line.starts_with("+CMS ERROR") || line.starts_with("+CME ERROR") {
+                return Err(anyhow::Error::msg(format!("{} failed: {}", command, line)));
+            } else if !self.handle_urc(&line) {
+                response.push(line);
+            }
+        }
+    }
+
+    fn send_sms(&mut self, target: &str, payload: &[u8]) -> anyhow::Result<()> {
+        let (pdu, tpdu_len) = pdu::SubmitPdu::new(target, payload.to_vec()).encode().map_err(anyhow::Error::msg)?;
+        let command = format!("AT+CMGS={}", tpdu_len);
+        debug!("modem tx {}", command);
+        self.port.write_all(format!("{}\r", command).as_bytes())?;
+
+        let deadline = Instant::now() + Duration::from_millis(MODEM_COMMAND_TIMEOUT_MS);
+        loop {
+            match self.read_line(deadline)? {
+                Some(ModemLine::Prompt) => break,
+                Some(ModemLine::Line(line)) => {
+                    if line == "ERROR" || line.starts_with("+CMS ERROR") || line.starts_with("+CME ERROR") {
+                        return Err(anyhow::Error::msg(format!("{} failed: {}", command, line)));
+                    }
+                    self.handle_urc(&line);
+                }
+                None => return Err(anyhow::Error::msg("Timed out waiting for CMGS prompt")),
+            }
+        }
+
+        self.port.write_all(pdu::to_hex(&pdu).as_bytes())?;
+        self.port.write_all(&[CTRL_Z])?;
+        self.collect_response(&command, MODEM_SEND_TIMEOUT_MS)?;
+        info!("Sent sms to {}", target);
+        Ok(())
+    }
+
+    fn read_stored(&mut self, idx: u32) {
+        let response = match self.command(&format!("AT+CMGR={}", idx), MODEM_COMMAND_TIMEOUT_MS) {
+            Ok(v) => v,
+            Err(e) => { warn!("Failed to read stored sms {}: {}", idx, e); return; }
+        };
+        // +CMGR: <stat>,[<alpha>],<length> followed by the pdu
+        match response.iter().position(|line| line.starts_with("+CMGR:")).and_then(|i| response.get(i+1)) {
+            Some(pdu) => { let pdu = pdu.clone(); self.deliver_pdu(&pdu); },
+            None => warn!("Empty +CMGR response for slot {}", idx),
+        }
+        self.delete_stored(idx);
+    }
+
+    fn delete_stored(&mut self, idx: u32) {
+        if let Err(e) = self.command(&format!("AT+CMGD={}", idx), MODEM_COMMAND_TIMEOUT_MS) {
+            warn!("Failed to delete stored sms {}: {}", idx, e);
+        }
+    }
+
+    fn deliver_pdu(&mut self, pdu: &str) {
+        match pdu::from_hex(pdu).and_then(|raw| pdu::DeliverPdu::decode(&raw)) {
+            Ok(deliver) => {
+                info!("Received new block of size {} from {}", deliver.data.len(), &deliver.originator);
+                let _ = self.inbox.send(block::Block::new(deliver.originator, BitVec::<u8,Lsb0>::from_vec(deliver.data)));
+            }
+            Err(e) => warn!("Discarding undecodable sms: {}", e),
+        }
+    }
+}
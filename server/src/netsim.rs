@@ -0,0 +1,163 @@
+/*
+    Fault injecting HandleSMS wrapper - simulates a real SMS network (loss, duplication, latency, reordering) on top of any transport
+    Faults are applied to blocks as they come in through recv_block, wrap both ends of a LoopbackSMSHandler pair to get them in both directions
+*/
+
+use futures::FutureExt;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::Duration;
+use tokio::time::Instant;
+use log::debug;
+
+use crate::block;
+use crate::retry;
+use crate::sms::HandleSMS;
+
+#[derive(Clone, Debug)]
+pub enum Latency {
+    None,
+    Fixed(Duration),
+    Uniform(Duration, Duration),  // (min, max)
+    Exponential(Duration),  // mean, long tail like a congested SMSC
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut StdRng) -> Duration {
+        match self {
+            Latency::None => Duration::ZERO,
+            Latency::Fixed(d) => *d,
+            Latency::Uniform(min, max) => if max > min { rng.gen_range(*min..=*max) } else { *min },
+            Latency::Exponential(mean) => mean.mul_f64(-(1.0 - rng.gen::<f64>()).ln()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NetworkConditions {
+    pub loss_rate: f64,  // probability that a block is dropped
+    pub duplicate_rate: f64,  // probability that a block is delivered twice
+    pub reorder_window: usize,  // a block may overtake up to this many earlier blocks that are also ready
+    pub latency: Latency,
+    pub seed: u64,
+}
+
+impl Default for NetworkConditions {
+    fn default() -> Self {
+        NetworkConditions {
+            loss_rate: 0.0,
+            duplicate_rate: 0.0,
+            reorder_window: 0,
+            latency: Latency::None,
+            seed: 0,
+        }
+    }
+}
+
+struct InFlight {
+    ready_at: Instant,
+    block: block::Block,
+}
+
+struct SimulatorState {
+    rng: StdRng,
+    in_flight: Vec<InFlight>,  // arrival order
+    inner_closed: bool,
+}
+
+pub struct FaultySMSHandler<T: HandleSMS> {
+    inner: T,
+    conditions: NetworkConditions,
+    state: tokio::sync::Mutex<SimulatorState>,
+}
+
+// gen_bool panics on anything outside [0, 1], out of range rates are taken to mean never or always
+fn probability(rate: f64) -> f64 {
+    if rate.is_nan() { 0.0 } else { rate.clamp(0.0, 1.0) }
+}
+
+impl<T: HandleSMS> FaultySMSHandler<T> {
+    pub fn new(inner: T, conditions: NetworkConditions) -> FaultySMSHandler<T> {
+        let conditions = NetworkConditions {
+            loss_rate: probability(conditions.loss_rate),
+            duplicate_rate: probability(conditions.duplicate_rate),
+            ..conditions
+        };
+        let state = SimulatorState {
+            rng: StdRng::seed_from_u64(conditions.seed),
+            in_flight: vec![],
+            inner_closed: false,
+        };
+        FaultySMSHandler { inner, conditions, state: tokio::sync::Mutex::new(state) }
+    }
+
+    pub fn inner(&self) -> &T { &self.inner }
+
+    fn admit(&self, state: &mut SimulatorState, new_block: block::Block) {
+        if state.rng.gen_bool(self.conditions.loss_rate) {
+            debug!("netsim: dropping block from {}", new_block.addr);
+            return;
+        }
+        let copies = if state.rng.gen_bool(self.conditions.duplicate_rate) { debug!("netsim: duplicating block from {}", new_block.addr); 2 } else { 1 };
+        for _ in 0..copies {
+            let ready_at = Instant::now() + self.conditions.latency.sample(&mut state.rng);
+            state.in_flight.push(InFlight { ready_at, block: new_block.clone() });
+        }
+    }
+
+    // pick one of the first reorder_window+1 blocks whose latency has elapsed
+    fn take_ready(&self, state: &mut SimulatorState) -> Option<block::Block> {
+        let now = Instant::now();
+        let ready: Vec<usize> = state.in_flight.iter().enumerate()
+            .filter(|(_, in_flight)| in_flight.ready_at <= now)
+            .map(|(i, _)| i)
+            .take(self.conditions.reorder_window + 1)
+            .collect();
+        if ready.is_empty() { return None; }
+        let pick = ready[state.rng.gen_range(0..ready.len())];
+        Some(state.in_flight.remove(pick).block)
+    }
+}
+
+impl<T: HandleSMS + Sync> HandleSMS for FaultySMSHandler<T> {
+    fn send_block(&self, target: &str, content: &block::Block) {
+        self.inner.send_block(target, content);
+    }
+
+    fn retry_policy(&self) -> retry::RetryPolicy {
+        self.inner.retry_policy()
+    }
+
+    async fn recv_block(&self) -> Option<block::Block> {
+        let mut state = self.state.lock().await;
+        loop {
+            // pull in everything the inner transport already has, so there is something to reorder
+            while !state.inner_closed {
+                match self.inner.recv_block().now_or_never() {
+                    Some(Some(new_block)) => self.admit(&mut state, new_block),
+                    Some(None) => state.inner_closed = true,
+                    None => break,
+                }
+            }
+
+            if let Some(ready_block) = self.take_ready(&mut state) {
+                return Some(ready_block);
+            }
+
+            let next_ready = state.in_flight.iter().map(|in_flight| in_flight.ready_at).min();
+            if state.inner_closed && next_ready.is_none() {
+                return None;
+            }
+
+            tokio::select! {
+                new_block = self.inner.recv_block(), if !state.inner_closed => {
+                    match new_block {
+                        Some(new_block) => self.admit(&mut state, new_block),
+                        None => state.inner_closed = true,
+                    }
+                }
+                _ = tokio::time::sleep_until(next_ready.unwrap_or_else(Instant::now)), if next_ready.is_some() => {}
+            }
+        }
+    }
+}
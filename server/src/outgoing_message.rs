@@ -1,25 +1,30 @@
 use crate::block;
 use crate::command;
+use crate::retry;
 
 use bitvec::prelude::*;
 use std::collections::HashMap;
 
-
-pub const OUTGOING_REFRESH_TIME_MS: u128 = 5*1000;  // every 5 seconds
-pub const MAX_SEND_RETRIES: u32 = 5;
-
 #[derive(Clone)]
 pub struct OutgoingMessage {
     pub msg_type: command::CommandInt,
     pub ack_data: u8,
+    pub message: BitVec<u8, Lsb0>,  // unencrypted, as passed to User::send_message
 
     pub stored_blocks: HashMap<u8, Option<block::Block>>,
-    pub last_send_instant: std::time::Instant,
+    pub last_send_instant: tokio::time::Instant,
     pub send_attempts: u32,
+    pub retry_delay: std::time::Duration,  // wait after last_send_instant before the next resend
+    pub cost: u32,  // sms sent so far
+    pub queued_at: u64,  // unix time, kept across restarts and redeliveries for the retention window
+}
+
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
 }
 
 impl OutgoingMessage {
-    pub fn new(msg_type: command::CommandInt, ack_data: u8, blocks: &Vec::<block::Block>) -> OutgoingMessage {
+    pub fn new(msg_type: command::CommandInt, ack_data: u8, message: &BitVec<u8, Lsb0>, blocks: &Vec::<block::Block>, retry_policy: &retry::RetryPolicy) -> OutgoingMessage {
 
         let num_blocks = blocks.len();
         let mut stored_blocks = HashMap::new();
@@ -35,15 +40,30 @@ impl OutgoingMessage {
         let new_outgoing = OutgoingMessage {
             msg_type,
             ack_data,
+            message: message.clone(),
 
             stored_blocks,
-            last_send_instant: std::time::Instant::now(),
+            last_send_instant: tokio::time::Instant::now(),
             send_attempts: 1,
+            retry_delay: retry_policy.delay_after_attempt(1),
+            cost: num_blocks as u32,
+            queued_at: unix_time(),
         };
 
         new_outgoing        
     }
 
+    pub fn next_send_instant(&self) -> tokio::time::Instant {
+        self.last_send_instant + self.retry_delay
+    }
+
+    // in position order, stored_blocks is keyed by the raw mpidx
+    pub fn unacked_blocks(&self) -> impl Iterator<Item = &block::Block> {
+        let mut unacked: Vec<&block::Block> = self.stored_blocks.values().flatten().collect();
+        unacked.sort_by_key(|unacked_block| unacked_block.position());
+        unacked.into_iter()
+    }
+
     pub fn acknowledge_block(&mut self, block_idx: u8) -> Option<command::CommandInt> {
         self.stored_blocks.insert(block_idx, None);
         let remaining = self.stored_blocks.iter().fold(0, |acc, item| match item.1 { Some(_) => acc+1, None => acc } );
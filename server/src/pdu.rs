@@ -0,0 +1,358 @@
+/*
+    SMS PDU codec (3GPP TS 23.040) for the binary transports in sms.rs
+    Only 8-bit data coding is used for payloads, blocks are raw binary and up to 140 octets
+*/
+
+pub const MAX_USER_DATA_OCTETS: usize = 140;
+
+// TP-MTI values (first octet, bits 0-1)
+const MTI_DELIVER: u8 = 0x00;
+const MTI_SUBMIT: u8 = 0x01;
+
+const FO_MMS: u8 = 0x04;  // TP-MMS (deliver) - set when there are no more messages waiting at the SMSC
+const FO_VPF_MASK: u8 = 0x18;
+const FO_VPF_RELATIVE: u8 = 0x10;
+const FO_UDHI: u8 = 0x40;
+
+pub const DCS_8BIT: u8 = 0x04;
+
+const TOA_INTERNATIONAL: u8 = 0x91;
+const TOA_UNKNOWN: u8 = 0x81;
+
+// GSM 03.38 default alphabet, used for alphanumeric originating addresses
+const GSM7_BASIC: [char; 128] = [
+    '@', '£', '$', '¥', 'è', 'é', 'ù', 'ì', 'ò', 'Ç', '\n', 'Ø', 'ø', '\r', 'Å', 'å',
+    'Δ', '_', 'Φ', 'Γ', 'Λ', 'Ω', 'Π', 'Ψ', 'Σ', 'Θ', 'Ξ', ' ', 'Æ', 'æ', 'ß', 'É',
+    ' ', '!', '"', '#', '¤', '%', '&', '\'', '(', ')', '*', '+', ',', '-', '.', '/',
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', ':', ';', '<', '=', '>', '?',
+    '¡', 'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O',
+    'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z', 'Ä', 'Ö', 'Ñ', 'Ü', '§',
+    '¿', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o',
+    'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z', 'ä', 'ö', 'ñ', 'ü', 'à',
+];
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InformationElement {
+    pub id: u8,
+    pub data: Vec<u8>,
+}
+
+// TP-SCTS, all fields as they appear on the wire (two digit year, timezone in signed quarter hours)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct Timestamp {
+    pub year: u8,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub tz_quarter_hours: i8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubmitPdu {
+    pub smsc: Option<String>, // None - use the SMSC configured on the modem/SIM
+    pub message_reference: u8,
+    pub destination: String,
+    pub validity_period: Option<u8>, // relative format
+    pub udh: Vec<InformationElement>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeliverPdu {
+    pub smsc: Option<String>,
+    pub originator: String,
+    pub protocol_id: u8,
+    pub data_coding: u8,
+    pub timestamp: Timestamp,
+    pub udh: Vec<InformationElement>,
+    pub data: Vec<u8>,
+}
+
+
+impl SubmitPdu {
+    pub fn new(destination: &str, data: Vec<u8>) -> SubmitPdu {
+        SubmitPdu {
+            smsc: None,
+            message_reference: 0,
+            destination: destination.to_string(),
+            validity_period: None,
+            udh: vec![],
+            data,
+        }
+    }
+
+    // Returns the full pdu, and the TPDU length (pdu without the SMSC part) required by AT+CMGS
+    pub fn encode(&self) -> Result<(Vec<u8>, usize), String> {
+        let mut pdu = encode_smsc(&self.smsc)?;
+        let smsc_len = pdu.len();
+
+        let mut first_octet = MTI_SUBMIT;
+        if self.validity_period.is_some() { first_octet |= FO_VPF_RELATIVE; }
+        if !self.udh.is_empty() { first_octet |= FO_UDHI; }
+        pdu.push(first_octet);
+        pdu.push(self.message_reference);
+        pdu.append(&mut encode_address(&self.destination)?);
+        pdu.push(0x00);  // TP-PID
+        pdu.push(DCS_8BIT);
+        if let Some(vp) = self.validity_period { pdu.push(vp); }
+        pdu.append(&mut encode_user_data(&self.udh, &self.data)?);
+
+        let tpdu_len = pdu.len() - smsc_len;
+        Ok((pdu, tpdu_len))
+    }
+
+    pub fn decode(pdu: &[u8]) -> Result<SubmitPdu, String> {
+        let mut reader = PduReader { pdu, pos: 0 };
+        let smsc = decode_smsc(&mut reader)?;
+
+        let first_octet = reader.next()?;
+        if first_octet & 0x03 != MTI_SUBMIT {
+            return Err(format!("Not an SMS-SUBMIT pdu (first octet {:02X})", first_octet));
+        }
+        let message_reference = reader.next()?;
+        let destination = decode_address(&mut reader)?;
+        let _protocol_id = reader.next()?;
+        let data_coding = reader.next()?;
+        let validity_period = match first_octet & FO_VPF_MASK {
+            0x00 => None,
+            FO_VPF_RELATIVE => Some(reader.next()?),
+            _ => { reader.take(7)?; None }  // enhanced/absolute - not something we generate, skip
+        };
+        let (udh, data) = decode_user_data(&mut reader, first_octet & FO_UDHI != 0, data_coding)?;
+
+        Ok(SubmitPdu { smsc, message_reference, destination, validity_period, udh, data })
+    }
+}
+
+impl DeliverPdu {
+    pub fn new(originator: &str, timestamp: Timestamp, data: Vec<u8>) -> DeliverPdu {
+        DeliverPdu {
+            smsc: None,
+            originator: originator.to_string(),
+            protocol_id: 0,
+            data_coding: DCS_8BIT,
+            timestamp,
+            udh: vec![],
+            data,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        if !is_8bit(self.data_coding) {
+            return Err(format!("Unsupported data coding scheme {:02X}", self.data_coding));
+        }
+        let mut pdu = encode_smsc(&self.smsc)?;
+        pdu.push(MTI_DELIVER | FO_MMS | if self.udh.is_empty() { 0 } else { FO_UDHI });
+        pdu.append(&mut encode_address(&self.originator)?);
+        pdu.push(self.protocol_id);
+        pdu.push(self.data_coding);
+        pdu.append(&mut self.timestamp.encode());
+        pdu.append(&mut encode_user_data(&self.udh, &self.data)?);
+        Ok(pdu)
+    }
+
+    pub fn decode(pdu: &[u8]) -> Result<DeliverPdu, String> {
+        let mut reader = PduReader { pdu, pos: 0 };
+        let smsc = decode_smsc(&mut reader)?;
+
+        let first_octet = reader.next()?;
+        if first_octet & 0x03 != MTI_DELIVER {
+            return Err(format!("Not an SMS-DELIVER pdu (first octet {:02X})", first_octet));
+        }
+        let originator = decode_address(&mut reader)?;
+        let protocol_id = reader.next()?;
+        let data_coding = reader.next()?;
+        let timestamp = Timestamp::decode(reader.take(7)?);
+        let (udh, data) = decode_user_data(&mut reader, first_octet & FO_UDHI != 0, data_coding)?;
+
+        Ok(DeliverPdu { smsc, originator, protocol_id, data_coding, timestamp, udh, data })
+    }
+}
+
+impl Timestamp {
+    fn encode(&self) -> Vec<u8> {
+        let swap = |v: u8| (v % 10) << 4 | (v / 10);
+        let tz = self.tz_quarter_hours.unsigned_abs();
+        let mut tz_octet = swap(tz);
+        if self.tz_quarter_hours < 0 { tz_octet |= 0x08; }
+        vec![swap(self.year), swap(self.month), swap(self.day), swap(self.hour), swap(self.minute), swap(self.second), tz_octet]
+    }
+
+    fn decode(octets: &[u8]) -> Timestamp {
+        let unswap = |v: u8| (v & 0x0f) * 10 + (v >> 4);
+        let tz = ((octets[6] & 0x07) * 10 + (octets[6] >> 4)) as i8;
+        Timestamp {
+            year: unswap(octets[0]),
+            month: unswap(octets[1]),
+            day: unswap(octets[2]),
+            hour: unswap(octets[3]),
+            minute: unswap(octets[4]),
+            second: unswap(octets[5]),
+            tz_quarter_hours: if octets[6] & 0x08 != 0 { -tz } else { tz },
+        }
+    }
+}
+
+
+struct PduReader<'a> {
+    pdu: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PduReader<'a> {
+    fn next(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        match self.pdu.get(self.pos..self.pos+n) {
+            Some(v) => { self.pos += n; Ok(v) },
+            None => Err(format!("Truncated pdu (wanted {} octets at offset {})", n, self.pos)),
+        }
+    }
+}
+
+pub fn is_8bit(data_coding: u8) -> bool {
+    (data_coding & 0xc0 == 0x00 && (data_coding >> 2) & 0x03 == 0x01) || (data_coding & 0xf0 == 0xf0 && data_coding & 0x04 != 0)
+}
+
+fn encode_semi_octets(digits: &str) -> Vec<u8> {
+    let digits: Vec<u8> = digits.bytes().map(|d| d - b'0').collect();
+    digits.chunks(2).map(|pair| pair[0] | (if pair.len() == 2 { pair[1] } else { 0xf }) << 4).collect()
+}
+
+fn decode_semi_octets(octets: &[u8], n_digits: usize) -> String {
+    octets.iter()
+        .flat_map(|octet| [octet & 0x0f, octet >> 4])
+        .take(n_digits)
+        .map(|d| if d < 10 { (b'0' + d) as char } else { '?' })
+        .collect()
+}
+
+fn split_number(number: &str) -> Result<(u8, &str), String> {
+    let (toa, digits) = match number.strip_prefix('+') {
+        Some(digits) => (TOA_INTERNATIONAL, digits),
+        None => (TOA_UNKNOWN, number),
+    };
+    if digits.is_empty() || digits.len() > 20 || !digits.bytes().all(|c| c.is_ascii_digit()) {
+        return Err(format!("Invalid address \"{}\"", number));
+    }
+    Ok((toa, digits))
+}
+
+// TP-OA / TP-DA: digit count, type of address, semi-octets
+fn encode_address(number: &str) -> Result<Vec<u8>, String> {
+    let (toa, digits) = split_number(number)?;
+    let mut out = vec![digits.len() as u8, toa];
+    out.append(&mut encode_semi_octets(digits));
+    Ok(out)
+}
+
+fn decode_address(reader: &mut PduReader) -> Result<String, String> {
+    let n_semi_octets = reader.next()? as usize;
+    let toa = reader.next()?;
+    let octets = reader.take(n_semi_octets.div_ceil(2))?;
+
+    match toa & 0x70 {
+        0x50 => Ok(unpack_gsm7(octets, n_semi_octets * 4 / 7)),
+        0x10 => Ok(format!("+{}", decode_semi_octets(octets, n_semi_octets))),
+        _ => Ok(decode_semi_octets(octets, n_semi_octets)),
+    }
+}
+
+// SMSC address: octet count (including type of address), type of address, semi-octets
+fn encode_smsc(smsc: &Option<String>) -> Result<Vec<u8>, String> {
+    match smsc {
+        None => Ok(vec![0x00]),
+        Some(number) => {
+            let (toa, digits) = split_number(number)?;
+            let mut octets = encode_semi_octets(digits);
+            let mut out = vec![(octets.len() + 1) as u8, toa];
+            out.append(&mut octets);
+            Ok(out)
+        }
+    }
+}
+
+fn decode_smsc(reader: &mut PduReader) -> Result<Option<String>, String> {
+    let len = reader.next()? as usize;
+    if len == 0 { return Ok(None); }
+    let toa = reader.next()?;
+    let octets = reader.take(len - 1)?;
+    let mut digits = decode_semi_octets(octets, octets.len() * 2);
+    if digits.ends_with('?') { digits.pop(); }  // odd digit count, F padded
+    Ok(Some(if toa & 0x70 == 0x10 { format!("+{}", digits) } else { digits }))
+}
+
+fn encode_user_data(udh: &[InformationElement], data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut user_data = vec![];
+    if !udh.is_empty() {
+        let mut header = vec![];
+        for ie in udh {
+            header.push(ie.id);
+            header.push(ie.data.len() as u8);
+            header.extend_from_slice(&ie.data);
+        }
+        user_data.push(header.len() as u8);
+        user_data.append(&mut header);
+    }
+    user_data.extend_from_slice(data);
+
+    if user_data.len() > MAX_USER_DATA_OCTETS {
+        return Err(format!("User data of {} octets exceeds {}", user_data.len(), MAX_USER_DATA_OCTETS));
+    }
+    user_data.insert(0, user_data.len() as u8);  // TP-UDL, in octets for 8-bit data
+    Ok(user_data)
+}
+
+fn decode_user_data(reader: &mut PduReader, has_udh: bool, data_coding: u8) -> Result<(Vec<InformationElement>, Vec<u8>), String> {
+    if !is_8bit(data_coding) {
+        return Err(format!("Unsupported data coding scheme {:02X}", data_coding));
+    }
+    let udl = reader.next()? as usize;
+    let mut user_data = reader.take(udl)?;
+
+    let mut udh = vec![];
+    if has_udh {
+        let udhl = *user_data.first().ok_or("Missing user data header length")? as usize;
+        let mut header = user_data.get(1..1+udhl).ok_or("Truncated user data header")?;
+        while !header.is_empty() {
+            let ie_len = *header.get(1).ok_or("Truncated information element")? as usize;
+            let ie_data = header.get(2..2+ie_len).ok_or("Truncated information element")?;
+            udh.push(InformationElement { id: header[0], data: ie_data.to_vec() });
+            header = &header[2+ie_len..];
+        }
+        user_data = &user_data[1+udhl..];
+    }
+
+    Ok((udh, user_data.to_vec()))
+}
+
+fn unpack_gsm7(octets: &[u8], n_septets: usize) -> String {
+    (0..n_septets).map(|i| {
+        let bit = i * 7;
+        let lo = octets[bit / 8] as u16;
+        let hi = *octets.get(bit / 8 + 1).unwrap_or(&0) as u16;
+        let septet = (((hi << 8) | lo) >> (bit % 8)) & 0x7f;
+        GSM7_BASIC[septet as usize]
+    }).collect()
+}
+
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let hex = hex.trim();
+    if !hex.len().is_multiple_of(2) {
+        return Err("Odd length hex pdu".to_string());
+    }
+    // by byte, the modem's response is read lossily and may not be ascii
+    hex.as_bytes().chunks(2)
+        .map(|pair| std::str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok()).ok_or_else(|| "Invalid hex in pdu".to_string()))
+        .collect()
+}
@@ -0,0 +1,130 @@
+/*
+    Renders matrix events as text for the phone - media, locations, stickers and polls have no useful body() of their own
+    Media keeps its mxc:// uri so it can be asked for later
+*/
+
+use matrix_sdk::ruma::events::{
+    poll::start::PollStartEventContent,
+    poll::unstable_start::UnstablePollStartEventContent,
+    room::message::MessageType,
+    room::MediaSource,
+    sticker::StickerEventContent,
+};
+use std::time::Duration;
+
+// eg. 512B, 3.4KB, 12MB
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match (unit, size < 10.0) {
+        (0, _) => format!("{}B", bytes),
+        (_, true) => format!("{:.1}{}", size, units[unit]),
+        (_, false) => format!("{:.0}{}", size, units[unit]),
+    }
+}
+
+// eg. 0:42, 12:05
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+pub fn media_uri(source: &MediaSource) -> String {
+    match source {
+        MediaSource::Plain(uri) => uri.to_string(),
+        MediaSource::Encrypted(file) => file.url.to_string(),
+    }
+}
+
+// [kind: filename details] caption mxc://..
+fn media(kind: &str, filename: &str, details: Vec<String>, caption: Option<&str>, source: &MediaSource) -> String {
+    let mut label = format!("[{}: {}", kind, filename);
+    for detail in details {
+        label.push(' ');
+        label.push_str(&detail);
+    }
+    label.push(']');
+    if let Some(caption) = caption {
+        label.push(' ');
+        label.push_str(caption);
+    }
+    format!("{} {}", label, media_uri(source))
+}
+
+fn details(duration: Option<Duration>, size: Option<u64>) -> Vec<String> {
+    duration.map(format_duration).into_iter().chain(size.map(format_size)).collect()
+}
+
+fn poll(question: &str, answers: Vec<&str>) -> String {
+    let options: Vec<String> = answers.iter().enumerate().map(|(i, answer)| format!("{}) {}", i + 1, answer)).collect();
+    format!("[poll: {}] {}", question, options.join(" "))
+}
+
+pub fn message(msgtype: &MessageType) -> String {
+    match msgtype {
+        MessageType::Text(text) => text.body.clone(),
+        MessageType::Emote(emote) => format!("* {}", emote.body),
+        MessageType::Notice(notice) => format!("[notice] {}", notice.body),
+        MessageType::ServerNotice(notice) => format!("[server notice] {}", notice.body),
+        MessageType::Image(image) => {
+            let size = image.info.as_ref().and_then(|info| info.size).map(u64::from);
+            media("image", image.filename(), details(None, size), image.caption(), &image.source)
+        }
+        MessageType::File(file) => {
+            let size = file.info.as_ref().and_then(|info| info.size).map(u64::from);
+            media("file", file.filename(), details(None, size), file.caption(), &file.source)
+        }
+        MessageType::Video(video) => {
+            let info = video.info.as_deref();
+            let duration = info.and_then(|info| info.duration);
+            let size = info.and_then(|info| info.size).map(u64::from);
+            media("video", video.filename(), details(duration, size), video.caption(), &video.source)
+        }
+        MessageType::Audio(audio) => {
+            let info = audio.info.as_deref();
+            let duration = info.and_then(|info| info.duration);
+            let size = info.and_then(|info| info.size).map(u64::from);
+            let kind = if audio.voice.is_some() { "voice" } else { "audio" };
+            media(kind, audio.filename(), details(duration, size), audio.caption(), &audio.source)
+        }
+        MessageType::Location(location) => format!("[location: {}] {}", location.body, location.geo_uri),
+        other => format!("[{}] {}", other.msgtype(), other.body()),
+    }
+}
+
+// what fetch_media needs to download (and decrypt) the media in a message
+pub fn media_source(msgtype: &MessageType) -> Option<MediaSource> {
+    match msgtype {
+        MessageType::Image(image) => Some(image.source.clone()),
+        MessageType::File(file) => Some(file.source.clone()),
+        MessageType::Video(video) => Some(video.source.clone()),
+        MessageType::Audio(audio) => Some(audio.source.clone()),
+        _ => None,
+    }
+}
+
+pub fn sticker(content: &StickerEventContent) -> String {
+    let source = MediaSource::from(content.source.clone());
+    format!("[sticker: {}] {}", content.body, media_uri(&source))
+}
+
+pub fn poll_start(content: &PollStartEventContent) -> String {
+    let question = content.poll.question.text.find_plain().unwrap_or("");
+    poll(question, content.poll.answers.iter().map(|answer| answer.text.find_plain().unwrap_or("")).collect())
+}
+
+// what element and most bridges still send
+pub fn unstable_poll_start(content: &UnstablePollStartEventContent) -> Option<String> {
+    match content {
+        UnstablePollStartEventContent::New(new) => {
+            let block = &new.poll_start;
+            Some(poll(&block.question.text, block.answers.iter().map(|answer| answer.text.as_str()).collect()))
+        }
+        _ => None,  // an edit, the phone already has the poll
+    }
+}
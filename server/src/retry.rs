@@ -0,0 +1,91 @@
+/*
+    Retry policy for outgoing messages - how long to wait between resends of unacked blocks and when to give up
+    Every resend is real SMS traffic, so each transport carries its own policy (see HandleSMS::retry_policy)
+*/
+
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::command;
+
+// Passed to RetryPolicy::on_give_up when an outgoing message is abandoned
+#[derive(Clone, Debug)]
+pub struct AbandonedMessage {
+    pub address: String,
+    pub msg_id: u8,
+    pub msg_type: command::CommandInt,
+    pub ack_data: u8,
+    pub send_attempts: u32,
+    pub cost: u32,  // sms sent for this message, including the first send
+}
+
+pub type GiveUpCallback = Arc<dyn Fn(&AbandonedMessage) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct RetryPolicy {
+    pub initial_delay: Duration,  // wait after the first send
+    pub multiplier: f64,  // applied to the delay after every resend
+    pub max_delay: Duration,
+    pub jitter: f64,  // each delay is scaled by a random factor in [1 - jitter, 1 + jitter]
+    pub max_attempts: u32,  // sends of the message, including the first
+    pub max_cost: Option<u32>,  // sms per message, including the first send. a resend that would exceed this is not made
+    pub on_give_up: Option<GiveUpCallback>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial_delay: Duration::from_secs(5),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(5*60),
+            jitter: 0.2,
+            max_attempts: 6,
+            max_cost: None,
+            on_give_up: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Fixed interval, no backoff - the behaviour before retry policies existed
+    pub fn fixed(interval: Duration, max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            initial_delay: interval,
+            multiplier: 1.0,
+            max_delay: interval,
+            jitter: 0.0,
+            max_attempts,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_give_up_callback(mut self, on_give_up: impl Fn(&AbandonedMessage) + Send + Sync + 'static) -> RetryPolicy {
+        self.on_give_up = Some(Arc::new(on_give_up));
+        self
+    }
+
+    // How long to wait after the given send (1 = the first send) before resending
+    pub fn delay_after_attempt(&self, send_attempts: u32) -> Duration {
+        let exponent = send_attempts.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let backoff = self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent);
+        let backoff = backoff.min(self.max_delay.as_secs_f64());
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let scale = if jitter > 0.0 { rand::thread_rng().gen_range((1.0 - jitter)..=(1.0 + jitter)) } else { 1.0 };
+        Duration::from_secs_f64(backoff * scale)
+    }
+
+    // Whether a message that has been sent send_attempts times at a cost of cost sms should be resent,
+    // resend_cost being the number of blocks that would go out again
+    pub fn should_retry(&self, send_attempts: u32, cost: u32, resend_cost: u32) -> bool {
+        send_attempts < self.max_attempts && self.within_cost(cost, resend_cost)
+    }
+
+    pub fn within_cost(&self, cost: u32, extra_cost: u32) -> bool {
+        match self.max_cost {
+            Some(max_cost) => cost + extra_cost <= max_cost,
+            None => true,
+        }
+    }
+}
@@ -0,0 +1,309 @@
+/*
+    Persistent user sessions - encryption state, authenticated bots, pending outgoing messages and the msg id pool,
+    so a restart does not force every phone to redo DhkeInit and AuthenticateToAccount over SMS
+    SessionStore is the extension point, SqliteSessionStore is what run() uses
+    Homeserver logins are kept here too, so a restart reuses the same device rather than logging in again
+*/
+
+use bitvec::prelude::*;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashMap;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::command;
+use crate::user;
+
+// Everything about a user that should survive a restart. Bots are kept by address, in domain order, and are
+// re-authenticated against the credential file on restore
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserSession {
+    pub address: String,
+    pub is_encrypted: bool,
+    pub shared_secret: [u8; 32],
+    pub unused_ids: Vec<u8>,
+    pub id_generations: [u32; 32],
+    pub bot_addresses: Vec<String>,
+    pub outgoing_messages: Vec<StoredOutgoingMessage>,
+    pub dead_letters: Vec<user::DeadLetter>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredOutgoingMessage {
+    pub msg_id: u8,
+    pub msg_type: command::CommandInt,
+    pub ack_data: u8,
+    pub message: BitVec<u8, Lsb0>,
+    pub unacked_blocks: Vec<(u8, BitVec<u8, Lsb0>)>,  // (raw block idx, encrypted block data)
+    pub send_attempts: u32,
+    pub cost: u32,
+    pub queued_at: u64,
+}
+
+// A homeserver login from a previous run, by homeserver section name
+#[derive(Clone, Debug, PartialEq)]
+pub struct HomeserverSession {
+    pub name: String,
+    pub homeserver_url: String,
+    pub user_id: String,
+    pub device_id: String,
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+}
+
+pub trait SessionStore {
+    fn load_all(&self) -> anyhow::Result<Vec<UserSession>>;
+    fn save(&self, session: &UserSession) -> anyhow::Result<()>;
+
+    // stores that do not keep homeserver logins make boost log in afresh on every start
+    fn load_homeserver_session(&self, _name: &str) -> anyhow::Result<Option<HomeserverSession>> { Ok(None) }
+    fn save_homeserver_session(&self, _session: &HomeserverSession) -> anyhow::Result<()> { Ok(()) }
+}
+
+// Keeps sessions for the lifetime of the process only - for tests, or running without persistence
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, UserSession>>,
+    homeserver_sessions: Mutex<HashMap<String, HomeserverSession>>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> MemorySessionStore {
+        MemorySessionStore::default()
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load_all(&self) -> anyhow::Result<Vec<UserSession>> {
+        Ok(self.sessions.lock().unwrap().values().cloned().collect())
+    }
+
+    fn save(&self, session: &UserSession) -> anyhow::Result<()> {
+        self.sessions.lock().unwrap().insert(session.address.clone(), session.clone());
+        Ok(())
+    }
+
+    fn load_homeserver_session(&self, name: &str) -> anyhow::Result<Option<HomeserverSession>> {
+        Ok(self.homeserver_sessions.lock().unwrap().get(name).cloned())
+    }
+
+    fn save_homeserver_session(&self, session: &HomeserverSession) -> anyhow::Result<()> {
+        self.homeserver_sessions.lock().unwrap().insert(session.name.clone(), session.clone());
+        Ok(())
+    }
+}
+
+const SQLITE_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+        address TEXT PRIMARY KEY,
+        is_encrypted INTEGER NOT NULL,
+        shared_secret BLOB NOT NULL,
+        unused_ids BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS id_generations (
+        address TEXT NOT NULL,
+        msg_id INTEGER NOT NULL,
+        generation INTEGER NOT NULL,
+        PRIMARY KEY (address, msg_id)
+    );
+    CREATE TABLE IF NOT EXISTS user_bots (
+        address TEXT NOT NULL,
+        domain_idx INTEGER NOT NULL,
+        bot_address TEXT NOT NULL,
+        PRIMARY KEY (address, domain_idx)
+    );
+    CREATE TABLE IF NOT EXISTS outgoing_messages (
+        address TEXT NOT NULL,
+        msg_id INTEGER NOT NULL,
+        msg_type INTEGER NOT NULL,
+        ack_data INTEGER NOT NULL,
+        message BLOB NOT NULL,
+        message_bits INTEGER NOT NULL,
+        send_attempts INTEGER NOT NULL,
+        cost INTEGER NOT NULL,
+        queued_at INTEGER NOT NULL,
+        PRIMARY KEY (address, msg_id)
+    );
+    CREATE TABLE IF NOT EXISTS outgoing_blocks (
+        address TEXT NOT NULL,
+        msg_id INTEGER NOT NULL,
+        block_idx INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        data_bits INTEGER NOT NULL,
+        PRIMARY KEY (address, msg_id, block_idx)
+    );
+    CREATE TABLE IF NOT EXISTS dead_letters (
+        address TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        bot_address TEXT NOT NULL,
+        room_id TEXT NOT NULL,
+        content BLOB NOT NULL,
+        queued_at INTEGER NOT NULL,
+        PRIMARY KEY (address, seq)
+    );
+    CREATE TABLE IF NOT EXISTS homeserver_sessions (
+        name TEXT PRIMARY KEY,
+        homeserver_url TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        device_id TEXT NOT NULL,
+        access_token TEXT NOT NULL,
+        refresh_token TEXT
+    );
+";
+
+// Bitvecs are stored as their bytes plus a bit length, blocks and messages are not always whole octets
+fn load_bits(bytes: Vec<u8>, bits: i64) -> BitVec<u8, Lsb0> {
+    let mut loaded = BitVec::<u8, Lsb0>::from_vec(bytes);
+    loaded.truncate(bits as usize);
+    loaded
+}
+
+pub struct SqliteSessionStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteSessionStore {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<SqliteSessionStore> {
+        // shared secrets and homeserver tokens are stored in the clear, only we get to read them
+        let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).mode(0o600).open(path.as_ref())?;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        SqliteSessionStore::from_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> anyhow::Result<SqliteSessionStore> {
+        SqliteSessionStore::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(conn: Connection) -> anyhow::Result<SqliteSessionStore> {
+        conn.execute_batch(SQLITE_SCHEMA)?;
+        Ok(SqliteSessionStore { conn: Mutex::new(conn) })
+    }
+
+    fn load(conn: &Connection, address: String) -> anyhow::Result<Option<UserSession>> {
+        let user_row = conn.query_row(
+            "SELECT is_encrypted, shared_secret, unused_ids FROM users WHERE address = ?1",
+            params![address],
+            |row| Ok((row.get::<_, bool>(0)?, row.get::<_, Vec<u8>>(1)?, row.get::<_, Vec<u8>>(2)?)),
+        ).optional()?;
+        let (is_encrypted, shared_secret, unused_ids) = match user_row {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let shared_secret: [u8; 32] = shared_secret.try_into().map_err(|_| anyhow::Error::msg(format!("Bad sized shared secret for {}", address)))?;
+
+        // only ids that have been recycled have a row
+        let mut id_generations = [0u32; 32];
+        let mut generation_query = conn.prepare("SELECT msg_id, generation FROM id_generations WHERE address = ?1")?;
+        for row in generation_query.query_map(params![address], |row| Ok((row.get::<_, u8>(0)?, row.get::<_, u32>(1)?)))? {
+            let (msg_id, generation) = row?;
+            if let Some(stored) = id_generations.get_mut(msg_id as usize) {
+                *stored = generation;
+            }
+        }
+
+        let bot_addresses = conn.prepare("SELECT bot_address FROM user_bots WHERE address = ?1 ORDER BY domain_idx")?
+            .query_map(params![address], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+
+        let mut outgoing_messages = conn.prepare("SELECT msg_id, msg_type, ack_data, message, message_bits, send_attempts, cost, queued_at FROM outgoing_messages WHERE address = ?1 ORDER BY msg_id")?
+            .query_map(params![address], |row| Ok(StoredOutgoingMessage {
+                msg_id: row.get(0)?,
+                msg_type: row.get(1)?,
+                ack_data: row.get(2)?,
+                message: load_bits(row.get(3)?, row.get(4)?),
+                unacked_blocks: vec![],
+                send_attempts: row.get(5)?,
+                cost: row.get(6)?,
+                queued_at: row.get(7)?,
+            }))?
+            .collect::<Result<Vec<StoredOutgoingMessage>, _>>()?;
+        let mut block_query = conn.prepare("SELECT block_idx, data, data_bits FROM outgoing_blocks WHERE address = ?1 AND msg_id = ?2 ORDER BY block_idx")?;
+        for outgoing_msg in &mut outgoing_messages {
+            outgoing_msg.unacked_blocks = block_query
+                .query_map(params![address, outgoing_msg.msg_id], |row| Ok((row.get::<_, u8>(0)?, load_bits(row.get(1)?, row.get(2)?))))?
+                .collect::<Result<Vec<(u8, BitVec<u8, Lsb0>)>, _>>()?;
+        }
+
+        let dead_letters = conn.prepare("SELECT bot_address, room_id, content, queued_at FROM dead_letters WHERE address = ?1 ORDER BY seq")?
+            .query_map(params![address], |row| Ok(user::DeadLetter { bot_address: row.get(0)?, room_id: row.get(1)?, content: row.get(2)?, queued_at: row.get(3)? }))?
+            .collect::<Result<Vec<user::DeadLetter>, _>>()?;
+
+        Ok(Some(UserSession { address, is_encrypted, shared_secret, unused_ids, id_generations, bot_addresses, outgoing_messages, dead_letters }))
+    }
+}
+
+impl SessionStore for SqliteSessionStore {
+    fn load_all(&self) -> anyhow::Result<Vec<UserSession>> {
+        let conn = self.conn.lock().unwrap();
+        let addresses = conn.prepare("SELECT address FROM users")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+
+        let mut sessions = vec![];
+        for address in addresses {
+            if let Some(session) = SqliteSessionStore::load(&conn, address)? {
+                sessions.push(session);
+            }
+        }
+        Ok(sessions)
+    }
+
+    // replaces everything stored for the user in one transaction
+    fn save(&self, session: &UserSession) -> anyhow::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for table in ["users", "id_generations", "user_bots", "outgoing_messages", "outgoing_blocks", "dead_letters"] {
+            tx.execute(&format!("DELETE FROM {} WHERE address = ?1", table), params![session.address])?;
+        }
+
+        tx.execute(
+            "INSERT INTO users (address, is_encrypted, shared_secret, unused_ids) VALUES (?1, ?2, ?3, ?4)",
+            params![session.address, session.is_encrypted, session.shared_secret.to_vec(), session.unused_ids],
+        )?;
+        for (msg_id, generation) in session.id_generations.iter().enumerate().filter(|(_, generation)| **generation > 0) {
+            tx.execute("INSERT INTO id_generations (address, msg_id, generation) VALUES (?1, ?2, ?3)", params![session.address, msg_id, generation])?;
+        }
+        for (domain_idx, bot_address) in session.bot_addresses.iter().enumerate() {
+            tx.execute("INSERT INTO user_bots (address, domain_idx, bot_address) VALUES (?1, ?2, ?3)", params![session.address, domain_idx, bot_address])?;
+        }
+        for outgoing_msg in &session.outgoing_messages {
+            tx.execute(
+                "INSERT INTO outgoing_messages (address, msg_id, msg_type, ack_data, message, message_bits, send_attempts, cost, queued_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![session.address, outgoing_msg.msg_id, outgoing_msg.msg_type, outgoing_msg.ack_data, outgoing_msg.message.as_raw_slice(), outgoing_msg.message.len(), outgoing_msg.send_attempts, outgoing_msg.cost, outgoing_msg.queued_at],
+            )?;
+            for (block_idx, data) in &outgoing_msg.unacked_blocks {
+                tx.execute(
+                    "INSERT INTO outgoing_blocks (address, msg_id, block_idx, data, data_bits) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![session.address, outgoing_msg.msg_id, block_idx, data.as_raw_slice(), data.len()],
+                )?;
+            }
+        }
+        for (seq, dead_letter) in session.dead_letters.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO dead_letters (address, seq, bot_address, room_id, content, queued_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![session.address, seq, dead_letter.bot_address, dead_letter.room_id, dead_letter.content, dead_letter.queued_at],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn load_homeserver_session(&self, name: &str) -> anyhow::Result<Option<HomeserverSession>> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.query_row(
+            "SELECT homeserver_url, user_id, device_id, access_token, refresh_token FROM homeserver_sessions WHERE name = ?1",
+            params![name],
+            |row| Ok(HomeserverSession { name: name.to_string(), homeserver_url: row.get(0)?, user_id: row.get(1)?, device_id: row.get(2)?, access_token: row.get(3)?, refresh_token: row.get(4)? }),
+        ).optional()?)
+    }
+
+    fn save_homeserver_session(&self, session: &HomeserverSession) -> anyhow::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO homeserver_sessions (name, homeserver_url, user_id, device_id, access_token, refresh_token) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![session.name, session.homeserver_url, session.user_id, session.device_id, session.access_token, session.refresh_token],
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,529 @@
+/*
+    HandleSMS implementation for an SMPP 3.4 SMSC, bound as a transceiver ESME
+*/
+
+use bitvec::prelude::*;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use log::{debug, info, warn};
+
+use crate::block;
+use crate::retry;
+use crate::pdu;
+use crate::sms::HandleSMS;
+
+pub const BIND_TRANSCEIVER: u32 = 0x00000009;
+pub const BIND_TRANSCEIVER_RESP: u32 = 0x80000009;
+pub const SUBMIT_SM: u32 = 0x00000004;
+pub const SUBMIT_SM_RESP: u32 = 0x80000004;
+pub const DELIVER_SM: u32 = 0x00000005;
+pub const DELIVER_SM_RESP: u32 = 0x80000005;
+pub const UNBIND: u32 = 0x00000006;
+pub const UNBIND_RESP: u32 = 0x80000006;
+pub const ENQUIRE_LINK: u32 = 0x00000015;
+pub const ENQUIRE_LINK_RESP: u32 = 0x80000015;
+pub const GENERIC_NACK: u32 = 0x80000000;
+
+pub const ESME_ROK: u32 = 0x00000000;
+pub const ESME_RINVCMDID: u32 = 0x00000003;
+
+const INTERFACE_VERSION: u8 = 0x34;
+const TON_INTERNATIONAL: u8 = 0x01;
+const TON_UNKNOWN: u8 = 0x00;
+const NPI_ISDN: u8 = 0x01;
+const ESM_CLASS_RECEIPT_MASK: u8 = 0x3c;
+
+const SMPP_HEADER_LEN: usize = 16;
+const SMPP_MAX_PDU_LEN: usize = 64*1024;
+const SMPP_READ_POLL_MS: u64 = 100;
+const SMPP_BIND_TIMEOUT_MS: u64 = 10*1000;
+
+
+#[derive(Clone, Debug)]
+pub struct SmppConfig {
+    pub address: String, // host:port of the SMSC
+    pub system_id: String,
+    pub password: String,
+    pub system_type: String,
+    pub source_addr: String, // our number, as the phone sees it
+    pub enquire_link_interval: Duration,
+    pub reconnect_delay: Duration,
+}
+
+impl SmppConfig {
+    pub fn new(address: &str, system_id: &str, password: &str, source_addr: &str) -> SmppConfig {
+        SmppConfig {
+            address: address.to_string(),
+            system_id: system_id.to_string(),
+            password: password.to_string(),
+            system_type: String::new(),
+            source_addr: source_addr.to_string(),
+            enquire_link_interval: Duration::from_secs(30),
+            reconnect_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SmppPdu {
+    pub command_id: u32,
+    pub command_status: u32,
+    pub sequence_number: u32,
+    pub body: Vec<u8>,
+}
+
+impl SmppPdu {
+    pub fn new(command_id: u32, command_status: u32, sequence_number: u32, body: Vec<u8>) -> SmppPdu {
+        SmppPdu { command_id, command_status, sequence_number, body }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(SMPP_HEADER_LEN + self.body.len());
+        out.extend_from_slice(&((SMPP_HEADER_LEN + self.body.len()) as u32).to_be_bytes());
+        out.extend_from_slice(&self.command_id.to_be_bytes());
+        out.extend_from_slice(&self.command_status.to_be_bytes());
+        out.extend_from_slice(&self.sequence_number.to_be_bytes());
+        out.extend_from_slice(&self.body);
+        out
+    }
+
+    // Pops one complete pdu off the front of buf, if there is one
+    pub fn decode(buf: &mut Vec<u8>) -> Result<Option<SmppPdu>, String> {
+        if buf.len() < 4 { return Ok(None); }
+        let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        if !(SMPP_HEADER_LEN..=SMPP_MAX_PDU_LEN).contains(&len) {
+            return Err(format!("Invalid SMPP command_length {}", len));
+        }
+        if buf.len() < len { return Ok(None); }
+
+        let raw: Vec<u8> = buf.drain(..len).collect();
+        let word = |i: usize| u32::from_be_bytes([raw[i], raw[i+1], raw[i+2], raw[i+3]]);
+        Ok(Some(SmppPdu {
+            command_id: word(4),
+            command_status: word(8),
+            sequence_number: word(12),
+            body: raw[SMPP_HEADER_LEN..].to_vec(),
+        }))
+    }
+}
+
+// Body of submit_sm and deliver_sm, which share a layout
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct ShortMessage {
+    pub service_type: String,
+    pub source_addr_ton: u8,
+    pub source_addr_npi: u8,
+    pub source_addr: String,
+    pub dest_addr_ton: u8,
+    pub dest_addr_npi: u8,
+    pub destination_addr: String,
+    pub esm_class: u8,
+    pub protocol_id: u8,
+    pub registered_delivery: u8,
+    pub data_coding: u8,
+    pub short_message: Vec<u8>,
+}
+
+impl ShortMessage {
+    pub fn new(source: &str, destination: &str, payload: Vec<u8>) -> ShortMessage {
+        let (source_addr_ton, source_addr) = split_address(source);
+        let (dest_addr_ton, destination_addr) = split_address(destination);
+        ShortMessage {
+            source_addr_ton,
+            source_addr_npi: NPI_ISDN,
+            source_addr,
+            dest_addr_ton,
+            dest_addr_npi: NPI_ISDN,
+            destination_addr,
+            data_coding: pdu::DCS_8BIT,
+            short_message: payload,
+            ..Default::default()
+        }
+    }
+
+    pub fn source(&self) -> String {
+        join_address(self.source_addr_ton, &self.source_addr)
+    }
+
+    pub fn destination(&self) -> String {
+        join_address(self.dest_addr_ton, &self.destination_addr)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = vec![];
+        push_cstring(&mut body, &self.service_type);
+        body.push(self.source_addr_ton);
+        body.push(self.source_addr_npi);
+        push_cstring(&mut body, &self.source_addr);
+        body.push(self.dest_addr_ton);
+        body.push(self.dest_addr_npi);
+        push_cstring(&mut body, &self.destination_addr);
+        body.push(self.esm_class);
+        body.push(self.protocol_id);
+        body.push(0);  // priority_flag
+        push_cstring(&mut body, "");  // schedule_delivery_time
+        push_cstring(&mut body, "");  // validity_period
+        body.push(self.registered_delivery);
+        body.push(0);  // replace_if_present_flag
+        body.push(self.data_coding);
+        body.push(0);  // sm_default_msg_id
+        body.push(self.short_message.len() as u8);
+        body.extend_from_slice(&self.short_message);
+        body
+    }
+
+    pub fn decode(body: &[u8]) -> Result<ShortMessage, String> {
+        let mut reader = BodyReader { body, pos: 0 };
+        let service_type = reader.cstring()?;
+        let source_addr_ton = reader.octet()?;
+        let source_addr_npi = reader.octet()?;
+        let source_addr = reader.cstring()?;
+        let dest_addr_ton = reader.octet()?;
+        let dest_addr_npi = reader.octet()?;
+        let destination_addr = reader.cstring()?;
+        let esm_class = reader.octet()?;
+        let protocol_id = reader.octet()?;
+        let _priority_flag = reader.octet()?;
+        let _schedule_delivery_time = reader.cstring()?;
+        let _validity_period = reader.cstring()?;
+        let registered_delivery = reader.octet()?;
+        let _replace_if_present = reader.octet()?;
+        let data_coding = reader.octet()?;
+        let _sm_default_msg_id = reader.octet()?;
+        let sm_length = reader.octet()? as usize;
+        let short_message = reader.take(sm_length)?.to_vec();
+
+        Ok(ShortMessage {
+            service_type, source_addr_ton, source_addr_npi, source_addr, dest_addr_ton, dest_addr_npi, destination_addr,
+            esm_class, protocol_id, registered_delivery, data_coding, short_message,
+        })
+    }
+}
+
+struct BodyReader<'a> {
+    body: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BodyReader<'a> {
+    fn octet(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        match self.body.get(self.pos..self.pos+n) {
+            Some(v) => { self.pos += n; Ok(v) },
+            None => Err("Truncated SMPP body".to_string()),
+        }
+    }
+
+    fn cstring(&mut self) -> Result<String, String> {
+        let len = match self.body[self.pos..].iter().position(|b| *b == 0) {
+            Some(len) => len,
+            None => return Err("Unterminated C-octet string in SMPP body".to_string()),
+        };
+        let value = String::from_utf8_lossy(&self.body[self.pos..self.pos+len]).to_string();
+        self.pos += len + 1;
+        Ok(value)
+    }
+}
+
+fn push_cstring(body: &mut Vec<u8>, value: &str) {
+    body.extend_from_slice(value.as_bytes());
+    body.push(0);
+}
+
+fn split_address(addr: &str) -> (u8, String) {
+    match addr.strip_prefix('+') {
+        Some(digits) => (TON_INTERNATIONAL, digits.to_string()),
+        None => (TON_UNKNOWN, addr.to_string()),
+    }
+}
+
+fn join_address(ton: u8, addr: &str) -> String {
+    if ton == TON_INTERNATIONAL { format!("+{}", addr) } else { addr.to_string() }
+}
+
+
+pub struct SmppSMSHandler {
+    outbox: Sender<(String, Vec<u8>)>,
+    inbox: tokio::sync::Mutex<UnboundedReceiver<block::Block>>,
+    running: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+    retry_policy: retry::RetryPolicy,
+}
+
+impl Drop for SmppSMSHandler {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(worker) = self.worker.take() { let _ = worker.join(); }
+    }
+}
+
+impl SmppSMSHandler {
+    // Connects and binds before returning, so bad credentials are reported at startup rather than in the worker
+    pub fn new(config: SmppConfig) -> anyhow::Result<SmppSMSHandler> {
+        let (outbox_tx, outbox_rx) = mpsc::channel();
+        let (inbox_tx, inbox_rx) = unbounded_channel();
+        let running = Arc::new(AtomicBool::new(true));
+
+        let mut session = SmppSession {
+            config,
+            stream: None,
+            rx_buf: vec![],
+            next_sequence: 1,
+            unacked_submits: BTreeMap::new(),
+            queued: VecDeque::new(),
+            last_enquire_link: Instant::now(),
+            awaiting_enquire_link: None,
+            outbox: outbox_rx,
+            inbox: inbox_tx,
+            running: running.clone(),
+        };
+        session.connect()?;
+
+        let worker = std::thread::spawn(move || session.run());
+
+        Ok(SmppSMSHandler {
+            outbox: outbox_tx,
+            inbox: tokio::sync::Mutex::new(inbox_rx),
+            running,
+            worker: Some(worker),
+            retry_policy: retry::RetryPolicy::default(),
+        })
+    }
+
+    // Policy for users on this transport, defaults to retry::RetryPolicy::default()
+    pub fn with_retry_policy(mut self, retry_policy: retry::RetryPolicy) -> SmppSMSHandler {
+        self.retry_policy = retry_policy;
+        self
+    }
+}
+
+impl HandleSMS for SmppSMSHandler {
+    fn send_block(&self, target: &str, content: &block::Block) {
+        info!("Queueing block for target {}", target);
+        if self.outbox.send((target.to_string(), content.data.as_raw_slice().to_vec())).is_err() {
+            warn!("SMPP session has stopped - dropping block for {}", target);
+        }
+    }
+
+    async fn recv_block(&self) -> Option<block::Block> {
+        self.inbox.lock().await.recv().await
+    }
+
+    fn retry_policy(&self) -> retry::RetryPolicy {
+        self.retry_policy.clone()
+    }
+}
+
+
+struct SmppSession {
+    config: SmppConfig,
+    stream: Option<TcpStream>,
+    rx_buf: Vec<u8>,
+    next_sequence: u32,
+    unacked_submits: BTreeMap<u32, (String, Vec<u8>)>, // sequence number -> submit, resent after a reconnect
+    queued: VecDeque<(String, Vec<u8>)>,
+    last_enquire_link: Instant,
+    awaiting_enquire_link: Option<Instant>,
+    outbox: Receiver<(String, Vec<u8>)>,
+    inbox: UnboundedSender<block::Block>,
+    running: Arc<AtomicBool>,
+}
+
+impl SmppSession {
+
+    fn sequence(&mut self) -> u32 {
+        let seq = self.next_sequence;
+        // sequence numbers are 0x00000001-0x7fffffff
+        self.next_sequence = if self.next_sequence >= 0x7fffffff { 1 } else { self.next_sequence + 1 };
+        seq
+    }
+
+    fn write_pdu(&mut self, pdu: &SmppPdu) -> anyhow::Result<()> {
+        match self.stream.as_mut() {
+            Some(stream) => { stream.write_all(&pdu.encode())?; Ok(()) },
+            None => Err(anyhow::Error::msg("Not connected")),
+        }
+    }
+
+    fn read_pdu(&mut self) -> anyhow::Result<Option<SmppPdu>> {
+        if let Some(pdu) = SmppPdu::decode(&mut self.rx_buf).map_err(anyhow::Error::msg)? {
+            return Ok(Some(pdu));
+        }
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => return Err(anyhow::Error::msg("Not connected")),
+        };
+        let mut buf = [0u8; 1024];
+        match stream.read(&mut buf) {
+            Ok(0) => Err(anyhow::Error::msg("SMSC closed the connection")),
+            Ok(n) => {
+                self.rx_buf.extend_from_slice(&buf[..n]);
+                SmppPdu::decode(&mut self.rx_buf).map_err(anyhow::Error::msg)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // run() only reconnects once stream is None, so a connection that fails to bind must not be left behind
+    fn connect(&mut self) -> anyhow::Result<()> {
+        let result = self.bind();
+        if result.is_err() {
+            self.stream = None;
+        }
+        result
+    }
+
+    fn bind(&mut self) -> anyhow::Result<()> {
+        let stream = TcpStream::connect(&self.config.address)?;
+        stream.set_read_timeout(Some(Duration::from_millis(SMPP_READ_POLL_MS)))?;
+        stream.set_nodelay(true)?;
+        self.stream = Some(stream);
+        self.rx_buf.clear();
+
+        let mut body = vec![];
+        push_cstring(&mut body, &self.config.system_id);
+        push_cstring(&mut body, &self.config.password);
+        push_cstring(&mut body, &self.config.system_type);
+        body.push(INTERFACE_VERSION);
+        body.push(TON_UNKNOWN);
+        body.push(TON_UNKNOWN);  // addr_npi
+        push_cstring(&mut body, "");  // address_range
+        let seq = self.sequence();
+        self.write_pdu(&SmppPdu::new(BIND_TRANSCEIVER, ESME_ROK, seq, body))?;
+
+        let deadline = Instant::now() + Duration::from_millis(SMPP_BIND_TIMEOUT_MS);
+        while Instant::now() < deadline {
+            let resp = match self.read_pdu()? {
+                Some(resp) => resp,
+                None => continue,
+            };
+            if resp.command_id == BIND_TRANSCEIVER_RESP && resp.sequence_number == seq {
+                if resp.command_status != ESME_ROK {
+                    return Err(anyhow::Error::msg(format!("bind_transceiver rejected with status {:#010x}", resp.command_status)));
+                }
+                info!("Bound to SMSC {} as {}", self.config.address, self.config.system_id);
+                self.last_enquire_link = Instant::now();
+                self.awaiting_enquire_link = None;
+
+                // anything the SMSC never confirmed goes out again on the new session
+                let unacked: Vec<(String, Vec<u8>)> = std::mem::take(&mut self.unacked_submits).into_values().collect();
+                for submit in unacked.into_iter().rev() { self.queued.push_front(submit); }
+                return Ok(());
+            }
+            debug!("Ignoring pdu {:#010x} while binding", resp.command_id);
+        }
+        Err(anyhow::Error::msg("Timed out waiting for bind_transceiver_resp"))
+    }
+
+    fn run(mut self) {
+        while self.running.load(Ordering::SeqCst) {
+            if self.stream.is_none() {
+                std::thread::sleep(self.config.reconnect_delay);
+                match self.connect() {
+                    Ok(()) => {},
+                    Err(e) => { warn!("SMPP reconnect failed: {}", e); continue; }
+                }
+            }
+
+            if let Err(e) = self.poll() {
+                warn!("SMPP session lost: {}", e);
+                self.stream = None;
+            }
+        }
+
+        // best effort, the SMSC will time us out otherwise
+        if self.stream.is_some() {
+            let seq = self.sequence();
+            let _ = self.write_pdu(&SmppPdu::new(UNBIND, ESME_ROK, seq, vec![]));
+        }
+        info!("SMPP session stopped");
+    }
+
+    fn poll(&mut self) -> anyhow::Result<()> {
+        while let Ok(submit) = self.outbox.try_recv() {
+            self.queued.push_back(submit);
+        }
+        while let Some((target, payload)) = self.queued.pop_front() {
+            let seq = self.sequence();
+            let body = ShortMessage::new(&self.config.source_addr, &target, payload.clone()).encode();
+            self.unacked_submits.insert(seq, (target.clone(), payload));
+            self.write_pdu(&SmppPdu::new(SUBMIT_SM, ESME_ROK, seq, body))?;
+            debug!("tx submit_sm {} to {}", seq, target);
+        }
+
+        if let Some(sent_at) = self.awaiting_enquire_link {
+            if sent_at.elapsed() > self.config.enquire_link_interval {
+                return Err(anyhow::Error::msg("No enquire_link_resp from SMSC"));
+            }
+        } else if self.last_enquire_link.elapsed() > self.config.enquire_link_interval {
+            let seq = self.sequence();
+            self.write_pdu(&SmppPdu::new(ENQUIRE_LINK, ESME_ROK, seq, vec![]))?;
+            self.awaiting_enquire_link = Some(Instant::now());
+        }
+
+        while let Some(pdu) = self.read_pdu()? {
+            self.handle_pdu(pdu)?;
+        }
+        Ok(())
+    }
+
+    fn submit_answered(&mut self, sequence_number: u32, command_status: u32) {
+        if let Some((target, _)) = self.unacked_submits.remove(&sequence_number) {
+            if command_status != ESME_ROK {
+                warn!("SMSC rejected submit_sm to {} with status {:#010x}", target, command_status);
+            }
+        }
+    }
+
+    fn handle_pdu(&mut self, pdu: SmppPdu) -> anyhow::Result<()> {
+        match pdu.command_id {
+            DELIVER_SM => {
+                self.write_pdu(&SmppPdu::new(DELIVER_SM_RESP, ESME_ROK, pdu.sequence_number, vec![0]))?;  // empty message_id
+                match ShortMessage::decode(&pdu.body) {
+                    Ok(sm) if sm.esm_class & ESM_CLASS_RECEIPT_MASK != 0 => debug!("Ignoring delivery receipt from {}", sm.source()),
+                    Ok(sm) if !pdu::is_8bit(sm.data_coding) => warn!("Ignoring deliver_sm with data_coding {:#04x} from {}", sm.data_coding, sm.source()),
+                    Ok(sm) => {
+                        info!("Received new block of size {} from {}", sm.short_message.len(), sm.source());
+                        let _ = self.inbox.send(block::Block::new(sm.source(), BitVec::<u8,Lsb0>::from_vec(sm.short_message)));
+                    }
+                    Err(e) => warn!("Malformed deliver_sm: {}", e),
+                }
+            }
+            SUBMIT_SM_RESP => self.submit_answered(pdu.sequence_number, pdu.command_status),
+            ENQUIRE_LINK => {
+                self.write_pdu(&SmppPdu::new(ENQUIRE_LINK_RESP, ESME_ROK, pdu.sequence_number, vec![]))?;
+            }
+            ENQUIRE_LINK_RESP => {
+                self.awaiting_enquire_link = None;
+                self.last_enquire_link = Instant::now();
+            }
+            UNBIND => {
+                self.write_pdu(&SmppPdu::new(UNBIND_RESP, ESME_ROK, pdu.sequence_number, vec![]))?;
+                return Err(anyhow::Error::msg("SMSC unbound the session"));
+            }
+            GENERIC_NACK => {
+                warn!("generic_nack from SMSC for sequence {} (status {:#010x})", pdu.sequence_number, pdu.command_status);
+                // a nacked submit_sm failed as surely as a rejected one, it is not resent on reconnect
+                self.submit_answered(pdu.sequence_number, pdu.command_status.max(1));
+            }
+            id if id & 0x80000000 == 0 => {
+                self.write_pdu(&SmppPdu::new(GENERIC_NACK, ESME_RINVCMDID, pdu.sequence_number, vec![]))?;
+            }
+            id => debug!("Ignoring SMPP response {:#010x}", id),
+        }
+        Ok(())
+    }
+}
@@ -1,20 +1,32 @@
 use bitvec::prelude::*;
 use std::fs;
 use std::path::{PathBuf};
-use std::os::unix::net::UnixDatagram;
+use std::future::Future;
+use tokio::net::UnixDatagram;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use log::{info, warn};
 
 use crate::block;
+use crate::retry;
 
+// send_block must not block - transports queue or send without waiting
+// recv_block waits for the next incoming block, None means the transport has shut down for good
+// retry_policy is how users on this transport resend unacked messages
 pub trait HandleSMS {
     fn send_block(&self, target: &str, content: &block::Block);
-    fn recv_block(&self) -> Option<block::Block>;
+    fn recv_block(&self) -> impl Future<Output = Option<block::Block>> + Send;
+    fn retry_policy(&self) -> retry::RetryPolicy { retry::RetryPolicy::default() }
 }
 
+// wait between failed reads of the socket, doubling up to the max
+const RECV_RETRY_MIN: std::time::Duration = std::time::Duration::from_millis(50);
+const RECV_RETRY_MAX: std::time::Duration = std::time::Duration::from_secs(5);
+
 pub struct SocketSMSHandler {
 	sock: UnixDatagram,
 	sock_in_path: PathBuf,
 	sock_out_path: PathBuf,
+	retry_policy: retry::RetryPolicy,
 }
 
 impl Drop for SocketSMSHandler {
@@ -36,16 +48,20 @@ impl SocketSMSHandler {
 
 	    let sock = UnixDatagram::bind(sock_in_path)?;
 		info!("Bound to socket {}", &sock_in_path.display());
-		// sock.connect(sock_out_path)?;
-	    sock.set_nonblocking(true)?;
 					
 		Ok(SocketSMSHandler { 
 			sock: sock,
 			sock_in_path: sock_in_path.to_owned(),
-			sock_out_path: sock_out_path.to_owned()
-
+			sock_out_path: sock_out_path.to_owned(),
+			retry_policy: retry::RetryPolicy::default(),
 		})
 	}
+
+	// Policy for users on this transport, defaults to retry::RetryPolicy::default()
+	pub fn with_retry_policy(mut self, retry_policy: retry::RetryPolicy) -> SocketSMSHandler {
+		self.retry_policy = retry_policy;
+		self
+	}
 }
 
 impl HandleSMS for SocketSMSHandler {
@@ -57,17 +73,25 @@ impl HandleSMS for SocketSMSHandler {
 		payload.push(0);
 		for &b in content.data.as_raw_slice() { payload.push(b); }
 		// let resp = self.sock.send_to(content.data.as_raw_slice(), &self.sock_out_path);
-		let resp = self.sock.send_to(payload.as_slice(), &self.sock_out_path);
+		let resp = self.sock.try_send_to(payload.as_slice(), &self.sock_out_path);
 		if let Err(send_res) = resp {
 		    warn!("Failed to send message: {}", send_res);
 		}	
 	}
 	
-	fn recv_block(&self) -> Option<block::Block> {
+	async fn recv_block(&self) -> Option<block::Block> {
 		let mut buf = vec![0; 160];
-		let bytes_read = match self.sock.recv(&mut buf) {
-		    Ok(n) => n,
-			Err(_e) => { return None; }
+		let mut retry_delay = RECV_RETRY_MIN;
+		let bytes_read = loop {
+			match self.sock.recv(&mut buf).await {
+				Ok(n) => break n,
+				Err(e) => {
+					// back off so a socket that keeps failing does not spin
+					warn!("Failed to receive from socket, retrying in {:?}: {}", retry_delay, e);
+					tokio::time::sleep(retry_delay).await;
+					retry_delay = (retry_delay * 2).min(RECV_RETRY_MAX);
+				}
+			}
 		};
 		let mut bufiter = buf.drain(..bytes_read);
 
@@ -81,10 +105,76 @@ impl HandleSMS for SocketSMSHandler {
 		info!("Received new block of size {} from {}", bytes_read, &addr);
 		Some(block::Block::new( addr.to_string(), BitVec::<u8,Lsb0>::from_vec(payload.to_vec()) ))
 	}
+
+	fn retry_policy(&self) -> retry::RetryPolicy {
+		self.retry_policy.clone()
+	}
 }
 
 pub struct VoidSMSHandler {}
 impl HandleSMS for VoidSMSHandler {
     fn send_block(&self, _target: &str, _content: &block::Block) { panic!("attempt to send with VoidSMSHandler"); }
-    fn recv_block(&self) -> Option<block::Block> { panic!("attempt to recv from VoidSMSHandler"); }
+    async fn recv_block(&self) -> Option<block::Block> { panic!("attempt to recv from VoidSMSHandler"); }
+}
+
+
+// In-memory transport, two handles wired back to back. Each handle stamps the blocks it sends with its own address,
+// so the server side of a pair sees the phone's number on every incoming block and vice versa
+pub struct LoopbackSMSHandler {
+    address: String,
+    peer_address: String,
+    tx: UnboundedSender<block::Block>,
+    rx: tokio::sync::Mutex<UnboundedReceiver<block::Block>>,
+    retry_policy: retry::RetryPolicy,
+}
+
+impl LoopbackSMSHandler {
+    // returns (server handle, phone handle)
+    pub fn pair(server_addr: &str, phone_addr: &str) -> (LoopbackSMSHandler, LoopbackSMSHandler) {
+        let (to_phone_tx, to_phone_rx) = unbounded_channel();
+        let (to_server_tx, to_server_rx) = unbounded_channel();
+        let server = LoopbackSMSHandler {
+            address: server_addr.to_string(),
+            peer_address: phone_addr.to_string(),
+            tx: to_phone_tx,
+            rx: tokio::sync::Mutex::new(to_server_rx),
+            retry_policy: retry::RetryPolicy::default(),
+        };
+        let phone = LoopbackSMSHandler {
+            address: phone_addr.to_string(),
+            peer_address: server_addr.to_string(),
+            tx: to_server_tx,
+            rx: tokio::sync::Mutex::new(to_phone_rx),
+            retry_policy: retry::RetryPolicy::default(),
+        };
+        (server, phone)
+    }
+
+    pub fn address(&self) -> &str { &self.address }
+
+    // Policy for users on this transport, defaults to retry::RetryPolicy::default()
+    pub fn with_retry_policy(mut self, retry_policy: retry::RetryPolicy) -> LoopbackSMSHandler {
+        self.retry_policy = retry_policy;
+        self
+    }
+}
+
+impl HandleSMS for LoopbackSMSHandler {
+    fn send_block(&self, target: &str, content: &block::Block) {
+        if target != self.peer_address {
+            warn!("Loopback handler {} has no route to {} - dropping block", self.address, target);
+            return;
+        }
+        if self.tx.send(block::Block::new(self.address.clone(), content.data.clone())).is_err() {
+            warn!("Loopback peer {} has gone away - dropping block", self.peer_address);
+        }
+    }
+
+    async fn recv_block(&self) -> Option<block::Block> {
+        self.rx.lock().await.recv().await
+    }
+
+    fn retry_policy(&self) -> retry::RetryPolicy {
+        self.retry_policy.clone()
+    }
 }
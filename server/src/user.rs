@@ -7,24 +7,54 @@ use crate::credential_manager;
 use crate::matrix_message::{ MatrixMessage, MatrixBotControlMessage };
 use crate::sms;
 use crate::command;
+use crate::retry;
+use crate::session_store;
+use crate::homeserver;
+use crate::media;
 
 use hkdf::Hkdf;
 use sha2::Sha256;
 use chacha20::{ ChaCha20, KeyIvInit, cipher::StreamCipher };
 use bitvec::prelude::*;
 use x25519_dalek;
-use matrix_sdk::Client;
-use futures::executor;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
  
-use std::sync::mpsc;
-use std::sync::mpsc::{Sender, Receiver};
-use std::sync::Arc;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender, UnboundedReceiver};
 use log::{info, debug, warn, error};
 
 
 const MESSAGE_KEEPFOR_DURATION_MS: u128 = 10*1000;  // Tunable!! 10s is proooobably too low but good for testing :p
+pub const MAX_DEAD_LETTERS: usize = 64;  // per user, oldest are dropped first
+
+// A matrix message we gave up delivering, held until the phone makes contact again.
+// Rooms and bots are kept by id rather than index, the indices may have moved by the time it is redelivered
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeadLetter {
+    pub bot_address: String,
+    pub room_id: String,
+    pub content: Vec<u8>,
+    pub queued_at: u64,  // unix time the message first came in from matrix
+}
+
+// How the queue of dead letters is kept, the [queue] section of the config file
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueuePolicy {
+    pub retention: std::time::Duration,  // how long a matrix message the phone could not be reached for is held for redelivery
+}
+
+impl Default for QueuePolicy {
+    fn default() -> Self {
+        QueuePolicy { retention: std::time::Duration::from_secs(7*24*60*60) }
+    }
+}
+
+// The same for every user on the server, unlike the retry policy these do not depend on the transport
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UserPolicies {
+    pub queue: QueuePolicy,
+    pub media: media::MediaPolicy,
+}
 
 pub struct User<'a, SMSHandlerT: sms::HandleSMS> {
     pub address: String,
@@ -32,37 +62,49 @@ pub struct User<'a, SMSHandlerT: sms::HandleSMS> {
 
     pub messages: HashMap<u8,message::Message>,
     pub outgoing_messages: HashMap<u8, outgoing_message::OutgoingMessage>,
+    pub dead_letters: VecDeque<DeadLetter>,
+    pub media_transfers: Vec::<media::MediaTransfer>,  // not kept across restarts, the phone has to fetch again
+    pub next_transfer_id: u16,
     pub unused_ids: Vec::<u8>, // unused outgoing ids
+    pub id_generations: [u32; 32],  // times each outgoing id has been recycled, part of its s2c nonce
 
     // todo: encryption parameters
     pub shared_secret: [u8; 32],
 
-    pub client: Arc<Client>,
+    pub homeservers: homeserver::HomeserverClients,
 
     pub matrix_bots: Vec::<matrix_bot::MatrixBotInfo>,
     pub matrix_bot_channels: Vec::<MatrixBotChannels>,
     pub client_has_latest_channel_list: Vec::<bool>,
     pub client_has_latest_domain_info: bool,
     
-    pub sms_handler: &'a SMSHandlerT
+    pub sms_handler: &'a SMSHandlerT,
+    pub retry_policy: retry::RetryPolicy,
+    pub policies: UserPolicies,
 }
 
 impl<SMSHandlerT: sms::HandleSMS> User<'_, SMSHandlerT> {
 
-    pub fn new(client: Arc<Client>, addr: String, is_enc: bool, sms_handler: &SMSHandlerT) -> User<'_, SMSHandlerT> {
+    pub fn new(homeservers: homeserver::HomeserverClients, addr: String, is_enc: bool, sms_handler: &SMSHandlerT) -> User<'_, SMSHandlerT> {
         let mut new_user = User {
             address: addr,
             is_encrypted: is_enc,
             outgoing_messages: HashMap::new(),
+            dead_letters: VecDeque::new(),
+            media_transfers: vec![],
+            next_transfer_id: 0,
             messages: HashMap::new(), // hashmap over <msgId, Message>
             unused_ids: vec![],
+            id_generations: [0; 32],
             shared_secret: [0; 32],
-            client,
+            homeservers,
             matrix_bots: vec![],
             matrix_bot_channels: vec![],
             client_has_latest_channel_list: vec![], // channel info: list of users on a given platform
             client_has_latest_domain_info: false,  // domain info: list of platforms
             sms_handler,
+            retry_policy: sms_handler.retry_policy(),
+            policies: UserPolicies::default(),
         };
 
         for i in 1..1<<5 {
@@ -73,37 +115,353 @@ impl<SMSHandlerT: sms::HandleSMS> User<'_, SMSHandlerT> {
         new_user
     }
 
+    // Rebuild a user from a stored session. Bots are re-authenticated from the credential file, any that are no longer
+    // in it are dropped
+    pub async fn restore<'a>(homeservers: homeserver::HomeserverClients, session: session_store::UserSession, sms_handler: &'a SMSHandlerT, bot_credentials: &Vec::<credential_manager::BridgeBotCredentials>) -> User<'a, SMSHandlerT> {
+        let mut restored_user = User::new(homeservers, session.address, session.is_encrypted, sms_handler);
+        restored_user.shared_secret = session.shared_secret;
+        restored_user.unused_ids = session.unused_ids;
+        restored_user.id_generations = session.id_generations;
+        restored_user.dead_letters = session.dead_letters.into();
+
+        for stored_msg in session.outgoing_messages {
+            let stored_blocks = stored_msg.unacked_blocks.into_iter()
+                .map(|(block_idx, data)| (block_idx, Some(block::Block::new(restored_user.address.clone(), data))))
+                .collect();
+            restored_user.outgoing_messages.insert(stored_msg.msg_id, outgoing_message::OutgoingMessage {
+                msg_type: stored_msg.msg_type,
+                ack_data: stored_msg.ack_data,
+                message: stored_msg.message,
+                stored_blocks,
+                last_send_instant: tokio::time::Instant::now(),
+                send_attempts: stored_msg.send_attempts,
+                retry_delay: restored_user.retry_policy.delay_after_attempt(stored_msg.send_attempts),
+                cost: stored_msg.cost,
+                queued_at: stored_msg.queued_at,
+            });
+        }
+
+        for bot_address in session.bot_addresses {
+            let botcred = match bot_credentials.iter().find(|botcred| botcred.bot_address == bot_address) {
+                Some(v) => v,
+                None => { warn!("Bot {} for {} is no longer configured - not restoring", &bot_address, &restored_user.address); continue; }
+            };
+            if restored_user.authenticate(botcred).await.is_err() {
+                warn!("Failed to restore bot {} for {}", &bot_address, &restored_user.address);
+            }
+        }
+
+        restored_user
+    }
+
+    pub fn session(&self) -> session_store::UserSession {
+        session_store::UserSession {
+            address: self.address.clone(),
+            is_encrypted: self.is_encrypted,
+            shared_secret: self.shared_secret,
+            unused_ids: self.unused_ids.clone(),
+            id_generations: self.id_generations,
+            bot_addresses: self.matrix_bots.iter().map(|bot_info| bot_info.bot_address.clone()).collect(),
+            outgoing_messages: self.outgoing_messages.iter().map(|(msg_id, outgoing_msg)| session_store::StoredOutgoingMessage {
+                msg_id: *msg_id,
+                msg_type: outgoing_msg.msg_type,
+                ack_data: outgoing_msg.ack_data,
+                message: outgoing_msg.message.clone(),
+                unacked_blocks: outgoing_msg.stored_blocks.iter()
+                    .filter_map(|(block_idx, stored_block)| stored_block.as_ref().map(|stored_block| (*block_idx, stored_block.data.clone())))
+                    .collect(),
+                send_attempts: outgoing_msg.send_attempts,
+                cost: outgoing_msg.cost,
+                queued_at: outgoing_msg.queued_at,
+            }).collect(),
+            dead_letters: self.dead_letters.iter().cloned().collect(),
+        }
+    }
+
+    // earliest point at which refresh_outgoing has something to resend
+    pub fn next_refresh_instant(&self) -> Option<tokio::time::Instant> {
+        self.outgoing_messages.values().map(|outgoing_msg| outgoing_msg.next_send_instant()).min()
+    }
+
     pub fn refresh_outgoing(&mut self) {
         let mut failed_outgoing: Vec::<u8> = vec![];
         for (outgoing_id, outgoing_msg) in &mut self.outgoing_messages {
-            let current_time = std::time::Instant::now();
-            if (current_time.duration_since(outgoing_msg.last_send_instant).as_millis()) > outgoing_message::OUTGOING_REFRESH_TIME_MS {
-                // should attempt to resend the whole message
-                outgoing_msg.last_send_instant = std::time::Instant::now();
-                outgoing_msg.send_attempts += 1;
-                if outgoing_msg.send_attempts > outgoing_message::MAX_SEND_RETRIES {
+            let current_time = tokio::time::Instant::now();
+            if current_time >= outgoing_msg.next_send_instant() {
+                // should attempt to resend everything that is still unacked
+                let resend_cost = outgoing_msg.unacked_blocks().count() as u32;
+                if !self.retry_policy.should_retry(outgoing_msg.send_attempts, outgoing_msg.cost, resend_cost) {
                     failed_outgoing.push(*outgoing_id);
+                    continue;
                 }
-                for (_block_id, block) in &outgoing_msg.stored_blocks {
-                    if let Some(block) = block { self.sms_handler.send_block(&self.address.as_str(), block); }
+                outgoing_msg.last_send_instant = current_time;
+                outgoing_msg.send_attempts += 1;
+                outgoing_msg.cost += resend_cost;
+                outgoing_msg.retry_delay = self.retry_policy.delay_after_attempt(outgoing_msg.send_attempts);
+                for block in outgoing_msg.unacked_blocks() {
+                    self.sms_handler.send_block(&self.address.as_str(), block);
                 }
             }
         }
 
         for failed_outgoing_id in failed_outgoing {
-            self.outgoing_messages.remove(&failed_outgoing_id);
+            self.abandon_outgoing(failed_outgoing_id);
         }
     }
 
-    fn get_nonce(&self, msg_id: u8, block_id: u8, dir: &str) -> [u8; 12] {
+    // Give up on an outgoing message - tell whoever registered for it, and the phone, instead of dropping it silently
+    fn abandon_outgoing(&mut self, msg_id: u8) {
+        let outgoing_msg = match self.outgoing_messages.remove(&msg_id) {
+            Some(v) => v,
+            None => return,
+        };
+        self.recycle_id(msg_id);
+        warn!("Giving up on msg {} to {} after {} sends ({} sms)", &msg_id, &self.address, &outgoing_msg.send_attempts, &outgoing_msg.cost);
+
+        if let Some(on_give_up) = &self.retry_policy.on_give_up {
+            on_give_up(&retry::AbandonedMessage {
+                address: self.address.clone(),
+                msg_id,
+                msg_type: outgoing_msg.msg_type,
+                ack_data: outgoing_msg.ack_data,
+                send_attempts: outgoing_msg.send_attempts,
+                cost: outgoing_msg.cost,
+            });
+        }
+
+        // Error payload: [msg_id of cause][utf8 message]
+        let mut payload = vec![command::CommandValue::Error as command::CommandInt, msg_id];
+        payload.extend_from_slice(format!("Gave up on message after {} sends", outgoing_msg.send_attempts).as_bytes());
+        self.send_message(BitVec::<u8,Lsb0>::from_vec(payload), true, false);
+
+        if outgoing_msg.msg_type == command::CommandValue::Data as command::CommandInt {
+            self.dead_letter(&outgoing_msg);
+        }
+        if outgoing_msg.msg_type == command::CommandValue::MediaChunk as command::CommandInt {
+            self.media_transfers.retain(|transfer| transfer.in_flight != Some(msg_id));
+        }
+    }
+
+    // Let the matrix side know a bridged message did not make it, and keep it for when the phone is back
+    fn dead_letter(&mut self, outgoing_msg: &outgoing_message::OutgoingMessage) {
+        // data payload: [room_idx][domain_idx][content]
+        let message_bytes = outgoing_msg.message.as_raw_slice();
+        if message_bytes.len() < 2 { return; }
+        let (room_idx, domain_idx) = (message_bytes[0] as usize, message_bytes[1] as usize);
+        if let Some(bot_channels) = self.matrix_bot_channels.get(domain_idx) {
+            let _ = bot_channels.2.send(MatrixBotControlMessage::DeliveryFailed {
+                room_idx,
+                notice: format!("Could not reach SMS recipient after {} attempts - messages will be held and redelivered when their phone is next in contact", outgoing_msg.send_attempts),
+            });
+        }
+        self.queue_dead_letter(room_idx, domain_idx, message_bytes[2..].to_vec(), outgoing_msg.queued_at);
+    }
+
+    fn queue_dead_letter(&mut self, room_idx: usize, domain_idx: usize, content: Vec<u8>, queued_at: u64) {
+        let bot_info = match self.matrix_bots.get(domain_idx) {
+            Some(v) => v,
+            None => { warn!("Undeliverable msg for unknown domain {} - dropping", &domain_idx); return; }
+        };
+        let room_id = match bot_info.channel_infos.get(room_idx) {
+            Some(channel_info) if !channel_info.room_id.is_empty() => channel_info.room_id.clone(),
+            _ => { warn!("Undeliverable msg for unknown room {}@{} - dropping", &room_idx, &domain_idx); return; }
+        };
+        if self.dead_letters.len() >= MAX_DEAD_LETTERS {
+            self.dead_letters.pop_front();
+        }
+        self.dead_letters.push_back(DeadLetter {
+            bot_address: bot_info.bot_address.clone(),
+            room_id,
+            content,
+            queued_at,
+        });
+    }
+
+    // Send a message from matrix to the phone. While there are dead letters the phone is presumed unreachable, so the
+    // message joins the queue instead of burning sms on retries that will not be answered. It waits there too while every msg_id is in use
+    pub fn forward_matrix_message(&mut self, room_idx: usize, domain_idx: usize, content: Vec<u8>) {
+        if !self.dead_letters.is_empty() || self.unused_ids.is_empty() {
+            info!("{} is unreachable or out of msg ids - holding msg for {}@{}", &self.address, &room_idx, &domain_idx);
+            self.queue_dead_letter(room_idx, domain_idx, content, outgoing_message::unix_time());
+            return;
+        }
+
+        let mut payload = vec![room_idx as u8, domain_idx as u8];
+        payload.extend(content);
+        self.send_message(BitVec::<u8,Lsb0>::from_vec(payload), false, true);
+    }
+
+    // drop anything held for longer than the queue policy's retention window
+    pub fn expire_dead_letters(&mut self) {
+        let now = outgoing_message::unix_time();
+        let retention = self.policies.queue.retention.as_secs();
+        let held = self.dead_letters.len();
+        self.dead_letters.retain(|dead_letter| now.saturating_sub(dead_letter.queued_at) <= retention);
+        if self.dead_letters.len() < held {
+            info!("Expired {} held msgs for {}", held - self.dead_letters.len(), &self.address);
+        }
+    }
+
+    // The phone has made contact - resend everything we gave up on while it was away
+    pub fn redeliver_dead_letters(&mut self) {
+        self.expire_dead_letters();
+        while !self.unused_ids.is_empty() {
+            let dead_letter = match self.dead_letters.pop_front() {
+                Some(v) => v,
+                None => break,
+            };
+            let domain_idx = self.matrix_bots.iter().position(|bot_info| bot_info.bot_address == dead_letter.bot_address);
+            let room_idx = domain_idx.and_then(|domain_idx| self.matrix_bots[domain_idx].channel_infos.iter().position(|channel_info| channel_info.room_id == dead_letter.room_id));
+            let (domain_idx, room_idx) = match (domain_idx, room_idx) {
+                (Some(domain_idx), Some(room_idx)) => (domain_idx, room_idx),
+                _ => { info!("Dropping dead letter for {} in {} - no longer bridged", &dead_letter.bot_address, &dead_letter.room_id); continue; }
+            };
+
+            info!("Redelivering dead letter to {}", &self.address);
+            let mut payload = vec![room_idx as u8, domain_idx as u8];
+            payload.extend_from_slice(&dead_letter.content);
+            // retention runs from when the message first came in, not from the redelivery
+            if let Some(outgoing_msg) = self.send_message(BitVec::<u8,Lsb0>::from_vec(payload), false, true).and_then(|msg_id| self.outgoing_messages.get_mut(&msg_id)) {
+                outgoing_msg.queued_at = dead_letter.queued_at;
+            }
+        }
+    }
+
+    // The phone says it is back - resend everything still unacked now rather than waiting out the backoff
+    pub fn resend_outgoing(&mut self) {
+        let current_time = tokio::time::Instant::now();
+        for (outgoing_id, outgoing_msg) in &mut self.outgoing_messages {
+            let resend_cost = outgoing_msg.unacked_blocks().count() as u32;
+            if !self.retry_policy.within_cost(outgoing_msg.cost, resend_cost) {
+                warn!("msg {} is at its sms budget - not resending", &outgoing_id);
+                continue;
+            }
+            outgoing_msg.last_send_instant = current_time;
+            outgoing_msg.cost += resend_cost;
+            outgoing_msg.retry_delay = self.retry_policy.delay_after_attempt(outgoing_msg.send_attempts);
+            for block in outgoing_msg.unacked_blocks() {
+                self.sms_handler.send_block(&self.address.as_str(), block);
+            }
+        }
+    }
+
+    // A fetch_media download, announced with media_start and then sent a chunk at a time. None if there are not the msg_ids to start it
+    pub fn start_media_transfer(&mut self, fetch_msg_id: u8, data: Vec<u8>, mimetype: &str) -> Option<u16> {
+        // media_start and the first chunk, keeping the last one for the error
+        if self.unused_ids.len() < 3 {
+            warn!("Out of msg ids to start a media transfer to {}", &self.address);
+            self.send_media_out_of_ids(fetch_msg_id);
+            return None;
+        }
+
+        let transfer_id = self.next_transfer_id;
+        self.next_transfer_id = self.next_transfer_id.wrapping_add(1);
+        info!("Starting media transfer {} of {} bytes to {}", &transfer_id, data.len(), &self.address);
+
+        // media_start payload: [msg_id of fetch_media][transfer_id u16][total bytes u32][mimetype]
+        let mut payload = vec![command::CommandValue::MediaStart as command::CommandInt, fetch_msg_id];
+        payload.extend_from_slice(&transfer_id.to_be_bytes());
+        payload.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        payload.extend_from_slice(mimetype.as_bytes());
+        self.send_message(BitVec::<u8,Lsb0>::from_vec(payload), true, true);
+
+        self.media_transfers.push(media::MediaTransfer::new(transfer_id, fetch_msg_id, data));
+        self.send_next_chunk(transfer_id);
+        Some(transfer_id)
+    }
+
+    fn send_next_chunk(&mut self, transfer_id: u16) {
+        let transfer_idx = match self.media_transfers.iter().position(|transfer| transfer.transfer_id == transfer_id) {
+            Some(v) => v,
+            None => return,
+        };
+        // every chunk takes a msg_id of its own, keep the last one for the error
+        if self.unused_ids.len() < 2 {
+            let transfer = self.media_transfers.remove(transfer_idx);
+            warn!("Out of msg ids for media transfer {} to {}", &transfer_id, &self.address);
+            self.send_media_out_of_ids(transfer.fetch_msg_id);
+            return;
+        }
+
+        match self.media_transfers[transfer_idx].next_chunk() {
+            Some(chunk) => {
+                let mut payload = vec![command::CommandValue::MediaChunk as command::CommandInt];
+                payload.extend(chunk);
+                self.media_transfers[transfer_idx].in_flight = self.send_message(BitVec::<u8,Lsb0>::from_vec(payload), true, true);
+            }
+            None => {
+                info!("Media transfer {} to {} complete", &transfer_id, &self.address);
+                self.media_transfers.remove(transfer_idx);
+            }
+        }
+    }
+
+    fn send_media_out_of_ids(&mut self, fetch_msg_id: u8) {
+        let mut payload = vec![command::CommandValue::Error as command::CommandInt, fetch_msg_id];
+        payload.extend_from_slice("Out of message ids, run dhke_init again and refetch".as_bytes());
+        self.send_message(BitVec::<u8,Lsb0>::from_vec(payload), true, false);
+    }
+
+    // The phone does not want the rest - drop the transfer along with the chunk still being resent
+    pub fn cancel_media_transfer(&mut self, transfer_id: u16) -> bool {
+        let transfer_idx = match self.media_transfers.iter().position(|transfer| transfer.transfer_id == transfer_id) {
+            Some(v) => v,
+            None => return false,
+        };
+        let transfer = self.media_transfers.remove(transfer_idx);
+        if let Some(msg_id) = transfer.in_flight {
+            if self.outgoing_messages.remove(&msg_id).is_some() {
+                self.recycle_id(msg_id);
+            }
+        }
+        info!("Cancelled media transfer {} to {} at {}/{} bytes", &transfer_id, &self.address, transfer.offset, transfer.data.len());
+        true
+    }
+
+    // earliest point at which request_missing_blocks has a stalled incoming message to ask about
+    pub fn next_nack_instant(&self) -> Option<tokio::time::Instant> {
+        self.messages.values().filter_map(|msg| msg.next_nack_instant()).min()
+    }
+
+    // ask the other side for the blocks of any partial message that has stalled
+    pub fn request_missing_blocks(&mut self) {
+        let current_time = tokio::time::Instant::now();
+        let mut nacks: Vec::<(u8, Vec<u8>)> = vec![];
+        for (msg_id, msg) in &mut self.messages {
+            match msg.next_nack_instant() {
+                Some(nack_instant) if current_time >= nack_instant => {},
+                _ => continue,
+            }
+            let missing = msg.missing_positions();
+            if missing.is_empty() { continue; }
+            // each request takes a msg_id, the rest are asked about once there are ids again
+            if nacks.len() >= self.unused_ids.len() { break; }
+            msg.nacks_sent += 1;
+            msg.last_block_instant = current_time;
+            nacks.push((*msg_id, missing));
+        }
+
+        for (msg_id, missing) in nacks {
+            info!("msg {} stalled - requesting blocks {:?}", &msg_id, &missing);
+            let mut payload = vec![command::CommandValue::MissingBlocks as command::CommandInt, msg_id];
+            payload.extend(missing);
+            self.send_message(BitVec::<u8,Lsb0>::from_vec(payload), true, false);
+        }
+    }
+
+    // generation 0 leaves the info as it was before ids were recycled
+    fn get_nonce(&self, msg_id: u8, block_id: u8, dir: &str, generation: u32) -> [u8; 12] {
         let hk = Hkdf::<Sha256>::from_prk(&self.shared_secret).expect("PRK length mismatch with SHA2");
         let mut nonce = [0u8; 12];
         let msg_id_u16: u16 = msg_id.try_into().unwrap();
         let block_id_u16: u16 = block_id.try_into().unwrap();
-        let i = (msg_id_u16 * 256 + block_id_u16) as u16;
+        let i = msg_id_u16 * 256 + block_id_u16;
         let mut info = Vec::new();
         info.extend_from_slice(dir.as_bytes());
         info.extend_from_slice(&i.to_be_bytes());
+        if generation > 0 {
+            info.extend_from_slice(&generation.to_be_bytes());
+        }
         hk.expand(&info, &mut nonce).expect("Nonce buffer length too large");
         return nonce;
     }
@@ -112,8 +470,8 @@ impl<SMSHandlerT: sms::HandleSMS> User<'_, SMSHandlerT> {
         if !self.is_encrypted { return block.clone(); }
         if (msg_id|block_id) == 0 { debug!("skipping enc due to 0:0"); return block.clone(); }
         
-        let enc_offset = if block.data.get(block::BLOCK_ISMLP_RANGE).unwrap().load::<u8>() == 1 { 2 } else { 1 };
-        let nonce = self.get_nonce(msg_id, block_id, "s2c");
+        let enc_offset = (if block.data.get(block::BLOCK_ISMLP_RANGE).unwrap().load::<u8>() == 1 { 2 } else { 1 }) + block::BLOCK_GENERATION_OCTETS;
+        let nonce = self.get_nonce(msg_id, block_id, "s2c", self.id_generations[msg_id as usize]);
         let mut cipher = ChaCha20::new(&self.shared_secret.into(), &nonce.into());
         let mut buffer = block.data.clone().into_vec();
         cipher.apply_keystream(&mut buffer[enc_offset..]);
@@ -126,7 +484,7 @@ impl<SMSHandlerT: sms::HandleSMS> User<'_, SMSHandlerT> {
         if (msg_id|block_id) == 0 { debug!("skipping dec due to 0:0"); return block.clone(); }
 
         let dec_offset = if block.data.get(block::BLOCK_ISMLP_RANGE).unwrap().load::<u8>() == 1 { 2 } else { 1 };
-        let nonce = self.get_nonce(msg_id, block_id, "c2s");
+        let nonce = self.get_nonce(msg_id, block_id, "c2s", 0);
         let mut cipher = ChaCha20::new(&self.shared_secret.into(), &nonce.into());
         let mut buffer = block.data.clone().into_vec();
         cipher.apply_keystream(&mut buffer[dec_offset..]);
@@ -143,33 +501,33 @@ impl<SMSHandlerT: sms::HandleSMS> User<'_, SMSHandlerT> {
         if !is_multipart {
             // Check if this block is a BlockAck
             let block_command = new_block.data.get(block::BLOCK_SPCOM_RANGE).unwrap().load::<u8>();
-            if block_command == command::CommandValue::BlockAck as command::CommandInt {
+            if block_command == command::CommandValue::BlockAck as command::CommandInt || block_command == command::CommandValue::MissingBlocks as command::CommandInt {
                 self.messages.insert(msg_id, message::Message::new(new_block));
                 return (block::BlockReceivedAction::ProcessNoAck, block_idx);
             }
         }
         
+        let position = new_block.position();
         if !self.messages.contains_key(&msg_id) {
             // new msg_id
             self.messages.insert(msg_id, message::Message::new(new_block));
         } else {
-            if !is_multipart {
+            let existing_msg = self.messages.get(&msg_id).expect("msg_id should! be present for fetching time");
+            let is_stale = existing_msg.is_complete && existing_msg.received_at.elapsed().as_millis() > MESSAGE_KEEPFOR_DURATION_MS;
+            if is_stale || existing_msg.is_multipart != is_multipart {
+                self.messages.remove(&msg_id);  // Old message, no need to keep
+                self.messages.insert(msg_id, message::Message::new(new_block));
+            } else if !is_multipart {
                 // single part message - already received
-                let current_time = std::time::Instant::now();
-                if (current_time.duration_since(self.messages.get(&msg_id).expect("msg_id should! be present for fetching time").received_at)).as_millis() > MESSAGE_KEEPFOR_DURATION_MS {
-                    self.messages.remove(&msg_id);  // Old message, no need to keep
-                    self.messages.insert(msg_id, message::Message::new(new_block));
-                } else {
-                    info!("Duplicate singlepart message (id {}) received within {}ms - skipping", &msg_id, &MESSAGE_KEEPFOR_DURATION_MS);
-                    return (block::BlockReceivedAction::SendBlockAck, block_idx);
-                }
-            } else if self.messages.get(&msg_id).unwrap().stored_blocks.contains(&block_idx) {
+                info!("Duplicate singlepart message (id {}) received within {}ms - skipping", &msg_id, &MESSAGE_KEEPFOR_DURATION_MS);
+                return (block::BlockReceivedAction::SendBlockAck, block_idx);
+            } else if existing_msg.has_block(position) {
                 // multipart message - this block already received
-                info!("Duplicate block {} on msg {} received", &block_idx, &msg_id);
+                info!("Duplicate block {} on msg {} received", &position, &msg_id);
                 return (block::BlockReceivedAction::SendBlockAck, block_idx);
             } else {
                 // multipart msg - new block
-                info!("New block {} on msg {}", &block_idx, &msg_id);
+                info!("New block {} on msg {}", &position, &msg_id);
                 self.messages.get_mut(&msg_id).expect("could not retrieve message to insert new block in user::receive_block").add_block(new_block);
             }
         }
@@ -177,7 +535,7 @@ impl<SMSHandlerT: sms::HandleSMS> User<'_, SMSHandlerT> {
 
         if self.messages.get(&msg_id).unwrap().is_complete {
             info!("Message {} complete", &msg_id);
-            (block::BlockReceivedAction::ProcessMessage, 0)
+            (block::BlockReceivedAction::ProcessMessage, block_idx)
         } else {
             info!("Message {} partial - sending block ack", &msg_id);
             (block::BlockReceivedAction::SendBlockAck, block_idx)
@@ -185,14 +543,16 @@ impl<SMSHandlerT: sms::HandleSMS> User<'_, SMSHandlerT> {
                 
     }
 
-    pub fn generate_msg_blocks(new_message: &BitVec::<u8,Lsb0>, is_command: bool, new_msg_id: u8, addr: &String) -> Vec::<block::Block> {
-        let payload_size: usize = 140;
+    // generation is only set for blocks from the server, which carry it after the header
+    pub fn generate_msg_blocks(new_message: &BitVec::<u8,Lsb0>, is_command: bool, new_msg_id: u8, generation: Option<u32>, addr: &String) -> Vec::<block::Block> {
+        let payload_size: usize = 140 - if generation.is_some() { block::BLOCK_GENERATION_OCTETS } else { 0 };
 
         // header size: 1 octet singlepart, 2 octets multipart
         let num_blocks = new_message.len().div_ceil(8 * (payload_size - 2));
         let mut output_blocks = Vec::<block::Block>::new();
 
         let header_size = if num_blocks == 1 { block::BLOCK_PAYLD_RANGE.start } else { block::BLOCK_MPPAY_RANGE.start };
+        let generation_bits = generation.map(|generation| BitVec::<u8,Lsb0>::from_vec(generation.to_be_bytes().to_vec())).unwrap_or_default();
         
         let mut block0 = bitvec![u8, Lsb0; 0; header_size];
         block0[0..5].store::<u8>(new_msg_id);
@@ -200,6 +560,7 @@ impl<SMSHandlerT: sms::HandleSMS> User<'_, SMSHandlerT> {
         block0[6..7].store::<u8>(if num_blocks > 1 { 1 } else { 0 }); // is_mp
         block0[7..8].store::<u8>(if num_blocks > 1 { 1 } else { 0 }); // mp_first
         if num_blocks > 1 { block0[8..16].store::<u8>((num_blocks - 1) as u8) };
+        block0.extend_from_bitslice(&generation_bits);
         for i in 0..std::cmp::min(new_message.len(), (payload_size-2)*8) {
             block0.push(new_message[i]);
         }
@@ -212,6 +573,7 @@ impl<SMSHandlerT: sms::HandleSMS> User<'_, SMSHandlerT> {
             new_block[6..7].store::<u8>(1);  // is_mp - guaranteed 1
             new_block[7..8].store::<u8>(0);  // mp_first - guaranteed 0
             if num_blocks > 1 { new_block[8..16].store::<u8>((i - 1) as u8); }
+            new_block.extend_from_bitslice(&generation_bits);
             for j in 0..std::cmp::min(new_message.len() - (payload_size - 2)*8*i, (payload_size - 2)*8) { // why: is there a -2??
                 new_block.push(new_message[i*(payload_size - 2)*8 + j]);
             }
@@ -223,13 +585,13 @@ impl<SMSHandlerT: sms::HandleSMS> User<'_, SMSHandlerT> {
     }
 
     // send full message through sms
-    pub fn send_message(&mut self, new_message: BitVec::<u8,Lsb0>, is_command: bool, outgoing: bool) {
-        let new_msg_id = self.unused_ids.pop().expect("No available id"); // todo: proper error handling
-        if !outgoing {
-            // if new_msg_id != 0 { self.unused_ids.push(new_msg_id); }
-        }
-
-        let output_blocks = User::<SMSHandlerT>::generate_msg_blocks(&new_message, is_command, new_msg_id, &self.address);
+    // None if every msg_id is in use, the message is dropped - senders with something better to do check unused_ids first
+    pub fn send_message(&mut self, new_message: BitVec::<u8,Lsb0>, is_command: bool, outgoing: bool) -> Option<u8> {
+        let new_msg_id = match self.unused_ids.pop() {
+            Some(v) => v,
+            None => { warn!("Out of msg ids for {} - dropping msg", &self.address); return None; }
+        };
+        let output_blocks = User::<SMSHandlerT>::generate_msg_blocks(&new_message, is_command, new_msg_id, Some(self.id_generations[new_msg_id as usize]), &self.address);
         let mut output_blocks_enc: Vec::<block::Block> = vec![];
         let num_blocks = output_blocks.len();
 
@@ -248,13 +610,18 @@ impl<SMSHandlerT: sms::HandleSMS> User<'_, SMSHandlerT> {
                 _ => 0,
             };
             info!("flagging msg {} as outgoing", &new_msg_id);
-            self.outgoing_messages.insert(new_msg_id, outgoing_message::OutgoingMessage::new(command_type, ack_data, &output_blocks_enc));
+            self.outgoing_messages.insert(new_msg_id, outgoing_message::OutgoingMessage::new(command_type, ack_data, &new_message, &output_blocks_enc, &self.retry_policy));
         }
 
         for i in 0..num_blocks {
             self.sms_handler.send_block(self.address.as_str(), &output_blocks_enc[i]);
         }
+        // nothing waits on an ack for it, so the id is done with as soon as it is sent
+        if !outgoing {
+            self.recycle_id(new_msg_id);
+        }
 
+        Some(new_msg_id)
     }
 
     pub fn key_exchange(&mut self, msg: &BitVec<u8, Lsb0>) -> Result<[u8;32], &'static str> {
@@ -295,10 +662,9 @@ impl<SMSHandlerT: sms::HandleSMS> User<'_, SMSHandlerT> {
 
         let full_message_acked = msg_obj.acknowledge_block(block_id);
         let mut should_remove = false;
+        let mut acked_chunk = false;
         match full_message_acked {
             Some(cmd_type) => {
-                // if msg_id != 0 { self.unused_ids.push(msg_id); }
-                // self.outgoing_messages.remove(&msg_id);
                 should_remove = true;
 
                 let cmd_type_u = match cmd_type.try_into() {
@@ -312,19 +678,77 @@ impl<SMSHandlerT: sms::HandleSMS> User<'_, SMSHandlerT> {
                         self.client_has_latest_domain_info = true;
                     },
                     command::CommandValue::ChannelUpdate => {
-                        self.client_has_latest_channel_list[msg_obj.ack_data as usize] = true;
+                        if let Some(has_latest) = self.client_has_latest_channel_list.get_mut(msg_obj.ack_data as usize) {
+                            *has_latest = true;
+                        }
+                    },
+                    command::CommandValue::MediaChunk => {
+                        acked_chunk = true;
                     },
                     _ => {}
                 }
             },
             None => {}
         }
-        if should_remove { self.outgoing_messages.remove(&msg_id); }
+        if should_remove {
+            self.outgoing_messages.remove(&msg_id);
+            self.recycle_id(msg_id);
+        }
+        if acked_chunk {
+            let transfer_id = self.media_transfers.iter().find(|transfer| transfer.in_flight == Some(msg_id)).map(|transfer| transfer.transfer_id);
+            if let Some(transfer_id) = transfer_id {
+                self.send_next_chunk(transfer_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    // The message on this id is done with - fully acked, given up on, or sent if it never needed acking - so the id can go back in the pool,
+    // behind the other free ids and under the next generation so none of its nonces come round again. Id 0 is never reused,
+    // block 0 of msg 0 goes out in the clear
+    fn recycle_id(&mut self, msg_id: u8) {
+        if msg_id == 0 || self.unused_ids.contains(&msg_id) {
+            return;
+        }
+        self.id_generations[msg_id as usize] += 1;
+        self.unused_ids.insert(0, msg_id);
+    }
+
+    // resend only the requested blocks of an outgoing message, msg is [msg_id][block positions...]
+    pub fn retransmit_blocks(&mut self, msg: &BitVec<u8,Lsb0>) -> Result<(), u8> {
+        let msg_bytes = msg.as_raw_slice();
+        if msg_bytes.len() < 2 {
+            return Err(1);
+        }
+        let msg_id = msg_bytes[0];
+
+        let outgoing_msg = match self.outgoing_messages.get_mut(&msg_id) {
+            Some(v) => v,
+            None => { info!("missing blocks for non-exist msgid {}", &msg_id); return Err(0); }
+        };
+
+        // stored_blocks is keyed by the raw block index, the request is by position
+        for position in &msg_bytes[1..] {
+            let block = match outgoing_msg.unacked_blocks().find(|block| block.position() == *position) {
+                Some(block) => block.clone(),
+                None => { debug!("msg {} block {} already acked or out of range", &msg_id, &position); continue; }
+            };
+            if !self.retry_policy.within_cost(outgoing_msg.cost, 1) {
+                warn!("msg {} is at its sms budget - not resending block {}", &msg_id, &position);
+                break;
+            }
+            self.sms_handler.send_block(self.address.as_str(), &block);
+            outgoing_msg.cost += 1;
+        }
+        // the other side is clearly listening - hold off on resending the whole message
+        outgoing_msg.last_send_instant = tokio::time::Instant::now();
 
         Ok(())
     }
 
-    pub fn authenticate(&mut self, botcred: &credential_manager::BridgeBotCredentials) -> Result<u8, u8> {
+    // Returns the bot's domain index, Err(0) at the bot limit and Err(1) if the bot could not be started
+    pub async fn authenticate(&mut self, botcred: &credential_manager::BridgeBotCredentials) -> Result<u8, u8> {
 
         if self.matrix_bots.len() > 256 {
             return Err(0);
@@ -337,14 +761,19 @@ impl<SMSHandlerT: sms::HandleSMS> User<'_, SMSHandlerT> {
             }
         }
 
+        let client = match self.homeservers.for_bot(botcred) {
+            Some(v) => v,
+            None => { error!("No homeserver for bot {}", &botcred.bot_address); return Err(1); }
+        };
+
         // we need a bidirectional channel interface, so two channels just for data
-        let (here_tx, mbot_rx): (Sender::<MatrixMessage>, Receiver::<MatrixMessage>) = mpsc::channel();
-        let (mbot_tx, here_rx): (Sender::<MatrixMessage>, Receiver::<MatrixMessage>) = mpsc::channel();
-        let (here_control_tx, mbot_control_rx): (Sender::<MatrixBotControlMessage>, Receiver::<MatrixBotControlMessage>) = mpsc::channel();
-        let (mbot_control_tx, here_control_rx): (Sender::<MatrixBotControlMessage>, Receiver::<MatrixBotControlMessage>) = mpsc::channel();
+        let (here_tx, mbot_rx): (UnboundedSender::<MatrixMessage>, UnboundedReceiver::<MatrixMessage>) = unbounded_channel();
+        let (mbot_tx, here_rx): (UnboundedSender::<MatrixMessage>, UnboundedReceiver::<MatrixMessage>) = unbounded_channel();
+        let (here_control_tx, mbot_control_rx): (UnboundedSender::<MatrixBotControlMessage>, UnboundedReceiver::<MatrixBotControlMessage>) = unbounded_channel();
+        let (mbot_control_tx, mut here_control_rx): (UnboundedSender::<MatrixBotControlMessage>, UnboundedReceiver::<MatrixBotControlMessage>) = unbounded_channel();
 
-        let mut new_bot = matrix_bot::MatrixBot::new(
-            self.client.clone(),
+        let new_bot = matrix_bot::MatrixBot::new(
+            client,
             botcred.bot_address.clone(),
             botcred.service_name.clone(),
             botcred.dm_room_id.clone(),
@@ -353,10 +782,15 @@ impl<SMSHandlerT: sms::HandleSMS> User<'_, SMSHandlerT> {
                 mbot_tx, mbot_rx, mbot_control_tx, mbot_control_rx
             ),
         );
+        let mut new_bot = match new_bot {
+            Ok(v) => v,
+            Err(why) => { error!("Failed to start bot {}: {}", &botcred.bot_address, why); return Err(1); }
+        };
 
-        
-        
-        executor::block_on(new_bot.initialize_channels());
+        if let Err(why) = new_bot.initialize_channels().await {
+            error!("Failed to start bot {}: {}", &botcred.bot_address, why);
+            return Err(1);
+        }
 
         tokio::spawn(async move {
             new_bot.init().await;
@@ -364,18 +798,22 @@ impl<SMSHandlerT: sms::HandleSMS> User<'_, SMSHandlerT> {
         });
         
 
-        let _ = here_control_tx.send(MatrixBotControlMessage::RequestChannels { domain_idx: self.matrix_bots.len().try_into().expect("Failed to case usize to u8") } );
+        let _ = here_control_tx.send(MatrixBotControlMessage::RequestChannels);
 
-        let recv_matrix_channel_infos = match here_control_rx.recv() {
-            Ok(data) => data,
-            Err(e) => panic!("Problem recv from control channel: {e:?}"),
+        let recv_matrix_channel_infos = match here_control_rx.recv().await {
+            Some(data) => data,
+            None => { error!("Control channel closed before mbot {} sent its channels", &botcred.bot_address); return Err(1); }
         };
 
 
         let matrix_channel_infos = match recv_matrix_channel_infos {
             MatrixBotControlMessage::UpdateChannels{ channels, .. } => channels,
-            _ => panic!("First message received from mbot on control channel was not of type MatrixBotControlMessage::UpdateChannels")
-        }; // blocking recv
+            _ => {
+                error!("First message received from mbot {} on control channel was not of type MatrixBotControlMessage::UpdateChannels", &botcred.bot_address);
+                let _ = here_control_tx.send(MatrixBotControlMessage::TerminateBot);
+                return Err(1);
+            }
+        };
         info!("rx channel_info");
 
         let _new_bot_idx = &self.matrix_bots.len();
@@ -405,6 +843,17 @@ impl<SMSHandlerT: sms::HandleSMS> User<'_, SMSHandlerT> {
         // send kill signal
         let _ = self.matrix_bot_channels.get(bot_index).unwrap().2.send(MatrixBotControlMessage::TerminateBot);
 
+        // channel lists still waiting on an ack are marked by domain index when they are, and the ones for bots after this one
+        // carry an index the phone is about to shift down - drop them, the phone asks again once it has a use for the list
+        let stale_updates: Vec<u8> = self.outgoing_messages.iter()
+            .filter(|(_, outgoing_msg)| outgoing_msg.msg_type == command::CommandValue::ChannelUpdate as command::CommandInt && outgoing_msg.ack_data as usize >= bot_index)
+            .map(|(msg_id, _)| *msg_id)
+            .collect();
+        for msg_id in stale_updates {
+            self.outgoing_messages.remove(&msg_id);
+            self.recycle_id(msg_id);
+        }
+
         self.client_has_latest_channel_list.remove(bot_index);
         self.matrix_bot_channels.remove(bot_index);
         self.matrix_bots.remove(bot_index);
@@ -0,0 +1,194 @@
+// Just enough of a homeserver for a matrix_sdk client to sync and send against. Syncs hand out whatever the test queued,
+// in order, and sent events are handed back to the test with the event id they were given
+use matrix_sdk::matrix_auth::{MatrixSession, MatrixSessionTokens};
+use matrix_sdk::ruma::api::MatrixVersion;
+use matrix_sdk::{Client, SessionMeta};
+
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+pub const BOT_USER: &str = "@boost:test";
+
+#[derive(Debug)]
+pub struct SentEvent {
+    pub room_id: String,
+    pub event_id: String,
+    pub content: Value,
+}
+
+struct State {
+    syncs: Mutex<VecDeque<Value>>,
+    next_batch: Mutex<u64>,
+    next_event: Mutex<u64>,
+    sent_tx: UnboundedSender<SentEvent>,
+}
+
+pub struct MockHomeserver {
+    pub url: String,
+    state: Arc<State>,
+    sent_rx: UnboundedReceiver<SentEvent>,
+}
+
+impl MockHomeserver {
+    pub async fn start() -> MockHomeserver {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sent_tx, sent_rx) = unbounded_channel();
+        let state = Arc::new(State { syncs: Mutex::new(VecDeque::new()), next_batch: Mutex::new(0), next_event: Mutex::new(0), sent_tx });
+
+        let serving_state = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, serving_state.clone()));
+            }
+        });
+        MockHomeserver { url, state, sent_rx }
+    }
+
+    // A client already logged in as BOT_USER, nothing is synced yet
+    pub async fn client(&self) -> Arc<Client> {
+        let client = Client::builder().homeserver_url(&self.url).server_versions([MatrixVersion::V1_1]).build().await.unwrap();
+        client.matrix_auth().restore_session(MatrixSession {
+            meta: SessionMeta { user_id: BOT_USER.try_into().unwrap(), device_id: "BOOSTTEST".into() },
+            tokens: MatrixSessionTokens { access_token: "token".to_string(), refresh_token: None },
+        }).await.unwrap();
+        Arc::new(client)
+    }
+
+    // Queue the rooms part of a sync response, see joined_rooms. Every response gets a fresh batch token, the client
+    // ignores a response carrying the token it already has
+    pub fn queue_sync(&self, rooms: Value) {
+        let next_batch = {
+            let mut next_batch = self.state.next_batch.lock().unwrap();
+            *next_batch += 1;
+            format!("s{}", next_batch)
+        };
+        self.state.syncs.lock().unwrap().push_back(json!({ "next_batch": next_batch, "rooms": rooms }));
+    }
+
+    pub async fn next_sent(&mut self) -> SentEvent {
+        tokio::time::timeout(Duration::from_secs(10), self.sent_rx.recv()).await
+            .expect("Timed out waiting for the client to send an event")
+            .unwrap()
+    }
+}
+
+// rooms: (room id, state events, timeline events)
+pub fn joined_rooms(rooms: &[(&str, Vec<Value>, Vec<Value>)]) -> Value {
+    let join: serde_json::Map<String, Value> = rooms.iter()
+        .map(|(room_id, state, timeline)| (room_id.to_string(), json!({ "state": { "events": state }, "timeline": { "events": timeline, "limited": false } })))
+        .collect();
+    json!({ "join": join })
+}
+
+// every event needs an id of its own, a later change to the same state included
+pub fn state_event(event_type: &str, state_key: &str, content: Value) -> Value {
+    static NEXT_STATE_EVENT: AtomicU64 = AtomicU64::new(0);
+    let event_id = format!("$state{}:test", NEXT_STATE_EVENT.fetch_add(1, Ordering::Relaxed));
+    json!({ "type": event_type, "state_key": state_key, "content": content, "sender": BOT_USER, "event_id": event_id, "origin_server_ts": 0 })
+}
+
+// The state a room needs before the client will name it and send to it
+pub fn room_state(name: &str) -> Vec<Value> {
+    vec![
+        state_event("m.room.create", "", json!({ "creator": BOT_USER })),
+        state_event("m.room.member", BOT_USER, json!({ "membership": "join" })),
+        state_event("m.room.name", "", json!({ "name": name })),
+    ]
+}
+
+pub fn space_child(room_id: &str, present: bool) -> Value {
+    state_event("m.space.child", room_id, if present { json!({ "via": ["test"] }) } else { json!({}) })
+}
+
+pub fn text_message(sender: &str, event_id: &str, body: &str) -> Value {
+    json!({ "type": "m.room.message", "content": { "msgtype": "m.text", "body": body }, "sender": sender, "event_id": event_id, "origin_server_ts": 0 })
+}
+
+async fn serve(stream: TcpStream, state: Arc<State>) {
+    let mut stream = BufReader::new(stream);
+    loop {
+        let mut request_line = String::new();
+        if stream.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+            return;
+        }
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            if stream.read_line(&mut header).await.unwrap_or(0) == 0 {
+                return;
+            }
+            if header == "\r\n" {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        if stream.read_exact(&mut body).await.is_err() {
+            return;
+        }
+
+        let mut request = request_line.split_whitespace();
+        let (method, path) = (request.next().unwrap_or_default(), request.next().unwrap_or_default());
+        let path = percent_decode(path.split('?').next().unwrap());
+        let (status, response) = state.respond(method, &path, &body);
+
+        let response = response.to_string();
+        let reply = format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", status, response.len(), response);
+        if stream.get_mut().write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+impl State {
+    fn respond(&self, method: &str, path: &str, body: &[u8]) -> (&'static str, Value) {
+        let segments: Vec<&str> = path.trim_start_matches("/_matrix/client/").split('/').collect();
+        match (method, segments.as_slice()) {
+            ("GET", ["versions"]) => ("200 OK", json!({ "versions": ["v1.1"] })),
+            ("GET", ["v3", "sync"]) => {
+                // nothing queued - an empty sync, as a real homeserver would give once its timeout is up
+                let sync = self.syncs.lock().unwrap().pop_front().unwrap_or_else(|| json!({ "next_batch": "s0" }));
+                ("200 OK", sync)
+            }
+            ("PUT", ["v3", "rooms", room_id, "send", _, _]) => {
+                let event_id = {
+                    let mut next_event = self.next_event.lock().unwrap();
+                    *next_event += 1;
+                    format!("$sent{}:test", next_event)
+                };
+                let _ = self.sent_tx.send(SentEvent { room_id: room_id.to_string(), event_id: event_id.clone(), content: serde_json::from_slice(body).unwrap_or_default() });
+                ("200 OK", json!({ "event_id": event_id }))
+            }
+            ("POST", ["v3", "keys", "upload"]) => ("200 OK", json!({ "one_time_key_counts": {} })),
+            ("POST", ["v3", "keys", "query"]) => ("200 OK", json!({ "device_keys": {} })),
+            ("POST", ["v3", "keys", "claim"]) => ("200 OK", json!({ "one_time_keys": {} })),
+            // rooms here are never encrypted
+            ("GET", ["v3", "rooms", _, "state", "m.room.encryption", ..]) => ("404 Not Found", json!({ "errcode": "M_NOT_FOUND", "error": "Event not found" })),
+            _ => ("404 Not Found", json!({ "errcode": "M_UNRECOGNIZED", "error": "Unrecognized request" })),
+        }
+    }
+}
+
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], path.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+            (b'%', Some(byte)) => { decoded.push(byte); i += 3; }
+            (byte, _) => { decoded.push(byte); i += 1; }
+        }
+    }
+    String::from_utf8(decoded).unwrap()
+}
@@ -0,0 +1,212 @@
+// Phone side of the protocol for driving the server end to end over a LoopbackSMSHandler pair
+#![allow(dead_code)]
+
+pub mod mock_homeserver;
+
+use boost::block;
+use boost::command;
+use boost::credential_manager::BridgeBotCredentials;
+use boost::homeserver::HomeserverClients;
+use boost::matrix_bot::{MatrixBotChannels, MatrixBotInfo, MatrixChannelInfo};
+use boost::matrix_message::{MatrixBotControlMessage, MatrixMessage};
+use boost::session_store::MemorySessionStore;
+use boost::sms::{HandleSMS, LoopbackSMSHandler};
+use boost::user;
+
+use bitvec::prelude::*;
+use chacha20::{ ChaCha20, KeyIvInit, cipher::StreamCipher };
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+pub const SERVER_ADDR: &str = "+15550000000";
+pub const PHONE_ADDR: &str = "+15551234567";
+
+const RECV_TIMEOUT_MS: u64 = 10*1000;  // long enough to outlast the server's own timers under paused time
+
+#[derive(Debug)]
+pub struct ReceivedBlock {
+    pub msg_id: u8,
+    pub is_command: bool,
+    pub block_idx: u8,
+    pub generation: u32,   // of the msg_id, the server reuses an id once it is done with the message on it
+    pub raw: Vec<u8>,      // as it came off the wire
+    pub payload: Vec<u8>,  // decrypted, header and generation stripped
+}
+
+impl ReceivedBlock {
+    pub fn command(&self) -> Option<command::CommandInt> {
+        if self.is_command { self.payload.first().copied() } else { None }
+    }
+}
+
+pub struct Phone {
+    pub sms: LoopbackSMSHandler,
+    pub shared_secret: Option<[u8; 32]>,
+}
+
+impl Phone {
+    pub fn new(sms: LoopbackSMSHandler) -> Phone {
+        Phone { sms, shared_secret: None }
+    }
+
+    fn nonce(&self, msg_id: u8, block_id: u8, dir: &str, generation: u32) -> [u8; 12] {
+        let hk = Hkdf::<Sha256>::from_prk(&self.shared_secret.unwrap()).unwrap();
+        let mut info = dir.as_bytes().to_vec();
+        info.extend_from_slice(&(msg_id as u16 * 256 + block_id as u16).to_be_bytes());
+        if generation > 0 {
+            info.extend_from_slice(&generation.to_be_bytes());
+        }
+        let mut nonce = [0u8; 12];
+        hk.expand(&info, &mut nonce).unwrap();
+        nonce
+    }
+
+    fn apply_keystream(&self, msg_id: u8, block_id: u8, dir: &str, generation: u32, data: &mut [u8]) {
+        if self.shared_secret.is_none() || (msg_id | block_id) == 0 { return; }
+        let mut cipher = ChaCha20::new(&self.shared_secret.unwrap().into(), &self.nonce(msg_id, block_id, dir, generation).into());
+        cipher.apply_keystream(data);
+    }
+
+    // Split and encrypt a message without sending it
+    pub fn encode_message(&self, msg_id: u8, is_command: bool, payload: &[u8]) -> Vec<block::Block> {
+        let blocks = user::User::<LoopbackSMSHandler>::generate_msg_blocks(&BitVec::<u8,Lsb0>::from_vec(payload.to_vec()), is_command, msg_id, None, &SERVER_ADDR.to_string());
+        let is_multipart = blocks.len() > 1;
+        let mut encoded = vec![];
+        for (i, block) in blocks.iter().enumerate() {
+            let mut raw = block.data.clone().into_vec();
+            self.apply_keystream(msg_id, i as u8, "c2s", 0, &mut raw[if is_multipart { 2 } else { 1 }..]);
+            encoded.push(block::Block::new(SERVER_ADDR.to_string(), BitVec::<u8,Lsb0>::from_vec(raw)));
+        }
+        encoded
+    }
+
+    // Split, encrypt and send a message, returns the blocks as they were put on the wire
+    pub fn send_message(&self, msg_id: u8, is_command: bool, payload: &[u8]) -> Vec<block::Block> {
+        let blocks = self.encode_message(msg_id, is_command, payload);
+        for block in &blocks {
+            self.sms.send_block(SERVER_ADDR, block);
+        }
+        blocks
+    }
+
+    pub fn send_command(&self, msg_id: u8, command_type: command::CommandValue, payload: &[u8]) -> Vec<block::Block> {
+        let mut full_payload = vec![command_type as command::CommandInt];
+        full_payload.extend_from_slice(payload);
+        self.send_message(msg_id, true, &full_payload)
+    }
+
+    // Ack a block from the server, the block id is its raw index field rather than its position. Acks are not acked
+    // themselves, so they can all go out on one msg_id
+    pub fn ack(&self, block: &ReceivedBlock) {
+        let block_id = if (block.raw[0] >> 6) & 1 == 1 { block.raw[1] } else { 0 };
+        self.send_command(31, command::CommandValue::BlockAck, &[block.msg_id, block_id]);
+    }
+
+    pub async fn recv_block(&self) -> ReceivedBlock {
+        let block = tokio::time::timeout(Duration::from_millis(RECV_TIMEOUT_MS), self.sms.recv_block()).await
+            .expect("Timed out waiting for block from server")
+            .expect("Loopback transport closed");
+        assert_eq!(block.addr, SERVER_ADDR);
+
+        let raw = block.data.into_vec();
+        let msg_id = raw[0] & 0x1f;
+        let is_command = (raw[0] >> 5) & 1 == 1;
+        let is_multipart = (raw[0] >> 6) & 1 == 1;
+        let mp_first = (raw[0] >> 7) & 1 == 1;
+        let (block_idx, offset) = if is_multipart { (if mp_first { 0 } else { raw[1] + 1 }, 2) } else { (0, 1) };
+        let generation = u32::from_be_bytes(raw[offset..offset + block::BLOCK_GENERATION_OCTETS].try_into().unwrap());
+
+        let mut payload = raw[offset + block::BLOCK_GENERATION_OCTETS..].to_vec();
+        self.apply_keystream(msg_id, block_idx, "s2c", generation, &mut payload);
+        ReceivedBlock { msg_id, is_command, block_idx, generation, raw, payload }
+    }
+
+    // Receive blocks until one carries the given command, acks and anything else along the way are dropped
+    pub async fn recv_command(&self, command_type: command::CommandValue) -> ReceivedBlock {
+        let command_type = command_type as command::CommandInt;
+        loop {
+            let block = self.recv_block().await;
+            if block.command() == Some(command_type) { return block; }
+        }
+    }
+
+    pub async fn assert_silent(&self, ms: u64) {
+        if let Ok(block) = tokio::time::timeout(Duration::from_millis(ms), self.sms.recv_block()).await {
+            panic!("Expected no traffic from server, got {:?}", block);
+        }
+    }
+
+    // Run DhkeInit against the server and switch this phone to encrypted mode, returns the server's DhkeInit reply
+    pub async fn key_exchange(&mut self) -> ReceivedBlock {
+        let phone_secret = x25519_dalek::EphemeralSecret::random_from_rng(rand::thread_rng());
+        let phone_public = x25519_dalek::PublicKey::from(&phone_secret);
+        self.send_command(0, command::CommandValue::DhkeInit, phone_public.as_bytes());
+
+        let reply = self.recv_command(command::CommandValue::DhkeInit).await;
+        let server_public: [u8; 32] = reply.payload[1..33].try_into().expect("Bad sized server key");
+        self.shared_secret = Some(phone_secret.diffie_hellman(&x25519_dalek::PublicKey::from(server_public)).to_bytes());
+        reply
+    }
+}
+
+// Registers a bot on the user without a matrix connection, returns the receiving end of its control channel
+pub fn fake_bot(server_user: &mut user::User<'_, LoopbackSMSHandler>, bot_address: &str, room_ids: &[&str]) -> UnboundedReceiver<MatrixBotControlMessage> {
+    let (here_tx, _) = unbounded_channel::<MatrixMessage>();
+    let (_, here_rx) = unbounded_channel::<MatrixMessage>();
+    let (here_control_tx, mbot_control_rx) = unbounded_channel::<MatrixBotControlMessage>();
+    let (_, here_control_rx) = unbounded_channel::<MatrixBotControlMessage>();
+
+    server_user.matrix_bots.push(MatrixBotInfo {
+        bot_address: bot_address.to_string(),
+        platform: "test".to_string(),
+        bot_client_name: format!("{}@test", bot_address),
+        num_channels: room_ids.len(),
+        channel_infos: room_ids.iter().map(|room_id| MatrixChannelInfo { room_id: room_id.to_string(), display_name: room_id.to_string() }).collect(),
+    });
+    server_user.matrix_bot_channels.push(MatrixBotChannels(here_tx, here_rx, here_control_tx, here_control_rx));
+    server_user.client_has_latest_channel_list.push(true);
+    mbot_control_rx
+}
+
+pub async fn test_client() -> Arc<matrix_sdk::Client> {
+    Arc::new(
+        matrix_sdk::Client::builder()
+            .homeserver_url("http://127.0.0.1:1")
+            .build()
+            .await
+            .unwrap()
+    )
+}
+
+pub async fn test_homeservers() -> HomeserverClients {
+    HomeserverClients::single("test", test_client().await)
+}
+
+// Run the server main loop on the given handle until body completes
+pub async fn with_server<F: Future<Output = ()>>(server: &LoopbackSMSHandler, body: F) {
+    with_server_sessions(server, &MemorySessionStore::new(), body).await;
+}
+
+// As with_server, keeping sessions in the given store so a later run can pick them up
+pub async fn with_server_sessions<F: Future<Output = ()>>(server: &LoopbackSMSHandler, session_store: &MemorySessionStore, body: F) {
+    with_server_bots(server, test_homeservers().await, &[], session_store, body).await;
+}
+
+// As with_server_sessions, with bots the phone can sign in to on the given homeservers
+pub async fn with_server_bots<F: Future<Output = ()>>(server: &LoopbackSMSHandler, homeservers: HomeserverClients, bot_credentials: &[BridgeBotCredentials], session_store: &MemorySessionStore, body: F) {
+    let bot_credentials = bot_credentials.to_vec();
+    let policies = user::UserPolicies::default();
+    tokio::select! {
+        res = boost::main_loop(homeservers, &bot_credentials, None, &policies, server, session_store) => panic!("Server main loop exited: {:?}", res),
+        _ = body => {},
+    }
+}
+
+pub fn loopback() -> (LoopbackSMSHandler, Phone) {
+    let (server, phone) = LoopbackSMSHandler::pair(SERVER_ADDR, PHONE_ADDR);
+    (server, Phone::new(phone))
+}
@@ -0,0 +1,87 @@
+use boost::cli::{Cli, Command, Settings, TransportSettings};
+
+use clap::Parser;
+use std::path::PathBuf;
+
+fn write_temp(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("boost_{}_{}", std::process::id(), name));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+pub fn test_cli_defaults() {
+    let cli = Cli::try_parse_from(["boost"]).unwrap();
+    assert_eq!(cli.command, None);
+
+    let settings = Settings::resolve(&cli).unwrap();
+    assert_eq!(settings.credfile, PathBuf::from("credfile.cfg"));
+    assert_eq!(settings.homeserver_creds, PathBuf::from("homeserver_creds.cfg"));
+    assert_eq!(settings.session_db_path(), PathBuf::from("./sessions.db"));
+    assert_eq!(settings.log_level, None);
+    match settings.transport {
+        TransportSettings::Socket { sock_in, sock_out } => {
+            assert_eq!(sock_in, PathBuf::from("./boost_sin.sock"));
+            assert_eq!(sock_out, PathBuf::from("./boost_sout.sock"));
+        }
+        other => panic!("expected the socket transport, got {:?}", other),
+    }
+}
+
+#[test]
+pub fn test_cli_config_file_and_flags() {
+    let config_path = write_temp("cli.toml", r#"
+        credfile = "/etc/boost/credfile.cfg"
+        data_dir = "/var/lib/boost"
+        log_level = "warn"
+
+        [transport]
+        kind = "smpp"
+        address = "smsc.example.com:2775"
+        system_id = "boost"
+        password = "secret"
+        source_addr = "+15550000000"
+    "#);
+
+    // flags win over the file, anything not given either way keeps its default
+    let cli = Cli::try_parse_from(["boost", "--config", config_path.to_str().unwrap(), "check-config", "--log-level", "debug", "--smpp-system-id", "other"]).unwrap();
+    assert_eq!(cli.command, Some(Command::CheckConfig));
+    let settings = Settings::resolve(&cli).unwrap();
+    assert_eq!(settings.credfile, PathBuf::from("/etc/boost/credfile.cfg"));
+    assert_eq!(settings.homeserver_creds, PathBuf::from("homeserver_creds.cfg"));
+    assert_eq!(settings.session_db_path(), PathBuf::from("/var/lib/boost/sessions.db"));
+    assert_eq!(settings.log_level.as_deref(), Some("debug"));
+    match settings.transport {
+        TransportSettings::Smpp(smpp_config) => {
+            assert_eq!(smpp_config.address, "smsc.example.com:2775");
+            assert_eq!(smpp_config.system_id, "other");
+            assert_eq!(smpp_config.source_addr, "+15550000000");
+        }
+        other => panic!("expected the smpp transport, got {:?}", other),
+    }
+
+    // switching transport on the command line drops the file's smpp settings on the floor
+    let cli = Cli::try_parse_from(["boost", "-c", config_path.to_str().unwrap(), "--transport", "modem", "--modem-tty", "/dev/ttyUSB2"]).unwrap();
+    match Settings::resolve(&cli).unwrap().transport {
+        TransportSettings::Modem { tty, baud } => assert_eq!((tty.as_str(), baud), ("/dev/ttyUSB2", 115200)),
+        other => panic!("expected the modem transport, got {:?}", other),
+    }
+
+    let _ = std::fs::remove_file(&config_path);
+}
+
+#[test]
+pub fn test_cli_errors() {
+    let cli = Cli::try_parse_from(["boost", "--transport", "modem"]).unwrap();
+    assert!(Settings::resolve(&cli).unwrap_err().to_string().contains("--modem-tty"));
+
+    assert!(Cli::try_parse_from(["boost", "--transport", "carrier-pigeon"]).is_err());
+
+    let config_path = write_temp("bad.toml", "credfiel = \"typo.cfg\"\n");
+    let cli = Cli::try_parse_from(["boost", "--config", config_path.to_str().unwrap()]).unwrap();
+    assert!(Settings::resolve(&cli).unwrap_err().to_string().contains("credfiel"));
+    let _ = std::fs::remove_file(&config_path);
+
+    let cli = Cli::try_parse_from(["boost", "hash-password", "hunter2"]).unwrap();
+    assert_eq!(cli.command, Some(Command::HashPassword { password: Some("hunter2".to_string()) }));
+}
@@ -0,0 +1,208 @@
+use boost::config::{self, BotConfig, ConfigErrorKind, ConfigFile};
+use boost::credential_manager;
+use boost::homeserver::HomeserverClients;
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+fn write_temp(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("boost_{}_{}", std::process::id(), name));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+const FULL_CONFIG: &str = r#"
+data_dir = "/var/lib/boost"
+
+[homeservers.main]
+url = "https://matrix.example.com"
+username = "@admin:matrix.example.com"
+password = "pass=word"
+
+[bots.discord]
+bot_address = "Discord@matrix.example.com"
+service_name = "discord"
+username = "User0"
+password = "$2y$12$L0P3R8I40bQR1cFzcEsQFOgk7QLL75tr/CxN6si/iC9VMtKwSjxiK"
+dm_space_id = "!abc:matrix.example.com"
+admin_room_id = "!def:matrix.example.com"
+
+[transport]
+kind = "modem"
+tty = "/dev/ttyUSB0"
+
+[retry]
+initial_delay_secs = 2.5
+max_attempts = 3
+
+[queue]
+retention_secs = 3600
+
+[media]
+budget_bytes = 16384
+"#;
+
+#[test]
+pub fn test_config_full_file() {
+    let config = ConfigFile::parse(FULL_CONFIG).unwrap();
+    assert_eq!(config.homeservers.as_ref().unwrap()["main"].credentials(), credential_manager::HomeserverCredentials {
+        address: "https://matrix.example.com".to_string(),
+        username: "@admin:matrix.example.com".to_string(),
+        password: "pass=word".to_string(),
+        access_token: None,
+        device_id: None,
+    });
+
+    // addresses and usernames are case insensitive, as in the legacy file
+    let bots = config.bots.as_ref().unwrap();
+    let botcred = bots["discord"].credentials();
+    assert_eq!((botcred.bot_address.as_str(), botcred.username.as_str()), ("discord@matrix.example.com", "user0"));
+    assert_eq!(config.transport.modem_tty.as_deref(), Some("/dev/ttyUSB0"));
+
+    let policy = config.retry.apply(boost::retry::RetryPolicy::default());
+    assert_eq!(policy.initial_delay, Duration::from_millis(2500));
+    assert_eq!(policy.max_attempts, 3);
+    assert_eq!(policy.multiplier, boost::retry::RetryPolicy::default().multiplier);
+    assert_eq!(config.queue.apply(boost::user::QueuePolicy::default()).retention, Duration::from_secs(3600));
+    assert_eq!(config.media.apply(boost::media::MediaPolicy::default()).budget, 16384);
+
+    // and it survives being written back out
+    assert_eq!(ConfigFile::parse(&config::to_toml(&config).unwrap()).unwrap(), config);
+}
+
+#[test]
+pub fn test_config_error_lines() {
+    let unsupported = FULL_CONFIG.replace("service_name = \"discord\"", "service_name = \"carrier_pigeon\"");
+    let why = ConfigFile::parse(&unsupported).unwrap_err();
+    assert_eq!((why.kind, why.line, why.column), (ConfigErrorKind::Parse, Some(11), Some(16)));
+    assert!(why.message.contains("Unsupported platform"));
+
+    let typo = FULL_CONFIG.replace("max_attempts", "max_atempts");
+    let why = ConfigFile::parse(&typo).unwrap_err();
+    assert_eq!(why.line, Some(23));
+    assert!(why.to_string().starts_with("line 23:"));
+
+    // would overflow a Duration
+    let too_long = FULL_CONFIG.replace("initial_delay_secs = 2.5", "initial_delay_secs = 2.5\nmax_delay_secs = 1e20");
+    let why = ConfigFile::parse(&too_long).unwrap_err();
+    assert_eq!((why.kind, why.line), (ConfigErrorKind::Parse, Some(23)));
+    assert!(why.message.contains("seconds"));
+
+    let duplicate = format!("{}\n[bots.discord2]\nbot_address = \"discord@matrix.example.com\"\nservice_name = \"discord\"\nusername = \"u\"\npassword = \"p\"\ndm_space_id = \"!a:m\"\nadmin_room_id = \"!b:m\"\n", FULL_CONFIG);
+    let why = ConfigFile::parse(&duplicate).unwrap_err();
+    assert_eq!((why.kind, why.line), (ConfigErrorKind::Invalid, Some(32)));
+
+    let token_only = FULL_CONFIG.replace("password = \"pass=word\"", "access_token = \"syt_abc\"");
+    assert!(ConfigFile::parse(&token_only).unwrap_err().message.contains("no device_id"));
+    let token_login = token_only.replace("access_token = \"syt_abc\"", "access_token = \"syt_abc\"\ndevice_id = \"BOOSTDEV\"");
+    let homeserver = ConfigFile::parse(&token_login).unwrap().homeservers.unwrap()["main"].credentials();
+    assert_eq!((homeserver.access_token.as_deref(), homeserver.device_id.as_deref(), homeserver.password.as_str()), (Some("syt_abc"), Some("BOOSTDEV"), ""));
+
+    // the name is a directory under the store, it cannot lead anywhere else
+    for name in ["\".\"", "\"..\"", "\"a/../..\"", "\"/tmp\"", "\"a/\"", "\"\""] {
+        let why = ConfigFile::parse(&FULL_CONFIG.replace("[homeservers.main]", &format!("[homeservers.{}]", name))).unwrap_err();
+        assert_eq!(why.kind, ConfigErrorKind::Invalid, "{}", name);
+    }
+
+    let both = format!("credfile = \"credfile.cfg\"\n{}", FULL_CONFIG);
+    assert_eq!(ConfigFile::parse(&both).unwrap_err().kind, ConfigErrorKind::Invalid);
+
+    let config_path = write_temp("empty_password.toml", "[homeservers.main]\nurl = \"u\"\nusername = \"n\"\npassword = \"\"\n");
+    let why = ConfigFile::load(&config_path).unwrap_err();
+    assert_eq!(why.to_string(), format!("{}:4:12: value cannot be empty", config_path.display()));
+    let _ = std::fs::remove_file(&config_path);
+}
+
+#[test]
+pub fn test_config_legacy() {
+    // '=' in values, no trailing newline, CRLF
+    let homeservers = config::parse_legacy_homeservers("[hs]\r\nurl=https://matrix.example.com\r\nusername=@admin:matrix.example.com\r\npassword=a=b==").unwrap();
+    assert_eq!(homeservers[0].1.password.as_deref(), Some("a=b=="));
+
+    // this used to panic
+    let why = config::parse_legacy_homeservers("[hs]\nurl=x\nusername=y\npassword=z\nport=8448\n").unwrap_err();
+    assert_eq!((why.line, why.message.as_str()), (Some(5), "Unknown key \"port\" in [hs]"));
+
+    // every section is its own homeserver, rather than all of them being merged into one
+    let two_homeservers = "[hs]\nurl=x\nusername=y\npassword=z\n\n[hs2]\nurl=x2\nusername=y2\npassword=z2\n";
+    let homeservers = config::parse_legacy_homeservers(two_homeservers).unwrap();
+    assert_eq!(homeservers.iter().map(|(name, homeserver)| (name.as_str(), homeserver.url.as_str())).collect::<Vec<_>>(), vec![("hs", "x"), ("hs2", "x2")]);
+    let why = config::parse_legacy_homeservers(&two_homeservers.replace("[hs2]", "[hs]")).unwrap_err();
+    assert_eq!((why.kind, why.line), (ConfigErrorKind::Invalid, Some(6)));
+    for name in ["..", "a/../..", "/tmp"] {
+        let why = config::parse_legacy_homeservers(&two_homeservers.replace("[hs2]", &format!("[{}]", name))).unwrap_err();
+        assert_eq!((why.kind, why.line), (ConfigErrorKind::Invalid, Some(6)), "{}", name);
+    }
+
+    let why = config::parse_legacy_credfile(&std::fs::read_to_string("./tests/test_credfile_dup.cfg").unwrap()).unwrap_err();
+    assert_eq!((why.kind, why.line), (ConfigErrorKind::Invalid, Some(10)));
+    let why = config::parse_legacy_credfile("[a]\nbot_address=x\nnot a pair\n").unwrap_err();
+    assert_eq!((why.kind, why.line), (ConfigErrorKind::Parse, Some(3)));
+
+    // section names are kept
+    let bots = config::parse_legacy_credfile(&std::fs::read_to_string("./tests/test_credfile.cfg").unwrap()).unwrap();
+    assert_eq!(bots.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), vec!["discord", "instagram"]);
+}
+
+#[test]
+pub fn test_config_convert_legacy() {
+    let base = ConfigFile { log_level: Some("warn".to_string()), ..Default::default() };
+    let converted = config::convert_legacy(base, "./tests/test_credfile.cfg".as_ref(), "./tests/test_homeserver_creds.cfg".as_ref()).unwrap();
+    assert_eq!(converted.credfile, None);
+    assert_eq!(converted.homeservers.as_ref().unwrap()["hs-alias"].credentials(), credential_manager::load_homeserver_creds("./tests/test_homeserver_creds.cfg").unwrap());
+
+    // same bots either way
+    let reparsed = ConfigFile::parse(&config::to_toml(&converted).unwrap()).unwrap();
+    let from_toml: Vec<_> = reparsed.bots.unwrap().values().map(BotConfig::credentials).collect();
+    assert_eq!(from_toml, credential_manager::load_credential_file("./tests/test_credfile.cfg").unwrap());
+    assert_eq!(reparsed.log_level.as_deref(), Some("warn"));
+}
+
+#[test]
+pub fn test_config_add_remove_bot() {
+    let config_path = write_temp("bots.toml", "# keep me\nlog_level = \"info\"\n");
+    let bot = BotConfig {
+        bot_address: "fb@matrix.example.com".to_string(),
+        service_name: "fb_messenger".to_string(),
+        username: "user0".to_string(),
+        password: credential_manager::hash_password("password").unwrap(),
+        dm_space_id: "!abc:matrix.example.com".to_string(),
+        admin_room_id: "!def:matrix.example.com".to_string(),
+        homeserver: Some("main".to_string()),
+    };
+
+    config::add_bot(&config_path, "fb", &bot).unwrap();
+    assert_eq!(config::add_bot(&config_path, "fb", &bot).unwrap_err().kind, ConfigErrorKind::Invalid);
+    assert_eq!(config::add_bot(&config_path, "fb2", &bot).unwrap_err().kind, ConfigErrorKind::Invalid);
+    let contents = std::fs::read_to_string(&config_path).unwrap();
+    assert!(contents.starts_with("# keep me\n"));
+    assert_eq!(ConfigFile::parse(&contents).unwrap().bots.unwrap()["fb"], bot);
+
+    assert_eq!(config::remove_bot(&config_path, "FB@matrix.example.com").unwrap(), "fb");
+    assert!(config::remove_bot(&config_path, "fb").is_err());
+    assert_eq!(ConfigFile::load(&config_path).unwrap().bots.unwrap_or_default().len(), 0);
+
+    let _ = std::fs::remove_file(&config_path);
+}
+
+#[tokio::test]
+pub async fn test_config_bot_homeservers() {
+    let bots = config::parse_legacy_credfile("[a]\nbot_address=a@one\nservice_name=discord\nusername=u\npassword=p\ndm_space_id=!a:one\nadmin_room_id=!b:one\nhomeserver=one\n\n\
+        [b]\nbot_address=b@two\nservice_name=discord\nusername=u\npassword=p\ndm_space_id=!a:two\nadmin_room_id=!b:two\n").unwrap();
+    let bot_credentials: Vec<_> = bots.iter().map(|(_, bot)| bot.credentials()).collect();
+    assert_eq!(bot_credentials[0].homeserver.as_deref(), Some("one"));
+
+    // a bot may leave its homeserver out only while there is just the one
+    assert!(config::check_bot_homeservers(&bot_credentials[..1], &["one"]).is_ok());
+    assert!(config::check_bot_homeservers(&bot_credentials, &["one"]).is_ok());
+    assert!(config::check_bot_homeservers(&bot_credentials, &["one", "two"]).unwrap_err().message.contains("b@two"));
+    assert!(config::check_bot_homeservers(&bot_credentials[..1], &["two"]).unwrap_err().message.contains("unknown homeserver \"one\""));
+
+    let client = |url: &'static str| async move { std::sync::Arc::new(matrix_sdk::Client::builder().homeserver_url(url).build().await.unwrap()) };
+    let mut homeservers = HomeserverClients::single("one", client("http://127.0.0.1:1").await);
+    assert_eq!(homeservers.for_bot(&bot_credentials[1]).unwrap().homeserver().as_str(), "http://127.0.0.1:1/");
+    homeservers.insert("two", client("http://127.0.0.1:2").await);
+    assert_eq!(homeservers.for_bot(&bot_credentials[0]).unwrap().homeserver().as_str(), "http://127.0.0.1:1/");
+    assert!(homeservers.for_bot(&bot_credentials[1]).is_none());
+    assert!(homeservers.for_bot(&bots[1].1.credentials().with_homeserver(Some("two".to_string()))).is_some());
+}
@@ -1,3 +1,4 @@
+use boost::config;
 use boost::credential_manager;
 
 use std::collections::HashSet;
@@ -11,7 +12,7 @@ fn iters_equal_anyorder<T: Eq + Hash>(mut i1:impl Iterator<Item = T>, i2: impl I
 #[test]
 pub fn test_homeserver_creds() {
     const HOMESERVER_CREDFILE_PATH: &str = "./tests/test_homeserver_creds.cfg";
-    let expected_homeserver_creds = credential_manager::HomeserverCredentials { address: "https://matrix.example.com".to_string(), username: "@admin:matrix.example.com".to_string(), password: "password".to_string() };
+    let expected_homeserver_creds = credential_manager::HomeserverCredentials { address: "https://matrix.example.com".to_string(), username: "@admin:matrix.example.com".to_string(), password: "password".to_string(), access_token: None, device_id: None };
     let homeserver_creds = match credential_manager::load_homeserver_creds(HOMESERVER_CREDFILE_PATH) {
         Ok(creds) => creds,
         Err(e) => panic!("Error loading homeserver credfile: {}", e),
@@ -41,3 +42,76 @@ pub fn test_bridgebot_creds_reject_dup() {
     const CREDFILE_PATH: &str = "./tests/test_credfile_dup.cfg";
     credential_manager::load_credential_file(CREDFILE_PATH).unwrap();
 }
+
+fn temp_credfile(name: &str, contents: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("boost_{}_{}", std::process::id(), name));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+fn test_botcred(bot_address: &str, service_name: &str) -> credential_manager::BridgeBotCredentials {
+    credential_manager::BridgeBotCredentials::new(bot_address.to_string(), service_name.to_string(), "user0".to_string(), credential_manager::hash_password("password").unwrap(), "!abc:matrix.example.com".to_string(), "!def:matrix.example.com".to_string())
+}
+
+#[test]
+pub fn test_bridgebot_creds_add_remove() {
+    let credfile_path = temp_credfile("add_remove.cfg", &std::fs::read_to_string("./tests/test_credfile.cfg").unwrap());
+
+    credential_manager::add_credential(&credfile_path, "fb", &test_botcred("fb@matrix.example.com", "fb_messenger")).unwrap();
+    let listed = credential_manager::list_credentials(&credfile_path).unwrap();
+    assert_eq!(listed.len(), 3);
+    let (name, added) = listed.last().unwrap();
+    assert_eq!((name.as_str(), added.bot_address.as_str()), ("fb", "fb@matrix.example.com"));
+    assert!(added.validate_credentials("user0", b"password").unwrap());
+
+    // the file is left alone when the result would not load
+    let before = std::fs::read_to_string(&credfile_path).unwrap();
+    assert!(credential_manager::add_credential(&credfile_path, "fb2", &test_botcred("fb@matrix.example.com", "discord")).unwrap_err().to_string().contains("Duplicated bot address"));
+    assert!(credential_manager::add_credential(&credfile_path, "fb", &test_botcred("other@matrix.example.com", "discord")).unwrap_err().to_string().contains("Duplicated block name"));
+    assert!(credential_manager::add_credential(&credfile_path, "sms", &test_botcred("sms@matrix.example.com", "carrier_pigeon")).unwrap_err().to_string().contains("Unsupported platform"));
+    assert_eq!(std::fs::read_to_string(&credfile_path).unwrap(), before);
+
+    // by bot address or by block name
+    credential_manager::remove_credential(&credfile_path, "DISCORD@matrix.example.com").unwrap();
+    assert_eq!(credential_manager::remove_credential(&credfile_path, "fb").unwrap(), "fb");
+    let remaining: Vec<String> = credential_manager::load_credential_file(&credfile_path).unwrap().into_iter().map(|botcred| botcred.bot_address).collect();
+    assert_eq!(remaining, vec!["instagram@matrix.example.com".to_string()]);
+    assert!(credential_manager::remove_credential(&credfile_path, "fb").is_err());
+
+    let _ = std::fs::remove_file(&credfile_path);
+}
+
+#[test]
+pub fn test_bridgebot_creds_add_keeps_line_endings() {
+    let credfile_path = temp_credfile("crlf.cfg", "[a]\r\nbot_address=a@m\r\nservice_name=discord\r\nusername=u\r\npassword=x\r\ndm_space_id=!a:m\r\nadmin_room_id=!b:m");
+    credential_manager::add_credential(&credfile_path, "b", &test_botcred("b@m", "instagram")).unwrap();
+    let contents = std::fs::read_to_string(&credfile_path).unwrap();
+    assert_eq!(contents.matches('\n').count(), contents.matches("\r\n").count());
+    assert_eq!(credential_manager::load_credential_file(&credfile_path).unwrap().len(), 2);
+    let _ = std::fs::remove_file(&credfile_path);
+}
+
+#[test]
+pub fn test_bridgebot_creds_rotate_password() {
+    // revoke_all_clients writes the new hash back to wherever the bots came from
+    let new_hash = credential_manager::hash_password("rotated").unwrap();
+    let credfile_path = temp_credfile("rotate.cfg", &std::fs::read_to_string("./tests/test_credfile.cfg").unwrap().replace('\n', "\r\n"));
+    let before = std::fs::read_to_string(&credfile_path).unwrap();
+    config::BotSource::Credfile(credfile_path.clone()).set_password("Discord@matrix.example.com", &new_hash).unwrap();
+
+    let after = std::fs::read_to_string(&credfile_path).unwrap();
+    assert_eq!(after.lines().count(), before.lines().count());
+    assert_eq!(after.matches('\n').count(), after.matches("\r\n").count());
+    let bots = credential_manager::load_credential_file(&credfile_path).unwrap();
+    assert!(bots[0].validate_credentials("user0", b"rotated").unwrap());
+    assert_eq!(after.lines().zip(before.lines()).filter(|(a, b)| a != b).count(), 1);  // the other bot keeps its password
+    assert!(config::BotSource::Credfile(credfile_path.clone()).set_password("nobody@matrix.example.com", &new_hash).is_err());
+    let _ = std::fs::remove_file(&credfile_path);
+
+    let config_path = temp_credfile("rotate.toml", "# keep me\n[bots.discord]\nbot_address = \"discord@matrix.example.com\"\nservice_name = \"discord\"\nusername = \"user0\"\npassword = \"x\"\ndm_space_id = \"!a:m\"\nadmin_room_id = \"!b:m\"\n");
+    config::BotSource::Config(config_path.clone()).set_password("discord@matrix.example.com", &new_hash).unwrap();
+    let contents = std::fs::read_to_string(&config_path).unwrap();
+    assert!(contents.starts_with("# keep me\n"));
+    assert!(config::ConfigFile::parse(&contents).unwrap().bots.unwrap()["discord"].credentials().validate_credentials("user0", b"rotated").unwrap());
+    let _ = std::fs::remove_file(&config_path);
+}
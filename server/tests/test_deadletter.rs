@@ -0,0 +1,183 @@
+mod common;
+
+use boost::command::CommandValue;
+use boost::matrix_message::MatrixBotControlMessage;
+use boost::retry::RetryPolicy;
+use boost::sms::LoopbackSMSHandler;
+use boost::user;
+use common::{fake_bot, loopback, with_server, Phone, PHONE_ADDR, SERVER_ADDR};
+
+use bitvec::prelude::*;
+use std::time::Duration;
+
+// Lets every retry of the outgoing messages go unanswered until they are abandoned
+async fn run_until_abandoned(server_user: &mut user::User<'_, LoopbackSMSHandler>, phone: &Phone) {
+    while !server_user.outgoing_messages.is_empty() {
+        tokio::select! {
+            _ = phone.recv_block() => {}
+            _ = tokio::time::sleep_until(server_user.next_refresh_instant().unwrap()) => server_user.refresh_outgoing(),
+        }
+        // the give up notice is itself outgoing, drop it so only the data message is abandoned
+        let notices: Vec<u8> = server_user.outgoing_messages.iter().filter(|(_, m)| m.msg_type == CommandValue::Error as u8).map(|(id, _)| *id).collect();
+        for notice_id in notices { server_user.outgoing_messages.remove(&notice_id); }
+    }
+    while tokio::time::timeout(Duration::from_millis(100), phone.recv_block()).await.is_ok() {}
+}
+
+fn test_sms() -> (LoopbackSMSHandler, LoopbackSMSHandler) {
+    let (server_sms, phone_sms) = LoopbackSMSHandler::pair(SERVER_ADDR, PHONE_ADDR);
+    (server_sms.with_retry_policy(RetryPolicy::fixed(Duration::from_secs(1), 3)), phone_sms)
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_deadletter_reported_and_redelivered() {
+    let (server_sms, phone_sms) = test_sms();
+    let phone = Phone::new(phone_sms);
+    let mut server_user = user::User::new(common::test_homeservers().await, PHONE_ADDR.to_string(), false, &server_sms);
+    let _other_bot = fake_bot(&mut server_user, "@other:test", &["!a:test"]);
+    let mut mbot_control_rx = fake_bot(&mut server_user, "@bot:test", &["!a:test", "!b:test"]);
+
+    server_user.send_message(BitVec::<u8,Lsb0>::from_vec([&[1u8, 1][..], b"hello"].concat()), false, true);
+    run_until_abandoned(&mut server_user, &phone).await;
+
+    // the sender hears about it in the room it wrote in
+    match mbot_control_rx.try_recv() {
+        Ok(MatrixBotControlMessage::DeliveryFailed { room_idx, .. }) => assert_eq!(room_idx, 1),
+        _ => panic!("bot was not told about the failed delivery"),
+    }
+    assert_eq!(server_user.dead_letters.len(), 1);
+
+    // the other bot goes away, so the room has moved to domain 0 by the time the phone is back
+    server_user.revoke_bot(0).unwrap();
+    server_user.redeliver_dead_letters();
+    assert!(server_user.dead_letters.is_empty());
+
+    let redelivered = phone.recv_block().await;
+    assert!(!redelivered.is_command);
+    assert_eq!(redelivered.payload, [&[1u8, 0][..], b"hello"].concat());
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_deadletter_dropped_when_unbridged() {
+    let (server_sms, phone_sms) = test_sms();
+    let phone = Phone::new(phone_sms);
+    let mut server_user = user::User::new(common::test_homeservers().await, PHONE_ADDR.to_string(), false, &server_sms);
+    let _mbot_control_rx = fake_bot(&mut server_user, "@bot:test", &["!a:test"]);
+
+    server_user.send_message(BitVec::<u8,Lsb0>::from_vec([&[0u8, 0][..], b"hello"].concat()), false, true);
+    run_until_abandoned(&mut server_user, &phone).await;
+    assert_eq!(server_user.dead_letters.len(), 1);
+
+    server_user.revoke_bot(0).unwrap();
+    server_user.redeliver_dead_letters();
+    assert!(server_user.dead_letters.is_empty());
+    phone.assert_silent(1000).await;
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_deadletter_commands_not_queued() {
+    let (server_sms, phone_sms) = test_sms();
+    let phone = Phone::new(phone_sms);
+    let mut server_user = user::User::new(common::test_homeservers().await, PHONE_ADDR.to_string(), false, &server_sms);
+    let mut mbot_control_rx = fake_bot(&mut server_user, "@bot:test", &["!a:test"]);
+
+    server_user.send_message(BitVec::<u8,Lsb0>::from_vec(vec![CommandValue::DomainUpdate as u8, 0, 0]), true, true);
+    run_until_abandoned(&mut server_user, &phone).await;
+    assert!(server_user.dead_letters.is_empty());
+    assert!(mbot_control_rx.try_recv().is_err());
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_deadletter_held_while_unreachable() {
+    let (server_sms, phone_sms) = test_sms();
+    let phone = Phone::new(phone_sms);
+    let mut server_user = user::User::new(common::test_homeservers().await, PHONE_ADDR.to_string(), false, &server_sms);
+    let _mbot_control_rx = fake_bot(&mut server_user, "@bot:test", &["!a:test", "!b:test"]);
+
+    server_user.forward_matrix_message(0, 0, b"first".to_vec());
+    run_until_abandoned(&mut server_user, &phone).await;
+
+    // the phone is known to be away, later messages queue up behind the first without any sms going out
+    server_user.forward_matrix_message(1, 0, b"second".to_vec());
+    phone.assert_silent(1000).await;
+    assert_eq!(server_user.dead_letters.iter().map(|d| d.content.as_slice()).collect::<Vec<_>>(), vec![&b"first"[..], &b"second"[..]]);
+
+    // anything past the retention window is dropped rather than redelivered
+    server_user.dead_letters[0].queued_at -= server_user.policies.queue.retention.as_secs() + 1;
+    server_user.redeliver_dead_letters();
+    let redelivered = phone.recv_block().await;
+    assert_eq!(redelivered.payload, [&[1u8, 0][..], b"second"].concat());
+    phone.assert_silent(1000).await;
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_deadletter_held_while_out_of_msg_ids() {
+    let (server_sms, phone_sms) = test_sms();
+    let phone = Phone::new(phone_sms);
+    let mut server_user = user::User::new(common::test_homeservers().await, PHONE_ADDR.to_string(), false, &server_sms);
+    let _mbot_control_rx = fake_bot(&mut server_user, "@bot:test", &["!a:test", "!b:test"]);
+    server_user.unused_ids = vec![];
+
+    // nothing can go out, the messages wait for an id rather than being dropped
+    assert!(server_user.send_message(BitVec::<u8,Lsb0>::from_vec(vec![CommandValue::DomainUpdate as u8, 0, 0]), true, true).is_none());
+    server_user.forward_matrix_message(0, 0, b"first".to_vec());
+    server_user.forward_matrix_message(1, 0, b"second".to_vec());
+    phone.assert_silent(1000).await;
+    assert_eq!(server_user.dead_letters.len(), 2);
+
+    // one id comes free, one message goes out
+    server_user.unused_ids = vec![5];
+    server_user.redeliver_dead_letters();
+    let redelivered = phone.recv_block().await;
+    assert_eq!((redelivered.msg_id, redelivered.payload), (5, [&[0u8, 0][..], b"first"].concat()));
+    assert_eq!(server_user.dead_letters.len(), 1);
+    phone.assert_silent(1000).await;
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_deadletter_many_abandoned_then_flushed() {
+    let (server_sms, phone_sms) = test_sms();
+    let phone = Phone::new(phone_sms);
+    let mut server_user = user::User::new(common::test_homeservers().await, PHONE_ADDR.to_string(), false, &server_sms);
+    let _mbot_control_rx = fake_bot(&mut server_user, "@bot:test", &["!a:test"]);
+
+    // each abandoned message and its give up notice would take two of the 31 ids if either kept its id
+    let contents: Vec<Vec<u8>> = (0..20).map(|i| format!("msg {}", i).into_bytes()).collect();
+    for content in &contents {
+        server_user.forward_matrix_message(0, 0, content.clone());
+    }
+    run_until_abandoned(&mut server_user, &phone).await;
+    assert_eq!(server_user.dead_letters.len(), contents.len());
+
+    // what FlushQueue does once the phone is back
+    server_user.resend_outgoing();
+    server_user.redeliver_dead_letters();
+    assert!(server_user.dead_letters.is_empty());
+    let mut redelivered = vec![];
+    for _ in 0..contents.len() {
+        redelivered.push(phone.recv_block().await.payload[2..].to_vec());
+    }
+    // they come back in the order they were given up on, which is not the order they were sent in
+    redelivered.sort();
+    let mut contents = contents;
+    contents.sort();
+    assert_eq!(redelivered, contents);
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_deadletter_flush_queue_resends_now() {
+    let (server, mut phone) = loopback();
+    with_server(&server, async {
+        phone.key_exchange().await;
+
+        // leave the domain update unacked, then come back before its first retry is due
+        phone.send_command(1, CommandValue::RequestDomains, &[]);
+        let update = phone.recv_command(CommandValue::DomainUpdate).await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        phone.send_command(2, CommandValue::FlushQueue, &[]);
+        let resent = tokio::time::timeout(Duration::from_millis(500), phone.recv_command(CommandValue::DomainUpdate)).await
+            .expect("FlushQueue did not resend the unacked message");
+        assert_eq!(resent.raw, update.raw);
+    }).await;
+}
@@ -0,0 +1,189 @@
+mod common;
+
+use boost::command::CommandValue;
+use boost::credential_manager::{self, BridgeBotCredentials};
+use boost::homeserver::HomeserverClients;
+use boost::matrix_bot;
+use boost::session_store::{MemorySessionStore, UserSession};
+use boost::sms::LoopbackSMSHandler;
+use boost::user;
+use common::mock_homeserver::{self, MockHomeserver, BOT_USER};
+use common::Phone;
+
+use matrix_sdk::config::SyncSettings;
+
+const DM_SPACE: &str = "!space:test";
+
+// A bot on the mock homeserver with one room in its dm space for each (room id, name), already synced
+async fn bridged_rooms(rooms: &[(&str, &str)]) -> (MockHomeserver, HomeserverClients, Vec<BridgeBotCredentials>) {
+    let mock = MockHomeserver::start().await;
+    let mut joined = vec![(DM_SPACE, mock_homeserver::room_state("DMs"), vec![])];
+    joined[0].1.extend(rooms.iter().map(|(room_id, _)| mock_homeserver::space_child(room_id, true)));
+    joined.extend(rooms.iter().map(|(room_id, name)| (*room_id, mock_homeserver::room_state(name), vec![])));
+    mock.queue_sync(mock_homeserver::joined_rooms(&joined));
+
+    let client = mock.client().await;
+    client.sync_once(SyncSettings::default()).await.unwrap();
+    let botcred = BridgeBotCredentials::new("@bridgebot:test".to_string(), "test".to_string(), "user0".to_string(), credential_manager::hash_password("password").unwrap(), DM_SPACE.to_string(), "!admin:test".to_string());
+    (mock, HomeserverClients::single("test", client), vec![botcred])
+}
+
+// Key exchange and sign in to the bot as domain 0, returns its room names by index
+async fn sign_in(phone: &mut Phone) -> Vec<String> {
+    phone.key_exchange().await;
+    phone.send_command(23, CommandValue::AuthenticateToAccount, b"test\0user0\0password");
+    let result = phone.recv_command(CommandValue::AuthenticationResult).await;
+    assert_eq!((result.payload[1], result.payload[3]), (1, 0));
+
+    // the server will not send on stale domain or channel lists
+    phone.send_command(24, CommandValue::RequestDomains, &[]);
+    let domains = phone.recv_command(CommandValue::DomainUpdate).await;
+    phone.ack(&domains);
+    phone.send_command(25, CommandValue::RequestKnownUsers, &[0]);
+    channel_names(phone).await
+}
+
+// The next channel_update, acked - room names by index, empty for a room that has left the space
+async fn channel_names(phone: &Phone) -> Vec<String> {
+    let update = phone.recv_command(CommandValue::ChannelUpdate).await;
+    phone.ack(&update);
+    assert_eq!(update.payload[1], 0);
+    update.payload[2..].split(|b| *b == 0).map(|name| String::from_utf8(name.to_vec()).unwrap()).collect()
+}
+
+#[test]
+pub fn test_find_user_bridge_replies() {
+    assert_eq!(matrix_bot::start_dm_command("someone#1234"), "pm someone#1234");
+
+    // mautrix links an existing dm rather than making a new room
+    let reply = "You already have a private chat portal with Someone at https://matrix.to/#/!abcDEF:matrix.example.com?via=matrix.example.com";
+    assert_eq!(matrix_bot::mentioned_room_ids(reply), vec!["!abcDEF:matrix.example.com"]);
+    let encoded = "Portal: <a href=\"https://matrix.to/#/%21abcDEF%3Amatrix.example.com\">Someone</a>.";
+    assert_eq!(matrix_bot::mentioned_room_ids(encoded), vec!["!abcDEF:matrix.example.com"]);
+
+    assert!(matrix_bot::mentioned_room_ids("Created portal room with Someone and invited you to it!").is_empty());
+    assert!(matrix_bot::mentioned_room_ids("Failed to find user: not found").is_empty());
+}
+
+#[test]
+pub fn test_find_user_room_matches() {
+    let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<String>>();
+
+    // by room name, member display name or puppet localpart
+    assert!(matrix_bot::room_matches_identifier("someone#1234", &names(&["Someone"])));
+    assert!(matrix_bot::room_matches_identifier("someone", &names(&["[Unnamed room]", "Someone (Discord)"])));
+    assert!(matrix_bot::room_matches_identifier("123456789", &names(&["Someone", "discord_123456789"])));
+
+    // a dm with someone else, or a name that only starts the same way
+    assert!(!matrix_bot::room_matches_identifier("someone", &names(&["Somebody Else", "discord_987"])));
+    assert!(!matrix_bot::room_matches_identifier("some", &names(&["Someone"])));
+    assert!(!matrix_bot::room_matches_identifier("#1234", &names(&["Someone"])));
+}
+
+#[test]
+pub fn test_rate_limit_delay() {
+    use matrix_sdk::ruma::api::client::error::RetryAfter;
+    use std::time::{Duration, SystemTime};
+
+    assert_eq!(matrix_bot::rate_limit_delay(Some(RetryAfter::Delay(Duration::from_millis(1500))), 0), Duration::from_millis(1500));
+    assert_eq!(matrix_bot::rate_limit_delay(Some(RetryAfter::DateTime(SystemTime::now() - Duration::from_secs(5))), 0), Duration::ZERO);
+    assert!(matrix_bot::rate_limit_delay(Some(RetryAfter::DateTime(SystemTime::now() + Duration::from_secs(30))), 0) > Duration::from_secs(25));
+
+    // no retry_after_ms at all - back off further on every attempt
+    assert!(matrix_bot::rate_limit_delay(None, 1) > matrix_bot::rate_limit_delay(None, 0));
+}
+
+#[tokio::test]
+pub async fn test_delivery_success_matches_echo() {
+    let (mut mock, homeservers, bot_credentials) = bridged_rooms(&[("!alice:test", "Alice")]).await;
+    let client = homeservers.get("test").unwrap();
+    let (server, mut phone) = common::loopback();
+    common::with_server_bots(&server, homeservers.clone(), &bot_credentials, &MemorySessionStore::new(), async {
+        assert_eq!(sign_in(&mut phone).await, ["Alice"]);
+
+        // every message costs the server a block_ack and a delivery_success, more than it has msg_ids for over the run
+        for round in 0..10 {
+            let msg_ids = [round * 2 + 1, round * 2 + 2];
+            let mut event_ids = vec![];
+            for msg_id in msg_ids {
+                phone.send_message(msg_id, false, &[0, 0, b'h', b'i']);
+                let sent = mock.next_sent().await;
+                assert_eq!((sent.room_id.as_str(), sent.content["body"].as_str()), ("!alice:test", Some("hi")));
+                event_ids.push(sent.event_id);
+            }
+
+            // echoed out of order, along with one of ours that no phone sent - each is matched up by event id
+            let echoes = [&event_ids[1], "$elsewhere:test", &event_ids[0]].map(|event_id| mock_homeserver::text_message(BOT_USER, event_id, "hi"));
+            mock.queue_sync(mock_homeserver::joined_rooms(&[("!alice:test", vec![], echoes.to_vec())]));
+            client.sync_once(SyncSettings::default()).await.unwrap();
+            assert_eq!(phone.recv_command(CommandValue::DeliverySuccess).await.payload[1], msg_ids[1]);
+            assert_eq!(phone.recv_command(CommandValue::DeliverySuccess).await.payload[1], msg_ids[0]);
+        }
+        phone.assert_silent(500).await;
+    }).await;
+}
+
+#[tokio::test]
+pub async fn test_space_children_keep_indices() {
+    let (mut mock, homeservers, bot_credentials) = bridged_rooms(&[("!alice:test", "Alice"), ("!bob:test", "Bob")]).await;
+    let client = homeservers.get("test").unwrap();
+    let (server, mut phone) = common::loopback();
+    common::with_server_bots(&server, homeservers.clone(), &bot_credentials, &MemorySessionStore::new(), async {
+        // the initial order is up to the store, what counts is that it holds from here on
+        let mut names = sign_in(&mut phone).await;
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(sorted, ["Alice", "Bob"]);
+        let bob_idx = names.iter().position(|name| name == "Bob").unwrap() as u8;
+
+        // a new room goes on the end goes on the end
+        mock.queue_sync(mock_homeserver::joined_rooms(&[
+            ("!carol:test", mock_homeserver::room_state("Carol"), vec![]),
+            (DM_SPACE, vec![], vec![mock_homeserver::space_child("!carol:test", true)]),
+        ]));
+        client.sync_once(SyncSettings::default()).await.unwrap();
+        names.push("Carol".to_string());
+        assert_eq!(channel_names(&phone).await, names);
+
+        // one that leaves keeps its slot, so the rooms after it keep their indices
+        mock.queue_sync(mock_homeserver::joined_rooms(&[(DM_SPACE, vec![], vec![mock_homeserver::space_child("!alice:test", false)])]));
+        client.sync_once(SyncSettings::default()).await.unwrap();
+        names[1 - bob_idx as usize].clear();
+        assert_eq!(channel_names(&phone).await, names);
+
+        for (msg_id, room_idx, room_id) in [(1, bob_idx, "!bob:test"), (2, 2, "!carol:test")] {
+            phone.send_message(msg_id, false, &[room_idx, 0, b'h', b'i']);
+            assert_eq!(mock.next_sent().await.room_id, room_id);
+        }
+
+        mock.queue_sync(mock_homeserver::joined_rooms(&[("!carol:test", vec![], vec![mock_homeserver::text_message("@carol:test", "$fromcarol:test", "hello")])]));
+        client.sync_once(SyncSettings::default()).await.unwrap();
+        let forwarded = loop {
+            let block = phone.recv_block().await;
+            if !block.is_command { break block; }
+        };
+        assert_eq!(forwarded.payload[..2], [2, 0]);
+    }).await;
+}
+
+#[tokio::test]
+pub async fn test_restore_skips_bot_that_fails_to_start() {
+    let (_mock, homeservers, mut bot_credentials) = bridged_rooms(&[("!alice:test", "Alice")]).await;
+    bot_credentials.push(BridgeBotCredentials::new("@gone:test".to_string(), "test".to_string(), "user1".to_string(), credential_manager::hash_password("password").unwrap(), "!nospace:test".to_string(), "!admin:test".to_string()));
+    let (server_sms, _phone_sms) = LoopbackSMSHandler::pair(common::SERVER_ADDR, common::PHONE_ADDR);
+
+    // the second bot's dm space is not on the homeserver, it is left out rather than taking the server down
+    let session = UserSession {
+        address: common::PHONE_ADDR.to_string(),
+        is_encrypted: true,
+        shared_secret: [7; 32],
+        unused_ids: (1..32).collect(),
+        id_generations: [0; 32],
+        bot_addresses: vec!["@gone:test".to_string(), "@bridgebot:test".to_string()],
+        outgoing_messages: vec![],
+        dead_letters: vec![],
+    };
+    let restored_user = user::User::restore(homeservers, session, &server_sms, &bot_credentials).await;
+    assert_eq!(restored_user.matrix_bots.iter().map(|bot_info| bot_info.bot_address.as_str()).collect::<Vec<_>>(), ["@bridgebot:test"]);
+    assert_eq!(restored_user.matrix_bots[0].channel_infos[0].display_name, "Alice");
+}
@@ -0,0 +1,155 @@
+mod common;
+
+use boost::command::CommandValue;
+use boost::media::{self, MEDIA_CHUNK_BLOCKS, MEDIA_CHUNK_BYTES};
+use boost::sms::LoopbackSMSHandler;
+use boost::user;
+use common::{Phone, ReceivedBlock};
+
+use bitvec::prelude::*;
+use std::io::Cursor;
+
+// busy enough that it will not fit the budget as a png
+fn test_png(width: u32, height: u32) -> Vec<u8> {
+    let img = image::RgbImage::from_fn(width, height, |x, y| image::Rgb([(x * 7 % 256) as u8, (y * 13 % 256) as u8, ((x ^ y) % 256) as u8]));
+    let mut png = Cursor::new(vec![]);
+    img.write_to(&mut png, image::ImageFormat::Png).unwrap();
+    png.into_inner()
+}
+
+fn ack(server_user: &mut user::User<LoopbackSMSHandler>, rx_block: &ReceivedBlock) {
+    let is_multipart = (rx_block.raw[0] >> 6) & 1 == 1;
+    let block_id = if is_multipart { rx_block.raw[1] } else { 0 };
+    let _ = server_user.process_block_ack(&BitVec::<u8,Lsb0>::from_vec(vec![rx_block.msg_id, block_id]));
+}
+
+// Acks every block as it arrives until the server has nothing left to send, returns each message's payload by msg_id in the order they started.
+// Ids come round again once a message is acked, so a message is its msg_id and generation
+async fn receive_all(server_user: &mut user::User<'_, LoopbackSMSHandler>, phone: &Phone) -> Vec<(u8, Vec<u8>)> {
+    let mut messages: Vec<(u8, u32, Vec<ReceivedBlock>)> = vec![];
+    while !server_user.outgoing_messages.is_empty() {
+        let rx_block = phone.recv_block().await;
+        ack(server_user, &rx_block);
+        match messages.iter_mut().find(|(msg_id, generation, _)| (*msg_id, *generation) == (rx_block.msg_id, rx_block.generation)) {
+            Some((_, _, blocks)) => blocks.push(rx_block),
+            None => messages.push((rx_block.msg_id, rx_block.generation, vec![rx_block])),
+        }
+    }
+    messages.into_iter().map(|(msg_id, _, mut msg_blocks)| {
+        msg_blocks.sort_by_key(|b| b.block_idx);
+        assert!(msg_blocks.len() <= MEDIA_CHUNK_BLOCKS);
+        (msg_id, msg_blocks.into_iter().flat_map(|b| b.payload).collect())
+    }).collect()
+}
+
+#[test]
+pub fn test_media_shrink() {
+    let png = test_png(800, 600);
+    assert!(png.len() > 8192);
+
+    let (shrunk, mimetype) = media::shrink(png.clone(), 8192).unwrap();
+    assert_eq!(mimetype, "image/jpeg");
+    assert!(shrunk.len() <= 8192);
+    assert_eq!(image::guess_format(&shrunk).unwrap(), image::ImageFormat::Jpeg);
+
+    // already under budget, sent as it is
+    let (unchanged, mimetype) = media::shrink(png.clone(), png.len()).unwrap();
+    assert_eq!((unchanged, mimetype.as_str()), (png, "image/png"));
+
+    // only images can be shrunk
+    let text = "not an image ".repeat(100).into_bytes();
+    assert!(media::shrink(text.clone(), 64).is_err());
+    assert_eq!(media::shrink(text.clone(), 4096).unwrap(), (text, "application/octet-stream".to_string()));
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_media_transfer() {
+    let (server_sms, phone) = common::loopback();
+    let mut server_user = user::User::new(common::test_homeservers().await, common::PHONE_ADDR.to_string(), false, &server_sms);
+
+    let data: Vec<u8> = (0..MEDIA_CHUNK_BYTES * 2 + 100).map(|i| (i * 31 % 251) as u8).collect();
+    let transfer_id = server_user.start_media_transfer(3, data.clone(), "image/jpeg").unwrap();
+    let messages = receive_all(&mut server_user, &phone).await;
+    assert!(server_user.media_transfers.is_empty());
+
+    // media_start, then the three chunks one after the other
+    let start = &messages[0].1;
+    assert_eq!(start[0], CommandValue::MediaStart as u8);
+    assert_eq!(start[1], 3);
+    assert_eq!(u16::from_be_bytes([start[2], start[3]]), transfer_id);
+    assert_eq!(u32::from_be_bytes(start[4..8].try_into().unwrap()) as usize, data.len());
+    assert_eq!(&start[8..], b"image/jpeg");
+
+    let mut received = vec![];
+    for (_, chunk) in &messages[1..] {
+        assert_eq!(chunk[0], CommandValue::MediaChunk as u8);
+        assert_eq!(u16::from_be_bytes([chunk[1], chunk[2]]), transfer_id);
+        assert_eq!(u32::from_be_bytes(chunk[3..7].try_into().unwrap()) as usize, received.len());
+        received.extend_from_slice(&chunk[7..]);
+    }
+    assert_eq!(messages.len(), 4);
+    assert_eq!(received, data);
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_media_cancel() {
+    let (server_sms, phone) = common::loopback();
+    let mut server_user = user::User::new(common::test_homeservers().await, common::PHONE_ADDR.to_string(), false, &server_sms);
+
+    let transfer_id = server_user.start_media_transfer(3, vec![0; MEDIA_CHUNK_BYTES * 4], "image/png").unwrap();
+    let start = phone.recv_command(CommandValue::MediaStart).await;
+    ack(&mut server_user, &start);
+    let chunk = phone.recv_command(CommandValue::MediaChunk).await;
+
+    // the chunk in flight is dropped with the transfer and its id freed, nothing more is sent
+    assert!(server_user.cancel_media_transfer(transfer_id));
+    assert!(!server_user.outgoing_messages.contains_key(&chunk.msg_id));
+    assert!(server_user.unused_ids.contains(&chunk.msg_id));
+    assert_eq!(server_user.unused_ids.len(), 31);
+    assert!(server_user.media_transfers.is_empty());
+    assert!(!server_user.cancel_media_transfer(transfer_id));
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_media_longer_than_msg_id_pool() {
+    let (server_sms, mut phone) = common::loopback();
+    let mut server_user = user::User::new(common::test_homeservers().await, common::PHONE_ADDR.to_string(), true, &server_sms);
+    server_user.shared_secret = [5; 32];
+    phone.shared_secret = Some([5; 32]);
+
+    // more chunks than there are msg_ids, each acked chunk hands its id back for a later one
+    let data: Vec<u8> = (0..MEDIA_CHUNK_BYTES * 34 + 100).map(|i| (i * 31 % 251) as u8).collect();
+    let transfer_id = server_user.start_media_transfer(3, data.clone(), "image/png").unwrap();
+    let messages = receive_all(&mut server_user, &phone).await;
+    assert!(server_user.media_transfers.is_empty());
+    assert_eq!(messages.len(), 36);
+
+    let mut received = vec![];
+    for (_, chunk) in &messages[1..] {
+        assert_eq!(chunk[0], CommandValue::MediaChunk as u8);
+        assert_eq!(u16::from_be_bytes([chunk[1], chunk[2]]), transfer_id);
+        received.extend_from_slice(&chunk[7..]);
+    }
+    assert_eq!(received, data);
+
+    // reused ids went out under a new generation, and all of them are free again
+    let msg_ids: std::collections::HashSet<u8> = messages.iter().map(|(msg_id, _)| *msg_id).collect();
+    assert!(msg_ids.len() < messages.len());
+    assert!(server_user.id_generations.iter().any(|generation| *generation > 1));
+    assert_eq!(server_user.unused_ids.len(), 31);
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_media_not_started_without_msg_ids() {
+    let (server_sms, phone) = common::loopback();
+    let mut server_user = user::User::new(common::test_homeservers().await, common::PHONE_ADDR.to_string(), false, &server_sms);
+    server_user.unused_ids = vec![7, 8];
+
+    // no media_start the chunks could not follow
+    assert!(server_user.start_media_transfer(3, vec![0; MEDIA_CHUNK_BYTES * 4], "image/png").is_none());
+    let error = phone.recv_block().await;
+    assert_eq!(error.command(), Some(CommandValue::Error as u8));
+    assert_eq!(error.payload[1], 3);
+    assert!(server_user.media_transfers.is_empty());
+    assert!(server_user.outgoing_messages.is_empty());
+}
@@ -0,0 +1,169 @@
+use boost::block;
+use boost::modem;
+use boost::sms::HandleSMS;
+
+use bitvec::prelude::*;
+use serialport::SerialPort;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// SMS-DELIVER from +15551234567 via SMSC +31624000000, 8-bit DCS, payload AA BB CC
+const DELIVER_PDU: &str = "07911326040000F0040B915155214365F700044210810100000003AABBCC";
+
+#[derive(Default)]
+struct EmulatorState {
+    storage: BTreeMap<u32, String>,
+    sent: Vec<String>,
+    commands: Vec<String>,
+}
+
+// Minimal AT-command GSM modem, running on the master side of a pty
+struct ModemEmulator {
+    state: Arc<Mutex<EmulatorState>>,
+    writer: Arc<Mutex<Box<dyn SerialPort>>>,
+    running: Arc<AtomicBool>,
+}
+
+impl ModemEmulator {
+    fn start(stored: &[&str]) -> (ModemEmulator, Box<dyn SerialPort>) {
+        let (mut master, slave) = serialport::TTYPort::pair().expect("Failed to open pty pair");
+        master.set_timeout(Duration::from_millis(50)).unwrap();
+        let writer: Arc<Mutex<Box<dyn SerialPort>>> = Arc::new(Mutex::new(master.try_clone().unwrap()));
+
+        let mut state = EmulatorState::default();
+        for (i, pdu) in stored.iter().enumerate() { state.storage.insert(i as u32 + 1, pdu.to_string()); }
+        let state = Arc::new(Mutex::new(state));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let emulator = ModemEmulator { state: state.clone(), writer: writer.clone(), running: running.clone() };
+        std::thread::spawn(move || {
+            let mut rx: Vec<u8> = vec![];
+            let mut awaiting_pdu = false;
+            while running.load(Ordering::SeqCst) {
+                let mut buf = [0u8; 256];
+                match master.read(&mut buf) {
+                    Ok(n) => rx.extend_from_slice(&buf[..n]),
+                    Err(_) => continue,
+                }
+
+                loop {
+                    let terminator = if awaiting_pdu { 0x1a } else { b'\r' };
+                    let end = match rx.iter().position(|b| *b == terminator) { Some(end) => end, None => break };
+                    let line: Vec<u8> = rx.drain(..=end).take(end).collect();
+                    let line = String::from_utf8(line).unwrap().trim().to_string();
+
+                    let reply = if awaiting_pdu {
+                        awaiting_pdu = false;
+                        state.lock().unwrap().sent.push(line);
+                        "\r\n+CMGS: 1\r\n\r\nOK\r\n".to_string()
+                    } else {
+                        state.lock().unwrap().commands.push(line.clone());
+                        respond(&state, &line, &mut awaiting_pdu)
+                    };
+                    writer.lock().unwrap().write_all(reply.as_bytes()).unwrap();
+                }
+            }
+        });
+
+        (emulator, Box::new(slave))
+    }
+
+    // store a new message and announce it
+    fn deliver(&self, pdu: &str) {
+        let mut state = self.state.lock().unwrap();
+        let idx = state.storage.keys().next_back().map_or(1, |k| k + 1);
+        state.storage.insert(idx, pdu.to_string());
+        self.writer.lock().unwrap().write_all(format!("\r\n+CMTI: \"SM\",{}\r\n", idx).as_bytes()).unwrap();
+    }
+}
+
+impl Drop for ModemEmulator {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+fn respond(state: &Arc<Mutex<EmulatorState>>, line: &str, awaiting_pdu: &mut bool) -> String {
+    let mut state = state.lock().unwrap();
+    if line.starts_with("AT+CMGS=") {
+        *awaiting_pdu = true;
+        "\r\n> ".to_string()
+    } else if line == "AT+CMGL=4" {
+        let mut reply = String::new();
+        for (idx, pdu) in &state.storage {
+            reply.push_str(&format!("\r\n+CMGL: {},0,,{}\r\n{}", idx, pdu.len() / 2 - 8, pdu));
+        }
+        reply + "\r\n\r\nOK\r\n"
+    } else if let Some(idx) = line.strip_prefix("AT+CMGR=") {
+        match state.storage.get(&idx.parse().unwrap()) {
+            Some(pdu) => format!("\r\n+CMGR: 0,,{}\r\n{}\r\n\r\nOK\r\n", pdu.len() / 2 - 8, pdu),
+            None => "\r\n+CMS ERROR: 321\r\n".to_string(),
+        }
+    } else if let Some(idx) = line.strip_prefix("AT+CMGD=") {
+        state.storage.remove(&idx.parse().unwrap());
+        "\r\nOK\r\n".to_string()
+    } else if line.starts_with("AT") {
+        "\r\nOK\r\n".to_string()
+    } else {
+        "\r\nERROR\r\n".to_string()
+    }
+}
+
+fn wait_for<T>(timeout_ms: u64, mut f: impl FnMut() -> Option<T>) -> T {
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+    while Instant::now() < deadline {
+        if let Some(v) = f() { return v; }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    panic!("Timed out waiting for modem");
+}
+
+#[test]
+pub fn test_modem_init() {
+    let (emulator, port) = ModemEmulator::start(&[]);
+    let _handler = modem::ModemSMSHandler::from_port(port).expect("Modem init failed");
+
+    let commands = emulator.state.lock().unwrap().commands.clone();
+    assert!(commands.contains(&"ATE0".to_string()));
+    assert!(commands.contains(&"AT+CMGF=0".to_string()));
+    assert!(commands.iter().any(|c| c.starts_with("AT+CNMI=")));
+}
+
+#[test]
+pub fn test_modem_send_block() {
+    let (emulator, port) = ModemEmulator::start(&[]);
+    let handler = modem::ModemSMSHandler::from_port(port).expect("Modem init failed");
+
+    let test_block = block::Block::new("+15551234567".to_string(), BitVec::<u8,Lsb0>::from_vec(vec![0xaa, 0xbb, 0xcc]));
+    handler.send_block("+15551234567", &test_block);
+
+    let sent = wait_for(2000, || emulator.state.lock().unwrap().sent.first().cloned());
+    assert_eq!(sent, "0001000B915155214365F7000403AABBCC");
+}
+
+#[tokio::test]
+pub async fn test_modem_receive_block() {
+    let (emulator, port) = ModemEmulator::start(&[]);
+    let handler = modem::ModemSMSHandler::from_port(port).expect("Modem init failed");
+
+    emulator.deliver(DELIVER_PDU);
+    let rx_block = tokio::time::timeout(Duration::from_secs(2), handler.recv_block()).await.expect("Timed out waiting for block").unwrap();
+    assert_eq!(rx_block.addr, "+15551234567");
+    assert_eq!(rx_block.data.into_vec(), vec![0xaa, 0xbb, 0xcc]);
+
+    // the slot is freed once the message has been read
+    wait_for(2000, || if emulator.state.lock().unwrap().storage.is_empty() { Some(()) } else { None });
+}
+
+#[tokio::test]
+pub async fn test_modem_reads_stored_on_startup() {
+    let (emulator, port) = ModemEmulator::start(&[DELIVER_PDU]);
+    let handler = modem::ModemSMSHandler::from_port(port).expect("Modem init failed");
+
+    let rx_block = tokio::time::timeout(Duration::from_secs(2), handler.recv_block()).await.expect("Timed out waiting for block").unwrap();
+    assert_eq!(rx_block.addr, "+15551234567");
+    assert!(emulator.state.lock().unwrap().storage.is_empty());
+}
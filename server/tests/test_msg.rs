@@ -1,3 +1,6 @@
+mod common;
+
+use boost::block;
 use boost::user;
 use boost::sms;
 
@@ -12,7 +15,7 @@ pub fn test_chunking() {
     let tx_payload_bitvec = BitVec::<u8,Lsb0>::from_vec(tx_payload.as_bytes().to_vec());
     let tx_is_command = true;
     let tx_msg_id = 15;
-    let test_blocks = user::User::<sms::VoidSMSHandler>::generate_msg_blocks(&tx_payload_bitvec, tx_is_command, tx_msg_id, &"test_addr".to_string());
+    let test_blocks = user::User::<sms::VoidSMSHandler>::generate_msg_blocks(&tx_payload_bitvec, tx_is_command, tx_msg_id, None, &"test_addr".to_string());
     let n_blocks = test_blocks.len();
 
     let mut cursor = 0;
@@ -49,3 +52,25 @@ pub fn test_chunking() {
         cursor += rx_payload.len();
     }
 }
+
+#[tokio::test]
+pub async fn test_reassembly_out_of_order() {
+    // 3 full blocks and a short final one
+    let tx_payload: Vec<u8> = (0..(138*3 + 20)).map(|i| (i % 251) as u8).collect();
+    let tx_blocks = user::User::<sms::VoidSMSHandler>::generate_msg_blocks(&BitVec::<u8,Lsb0>::from_vec(tx_payload.clone()), false, 9, None, &"test_addr".to_string());
+    assert_eq!(tx_blocks.len(), 4);
+
+    let homeservers = common::test_homeservers().await;
+    let sms_handler = sms::VoidSMSHandler {};
+    for order in [[0, 1, 2, 3], [3, 2, 1, 0], [1, 3, 0, 2], [2, 0, 3, 1]] {
+        let mut test_user = user::User::new(homeservers.clone(), "test_addr".to_string(), false, &sms_handler);
+        let mut completions = 0;
+        // every block twice, the duplicates must not disturb the reassembly
+        for i in order.iter().chain(order.iter()) {
+            let (action, _) = test_user.receive_block(&mut tx_blocks[*i].clone());
+            if let block::BlockReceivedAction::ProcessMessage = action { completions += 1; }
+        }
+        assert_eq!(completions, 1, "order {:?}", order);
+        assert_eq!(test_user.messages.get(&9).unwrap().payload.clone().into_vec(), tx_payload, "order {:?}", order);
+    }
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use boost::command::CommandValue;
+use boost::sms::HandleSMS;
+use boost::user;
+use common::{loopback, with_server, PHONE_ADDR, SERVER_ADDR};
+
+use bitvec::prelude::*;
+
+#[tokio::test(start_paused = true)]
+pub async fn test_nack_server_requests_missing() {
+    let (server, mut phone) = loopback();
+    with_server(&server, async {
+        phone.key_exchange().await;
+
+        let mut request = vec![CommandValue::RequestDomains as u8];
+        request.extend_from_slice(&[0x55; 300]);
+        let blocks = phone.encode_message(2, true, &request);
+        assert_eq!(blocks.len(), 3);
+
+        // block 1 is lost on the way, the server should ask for exactly that one
+        phone.sms.send_block(SERVER_ADDR, &blocks[0]);
+        phone.sms.send_block(SERVER_ADDR, &blocks[2]);
+        let nack = phone.recv_command(CommandValue::MissingBlocks).await;
+        assert_eq!(nack.payload, vec![CommandValue::MissingBlocks as u8, 2, 1]);
+
+        phone.sms.send_block(SERVER_ADDR, &blocks[1]);
+        phone.recv_command(CommandValue::DomainUpdate).await;
+    }).await;
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_nack_first_block_missing() {
+    let (server, mut phone) = loopback();
+    with_server(&server, async {
+        phone.key_exchange().await;
+
+        let mut request = vec![CommandValue::RequestDomains as u8];
+        request.extend_from_slice(&[0x55; 400]);
+        let blocks = phone.encode_message(3, true, &request);
+        assert_eq!(blocks.len(), 3);
+
+        // without the mp_first block the server does not know the block count, but it knows it needs block 0
+        phone.sms.send_block(SERVER_ADDR, &blocks[1]);
+        let nack = phone.recv_command(CommandValue::MissingBlocks).await;
+        assert_eq!(nack.payload, vec![CommandValue::MissingBlocks as u8, 3, 0]);
+
+        phone.sms.send_block(SERVER_ADDR, &blocks[0]);
+        let nack = phone.recv_command(CommandValue::MissingBlocks).await;
+        assert_eq!(nack.payload, vec![CommandValue::MissingBlocks as u8, 3, 2]);
+
+        phone.sms.send_block(SERVER_ADDR, &blocks[2]);
+        phone.recv_command(CommandValue::DomainUpdate).await;
+    }).await;
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_nack_selective_retransmit() {
+    let (server_sms, phone) = loopback();
+    let homeservers = common::test_homeservers().await;
+    let mut server_user = user::User::new(homeservers, PHONE_ADDR.to_string(), false, &server_sms);
+
+    let message: Vec<u8> = (0..500).map(|i| i as u8).collect();
+    server_user.send_message(BitVec::<u8,Lsb0>::from_vec(message), false, true);
+    let msg_id = *server_user.outgoing_messages.keys().next().unwrap();
+
+    let mut first_send = vec![];
+    for _ in 0..4 { first_send.push(phone.recv_block().await); }
+    assert_eq!(first_send.iter().map(|b| b.block_idx).collect::<Vec<u8>>(), vec![0, 1, 2, 3]);
+
+    // block 3 has been acked (its raw index is 2), so only block 1 should come back
+    server_user.process_block_ack(&BitVec::<u8,Lsb0>::from_vec(vec![msg_id, first_send[3].raw[1]])).unwrap();
+    server_user.retransmit_blocks(&BitVec::<u8,Lsb0>::from_vec(vec![msg_id, 1, 3])).unwrap();
+
+    let resent = phone.recv_block().await;
+    assert_eq!((resent.msg_id, resent.block_idx), (msg_id, 1));
+    assert_eq!(resent.raw, first_send[1].raw);
+    phone.assert_silent(1000).await;
+
+    // nothing is outstanding for an unknown message, and a request without positions is malformed
+    assert_eq!(server_user.retransmit_blocks(&BitVec::<u8,Lsb0>::from_vec(vec![msg_id + 1, 0])), Err(0));
+    assert_eq!(server_user.retransmit_blocks(&BitVec::<u8,Lsb0>::from_vec(vec![msg_id])), Err(1));
+}
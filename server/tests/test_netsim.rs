@@ -0,0 +1,190 @@
+mod common;
+
+use boost::block;
+use boost::command::CommandValue;
+use boost::netsim::{FaultySMSHandler, Latency, NetworkConditions};
+use boost::sms::{HandleSMS, LoopbackSMSHandler};
+use boost::user;
+use common::{PHONE_ADDR, SERVER_ADDR};
+
+use bitvec::prelude::*;
+use std::time::Duration;
+
+type SimHandler = FaultySMSHandler<LoopbackSMSHandler>;
+
+// down: faults on blocks reaching the phone, up: faults on blocks reaching the server
+fn link(down: NetworkConditions, up: NetworkConditions) -> (SimHandler, SimHandler) {
+    let (server, phone) = LoopbackSMSHandler::pair(SERVER_ADDR, PHONE_ADDR);
+    (FaultySMSHandler::new(server, up), FaultySMSHandler::new(phone, down))
+}
+
+fn test_message(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + 3) as u8).collect()
+}
+
+// Server sends message as an outgoing data message, the phone reassembles it, acks every block it gets and asks for
+// missing blocks when the message stalls. Returns the payload the phone reassembled, panics if the server gives up first
+async fn transfer(message: &[u8], down: NetworkConditions, up: NetworkConditions) -> Vec<u8> {
+    let (server_sms, phone_sms) = link(down, up);
+    let homeservers = common::test_homeservers().await;
+    let mut server_user = user::User::new(homeservers.clone(), PHONE_ADDR.to_string(), false, &server_sms);
+    let mut phone_user = user::User::new(homeservers, SERVER_ADDR.to_string(), false, &phone_sms);
+
+    server_user.send_message(BitVec::<u8,Lsb0>::from_vec(message.to_vec()), false, true);
+    let mut received: Option<Vec<u8>> = None;
+
+    loop {
+        if server_user.outgoing_messages.is_empty() {
+            match received {
+                Some(payload) => return payload,
+                None => panic!("Server gave up on the message before the phone reassembled it"),
+            }
+        }
+
+        let next_refresh = [server_user.next_refresh_instant(), phone_user.next_nack_instant()].into_iter().flatten().min();
+        tokio::select! {
+            Some(new_block) = phone_sms.recv_block() => {
+                let mut new_block = strip_generation(new_block);
+                let msg_id = new_block.data.get(block::BLOCK_MSGID_RANGE).unwrap().load::<u8>();
+                let (action, block_idx) = phone_user.receive_block(&mut new_block);
+                match action {
+                    block::BlockReceivedAction::SendBlockAck => send_ack(&phone_sms, msg_id, block_idx),
+                    block::BlockReceivedAction::ProcessMessage => {
+                        send_ack(&phone_sms, msg_id, block_idx);
+                        received = Some(phone_user.messages.get(&msg_id).unwrap().payload.clone().into_vec());
+                    }
+                    other => panic!("Unexpected action on phone: {:?}", other),
+                }
+            }
+            Some(reply) = server_sms.recv_block() => {
+                let raw = strip_generation(reply).data.into_vec();
+                let reply_payload = BitVec::<u8,Lsb0>::from_vec(raw[2..].to_vec());
+                if raw[1] == CommandValue::BlockAck as u8 {
+                    let _ = server_user.process_block_ack(&reply_payload);
+                } else {
+                    assert_eq!(raw[1], CommandValue::MissingBlocks as u8);
+                    let _ = server_user.retransmit_blocks(&reply_payload);
+                }
+            }
+            _ = tokio::time::sleep_until(next_refresh.unwrap()) => {
+                server_user.refresh_outgoing();
+                phone_user.request_missing_blocks();
+            }
+        }
+    }
+}
+
+// Both ends are a User here, and a User sends the generation after the header but does not expect one
+fn strip_generation(mut server_block: block::Block) -> block::Block {
+    let header_size = if server_block.data[block::BLOCK_ISMLP_RANGE].load::<u8>() == 1 { block::BLOCK_MPPAY_RANGE.start } else { block::BLOCK_PAYLD_RANGE.start };
+    server_block.data.drain(header_size..header_size + 8 * block::BLOCK_GENERATION_OCTETS);
+    server_block
+}
+
+fn send_ack(phone_sms: &SimHandler, msg_id: u8, block_idx: u8) {
+    // header: msg 0, command, singlepart, then generation 0 like everything else phone_user sends
+    let ack = vec![0x20, 0, 0, 0, 0, CommandValue::BlockAck as u8, msg_id, block_idx];
+    phone_sms.send_block(SERVER_ADDR, &block::Block::new(SERVER_ADDR.to_string(), BitVec::<u8,Lsb0>::from_vec(ack)));
+}
+
+// sends count numbered single byte blocks through one direction of the link and returns them in arrival order
+async fn arrival_order(conditions: NetworkConditions, count: u8) -> Vec<u8> {
+    let (server, phone) = link(conditions, NetworkConditions::default());
+    for i in 0..count {
+        server.send_block(PHONE_ADDR, &block::Block::new(PHONE_ADDR.to_string(), BitVec::<u8,Lsb0>::from_vec(vec![i])));
+    }
+    drop(server);
+
+    let mut order = vec![];
+    while let Some(rx_block) = phone.recv_block().await {
+        order.push(rx_block.data.into_vec()[0]);
+    }
+    order
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_netsim_perfect_link() {
+    assert_eq!(arrival_order(NetworkConditions::default(), 20).await, (0..20).collect::<Vec<u8>>());
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_netsim_seeded() {
+    let conditions = NetworkConditions { loss_rate: 0.2, duplicate_rate: 0.2, reorder_window: 4, latency: Latency::Uniform(Duration::from_millis(100), Duration::from_secs(2)), seed: 42 };
+    let first = arrival_order(conditions.clone(), 50).await;
+    assert_eq!(first, arrival_order(conditions.clone(), 50).await);
+    assert_ne!(first, arrival_order(NetworkConditions { seed: 43, ..conditions }, 50).await);
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_netsim_faults() {
+    let lossy = arrival_order(NetworkConditions { loss_rate: 1.0, ..Default::default() }, 20).await;
+    assert!(lossy.is_empty());
+
+    let duplicated = arrival_order(NetworkConditions { duplicate_rate: 1.0, ..Default::default() }, 20).await;
+    assert_eq!(duplicated, (0..20).flat_map(|i| [i, i]).collect::<Vec<u8>>());
+
+    let reordered = arrival_order(NetworkConditions { reorder_window: 3, seed: 7, ..Default::default() }, 20).await;
+    let mut sorted = reordered.clone();
+    sorted.sort();
+    assert_eq!(sorted, (0..20).collect::<Vec<u8>>());
+    assert_ne!(reordered, sorted);
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_netsim_rates_out_of_range() {
+    assert!(arrival_order(NetworkConditions { loss_rate: 2.0, ..Default::default() }, 20).await.is_empty());
+    let untouched = arrival_order(NetworkConditions { loss_rate: -1.0, duplicate_rate: f64::NAN, ..Default::default() }, 20).await;
+    assert_eq!(untouched, (0..20).collect::<Vec<u8>>());
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_netsim_single_block_lossy() {
+    let message = test_message(100);
+    for seed in 0..20 {
+        let conditions = NetworkConditions { loss_rate: 0.2, seed, ..Default::default() };
+        let received = transfer(&message, conditions.clone(), NetworkConditions { seed: seed + 1000, ..conditions }).await;
+        assert_eq!(received, message, "seed {}", seed);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_netsim_single_block_duplicated_and_slow() {
+    let message = test_message(100);
+    for seed in 0..10 {
+        // mean latency close to the retransmit interval, so acks routinely cross retransmissions
+        let conditions = NetworkConditions { duplicate_rate: 0.5, latency: Latency::Exponential(Duration::from_secs(3)), seed, ..Default::default() };
+        let received = transfer(&message, conditions.clone(), NetworkConditions { seed: seed + 1000, ..conditions }).await;
+        assert_eq!(received, message, "seed {}", seed);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_netsim_multipart_perfect() {
+    let message = test_message(500);
+    assert_eq!(transfer(&message, NetworkConditions::default(), NetworkConditions::default()).await, message);
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_netsim_multipart_reordered() {
+    let message = test_message(700);
+    for seed in 0..20 {
+        let conditions = NetworkConditions { reorder_window: 4, seed, ..Default::default() };
+        assert_eq!(transfer(&message, conditions, NetworkConditions::default()).await, message, "seed {}", seed);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_netsim_multipart_hostile() {
+    let message = test_message(1000);
+    for seed in 0..20 {
+        let conditions = NetworkConditions {
+            loss_rate: 0.1,
+            duplicate_rate: 0.2,
+            reorder_window: 3,
+            latency: Latency::Uniform(Duration::from_millis(500), Duration::from_secs(4)),
+            seed,
+        };
+        let received = transfer(&message, conditions.clone(), NetworkConditions { seed: seed + 1000, ..conditions }).await;
+        assert_eq!(received, message, "seed {}", seed);
+    }
+}
@@ -0,0 +1,89 @@
+use boost::pdu;
+
+// SMS-DELIVER from +15551234567 via SMSC +31624000000, 8-bit DCS, payload AA BB CC
+const DELIVER_PDU: &str = "07911326040000F0040B915155214365F700044210810100000003AABBCC";
+// SMS-SUBMIT to +15551234567 with the modem's SMSC, payload AA BB CC
+const SUBMIT_PDU: &str = "0001000B915155214365F7000403AABBCC";
+// SMS-SUBMIT to +447700900123, relative validity, concatenation UDH (ref CC, part 1 of 2)
+const SUBMIT_UDH_PDU: &str = "00512A0C914477000910320004A708050003CC02010102";
+// SMS-DELIVER from alphanumeric sender "Boost", timezone -05:00
+const DELIVER_ALNUM_PDU: &str = "000409D0C2F77B4E0700046201813295030A02BEEF";
+
+#[test]
+pub fn test_deliver_decode() {
+    let deliver = pdu::DeliverPdu::decode(&pdu::from_hex(DELIVER_PDU).unwrap()).unwrap();
+
+    assert_eq!(deliver.smsc, Some("+31624000000".to_string()));
+    assert_eq!(deliver.originator, "+15551234567");
+    assert_eq!(deliver.data_coding, pdu::DCS_8BIT);
+    assert_eq!(deliver.timestamp, pdu::Timestamp { year: 24, month: 1, day: 18, hour: 10, minute: 0, second: 0, tz_quarter_hours: 0 });
+    assert!(deliver.udh.is_empty());
+    assert_eq!(deliver.data, vec![0xaa, 0xbb, 0xcc]);
+}
+
+#[test]
+pub fn test_deliver_roundtrip() {
+    let raw = pdu::from_hex(DELIVER_PDU).unwrap();
+    let deliver = pdu::DeliverPdu::decode(&raw).unwrap();
+    assert_eq!(pdu::to_hex(&deliver.encode().unwrap()), DELIVER_PDU);
+}
+
+#[test]
+pub fn test_deliver_alphanumeric_originator() {
+    let deliver = pdu::DeliverPdu::decode(&pdu::from_hex(DELIVER_ALNUM_PDU).unwrap()).unwrap();
+
+    assert_eq!(deliver.smsc, None);
+    assert_eq!(deliver.originator, "Boost");
+    assert_eq!(deliver.timestamp, pdu::Timestamp { year: 26, month: 10, day: 18, hour: 23, minute: 59, second: 30, tz_quarter_hours: -20 });
+    assert_eq!(deliver.data, vec![0xbe, 0xef]);
+}
+
+#[test]
+pub fn test_submit_encode() {
+    let submit = pdu::SubmitPdu::new("+15551234567", vec![0xaa, 0xbb, 0xcc]);
+    let (raw, tpdu_len) = submit.encode().unwrap();
+
+    assert_eq!(pdu::to_hex(&raw), SUBMIT_PDU);
+    assert_eq!(tpdu_len, raw.len() - 1);
+    assert_eq!(pdu::SubmitPdu::decode(&raw).unwrap(), submit);
+}
+
+#[test]
+pub fn test_submit_udh_roundtrip() {
+    let submit = pdu::SubmitPdu::decode(&pdu::from_hex(SUBMIT_UDH_PDU).unwrap()).unwrap();
+
+    assert_eq!(submit.message_reference, 0x2a);
+    assert_eq!(submit.destination, "+447700900123");
+    assert_eq!(submit.validity_period, Some(0xa7));
+    assert_eq!(submit.udh, vec![pdu::InformationElement { id: 0x00, data: vec![0xcc, 0x02, 0x01] }]);
+    assert_eq!(submit.data, vec![0x01, 0x02]);
+
+    let (raw, _) = submit.encode().unwrap();
+    assert_eq!(pdu::to_hex(&raw), SUBMIT_UDH_PDU);
+}
+
+#[test]
+pub fn test_full_block_roundtrip() {
+    let payload: Vec<u8> = (0..pdu::MAX_USER_DATA_OCTETS).map(|i| i as u8).collect();
+    let deliver = pdu::DeliverPdu::new("0612345678", pdu::Timestamp::default(), payload.clone());
+    let decoded = pdu::DeliverPdu::decode(&deliver.encode().unwrap()).unwrap();
+
+    assert_eq!(decoded.originator, "0612345678");
+    assert_eq!(decoded.data, payload);
+}
+
+#[test]
+pub fn test_reject_invalid() {
+    // payload too large for a single sms
+    assert!(pdu::SubmitPdu::new("+15551234567", vec![0; pdu::MAX_USER_DATA_OCTETS + 1]).encode().is_err());
+    // not a phone number
+    assert!(pdu::SubmitPdu::new("+1555abc", vec![0]).encode().is_err());
+    // truncated user data
+    assert!(pdu::DeliverPdu::decode(&pdu::from_hex(&DELIVER_PDU[..DELIVER_PDU.len() - 2]).unwrap()).is_err());
+    // 7-bit text sms (DCS 00) is not a block
+    assert!(pdu::DeliverPdu::decode(&pdu::from_hex("07911326040000F0040B915155214365F700004210810100000003AABBCC").unwrap()).is_err());
+    // submit is not a deliver
+    assert!(pdu::DeliverPdu::decode(&pdu::from_hex(SUBMIT_PDU).unwrap()).is_err());
+    // a lossily read response can put a multibyte char where a hex pair should be
+    assert!(pdu::from_hex("07\u{fffd}0").is_err());
+}
@@ -0,0 +1,81 @@
+use boost::render;
+
+use matrix_sdk::ruma::events::{
+    poll::unstable_start::UnstablePollStartEventContent,
+    room::message::RoomMessageEventContent,
+    sticker::StickerEventContent,
+};
+use matrix_sdk::ruma::serde::Raw;
+use std::time::Duration;
+
+// content as it comes off the wire
+fn parse<T: serde::de::DeserializeOwned>(json: &str) -> T {
+    Raw::<T>::from_json_string(json.to_string()).unwrap().deserialize().unwrap()
+}
+
+fn render_message(json: &str) -> String {
+    render::message(&parse::<RoomMessageEventContent>(json).msgtype)
+}
+
+#[test]
+pub fn test_render_sizes() {
+    assert_eq!(render::format_size(512), "512B");
+    assert_eq!(render::format_size(3482), "3.4KB");
+    assert_eq!(render::format_size(12 * 1024 * 1024 + 1), "12MB");
+    assert_eq!(render::format_duration(Duration::from_millis(42_900)), "0:42");
+    assert_eq!(render::format_duration(Duration::from_secs(725)), "12:05");
+}
+
+#[test]
+pub fn test_render_messages() {
+    assert_eq!(render_message(r#"{"msgtype": "m.text", "body": "hello"}"#), "hello");
+    assert_eq!(render_message(r#"{"msgtype": "m.notice", "body": "bridge restarted"}"#), "[notice] bridge restarted");
+    assert_eq!(render_message(r#"{"msgtype": "m.emote", "body": "waves"}"#), "* waves");
+
+    // body is the filename unless a separate filename makes it a caption
+    assert_eq!(
+        render_message(r#"{"msgtype": "m.image", "body": "IMG_0001.jpg", "url": "mxc://example.com/abc", "info": {"size": 245760, "mimetype": "image/jpeg"}}"#),
+        "[image: IMG_0001.jpg 240KB] mxc://example.com/abc"
+    );
+    assert_eq!(
+        render_message(r#"{"msgtype": "m.image", "body": "look at this", "filename": "cat.png", "url": "mxc://example.com/cat"}"#),
+        "[image: cat.png] look at this mxc://example.com/cat"
+    );
+    assert_eq!(
+        render_message(r#"{"msgtype": "m.file", "body": "report.pdf", "url": "mxc://example.com/pdf", "info": {"size": 1258291}}"#),
+        "[file: report.pdf 1.2MB] mxc://example.com/pdf"
+    );
+    assert_eq!(
+        render_message(r#"{"msgtype": "m.video", "body": "clip.mp4", "url": "mxc://example.com/vid", "info": {"duration": 42000, "size": 4299161}}"#),
+        "[video: clip.mp4 0:42 4.1MB] mxc://example.com/vid"
+    );
+    assert_eq!(
+        render_message(r#"{"msgtype": "m.audio", "body": "Voice message.ogg", "url": "mxc://example.com/ogg", "info": {"duration": 12000, "size": 20480}, "org.matrix.msc3245.voice": {}}"#),
+        "[voice: Voice message.ogg 0:12 20KB] mxc://example.com/ogg"
+    );
+    assert_eq!(
+        render_message(r#"{"msgtype": "m.location", "body": "Big Ben", "geo_uri": "geo:51.5007,-0.1246"}"#),
+        "[location: Big Ben] geo:51.5007,-0.1246"
+    );
+
+    // encrypted media still has an mxc uri, the key travels with the event
+    let encrypted = r#"{"msgtype": "m.file", "body": "secret.txt", "file": {"url": "mxc://example.com/enc", "key": {"kty": "oct", "key_ops": ["encrypt", "decrypt"], "alg": "A256CTR", "k": "qcHVMSgYg-71CauWBezXI5qkaRb0LuIy-Wx5kIaHMIA", "ext": true}, "iv": "X85+XgHN+HEAAAAAAAAAAA", "hashes": {"sha256": "5qG4fFnbbVdlAB1Q72JDKwCagV6Dbkx9uds4rSak37c"}, "v": "v2"}}"#;
+    assert_eq!(render_message(encrypted), "[file: secret.txt] mxc://example.com/enc");
+}
+
+#[test]
+pub fn test_render_stickers_and_polls() {
+    let sticker = parse::<StickerEventContent>(r#"{"body": "thumbs up", "url": "mxc://example.com/sticker", "info": {}}"#);
+    assert_eq!(render::sticker(&sticker), "[sticker: thumbs up] mxc://example.com/sticker");
+
+    let poll = parse::<UnstablePollStartEventContent>(r#"{
+        "org.matrix.msc3381.poll.start": {
+            "question": {"org.matrix.msc1767.text": "Lunch?"},
+            "kind": "org.matrix.msc3381.poll.disclosed",
+            "max_selections": 1,
+            "answers": [{"id": "a", "org.matrix.msc1767.text": "Pizza"}, {"id": "b", "org.matrix.msc1767.text": "Sushi"}]
+        },
+        "org.matrix.msc1767.text": "Lunch?\n1. Pizza\n2. Sushi"
+    }"#);
+    assert_eq!(render::unstable_poll_start(&poll).unwrap(), "[poll: Lunch?] 1) Pizza 2) Sushi");
+}
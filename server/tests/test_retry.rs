@@ -0,0 +1,111 @@
+mod common;
+
+use boost::command::CommandValue;
+use boost::retry::{AbandonedMessage, RetryPolicy};
+use boost::sms::LoopbackSMSHandler;
+use boost::user;
+use common::{Phone, PHONE_ADDR, SERVER_ADDR};
+
+use bitvec::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Drives refresh_outgoing until the message is acked or abandoned, on_block sees every block the phone receives.
+// Returns the received blocks with their arrival time relative to the first send
+async fn run_retries<F: FnMut(&mut user::User<LoopbackSMSHandler>, &common::ReceivedBlock)>(server_user: &mut user::User<'_, LoopbackSMSHandler>, phone: &Phone, mut on_block: F) -> Vec<(Duration, common::ReceivedBlock)> {
+    let start = Instant::now();
+    let mut received = vec![];
+    while !server_user.outgoing_messages.is_empty() {
+        tokio::select! {
+            rx_block = phone.recv_block() => {
+                on_block(server_user, &rx_block);
+                received.push((start.elapsed(), rx_block));
+            }
+            _ = tokio::time::sleep_until(server_user.next_refresh_instant().unwrap()) => server_user.refresh_outgoing(),
+        }
+    }
+    // the give up notice goes out as the message is dropped
+    while let Ok(rx_block) = tokio::time::timeout(Duration::from_millis(100), phone.recv_block()).await {
+        received.push((start.elapsed(), rx_block));
+    }
+    received
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_retry_exponential_backoff() {
+    let abandoned: Arc<Mutex<Vec<AbandonedMessage>>> = Arc::new(Mutex::new(vec![]));
+    let abandoned_cb = abandoned.clone();
+    let policy = RetryPolicy {
+        initial_delay: Duration::from_secs(1),
+        multiplier: 2.0,
+        max_delay: Duration::from_secs(5),
+        jitter: 0.0,
+        max_attempts: 5,
+        ..Default::default()
+    }.with_give_up_callback(move |msg| abandoned_cb.lock().unwrap().push(msg.clone()));
+
+    let (server_sms, phone_sms) = LoopbackSMSHandler::pair(SERVER_ADDR, PHONE_ADDR);
+    let server_sms = server_sms.with_retry_policy(policy);
+    let phone = Phone::new(phone_sms);
+    let mut server_user = user::User::new(common::test_homeservers().await, PHONE_ADDR.to_string(), false, &server_sms);
+
+    server_user.send_message(BitVec::<u8,Lsb0>::from_vec(b"hello".to_vec()), false, true);
+    let msg_id = *server_user.outgoing_messages.keys().next().unwrap();
+    let received = run_retries(&mut server_user, &phone, |_, _| {}).await;
+
+    // 1s, 2s, 4s, then capped at 5s
+    let send_times: Vec<u64> = received.iter().filter(|(_, b)| b.msg_id == msg_id).map(|(t, _)| t.as_secs()).collect();
+    assert_eq!(send_times, vec![0, 1, 3, 7, 12]);
+
+    // the phone is told, and so is the callback
+    let notice = &received.last().unwrap().1;
+    assert_eq!(notice.command(), Some(CommandValue::Error as u8));
+    assert_eq!(notice.payload[1], msg_id);
+    let abandoned = abandoned.lock().unwrap();
+    assert_eq!(abandoned.len(), 1);
+    assert_eq!((abandoned[0].address.as_str(), abandoned[0].msg_id, abandoned[0].send_attempts, abandoned[0].cost), (PHONE_ADDR, msg_id, 5, 5));
+
+    // neither the abandoned message nor the notice keeps its id
+    assert!(server_user.unused_ids.contains(&msg_id));
+    assert_eq!(server_user.unused_ids.len(), 31);
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_retry_max_cost() {
+    let policy = RetryPolicy { max_cost: Some(7), ..RetryPolicy::fixed(Duration::from_secs(5), 100) };
+    let (server_sms, phone_sms) = LoopbackSMSHandler::pair(SERVER_ADDR, PHONE_ADDR);
+    let server_sms = server_sms.with_retry_policy(policy);
+    let phone = Phone::new(phone_sms);
+    let mut server_user = user::User::new(common::test_homeservers().await, PHONE_ADDR.to_string(), false, &server_sms);
+
+    let message: Vec<u8> = (0..300).map(|i| i as u8).collect();
+    server_user.send_message(BitVec::<u8,Lsb0>::from_vec(message), false, true);
+    let msg_id = *server_user.outgoing_messages.keys().next().unwrap();
+
+    // ack the mp_first block as soon as it shows up, only the other two are resent after that
+    let received = run_retries(&mut server_user, &phone, |server_user, rx_block| {
+        if rx_block.msg_id == msg_id && rx_block.block_idx == 0 {
+            let _ = server_user.process_block_ack(&BitVec::<u8,Lsb0>::from_vec(vec![msg_id, rx_block.raw[1]]));
+        }
+    }).await;
+
+    // 3 on the first send and 2 on each resend after the ack, a third resend would take it to 9
+    let sends: Vec<(u64, u8)> = received.iter().filter(|(_, b)| b.msg_id == msg_id).map(|(t, b)| (t.as_secs(), b.block_idx)).collect();
+    assert_eq!(sends, vec![(0, 0), (0, 1), (0, 2), (5, 1), (5, 2), (10, 1), (10, 2)]);
+    assert_eq!(received.last().unwrap().1.command(), Some(CommandValue::Error as u8));
+}
+
+#[test]
+pub fn test_retry_jitter() {
+    let policy = RetryPolicy { initial_delay: Duration::from_secs(10), jitter: 0.5, ..Default::default() };
+    let delays: Vec<Duration> = (0..100).map(|_| policy.delay_after_attempt(1)).collect();
+    assert!(delays.iter().all(|d| *d >= Duration::from_secs(5) && *d <= Duration::from_secs(15)));
+    assert!(delays.iter().any(|d| *d != delays[0]));
+
+    let fixed = RetryPolicy::fixed(Duration::from_secs(5), 6);
+    assert_eq!(fixed.delay_after_attempt(1), Duration::from_secs(5));
+    assert_eq!(fixed.delay_after_attempt(6), Duration::from_secs(5));
+    assert!(fixed.should_retry(5, 100, 100));
+    assert!(!fixed.should_retry(6, 0, 0));
+}
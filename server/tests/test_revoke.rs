@@ -0,0 +1,95 @@
+mod common;
+
+use boost::command::CommandValue;
+use boost::credential_manager::{self, BridgeBotCredentials};
+use boost::sms::{HandleSMS, LoopbackSMSHandler};
+use boost::user;
+use common::{fake_bot, Phone, SERVER_ADDR};
+
+use bitvec::prelude::*;
+use std::collections::HashMap;
+
+const PHONE_ADDRS: [&str; 3] = ["+15551110001", "+15551110002", "+15551110003"];
+
+fn test_botcred(bot_address: &str, password: &str) -> BridgeBotCredentials {
+    BridgeBotCredentials::new(bot_address.to_string(), "test".to_string(), "user0".to_string(), credential_manager::hash_password(password).unwrap(), "!dm:test".to_string(), "!admin:test".to_string())
+}
+
+// Hands the next block a phone sent to the server
+async fn deliver<'a>(users: &mut HashMap<String, user::User<'a, LoopbackSMSHandler>>, server_sms: &'a LoopbackSMSHandler, bot_credentials: &mut Vec<BridgeBotCredentials>) -> Vec<String> {
+    let block = server_sms.recv_block().await.unwrap();
+    boost::handle_block(users, &common::test_homeservers().await, server_sms, bot_credentials, None, &user::UserPolicies::default(), block).await
+}
+
+fn revoke_payload(domain_idx: u8, username: &str, password: &str) -> Vec<u8> {
+    [&[domain_idx][..], username.as_bytes(), &[0], password.as_bytes()].concat()
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_revoke_all_clients() {
+    let mut bot_credentials = vec![test_botcred("@bot:test", "password"), test_botcred("@other:test", "other")];
+    let (server_sms, mut phones): (Vec<LoopbackSMSHandler>, Vec<Phone>) = PHONE_ADDRS.iter()
+        .map(|addr| { let (server, phone) = LoopbackSMSHandler::pair(SERVER_ADDR, addr); (server, Phone::new(phone)) })
+        .unzip();
+
+    let mut users = HashMap::new();
+    for (phone, sms) in phones.iter_mut().zip(&server_sms) {
+        tokio::join!(phone.key_exchange(), deliver(&mut users, sms, &mut bot_credentials));
+    }
+
+    // the first two phones are on @bot:test, the first at domain 1, the third is only on @other:test
+    let _bot_control_rx: Vec<_> = [(0, "@other:test"), (0, "@bot:test"), (1, "@bot:test"), (2, "@other:test")].into_iter()
+        .map(|(phone_idx, bot_address)| fake_bot(users.get_mut(PHONE_ADDRS[phone_idx]).unwrap(), bot_address, &["!a:test"]))
+        .collect();
+
+    // signed in is not enough, the bot's password has to come along
+    phones[0].send_command(1, CommandValue::RevokeAllClients, &revoke_payload(1, "user0", "wrong"));
+    assert!(deliver(&mut users, &server_sms[0], &mut bot_credentials).await.is_empty());
+    phones[0].recv_command(CommandValue::Error).await;
+    phones[0].send_command(2, CommandValue::RevokeAllClients, &revoke_payload(1, "user0", "other"));
+    assert!(deliver(&mut users, &server_sms[0], &mut bot_credentials).await.is_empty());
+    phones[0].recv_command(CommandValue::Error).await;
+    assert_eq!(users.values().map(|user| user.matrix_bots.len()).sum::<usize>(), 4);
+
+    phones[0].send_command(3, CommandValue::RevokeAllClients, &revoke_payload(1, "User0", "password"));
+    let mut revoked = deliver(&mut users, &server_sms[0], &mut bot_credentials).await;
+    revoked.sort();
+    assert_eq!(revoked, [PHONE_ADDRS[0], PHONE_ADDRS[1]]);
+
+    // each hears which of its domains went, the phone on the other bot hears nothing
+    assert_eq!(phones[0].recv_command(CommandValue::SignOutSuccess).await.payload[1], 1);
+    assert_eq!(phones[1].recv_command(CommandValue::SignOutSuccess).await.payload[1], 0);
+    phones[2].assert_silent(1000).await;
+
+    let bot_addresses = |addr: &str| users[addr].matrix_bots.iter().map(|bot_info| bot_info.bot_address.clone()).collect::<Vec<String>>();
+    assert_eq!(bot_addresses(PHONE_ADDRS[0]), ["@other:test"]);
+    assert!(bot_addresses(PHONE_ADDRS[1]).is_empty());
+    assert_eq!(bot_addresses(PHONE_ADDRS[2]), ["@other:test"]);
+}
+
+#[tokio::test]
+pub async fn test_revoke_drops_pending_channel_updates() {
+    let (server_sms, _phone_sms) = LoopbackSMSHandler::pair(SERVER_ADDR, PHONE_ADDRS[0]);
+    let mut server_user = user::User::new(common::test_homeservers().await, PHONE_ADDRS[0].to_string(), false, &server_sms);
+    let _bot_control_rx: Vec<_> = ["@a:test", "@b:test", "@c:test"].iter().map(|bot_address| fake_bot(&mut server_user, bot_address, &["!a:test"])).collect();
+
+    // a channel list goes out for every domain, none of them acked yet
+    let update_ids: Vec<u8> = (0..3u8).map(|domain_idx| {
+        server_user.client_has_latest_channel_list[domain_idx as usize] = false;
+        let payload = vec![CommandValue::ChannelUpdate as u8, domain_idx, b'x'];
+        server_user.send_message(BitVec::<u8,Lsb0>::from_vec(payload), true, true).unwrap()
+    }).collect();
+
+    // the revoked bot's list and the one behind it are dropped, the one before is untouched
+    server_user.revoke_bot(1).unwrap();
+    assert!(server_user.outgoing_messages.contains_key(&update_ids[0]));
+    for msg_id in &update_ids[1..] {
+        assert!(!server_user.outgoing_messages.contains_key(msg_id));
+        assert!(server_user.unused_ids.contains(msg_id));
+    }
+
+    // a late ack for a dropped list changes nothing
+    assert!(server_user.process_block_ack(&BitVec::<u8,Lsb0>::from_vec(vec![update_ids[2], 0])).is_err());
+    assert!(server_user.process_block_ack(&BitVec::<u8,Lsb0>::from_vec(vec![update_ids[0], 0])).is_ok());
+    assert_eq!(server_user.client_has_latest_channel_list, [true, false]);
+}
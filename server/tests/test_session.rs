@@ -0,0 +1,182 @@
+mod common;
+
+use boost::command::CommandValue;
+use boost::credential_manager::HomeserverCredentials;
+use boost::homeserver;
+use boost::session_store::{HomeserverSession, MemorySessionStore, SessionStore, SqliteSessionStore, StoredOutgoingMessage, UserSession};
+use boost::sms::LoopbackSMSHandler;
+use boost::user;
+use common::{loopback, with_server_sessions, Phone, PHONE_ADDR, SERVER_ADDR};
+
+use bitvec::prelude::*;
+use std::os::unix::fs::PermissionsExt;
+
+fn sample_session() -> UserSession {
+    let mut odd_block = BitVec::<u8,Lsb0>::from_vec(vec![0xc3, 0x01, 0xff]);
+    odd_block.truncate(20);
+    let mut id_generations = [0; 32];
+    id_generations[4] = 2;
+    id_generations[29] = 70_000;
+    UserSession {
+        address: PHONE_ADDR.to_string(),
+        is_encrypted: true,
+        shared_secret: [7; 32],
+        unused_ids: vec![1, 2, 3, 30],
+        id_generations,
+        bot_addresses: vec!["@a:test".to_string(), "@b:test".to_string()],
+        outgoing_messages: vec![StoredOutgoingMessage {
+            msg_id: 31,
+            msg_type: CommandValue::Data as u8,
+            ack_data: 0,
+            message: BitVec::<u8,Lsb0>::from_vec(b"\x00\x01hello".to_vec()),
+            unacked_blocks: vec![(0, BitVec::<u8,Lsb0>::from_vec(vec![0xdf, 0x01, 0x41])), (1, odd_block)],
+            send_attempts: 2,
+            cost: 5,
+            queued_at: 1_700_000_000,
+        }],
+        dead_letters: vec![user::DeadLetter { bot_address: "@b:test".to_string(), room_id: "!r:test".to_string(), content: b"missed".to_vec(), queued_at: 1_700_000_100 }],
+    }
+}
+
+#[test]
+pub fn test_session_sqlite_round_trip() {
+    let db_path = std::env::temp_dir().join(format!("boost_sessions_{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&db_path);
+
+    let session = sample_session();
+    SqliteSessionStore::open(&db_path).unwrap().save(&session).unwrap();
+    assert_eq!(SqliteSessionStore::open(&db_path).unwrap().load_all().unwrap(), vec![session.clone()]);
+    assert_eq!(std::fs::metadata(&db_path).unwrap().permissions().mode() & 0o777, 0o600);
+
+    // saving again replaces the old state rather than adding to it
+    let store = SqliteSessionStore::open(&db_path).unwrap();
+    let acked = UserSession { outgoing_messages: vec![], dead_letters: vec![], ..session };
+    store.save(&acked).unwrap();
+    assert_eq!(store.load_all().unwrap(), vec![acked]);
+
+    let _ = std::fs::remove_file(&db_path);
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_session_encryption_survives_restart() {
+    let (server, mut phone) = loopback();
+    let session_store = MemorySessionStore::new();
+    with_server_sessions(&server, &session_store, async {
+        phone.key_exchange().await;
+    }).await;
+
+    // a fresh main loop picks the shared secret up again, no DhkeInit needed
+    with_server_sessions(&server, &session_store, async {
+        phone.send_command(1, CommandValue::RequestDomains, &[]);
+        phone.recv_command(CommandValue::DomainUpdate).await;
+    }).await;
+}
+
+#[tokio::test(start_paused = true)]
+pub async fn test_session_outgoing_survives_restart() {
+    let (server_sms, phone_sms) = LoopbackSMSHandler::pair(SERVER_ADDR, PHONE_ADDR);
+    let phone = Phone::new(phone_sms);
+    let session_store = SqliteSessionStore::open_in_memory().unwrap();
+
+    let mut server_user = user::User::new(common::test_homeservers().await, PHONE_ADDR.to_string(), true, &server_sms);
+    server_user.shared_secret = [9; 32];
+    let message: Vec<u8> = (0..300).map(|i| i as u8).collect();
+    server_user.send_message(BitVec::<u8,Lsb0>::from_vec(message), false, true);
+    let msg_id = *server_user.outgoing_messages.keys().next().unwrap();
+
+    let mut first_send = vec![];
+    for _ in 0..3 { first_send.push(phone.recv_block().await); }
+    server_user.process_block_ack(&BitVec::<u8,Lsb0>::from_vec(vec![msg_id, first_send[0].raw[1]])).unwrap();
+    session_store.save(&server_user.session()).unwrap();
+    drop(server_user);
+
+    let session = session_store.load_all().unwrap().pop().unwrap();
+    let mut restored_user = user::User::restore(common::test_homeservers().await, session, &server_sms, &vec![]).await;
+    assert!(restored_user.is_encrypted);
+    assert_eq!(restored_user.shared_secret, [9; 32]);
+    assert!(!restored_user.unused_ids.contains(&msg_id));
+
+    // only the unacked blocks come back, byte for byte as they were first sent
+    tokio::time::sleep_until(restored_user.next_refresh_instant().unwrap()).await;
+    restored_user.refresh_outgoing();
+    let resent = [phone.recv_block().await, phone.recv_block().await];
+    assert_eq!(resent.iter().map(|b| &b.raw).collect::<Vec<_>>(), vec![&first_send[1].raw, &first_send[2].raw]);
+    phone.assert_silent(100).await;
+    assert_eq!(restored_user.outgoing_messages[&msg_id].send_attempts, 2);
+
+    // once it is all acked the id is free again, under the next generation
+    for block in &resent {
+        restored_user.process_block_ack(&BitVec::<u8,Lsb0>::from_vec(vec![msg_id, block.raw[1]])).unwrap();
+    }
+    assert_eq!(restored_user.unused_ids[0], msg_id);
+    assert_eq!(restored_user.id_generations[msg_id as usize], 1);
+}
+
+#[test]
+pub fn test_session_homeserver_login_saved() {
+    let store = SqliteSessionStore::open_in_memory().unwrap();
+    assert_eq!(store.load_homeserver_session("main").unwrap(), None);
+
+    let mut login = HomeserverSession {
+        name: "main".to_string(),
+        homeserver_url: "https://matrix.example.com".to_string(),
+        user_id: "@admin:matrix.example.com".to_string(),
+        device_id: "BOOSTDEV".to_string(),
+        access_token: "syt_first".to_string(),
+        refresh_token: None,
+    };
+    store.save_homeserver_session(&login).unwrap();
+    login.access_token = "syt_second".to_string();
+    store.save_homeserver_session(&login).unwrap();
+    assert_eq!(store.load_homeserver_session("main").unwrap(), Some(login));
+    assert_eq!(store.load_homeserver_session("other").unwrap(), None);
+}
+
+#[tokio::test]
+pub async fn test_session_homeserver_access_token() {
+    // a pre-issued token is used as is - no password, and nothing to save
+    let homeserver_creds = HomeserverCredentials {
+        address: "http://127.0.0.1:1".to_string(),
+        username: "@admin:matrix.example.com".to_string(),
+        password: String::new(),
+        access_token: Some("syt_issued".to_string()),
+        device_id: Some("BOOSTDEV".to_string()),
+    };
+    let store = MemorySessionStore::new();
+    let store_dir = std::env::temp_dir().join(format!("boost_{}_matrix_store_token", std::process::id()));
+    let client = homeserver::login("main", &homeserver_creds, &store_dir, &store).await.unwrap();
+    assert_eq!(client.device_id().unwrap().as_str(), "BOOSTDEV");
+    assert_eq!(client.access_token().as_deref(), Some("syt_issued"));
+    assert_eq!(store.load_homeserver_session("main").unwrap(), None);
+
+    let no_device = HomeserverCredentials { device_id: None, ..homeserver_creds };
+    assert!(homeserver::login("main", &no_device, &store_dir, &store).await.is_err());
+    let _ = std::fs::remove_dir_all(&store_dir);
+}
+
+#[tokio::test]
+pub async fn test_session_homeserver_crypto_store() {
+    let homeserver_creds = HomeserverCredentials {
+        address: "http://127.0.0.1:1".to_string(),
+        username: "@admin:matrix.example.com".to_string(),
+        password: String::new(),
+        access_token: Some("syt_issued".to_string()),
+        device_id: Some("BOOSTDEV".to_string()),
+    };
+    let store = MemorySessionStore::new();
+    let store_dir = std::env::temp_dir().join(format!("boost_{}_matrix_store_crypto", std::process::id()));
+
+    // the device keys are created on first login and kept on disk, so a restart is still the same device to everyone else
+    let client = homeserver::login("main", &homeserver_creds, &store_dir, &store).await.unwrap();
+    let identity = client.encryption().ed25519_key().await.unwrap();
+    assert!(homeserver::store_path(&store_dir, "main").is_dir());
+    drop(client);
+
+    let client = homeserver::login("main", &homeserver_creds, &store_dir, &store).await.unwrap();
+    assert_eq!(client.encryption().ed25519_key().await, Some(identity.clone()));
+
+    // each homeserver has a store of its own
+    let other = homeserver::login("other", &homeserver_creds, &store_dir, &store).await.unwrap();
+    assert_ne!(other.encryption().ed25519_key().await, Some(identity));
+    let _ = std::fs::remove_dir_all(&store_dir);
+}
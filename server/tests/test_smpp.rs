@@ -0,0 +1,239 @@
+use boost::block;
+use boost::smpp;
+use boost::sms::HandleSMS;
+
+use bitvec::prelude::*;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct SmscState {
+    binds: Vec<(String, String)>, // (system_id, password)
+    submits: Vec<smpp::ShortMessage>,
+    enquire_links: usize,
+    connection: Option<TcpStream>,
+    next_sequence: u32,
+    nack_submits: bool,  // answer submit_sm with generic_nack
+    hang_up_binds: usize,  // binds still to be answered by hanging up instead of a bind_transceiver_resp
+    unbound_pdus: usize,  // pdus other than a bind on a connection that never bound
+}
+
+// In-process SMSC stand-in, accepts one session at a time
+struct SmscStandIn {
+    address: String,
+    state: Arc<Mutex<SmscState>>,
+}
+
+impl SmscStandIn {
+    fn start(accepted_password: &'static str) -> SmscStandIn {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let state = Arc::new(Mutex::new(SmscState { next_sequence: 1, ..Default::default() }));
+
+        let server_state = state.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream { Ok(s) => s, Err(_) => continue };
+                let state = server_state.clone();
+                std::thread::spawn(move || serve(stream, state, accepted_password));
+            }
+        });
+
+        SmscStandIn { address, state }
+    }
+
+    fn config(&self, password: &str) -> smpp::SmppConfig {
+        let mut config = smpp::SmppConfig::new(&self.address, "boost", password, "+15550000000");
+        config.enquire_link_interval = Duration::from_millis(200);
+        config.reconnect_delay = Duration::from_millis(50);
+        config
+    }
+
+    fn deliver(&self, source: &str, payload: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.next_sequence;
+        state.next_sequence += 1;
+        let body = smpp::ShortMessage::new(source, "+15550000000", payload).encode();
+        let pdu = smpp::SmppPdu::new(smpp::DELIVER_SM, smpp::ESME_ROK, seq, body);
+        state.connection.as_mut().expect("No ESME connected").write_all(&pdu.encode()).unwrap();
+    }
+
+    fn drop_connection(&self) {
+        if let Some(conn) = self.state.lock().unwrap().connection.take() {
+            let _ = conn.shutdown(std::net::Shutdown::Both);
+        }
+    }
+}
+
+fn serve(mut stream: TcpStream, state: Arc<Mutex<SmscState>>, accepted_password: &str) {
+    state.lock().unwrap().connection = Some(stream.try_clone().unwrap());
+    let mut rx: Vec<u8> = vec![];
+    let mut bound = false;
+    loop {
+        let mut buf = [0u8; 1024];
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => rx.extend_from_slice(&buf[..n]),
+        }
+
+        while let Some(pdu) = smpp::SmppPdu::decode(&mut rx).unwrap() {
+            if pdu.command_id != smpp::BIND_TRANSCEIVER && !bound {
+                state.lock().unwrap().unbound_pdus += 1;
+            }
+            let resp = match pdu.command_id {
+                smpp::BIND_TRANSCEIVER => {
+                    let fields: Vec<String> = pdu.body.split(|b| *b == 0).take(2).map(|f| String::from_utf8_lossy(f).to_string()).collect();
+                    let status = if fields[1] == accepted_password { smpp::ESME_ROK } else { 0x0000000e };  // ESME_RINVPASWD
+                    let hang_up = {
+                        let mut state = state.lock().unwrap();
+                        state.binds.push((fields[0].clone(), fields[1].clone()));
+                        let hang_up = state.hang_up_binds > 0;
+                        state.hang_up_binds = state.hang_up_binds.saturating_sub(1);
+                        hang_up
+                    };
+                    if hang_up {
+                        // give the test time to queue something, then stop talking but keep listening for what the ESME sends
+                        std::thread::sleep(Duration::from_millis(200));
+                        let _ = stream.shutdown(std::net::Shutdown::Write);
+                        continue;
+                    }
+                    bound = status == smpp::ESME_ROK;
+                    let mut body = b"smsc".to_vec();
+                    body.push(0);
+                    Some(smpp::SmppPdu::new(smpp::BIND_TRANSCEIVER_RESP, status, pdu.sequence_number, body))
+                }
+                smpp::SUBMIT_SM => {
+                    let mut state = state.lock().unwrap();
+                    state.submits.push(smpp::ShortMessage::decode(&pdu.body).unwrap());
+                    if state.nack_submits {
+                        Some(smpp::SmppPdu::new(smpp::GENERIC_NACK, 0x00000008, pdu.sequence_number, vec![]))  // ESME_RSYSERR
+                    } else {
+                        Some(smpp::SmppPdu::new(smpp::SUBMIT_SM_RESP, smpp::ESME_ROK, pdu.sequence_number, b"id1\0".to_vec()))
+                    }
+                }
+                smpp::ENQUIRE_LINK => {
+                    state.lock().unwrap().enquire_links += 1;
+                    Some(smpp::SmppPdu::new(smpp::ENQUIRE_LINK_RESP, smpp::ESME_ROK, pdu.sequence_number, vec![]))
+                }
+                smpp::UNBIND => Some(smpp::SmppPdu::new(smpp::UNBIND_RESP, smpp::ESME_ROK, pdu.sequence_number, vec![])),
+                _ => None,
+            };
+            if let Some(resp) = resp {
+                if stream.write_all(&resp.encode()).is_err() { return; }
+            }
+        }
+    }
+}
+
+fn wait_for<T>(timeout_ms: u64, mut f: impl FnMut() -> Option<T>) -> T {
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+    while Instant::now() < deadline {
+        if let Some(v) = f() { return v; }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    panic!("Timed out waiting for SMPP session");
+}
+
+#[test]
+pub fn test_smpp_bind() {
+    let smsc = SmscStandIn::start("secret");
+    let _handler = smpp::SmppSMSHandler::new(smsc.config("secret")).expect("bind failed");
+    assert_eq!(smsc.state.lock().unwrap().binds, vec![("boost".to_string(), "secret".to_string())]);
+}
+
+#[test]
+pub fn test_smpp_bind_rejected() {
+    let smsc = SmscStandIn::start("secret");
+    assert!(smpp::SmppSMSHandler::new(smsc.config("wrong")).is_err());
+}
+
+#[test]
+pub fn test_smpp_submit() {
+    let smsc = SmscStandIn::start("secret");
+    let handler = smpp::SmppSMSHandler::new(smsc.config("secret")).expect("bind failed");
+
+    let test_block = block::Block::new("+15551234567".to_string(), BitVec::<u8,Lsb0>::from_vec(vec![0x01, 0x02, 0x03]));
+    handler.send_block("+15551234567", &test_block);
+
+    let submit = wait_for(2000, || smsc.state.lock().unwrap().submits.first().cloned());
+    assert_eq!(submit.destination(), "+15551234567");
+    assert_eq!(submit.source(), "+15550000000");
+    assert_eq!(submit.data_coding, 0x04);
+    assert_eq!(submit.short_message, vec![0x01, 0x02, 0x03]);
+}
+
+#[tokio::test]
+pub async fn test_smpp_deliver() {
+    let smsc = SmscStandIn::start("secret");
+    let handler = smpp::SmppSMSHandler::new(smsc.config("secret")).expect("bind failed");
+
+    smsc.deliver("+15551234567", vec![0xaa, 0xbb]);
+    let rx_block = tokio::time::timeout(Duration::from_secs(2), handler.recv_block()).await.expect("Timed out waiting for block").unwrap();
+    assert_eq!(rx_block.addr, "+15551234567");
+    assert_eq!(rx_block.data.into_vec(), vec![0xaa, 0xbb]);
+}
+
+#[test]
+pub fn test_smpp_enquire_link() {
+    let smsc = SmscStandIn::start("secret");
+    let _handler = smpp::SmppSMSHandler::new(smsc.config("secret")).expect("bind failed");
+
+    wait_for(3000, || if smsc.state.lock().unwrap().enquire_links >= 2 { Some(()) } else { None });
+}
+
+#[tokio::test]
+pub async fn test_smpp_reconnect() {
+    let smsc = SmscStandIn::start("secret");
+    let handler = smpp::SmppSMSHandler::new(smsc.config("secret")).expect("bind failed");
+
+    smsc.drop_connection();
+    wait_for(3000, || if smsc.state.lock().unwrap().binds.len() == 2 { Some(()) } else { None });
+
+    // the new session carries traffic both ways
+    let test_block = block::Block::new("+15551234567".to_string(), BitVec::<u8,Lsb0>::from_vec(vec![0x07]));
+    handler.send_block("+15551234567", &test_block);
+    wait_for(2000, || smsc.state.lock().unwrap().submits.first().cloned());
+
+    wait_for(2000, || smsc.state.lock().unwrap().connection.as_ref().map(|_| ()));
+    smsc.deliver("+15551234567", vec![0x08]);
+    let rx_block = tokio::time::timeout(Duration::from_secs(2), handler.recv_block()).await.expect("Timed out waiting for block").unwrap();
+    assert_eq!(rx_block.data.into_vec(), vec![0x08]);
+}
+
+#[test]
+pub fn test_smpp_hang_up_during_bind() {
+    let smsc = SmscStandIn::start("secret");
+    let handler = smpp::SmppSMSHandler::new(smsc.config("secret")).expect("bind failed");
+
+    smsc.state.lock().unwrap().hang_up_binds = 1;
+    smsc.drop_connection();
+    wait_for(3000, || if smsc.state.lock().unwrap().binds.len() == 2 { Some(()) } else { None });
+    let test_block = block::Block::new("+15551234567".to_string(), BitVec::<u8,Lsb0>::from_vec(vec![0x0a]));
+    handler.send_block("+15551234567", &test_block);
+
+    // the failed bind is followed by a new connection, nothing goes out on the one that never bound
+    wait_for(3000, || smsc.state.lock().unwrap().submits.first().cloned());
+    let state = smsc.state.lock().unwrap();
+    assert_eq!(state.binds.len(), 3);
+    assert_eq!(state.unbound_pdus, 0);
+}
+
+#[test]
+pub fn test_smpp_generic_nack() {
+    let smsc = SmscStandIn::start("secret");
+    let handler = smpp::SmppSMSHandler::new(smsc.config("secret")).expect("bind failed");
+    smsc.state.lock().unwrap().nack_submits = true;
+
+    let test_block = block::Block::new("+15551234567".to_string(), BitVec::<u8,Lsb0>::from_vec(vec![0x09]));
+    handler.send_block("+15551234567", &test_block);
+    wait_for(2000, || smsc.state.lock().unwrap().submits.first().cloned());
+    std::thread::sleep(Duration::from_millis(100));
+
+    // the nacked submit is done with, a new session does not send it again
+    smsc.drop_connection();
+    wait_for(3000, || if smsc.state.lock().unwrap().binds.len() == 2 { Some(()) } else { None });
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(smsc.state.lock().unwrap().submits.len(), 1);
+}
@@ -141,13 +141,77 @@ class CommandHandler:
         cli.agent.send_msg("SignOut", hex(domain_idx)[2:])
 
     def handle_revoke_all_clients(cli, com):
-        cli.display("Error: Unimplemented (RevokeAllClients)", lvl="err")
+        if not (com and len(com.split(' ')) in (4, 5)):
+            cli.display("Incorrect format", lvl='err')
+            return
+
+        domain_idx = com.split(' ')[1]
+        try:
+            domain_idx = int(domain_idx)
+            assert(cli.agent.domains[domain_idx] != None)
+        except (ValueError, AssertionError):
+            cli.display("Invalid domain", lvl="err")
+            return
+
+        username = bytes(com.split(' ')[2], 'utf-8').hex()
+        password = bytes(com.split(' ')[3], 'utf-8').hex()
+        new_password = "00" + bytes(com.split(' ')[4], 'utf-8').hex() if len(com.split(' ')) == 5 else ''
+        cli.agent.send_msg("RevokeAllClients", f"{domain_idx:02x}" + username + "00" + password + new_password)
         
     def handle_finduser(cli, com):
         if not (com and len(com.split(' ')) == 3):
             cli.display("Incorrect format", lvl='err')
             return
-        cli.display("Error: Unimplemented (FindUser)", lvl="err")
+
+        domain_idx = com.split(' ')[1]
+        try:
+            domain_idx = int(domain_idx)
+            assert(cli.agent.domains[domain_idx] != None)
+        except (ValueError, AssertionError):
+            cli.display("Invalid domain", lvl="err")
+            return
+
+        handle = bytes(com.split(' ')[2], 'utf-8').hex()
+        cli.display("Asking the bridge, this can take a while", lvl="prod")
+        cli.agent.send_msg("FindUser", f"{domain_idx:02x}" + handle)
+
+    def handle_fetchmedia(cli, com):
+        if not (com and len(com.split(' ')) in (3, 4)):
+            cli.display("Incorrect format", lvl='err')
+            return
+
+        domain_idx = com.split(' ')[1]
+        try:
+            domain_idx = int(domain_idx)
+            assert(cli.agent.domains[domain_idx] != None)
+        except (ValueError, AssertionError):
+            cli.display("Invalid domain", lvl="err")
+            return
+
+        try:
+            budget = int(com.split(' ')[3]) if len(com.split(' ')) == 4 else 0  # 0 for the server's
+            assert(0 <= budget < 1<<32)
+        except (ValueError, AssertionError):
+            cli.display("Invalid budget", lvl="err")
+            return
+
+        uri = bytes(com.split(' ')[2], 'utf-8').hex()
+        cli.agent.send_msg("FetchMedia", f"{domain_idx:02x}{budget:08x}" + uri)
+
+    def handle_cancelmedia(cli, com):
+        if not (com and len(com.split(' ')) == 2):
+            cli.display("Incorrect format", lvl='err')
+            return
+
+        try:
+            transfer_id = int(com.split(' ')[1])
+            assert(transfer_id in cli.agent.media_transfers)
+        except (ValueError, AssertionError):
+            cli.display("Invalid transfer", lvl="err")
+            return
+
+        del cli.agent.media_transfers[transfer_id]
+        cli.agent.send_msg("CancelMedia", f"{transfer_id:04x}")
 
 
 
@@ -167,6 +231,8 @@ CommandHandler.COMMAND_PREFIX_FUNCS = {
     ".logout": CommandHandler.handle_logout,
     ".revokeall": CommandHandler.handle_revoke_all_clients,
     ".finduser": CommandHandler.handle_finduser,
+    ".fetchmedia": CommandHandler.handle_fetchmedia,
+    ".cancelmedia": CommandHandler.handle_cancelmedia,
 }
 
 class ResponseCommandHandler:
@@ -213,7 +279,49 @@ class ResponseCommandHandler:
         domain_idx  = int(dat[:2], 16)
         cli.agent.users[domain_idx] = bytes.fromhex(dat[2:]).decode('utf-8').split('\x00')
         cli.display(f"New data on domain {domain_idx}", lvl='prod')
-        cli.display(f'{f'\n{' ' * 8}'.join([f'[{i}] {u}' for i,u in enumerate(cli.agent.users[domain_idx])])}', lvl='prod')
+        cli.display(f'{f'\n{' ' * 8}'.join([f'[{i}] {u}' for i,u in enumerate(cli.agent.users[domain_idx]) if u])}', lvl='prod')  # rooms that left the space are empty
+
+    def recvhandle_deliverysuccess(cli, dat):
+        cli.display(f"Msg {int(dat[:2], 16)} delivered", lvl='prod')
+
+    def recvhandle_error(cli, dat):
+        cli.display(f"Msg {int(dat[:2], 16)} failed: {bytes.fromhex(dat[2:]).decode('utf-8', errors='ignore')}", lvl='err')
+
+    def recvhandle_userfound(cli, dat):
+        # [u8 msg_id of find_user][u8 domain_idx][u8 user_idx]
+        domain_idx = int(dat[2:4], 16)
+        user_idx = int(dat[4:6], 16)
+        cli.display(f"Found user, send to {user_idx}@{domain_idx}", lvl='prod')
+
+    def recvhandle_usernotfound(cli, dat):
+        cli.display(f"User not found: {bytes.fromhex(dat[2:]).decode('utf-8', errors='ignore')}", lvl='err')
+
+    def recvhandle_mediastart(cli, dat):
+        # [u8 msg_id of fetch_media][u16 transfer_id][u32 total bytes][mimetype]
+        transfer_id = int(dat[2:6], 16)
+        total = int(dat[6:14], 16)
+        mimetype = bytes.fromhex(dat[14:]).decode('utf-8', errors='ignore')
+        cli.agent.media_transfers[transfer_id] = (mimetype, total, bytearray(total))
+        cli.display(f"Receiving {total} bytes of {mimetype} as transfer {transfer_id}", lvl='prod')
+
+    def recvhandle_mediachunk(cli, dat):
+        # [u16 transfer_id][u32 offset][bytes]
+        transfer_id = int(dat[:4], 16)
+        offset = int(dat[4:12], 16)
+        if transfer_id not in cli.agent.media_transfers:
+            cli.display(f"Chunk for unknown transfer {transfer_id}", lvl='warn')
+            return
+
+        (mimetype, total, data) = cli.agent.media_transfers[transfer_id]
+        chunk = bytes.fromhex(dat[12:])
+        data[offset:offset + len(chunk)] = chunk
+        cli.display(f"Transfer {transfer_id}: {offset + len(chunk)}/{total} bytes", lvl='prod')
+        if offset + len(chunk) >= total:
+            del cli.agent.media_transfers[transfer_id]
+            path = f"media_{transfer_id}.{mimetype.split('/')[-1]}"
+            with open(path, 'wb') as f:
+                f.write(data)
+            cli.display(f"Saved transfer {transfer_id} to {path}", lvl='prod')
 
     def recvhandle_signoutsuccess(cli, dat):
         domain_idx = int(dat[:2], 16)
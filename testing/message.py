@@ -22,6 +22,12 @@ class Message:
         "FindUser": 19,
         "UserFound": 20,
         "DeliverySuccess": 21,
+        "MissingBlocks": 22,
+        "FlushQueue": 23,
+        "FetchMedia": 24,
+        "MediaStart": 25,
+        "MediaChunk": 26,
+        "CancelMedia": 27,
     }
 
     NEEDS_ACK = {
@@ -44,8 +50,14 @@ class Message:
         "SignOutSuccess": 1,
         "DomainUpdate": 1,
         "FindUser": 0,
-        "UserFound": 0,
+        "UserFound": 1,
         "DeliverySuccess": 0,
+        "MissingBlocks": 0,
+        "FlushQueue": 0,
+        "FetchMedia": 0,
+        "MediaStart": 1,
+        "MediaChunk": 1,
+        "CancelMedia": 0,
     }
     NO_DELETE_ON_ACK = {
         "DAT": 0,
@@ -69,6 +81,12 @@ class Message:
         "FindUser": 0,
         "UserFound": 0,
         "DeliverySuccess": 0,
+        "MissingBlocks": 0,
+        "FlushQueue": 0,
+        "FetchMedia": 0,
+        "MediaStart": 0,
+        "MediaChunk": 0,
+        "CancelMedia": 0,
 
     }
 
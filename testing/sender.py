@@ -23,6 +23,7 @@ class Sender:
         self.users = [[None for i in range(256)] for j in range(256)]  # [userInfo0, userInfo1, ...]
         self.domains = [None for i in range(256)]  # username@service-name, ...
         self.outstanding_mp_msgs = {}  # Map<MsgId: PartialMessage>  - used for INCOMING messages
+        self.media_transfers = {}  # { transfer_id: (mimetype, total bytes, bytearray) }
         
         self.sock = sock
         self.sock_path = sock_path
@@ -81,9 +82,11 @@ class Sender:
         
         return data
 
-    def get_nonce(self, msg_id, block_id, dir):
+    def get_nonce(self, msg_id, block_id, dir, generation=0):
         i = msg_id * 256 + block_id
         info = dir + i.to_bytes(2, 'big')
+        if generation:
+            info += generation.to_bytes(4, 'big')
         
         hkdf = HKDFExpand(
             algorithm=hashes.SHA256(),
@@ -105,14 +108,14 @@ class Sender:
         ciphertext = cipher.encrypt(msg_bytes)
         return ciphertext
 
-    def decrypt_msg(self, msg_id, block_id, msg_hex):
+    def decrypt_msg(self, msg_id, block_id, msg_hex, generation=0):
         if not self.is_enc:
             return msg_hex
 
         if not (msg_id | block_id):
             return msg_hex
 
-        nonce = self.get_nonce(msg_id, block_id, b"s2c")
+        nonce = self.get_nonce(msg_id, block_id, b"s2c", generation)
         cipher = ChaCha20.new(key=self.enc_key, nonce=nonce)
         plaintext = cipher.decrypt(bytes.fromhex(msg_hex))
         return plaintext.hex()
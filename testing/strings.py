@@ -15,8 +15,10 @@ HELP_MSG = "\
 \x1b[1m                               \x1b[38;5;203m.reqdomains\x1b[0m  Request an updated list of domains from the server\n\
 \x1b[1m                        \x1b[38;5;203m.requsers [domain]\x1b[0m  Request an updated list of users and indices on [domain]\n\
 \x1b[1m                    \x1b[38;5;203m.logout [domain index]\x1b[0m  Sign out of domain [domain index]\n\
-\x1b[1m                 \x1b[38;5;203m.revokeall [domain index]\x1b[0m  Sign out of domain [domain index] on all clients\n\
+\x1b[1m \x1b[38;5;203m.revokeall [idx] [user] [pass] [new pass]\x1b[0m  Sign out of domain [idx] on all clients with its [user] and [pass], optionally changing the password to [new pass]\n\
 \x1b[1m         \x1b[38;5;203m.finduser [domain index] [handle]\x1b[0m  Find user with handle [handle] on domain [domain index]\n\
+\x1b[1m \x1b[38;5;203m.fetchmedia [domain index] [mxc] [budget]\x1b[0m  Download media [mxc] on domain [domain index], shrunk to [budget] bytes (optional)\n\
+\x1b[1m                \x1b[38;5;203m.cancelmedia [transfer id]\x1b[0m  Stop receiving transfer [transfer id]\n\
 "
  
 PH_INPUT = "\x1b[1m\x1b[38;5;218m ph# \x1b[0m "
@@ -77,7 +77,9 @@ class Cli:
             is_mp_first = data_vals[0]
             is_command = data_vals[2]
             block_id = int(payload[:2], 16)
-            actual_payload = self.agent.decrypt_msg(msg_id, 0 if is_mp_first else block_id+1, payload[2:])
+            block_pos = 0 if is_mp_first else block_id+1
+            generation = int(payload[2:10], 16)  # the server reuses msg_ids, this tells the messages on one apart
+            actual_payload = self.agent.decrypt_msg(msg_id, block_pos, payload[10:], generation)
 
             if msg_id not in self.agent.outstanding_mp_msgs:
                 self.agent.outstanding_mp_msgs[msg_id] = PartialMessage(msg_id)
@@ -92,7 +94,8 @@ class Cli:
                 processableMsg = full_msg
         else:
             is_command = data_vals[2]
-            processableMsg = self.agent.decrypt_msg(msg_id, 0, payload)
+            generation = int(payload[:8], 16)
+            processableMsg = self.agent.decrypt_msg(msg_id, 0, payload[8:], generation)
             command_id = int(processableMsg[:2], 16)
             if not is_command or Message.NEEDS_ACK[Message.COMMANDS_REVERSE[command_id]]:
                 self.agent.send_msg("BlockAck", f'{msg_id:0>2X}' + '00')
@@ -146,12 +149,30 @@ class Cli:
             elif Message.COMMANDS_REVERSE[command_type] == "ChannelUpdate":
                 ResponseCommandHandler.recvhandle_chupdate(self, payload)
 
+            elif Message.COMMANDS_REVERSE[command_type] == "DeliverySuccess":
+                ResponseCommandHandler.recvhandle_deliverysuccess(self, payload)
+
+            elif Message.COMMANDS_REVERSE[command_type] == "Error":
+                ResponseCommandHandler.recvhandle_error(self, payload)
+
+            elif Message.COMMANDS_REVERSE[command_type] == "UserFound":
+                ResponseCommandHandler.recvhandle_userfound(self, payload)
+
+            elif Message.COMMANDS_REVERSE[command_type] == "TargetUserNotFound":
+                ResponseCommandHandler.recvhandle_usernotfound(self, payload)
+
             elif Message.COMMANDS_REVERSE[command_type] == "SignOutSuccess":
                 ResponseCommandHandler.recvhandle_signoutsuccess(self, payload)
 
             elif Message.COMMANDS_REVERSE[command_type] == "DomainUpdate":
                 ResponseCommandHandler.recvhandle_domainupdate(self, payload)
 
+            elif Message.COMMANDS_REVERSE[command_type] == "MediaStart":
+                ResponseCommandHandler.recvhandle_mediastart(self, payload)
+
+            elif Message.COMMANDS_REVERSE[command_type] == "MediaChunk":
+                ResponseCommandHandler.recvhandle_mediachunk(self, payload)
+
 
     def user_input(self):
         command = input(strings.COMMAND_INPUT)